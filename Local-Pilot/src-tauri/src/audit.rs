@@ -0,0 +1,219 @@
+//! 工具调用审计日志
+//! 将每次工具调用（执行、批准、拒绝、失败）的结果写入DuckDB，只追加、不修改，供用户事后查看
+
+use crate::error::LocalPilotError;
+use crate::lock::LockExt;
+use crate::orchestrator::{ToolCallResult, ToolCallStatus};
+use chrono::{DateTime, SecondsFormat, Utc};
+use duckdb::{params, Connection, ToSql};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager};
+
+/// 未指定条数时返回的审计记录数
+pub const DEFAULT_AUDIT_LIMIT: u32 = 100;
+
+/// 一条审计记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// 记录时间，RFC 3339格式（UTC，精确到毫秒）
+    pub timestamp: String,
+    pub tool_name: String,
+    pub arguments: Value,
+    pub status: ToolCallStatus,
+    /// 与反馈给模型的结果相同，超过大小上限的部分已被截断
+    pub result: Option<Value>,
+    pub error: Option<String>,
+    pub truncated: bool,
+}
+
+/// 审计日志，使用独立的数据库文件，与文件索引互不影响
+pub struct AuditLog {
+    db_connection: Mutex<Connection>,
+}
+
+fn format_timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+impl AuditLog {
+    /// 打开（或创建）审计日志数据库
+    pub fn new(db_path: &str) -> Result<Self, LocalPilotError> {
+        let conn = Connection::open(db_path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS tool_audit (
+                timestamp TEXT NOT NULL,
+                tool_name TEXT NOT NULL,
+                arguments TEXT NOT NULL,
+                status TEXT NOT NULL,
+                result TEXT,
+                error TEXT,
+                truncated BOOLEAN NOT NULL
+            )",
+            [],
+        )?;
+
+        Ok(Self { db_connection: Mutex::new(conn) })
+    }
+
+    /// 记录一次工具调用的结果
+    pub fn record(&self, result: &ToolCallResult) -> Result<(), LocalPilotError> {
+        let output = result.result.as_ref().map(Value::to_string);
+
+        let conn = self.db_connection.lock_or_recover();
+        conn.execute(
+            "INSERT INTO tool_audit (timestamp, tool_name, arguments, status, result, error, truncated)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                format_timestamp(Utc::now()),
+                result.tool_name,
+                result.arguments.to_string(),
+                result.status.as_str(),
+                output,
+                result.error,
+                result.truncated,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// 最近的审计记录，最新的在前；指定 `since` 时只返回该时间及之后的记录
+    pub fn entries(&self, limit: u32, since: Option<DateTime<Utc>>) -> Result<Vec<AuditEntry>, LocalPilotError> {
+        let since = since.map(format_timestamp).unwrap_or_default();
+        self.query("timestamp >= ?1", params![since, limit])
+    }
+
+    /// 指定状态的工具调用记录，最新的在前；`status` 为 `None` 时不按状态过滤
+    pub fn tool_calls(&self, status: Option<&ToolCallStatus>, limit: u32) -> Result<Vec<AuditEntry>, LocalPilotError> {
+        match status {
+            Some(status) => self.query("status = ?1", params![status.as_str(), limit]),
+            None => self.entries(limit, None),
+        }
+    }
+
+    /// 按条件查询记录，`condition` 使用参数 `?1`，`?2` 为条数上限
+    fn query(&self, condition: &str, query_params: &[&dyn ToSql]) -> Result<Vec<AuditEntry>, LocalPilotError> {
+        let conn = self.db_connection.lock_or_recover();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT timestamp, tool_name, arguments, status, result, error, truncated
+             FROM tool_audit
+             WHERE {}
+             ORDER BY timestamp DESC, rowid DESC
+             LIMIT ?2",
+            condition
+        ))?;
+        let rows = stmt
+            .query_map(query_params, |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                    row.get::<_, bool>(6)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        rows.into_iter()
+            .map(|(timestamp, tool_name, arguments, status, result, error, truncated)| {
+                Ok(AuditEntry {
+                    timestamp,
+                    tool_name,
+                    arguments: serde_json::from_str(&arguments)?,
+                    status: serde_json::from_value(Value::String(status))?,
+                    result: result.map(|result| serde_json::from_str(&result)).transpose()?,
+                    error,
+                    truncated,
+                })
+            })
+            .collect()
+    }
+}
+
+/// 在应用数据目录中打开审计日志
+pub fn initialize_audit_log(app_handle: &AppHandle) -> Result<AuditLog, LocalPilotError> {
+    let app_data_dir = app_handle.path().app_data_dir()?;
+    std::fs::create_dir_all(&app_data_dir)?;
+    let db_path = app_data_dir.join("tool_audit.db");
+    AuditLog::new(&db_path.to_string_lossy())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_audit_log() -> AuditLog {
+        let db_path = std::env::temp_dir().join(format!("test_audit_{}.db", uuid::Uuid::new_v4()));
+        AuditLog::new(db_path.to_str().unwrap()).unwrap()
+    }
+
+    fn result(tool_name: &str, status: ToolCallStatus) -> ToolCallResult {
+        ToolCallResult {
+            tool_name: tool_name.to_string(),
+            arguments: serde_json::json!({ "path": "/tmp/a.txt" }),
+            status,
+            result: Some(serde_json::json!({ "content": "hello" })),
+            error: None,
+            truncated: false,
+            images: Vec::new(),
+            cached: false,
+            tool_use_id: None,
+        }
+    }
+
+    #[test]
+    fn test_entries_are_returned_newest_first_with_limit_and_since() {
+        let log = test_audit_log();
+        log.record(&result("fs__read_file", ToolCallStatus::Executed)).unwrap();
+        log.record(&result("fs__delete_file", ToolCallStatus::Rejected)).unwrap();
+
+        let entries = log.entries(DEFAULT_AUDIT_LIMIT, None).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].tool_name, "fs__delete_file");
+        assert!(matches!(entries[0].status, ToolCallStatus::Rejected));
+        assert_eq!(entries[1].arguments["path"], "/tmp/a.txt");
+        assert_eq!(entries[1].result.as_ref().unwrap()["content"], "hello");
+
+        assert_eq!(log.entries(1, None).unwrap().len(), 1);
+        let future = Utc::now() + chrono::Duration::hours(1);
+        assert!(log.entries(DEFAULT_AUDIT_LIMIT, Some(future)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tool_calls_are_filtered_by_status() {
+        let log = test_audit_log();
+        log.record(&result("fs__read_file", ToolCallStatus::Executed)).unwrap();
+        log.record(&ToolCallResult { error: Some("not found".to_string()), ..result("fs__read_file", ToolCallStatus::Failed) })
+            .unwrap();
+        log.record(&result("fs__write_file", ToolCallStatus::PendingApproval)).unwrap();
+        log.record(&result("fs__list_directory", ToolCallStatus::Failed)).unwrap();
+
+        let failed = log.tool_calls(Some(&ToolCallStatus::Failed), DEFAULT_AUDIT_LIMIT).unwrap();
+        assert_eq!(failed.len(), 2);
+        assert!(failed.iter().all(|entry| matches!(entry.status, ToolCallStatus::Failed)));
+        assert_eq!(failed[0].tool_name, "fs__list_directory");
+        assert_eq!(failed[1].error.as_deref(), Some("not found"));
+
+        let pending = log.tool_calls(Some(&ToolCallStatus::PendingApproval), DEFAULT_AUDIT_LIMIT).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].tool_name, "fs__write_file");
+        assert!(log.tool_calls(Some(&ToolCallStatus::Rejected), DEFAULT_AUDIT_LIMIT).unwrap().is_empty());
+
+        assert_eq!(log.tool_calls(Some(&ToolCallStatus::Failed), 1).unwrap().len(), 1);
+        assert_eq!(log.tool_calls(None, DEFAULT_AUDIT_LIMIT).unwrap().len(), 4);
+
+        // 保存的名称与序列化结果一致，旧记录也能按状态查到
+        for status in [
+            ToolCallStatus::PendingApproval,
+            ToolCallStatus::Approved,
+            ToolCallStatus::Executed,
+            ToolCallStatus::Failed,
+            ToolCallStatus::Rejected,
+        ] {
+            assert_eq!(serde_json::to_value(&status).unwrap(), status.as_str());
+        }
+    }
+}
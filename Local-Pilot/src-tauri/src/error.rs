@@ -0,0 +1,125 @@
+//! 统一错误类型
+//! 区分超时、服务器退出、协议错误等情况，便于Tauri命令返回有针对性的提示
+
+use std::time::Duration;
+use thiserror::Error;
+
+/// 应用内统一的错误类型
+#[derive(Debug, Error)]
+pub enum LocalPilotError {
+    /// 等待响应超时
+    #[error("Timeout waiting for response after {0:?}")]
+    Timeout(Duration),
+
+    /// MCP服务器进程已退出或连接已关闭
+    #[error("MCP server exited")]
+    ServerExited,
+
+    /// 收到不符合预期的协议消息，或服务器返回了错误
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+
+    /// 组件尚未初始化
+    #[error("{0} not initialized")]
+    NotInitialized(String),
+
+    /// 参数或用户输入无效
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+
+    /// 用户取消了正在处理的消息
+    #[error("Cancelled")]
+    Cancelled,
+
+    /// 上一条消息仍在处理，且配置为拒绝同时处理多条消息
+    #[error("Busy: another message is still being processed")]
+    Busy,
+
+    /// 访问的路径不在允许的目录内
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    /// LLM API返回了非2xx状态码
+    #[error("API returned {status}: {message}")]
+    Api { status: u16, message: String },
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Database error: {0}")]
+    Db(#[from] duckdb::Error),
+
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Invalid pattern: {0}")]
+    Pattern(#[from] globset::Error),
+
+    #[error("Tauri error: {0}")]
+    Tauri(#[from] tauri::Error),
+}
+
+impl LocalPilotError {
+    /// 面向用户的错误提示
+    pub fn user_message(&self) -> String {
+        match self {
+            LocalPilotError::Timeout(duration) => format!(
+                "The MCP server did not respond within {} seconds. Try again or increase the timeout.",
+                duration.as_secs_f64()
+            ),
+            LocalPilotError::ServerExited => {
+                "The MCP server has stopped. Reconnect it and try again.".to_string()
+            }
+            LocalPilotError::Protocol(message) => {
+                format!("The MCP server returned an unexpected response: {}", message)
+            }
+            LocalPilotError::NotInitialized(component) => {
+                format!("{} is still starting up. Please try again in a moment.", component)
+            }
+            LocalPilotError::InvalidInput(message) => message.clone(),
+            LocalPilotError::Cancelled => "The request was cancelled.".to_string(),
+            LocalPilotError::Busy => {
+                "Another message is still being processed. Wait for it to finish or cancel it.".to_string()
+            }
+            LocalPilotError::PermissionDenied(message) => {
+                format!("Access was denied because the path is outside the allowed folders: {}", message)
+            }
+            LocalPilotError::Api { status: 401 | 403, message } => {
+                format!("The AI service rejected the API key ({})", message)
+            }
+            LocalPilotError::Api { status: 429, message } => {
+                format!("The AI service is rate limiting requests. Please wait and try again. ({})", message)
+            }
+            LocalPilotError::Api { status, message } => {
+                format!("The AI service returned an error ({}): {}", status, message)
+            }
+            LocalPilotError::Io(e) => format!("File system error: {}", e),
+            LocalPilotError::Db(e) => format!("The file index database reported an error: {}", e),
+            LocalPilotError::Json(e) => format!("Failed to parse data: {}", e),
+            LocalPilotError::Http(e) => format!("Failed to reach the AI service: {}", e),
+            LocalPilotError::Pattern(e) => format!("Invalid ignore pattern: {}", e),
+            LocalPilotError::Tauri(e) => format!("Application error: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variants_map_to_distinct_user_messages() {
+        let timeout = LocalPilotError::Timeout(Duration::from_secs(5)).user_message();
+        let exited = LocalPilotError::ServerExited.user_message();
+        let not_initialized = LocalPilotError::NotInitialized("File indexer".to_string()).user_message();
+        assert!(timeout.contains("5 seconds"));
+        assert!(exited.contains("stopped"));
+        assert!(not_initialized.starts_with("File indexer"));
+
+        let io: LocalPilotError = std::io::Error::new(std::io::ErrorKind::NotFound, "gone").into();
+        assert!(matches!(io, LocalPilotError::Io(_)));
+    }
+}
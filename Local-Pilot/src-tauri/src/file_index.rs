@@ -1,15 +1,24 @@
 //! 本地文件索引模块
 //! 使用DuckDB创建和维护本地文件索引
 
-use duckdb::{Connection, params, types::Value};
-use std::path::{Path, PathBuf};
+use crate::error::LocalPilotError;
+use crate::lock::{LockExt, RwLockExt};
+use duckdb::{Connection, params, types::Value, ToSql};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
 use std::fs;
-use walkdir::WalkDir;
+use std::io::{BufWriter, Write};
+use std::time::SystemTime;
+use walkdir::{DirEntry, WalkDir};
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use directories::UserDirs;
-use tauri::AppHandle;
+use tauri::{AppHandle, Manager};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, RwLock, TryLockError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use tokio::sync::OnceCell;
+use tokio_util::sync::CancellationToken;
 
 /// 文件信息结构
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,16 +30,462 @@ pub struct FileInfo {
     pub modified: String, // ISO 8601格式
     pub created: String,  // ISO 8601格式
     pub is_directory: bool,
+    /// 路径本身是符号链接，其余字段描述链接指向的目标
+    #[serde(default)]
+    pub is_symlink: bool,
+    /// 权限位（如 `0o755`，含setuid、setgid和sticky位），仅Unix
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// 所有者的用户id和组id，仅Unix
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+}
+
+/// 内容搜索的匹配结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentMatch {
+    pub file: FileInfo,
+    /// 匹配位置前后的文本片段
+    pub snippet: String,
+}
+
+/// 超过此大小的文件不索引内容
+pub const MAX_CONTENT_SIZE: u64 = 1024 * 1024;
+
+/// 会索引内容的文本类扩展名
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "markdown", "rst", "log", "csv", "json", "toml", "yaml", "yml", "ini", "cfg", "conf",
+    "xml", "html", "htm", "css", "js", "jsx", "ts", "tsx", "rs", "py", "go", "java", "c", "h", "cpp",
+    "hpp", "sh", "sql",
+];
+
+/// 内容片段在匹配位置前后各保留的字符数
+const SNIPPET_CONTEXT: usize = 60;
+
+/// 清理失效条目时每批检查的路径数
+const PRUNE_BATCH_SIZE: u32 = 1000;
+
+/// 超过此大小的文件不计算内容哈希
+pub const MAX_HASH_SIZE: u64 = 256 * 1024 * 1024;
+
+/// 内容相同的一组文件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateGroup {
+    pub hash: String,  // 文件内容的BLAKE3哈希（十六进制）
+    pub size: u64,     // 单个文件的大小
+    pub files: Vec<FileInfo>,
+}
+
+/// 默认每页返回的搜索结果数量
+pub const DEFAULT_SEARCH_LIMIT: u32 = 100;
+
+/// 索引统计中列出的扩展名数量
+const TOP_EXTENSIONS_LIMIT: u32 = 10;
+
+/// 分页搜索结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchPage {
+    pub files: Vec<FileInfo>,
+    pub total: u64,  // 匹配的总条数，用于显示"showing 100 of N"
+    pub limit: u32,
+    pub offset: u32,
+}
+
+/// 索引统计中单个扩展名的文件数
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtensionCount {
+    pub extension: Option<String>,  // 无扩展名的文件为None
+    pub count: u64,
+}
+
+/// 索引统计，目录不计入文件数和总大小
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexStats {
+    pub total_files: u64,
+    pub total_directories: u64,
+    pub total_size: u64,
+    pub top_extensions: Vec<ExtensionCount>,  // 文件数最多的前10个扩展名
+}
+
+/// 扫描时过滤文件的条件，目录只受 `skip_hidden` 影响
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IndexFilter {
+    pub min_size: u64,                    // 小于此字节数的文件不索引
+    pub skip_empty: bool,                 // 不索引空文件
+    pub skip_hidden: bool,                // 不索引以 `.` 开头的文件和目录（整个子树）
+    pub include_extensions: Vec<String>,  // 非空时只索引这些扩展名的文件
+    pub exclude_extensions: Vec<String>,  // 不索引这些扩展名的文件
+}
+
+impl IndexFilter {
+    /// 扩展名统一为不带 `.` 的小写形式
+    fn normalized(mut self) -> Self {
+        for extensions in [&mut self.include_extensions, &mut self.exclude_extensions] {
+            *extensions = extensions
+                .iter()
+                .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+                .filter(|extension| !extension.is_empty())
+                .collect();
+            extensions.sort();
+            extensions.dedup();
+        }
+        self
+    }
+
+    /// 条目是否应当被索引
+    pub fn accepts(&self, file_info: &FileInfo) -> bool {
+        if self.skip_hidden && is_hidden_name(&file_info.name) {
+            return false;
+        }
+        if file_info.is_directory {
+            return true;
+        }
+        if file_info.size < self.min_size || (self.skip_empty && file_info.size == 0) {
+            return false;
+        }
+        let extension = file_info.extension.as_deref().map(str::to_lowercase);
+        let listed = |extensions: &[String]| extension.as_ref().is_some_and(|extension| extensions.contains(extension));
+        if !self.include_extensions.is_empty() && !listed(&self.include_extensions) {
+            return false;
+        }
+        !listed(&self.exclude_extensions)
+    }
+}
+
+/// 以 `.` 开头的文件和目录在类Unix系统中默认隐藏
+fn is_hidden_name(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+/// 设置表中保存扫描过滤条件的键
+const INDEX_FILTER_SETTING: &str = "index_filter";
+
+/// 索引数据库的存储占用
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StorageStats {
+    pub file_size: u64,  // 数据库文件及其WAL在磁盘上的字节数
+    pub row_count: u64,  // 文件表的行数
+    pub used_size: u64,  // 数据库中已使用的块的字节数
+    pub free_size: u64,  // 空闲块的字节数
+}
+
+/// 压缩索引数据库的结果
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VacuumSummary {
+    pub reclaimed_bytes: u64,  // 磁盘上减少的字节数
+    pub storage: StorageStats, // 压缩后的占用
+}
+
+/// 索引导出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// `FileInfo` 对象组成的JSON数组
+    Json,
+    /// 带表头的CSV，列与 `FileInfo` 的字段相同
+    Csv,
+}
+
+/// 索引中存储的权限位、用户id和组id
+type Permissions = (Option<u32>, Option<u32>, Option<u32>);
+
+/// `row_to_file_info` 读取的列，所有返回 `FileInfo` 的查询都以这些列开头
+const FILE_COLUMNS: &str = "path, name, extension, size, modified, created, is_directory, is_symlink, mode, uid, gid";
+
+/// CSV导出的表头
+const CSV_HEADER: &str = "path,name,extension,size,modified,created,is_directory,is_symlink,mode,uid,gid";
+
+/// 文件查询条件
+/// 所有条件都以参数形式传给数据库，调用方的输入不会被拼接进SQL
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileQuery {
+    pub name_contains: Option<String>,
+    pub extension: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub is_directory: Option<bool>,
+    pub modified_after: Option<String>,  // RFC 3339（UTC，精确到秒），与存储格式一致以便直接比较
+    pub modified_before: Option<String>, // 格式同上
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+impl FileQuery {
+    /// 创建不带任何条件的查询
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 文件名包含指定文本（`%`和`_`按字面匹配）
+    pub fn name_contains(mut self, name: impl Into<String>) -> Self {
+        self.name_contains = Some(name.into());
+        self
+    }
+
+    /// 文件扩展名等于指定值（不含`.`）
+    pub fn extension(mut self, extension: impl Into<String>) -> Self {
+        self.extension = Some(extension.into());
+        self
+    }
+
+    /// 文件大小不小于指定字节数
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = Some(bytes);
+        self
+    }
+
+    /// 文件大小不大于指定字节数
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// 只返回目录或只返回文件
+    pub fn is_directory(mut self, is_directory: bool) -> Self {
+        self.is_directory = Some(is_directory);
+        self
+    }
+
+    /// 修改时间晚于指定时间
+    pub fn modified_after(mut self, time: DateTime<Utc>) -> Self {
+        self.modified_after = Some(time.to_rfc3339_opts(SecondsFormat::Secs, true));
+        self
+    }
+
+    /// 修改时间早于指定时间
+    pub fn modified_before(mut self, time: DateTime<Utc>) -> Self {
+        self.modified_before = Some(time.to_rfc3339_opts(SecondsFormat::Secs, true));
+        self
+    }
+
+    /// 限制返回条数
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// 跳过前若干条结果
+    pub fn offset(mut self, offset: u32) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// 生成参数化SQL及对应的参数列表
+    fn to_sql(&self) -> (String, Vec<Box<dyn ToSql>>) {
+        let mut conditions: Vec<&str> = Vec::new();
+        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+        
+        if let Some(ref name) = self.name_contains {
+            conditions.push("name LIKE ? ESCAPE '\\'");
+            values.push(Box::new(format!("%{}%", escape_like(name))));
+        }
+        if let Some(ref extension) = self.extension {
+            conditions.push("extension = ?");
+            values.push(Box::new(extension.clone()));
+        }
+        if let Some(min_size) = self.min_size {
+            conditions.push("size >= ?");
+            values.push(Box::new(min_size));
+        }
+        if let Some(max_size) = self.max_size {
+            conditions.push("size <= ?");
+            values.push(Box::new(max_size));
+        }
+        if let Some(is_directory) = self.is_directory {
+            conditions.push("is_directory = ?");
+            values.push(Box::new(is_directory));
+        }
+        if let Some(ref modified_after) = self.modified_after {
+            conditions.push("modified > ?");
+            values.push(Box::new(modified_after.clone()));
+        }
+        if let Some(ref modified_before) = self.modified_before {
+            conditions.push("modified < ?");
+            values.push(Box::new(modified_before.clone()));
+        }
+        
+        let mut sql = format!("SELECT {} FROM files", FILE_COLUMNS);
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY modified DESC, path LIMIT ? OFFSET ?");
+        values.push(Box::new(self.limit.unwrap_or(DEFAULT_SEARCH_LIMIT)));
+        values.push(Box::new(self.offset.unwrap_or(0)));
+        
+        (sql, values)
+    }
+}
+
+/// 转义LIKE模式中的通配符，使其按字面匹配
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
 }
 
 /// 文件索引器结构
+/// 所有方法都是同步的阻塞操作（DuckDB查询、磁盘遍历），
+/// 在异步上下文中应通过 `tokio::task::spawn_blocking` 调用
 pub struct FileIndexer {
+    /// 数据库文件的路径
+    db_path: PathBuf,
+    /// 写入（以及配置读取）使用的连接
     db_connection: Arc<Mutex<Connection>>,
+    /// 搜索使用的连接，与写入互不阻塞
+    read_pool: ReadPool,
+    indexing: AtomicBool,
+    /// 当前索引构建的取消令牌，每次开始构建时替换
+    indexing_cancel: Mutex<CancellationToken>,
+    ignore_matcher: RwLock<IgnoreMatcher>,
+    /// 扫描时的文件过滤条件，保存在数据库中
+    index_filter: RwLock<IndexFilter>,
+    /// 文件系统不区分大小写时，只有大小写不同的路径视为同一个文件
+    case_insensitive_paths: bool,
+    /// 同时遍历的根目录数
+    scan_parallelism: AtomicUsize,
+    /// 扫描时是否进入符号链接指向的目录和文件，默认不跟随
+    follow_symlinks: AtomicBool,
+    /// 扫描时是否计算文件内容的哈希（需要读取每个文件），默认不计算
+    hash_contents: AtomicBool,
+}
+
+/// 只读连接池中的连接数，即最多同时进行的搜索数
+pub const READ_POOL_SIZE: usize = 4;
+
+/// 同一数据库的一组连接，搜索时取一个空闲的，多个搜索可以同时进行
+struct ReadPool {
+    connections: Vec<Mutex<Connection>>,
+    next: AtomicUsize,
+}
+
+impl ReadPool {
+    fn new(conn: &Connection, size: usize) -> Result<Self, LocalPilotError> {
+        let connections = (0..size.max(1))
+            .map(|_| conn.try_clone().map(Mutex::new))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { connections, next: AtomicUsize::new(0) })
+    }
+
+    /// 取一个空闲的连接；全部被占用时轮流等待其中一个
+    fn get(&self) -> MutexGuard<'_, Connection> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        for i in 0..self.connections.len() {
+            match self.connections[(start + i) % self.connections.len()].try_lock() {
+                Ok(conn) => return conn,
+                Err(TryLockError::Poisoned(poisoned)) => return poisoned.into_inner(),
+                Err(TryLockError::WouldBlock) => {}
+            }
+        }
+        self.connections[start % self.connections.len()].lock_or_recover()
+    }
+}
+
+/// macOS和Windows的默认文件系统不区分大小写
+pub const CASE_INSENSITIVE_PATHS: bool = cfg!(any(target_os = "macos", target_os = "windows"));
+
+/// 编辑距离相似度低于此值的候选会被丢弃
+const FUZZY_MIN_SIMILARITY: f64 = 0.4;
+
+/// 默认忽略的目录，用户可在此基础上追加
+pub const DEFAULT_IGNORE_PATTERNS: &[&str] = &["node_modules", "target", ".git", ".cache"];
+
+/// gitignore风格的忽略规则
+/// 不含`/`的规则匹配任意层级的文件名，含`/`的规则匹配路径后缀；末尾的`/`会被忽略
+#[derive(Debug, Clone)]
+pub struct IgnoreMatcher {
+    name_set: GlobSet,
+    path_set: GlobSet,
+}
+
+impl IgnoreMatcher {
+    /// 根据规则列表构建匹配器
+    pub fn new(patterns: &[String]) -> Result<Self, globset::Error> {
+        let mut name_builder = GlobSetBuilder::new();
+        let mut path_builder = GlobSetBuilder::new();
+        
+        for pattern in patterns {
+            let pattern = pattern.trim().trim_end_matches('/');
+            if pattern.is_empty() || pattern.starts_with('#') {
+                continue;
+            }
+            if pattern.contains('/') {
+                path_builder.add(Glob::new(&format!("**/{}", pattern.trim_start_matches('/')))?);
+            } else {
+                name_builder.add(Glob::new(pattern)?);
+            }
+        }
+        
+        Ok(Self {
+            name_set: name_builder.build()?,
+            path_set: path_builder.build()?,
+        })
+    }
+
+    /// 判断路径是否被忽略
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        path.file_name().map_or(false, |name| self.name_set.is_match(name))
+            || self.path_set.is_match(path)
+    }
+}
+
+/// 默认同时遍历的根目录数
+pub const DEFAULT_SCAN_PARALLELISM: usize = 4;
+
+/// 遍历线程与写入线程之间最多缓存的条目数
+const SCAN_CHANNEL_CAPACITY: usize = 1024;
+
+/// 每扫描多少个条目报告一次进度
+pub const PROGRESS_INTERVAL: u64 = 500;
+
+/// 扫描进度
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanProgress {
+    pub scanned: u64,
+    pub current_path: String,
+}
+
+/// 增量扫描结果统计
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScanSummary {
+    pub added: u64,
+    pub updated: u64,
+    pub removed: u64,
+    pub unchanged: u64,
+}
+
+/// 索引构建标记，离开作用域时自动清除
+pub struct IndexingGuard<'a> {
+    flag: &'a AtomicBool,
+    cancel: CancellationToken,
+}
+
+impl IndexingGuard<'_> {
+    /// 本次构建的取消令牌，`FileIndexer::cancel_indexing` 会触发它
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancel
+    }
+}
+
+impl Drop for IndexingGuard<'_> {
+    fn drop(&mut self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
 }
 
 impl FileIndexer {
     /// 创建新的文件索引器
-    pub fn new(db_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    /// 路径直接交给DuckDB，不要求是有效的UTF-8
+    pub fn new(db_path: impl AsRef<Path>) -> Result<Self, LocalPilotError> {
+        let db_path = db_path.as_ref();
         let conn = Connection::open(db_path)?;
         
         // 创建文件表
@@ -42,171 +497,993 @@ impl FileIndexer {
                 size INTEGER,
                 modified TEXT,
                 created TEXT,
-                is_directory BOOLEAN
+                is_directory BOOLEAN,
+                is_symlink BOOLEAN DEFAULT false,
+                hash TEXT,
+                mode INTEGER,
+                uid INTEGER,
+                gid INTEGER
+            )",
+            [],
+        )?;
+        
+        // 用户配置的索引根目录
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS index_roots (
+                path TEXT PRIMARY KEY
+            )",
+            [],
+        )?;
+        
+        // 开启了内容索引的目录（内容索引开销较大，需按目录单独开启）
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS content_roots (
+                path TEXT PRIMARY KEY
+            )",
+            [],
+        )?;
+        
+        // 文本文件内容
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS file_contents (
+                path TEXT PRIMARY KEY,
+                content TEXT
+            )",
+            [],
+        )?;
+        
+        // 用户追加的忽略规则
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS ignore_patterns (
+                pattern TEXT PRIMARY KEY
+            )",
+            [],
+        )?;
+        
+        // 索引设置，值为JSON
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS index_settings (
+                key TEXT PRIMARY KEY,
+                value TEXT
             )",
             [],
         )?;
         
+        // 旧版本的表没有is_symlink、hash和权限列
+        add_symlink_column(&conn)?;
+        add_hash_column(&conn)?;
+        add_permission_columns(&conn)?;
+        
+        // 旧版本以Debug格式存储时间，需要迁移为ISO 8601格式
+        migrate_legacy_timestamps(&conn)?;
+        
+        let ignore_matcher = IgnoreMatcher::new(&load_ignore_patterns(&conn)?)?;
+        let index_filter = load_index_filter(&conn)?;
+        
+        let read_pool = ReadPool::new(&conn, READ_POOL_SIZE)?;
         Ok(Self {
+            db_path: db_path.to_path_buf(),
             db_connection: Arc::new(Mutex::new(conn)),
+            read_pool,
+            indexing: AtomicBool::new(false),
+            indexing_cancel: Mutex::new(CancellationToken::new()),
+            ignore_matcher: RwLock::new(ignore_matcher),
+            index_filter: RwLock::new(index_filter),
+            case_insensitive_paths: CASE_INSENSITIVE_PATHS,
+            scan_parallelism: AtomicUsize::new(DEFAULT_SCAN_PARALLELISM),
+            follow_symlinks: AtomicBool::new(false),
+            hash_contents: AtomicBool::new(false),
         })
     }
 
+    /// 指定文件系统是否区分大小写，默认按平台判断
+    pub fn with_case_insensitive_paths(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive_paths = case_insensitive;
+        self
+    }
+
+    /// 设置扫描多个根目录时同时遍历的目录数，至少为1
+    pub fn set_scan_parallelism(&self, parallelism: usize) {
+        self.scan_parallelism.store(parallelism.max(1), Ordering::SeqCst);
+    }
+
+    /// 扫描多个根目录时同时遍历的目录数
+    pub fn scan_parallelism(&self) -> usize {
+        self.scan_parallelism.load(Ordering::SeqCst)
+    }
+
+    /// 设置扫描时是否跟随符号链接；跟随时每个目标只索引一次，链接形成的环不会导致重复遍历
+    pub fn set_follow_symlinks(&self, follow: bool) {
+        self.follow_symlinks.store(follow, Ordering::SeqCst);
+    }
+
+    /// 扫描时是否跟随符号链接
+    pub fn follow_symlinks(&self) -> bool {
+        self.follow_symlinks.load(Ordering::SeqCst)
+    }
+
+    /// 设置扫描时是否计算文件内容的哈希，用于查找重复文件；开启后下次扫描补算未变化的文件
+    pub fn set_content_hashing(&self, enabled: bool) {
+        self.hash_contents.store(enabled, Ordering::SeqCst);
+    }
+
+    /// 扫描时是否计算文件内容的哈希
+    pub fn content_hashing(&self) -> bool {
+        self.hash_contents.load(Ordering::SeqCst)
+    }
+
     /// 扫描指定目录并将文件信息添加到索引
-    pub fn scan_directory(&self, dir_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let conn = self.db_connection.lock().unwrap();
-        
-        for entry in WalkDir::new(dir_path)
-            .follow_links(false)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            if entry.file_type().is_file() || entry.file_type().is_dir() {
-                if let Some(file_info) = self.get_file_info(&entry.path())? {
-                    // 插入或更新文件信息
-                    conn.execute(
-                        "INSERT OR REPLACE INTO files (path, name, extension, size, modified, created, is_directory) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-                        params![
-                            file_info.path,
-                            file_info.name,
-                            file_info.extension,
-                            file_info.size as i64,
-                            file_info.modified,
-                            file_info.created,
-                            file_info.is_directory
-                        ],
-                    )?;
-                }
-            }
-        }
-        
+    pub fn scan_directory(&self, dir_path: &str) -> Result<(), LocalPilotError> {
+        self.scan_directory_with_progress(dir_path, PROGRESS_INTERVAL, |_| {})?;
         Ok(())
     }
 
-    /// 从路径获取文件信息
-    fn get_file_info(&self, path: &Path) -> Result<Option<FileInfo>, Box<dyn std::error::Error>> {
-        match fs::metadata(path) {
-            Ok(metadata) => {
-                let file_type = metadata.file_type();
-                let is_directory = file_type.is_dir();
-                
-                // 获取文件名和扩展名
-                let name = path.file_name()
-                    .unwrap_or_default()
-                    .to_string_lossy()
-                    .to_string();
-                
-                let extension = path.extension()
-                    .map(|ext| ext.to_string_lossy().to_string());
-                
-                // 获取时间信息
-                let modified = metadata.modified()
-                    .ok()
-                    .map(|t| format!("{:?}", t))
-                    .unwrap_or_default();
-                
-                let created = metadata.created()
-                    .ok()
-                    .map(|t| format!("{:?}", t))
-                    .unwrap_or_default();
-                
-                let size = if is_directory {
-                    0 // 目录大小为0
-                } else {
-                    metadata.len()
-                };
-                
-                Ok(Some(FileInfo {
-                    path: path.to_string_lossy().to_string(),
-                    name,
-                    extension,
-                    size,
-                    modified,
-                    created,
-                    is_directory,
-                }))
+    /// 扫描指定目录，每扫描 `interval` 个条目调用一次 `on_progress`，返回扫描的条目总数
+    pub fn scan_directory_with_progress<F>(
+        &self,
+        dir_path: &str,
+        interval: u64,
+        on_progress: F,
+    ) -> Result<u64, LocalPilotError>
+    where
+        F: FnMut(&ScanProgress),
+    {
+        let roots = [PathBuf::from(dir_path)];
+        self.scan_directories_with_progress(&roots, interval, &CancellationToken::new(), on_progress)
+    }
+
+    /// 同时扫描多个目录，进度和返回的条目数是所有目录的合计
+    /// `cancel` 被触发后在下一个条目处停止并返回 `Cancelled`，已写入的条目保留
+    pub fn scan_directories_with_progress<F>(
+        &self,
+        roots: &[PathBuf],
+        interval: u64,
+        cancel: &CancellationToken,
+        on_progress: F,
+    ) -> Result<u64, LocalPilotError>
+    where
+        F: FnMut(&ScanProgress),
+    {
+        let conn = self.db_connection.lock_or_recover();
+        self.scan_with_connection(&conn, roots, interval, cancel, on_progress)
+    }
+
+    /// 完整重建多个目录的索引：清空这些目录下的条目后重新扫描
+    /// 清空和扫描在同一个事务中进行，被取消或出错时回滚，原有的条目保持不变
+    pub fn rebuild_directories_with_progress<F>(
+        &self,
+        roots: &[PathBuf],
+        interval: u64,
+        cancel: &CancellationToken,
+        on_progress: F,
+    ) -> Result<ScanSummary, LocalPilotError>
+    where
+        F: FnMut(&ScanProgress),
+    {
+        let conn = self.db_connection.lock_or_recover();
+        conn.execute_batch("BEGIN TRANSACTION")?;
+        let rebuilt = roots
+            .iter()
+            .try_fold(0, |removed, root| Ok::<_, LocalPilotError>(removed + remove_subtree(&conn, &root.to_string_lossy())?))
+            .and_then(|removed| {
+                let added = self.scan_with_connection(&conn, roots, interval, cancel, on_progress)?;
+                Ok(ScanSummary { added, removed: removed as u64, ..Default::default() })
+            });
+        match rebuilt {
+            Ok(summary) => {
+                conn.execute_batch("COMMIT")?;
+                Ok(summary)
+            }
+            Err(e) => {
+                if let Err(rollback) = conn.execute_batch("ROLLBACK") {
+                    tracing::error!(error = %rollback, "Failed to roll back the index rebuild");
+                }
+                Err(e)
             }
-            Err(_) => Ok(None), // 无法访问的文件，跳过
         }
     }
 
-    /// 搜索文件
-    pub fn search_files(&self, query: &str) -> Result<Vec<FileInfo>, Box<dyn std::error::Error>> {
-        let conn = self.db_connection.lock().unwrap();
-        
-        let mut stmt = conn.prepare(query)?;
-        let file_iter = stmt.query_map([], |row| {
-            Ok(FileInfo {
-                path: row.get(0)?,
-                name: row.get(1)?,
-                extension: row.get(2)?,
-                size: row.get(3)?,
-                modified: row.get(4)?,
-                created: row.get(5)?,
-                is_directory: row.get(6)?,
-            })
-        })?;
+    /// `scan_directories_with_progress` 的实现，在调用方持有的写连接上执行
+    fn scan_with_connection<F>(
+        &self,
+        conn: &Connection,
+        roots: &[PathBuf],
+        interval: u64,
+        cancel: &CancellationToken,
+        mut on_progress: F,
+    ) -> Result<u64, LocalPilotError>
+    where
+        F: FnMut(&ScanProgress),
+    {
+        let content_roots = load_content_roots(conn)?;
+        let hash_contents = self.content_hashing();
+        let mut scanned = 0u64;
         
-        let mut files = Vec::new();
-        for file_result in file_iter {
-            files.push(file_result?);
+        // 不区分大小写时记下已索引的路径（按小写），用来发现只改了大小写的改名
+        let mut indexed_paths: HashMap<String, String> = HashMap::new();
+        if self.case_insensitive_paths {
+            for root in roots {
+                let dir_path = root.to_string_lossy();
+                let mut stmt = conn.prepare("SELECT path FROM files WHERE path = ?1 OR path LIKE ?2 ESCAPE '\\'")?;
+                let rows = stmt.query_map(params![dir_path, subtree_pattern(&dir_path)], |row| row.get::<_, String>(0))?;
+                for row in rows {
+                    let path = row?;
+                    indexed_paths.insert(path.to_lowercase(), path);
+                }
+            }
         }
         
-        Ok(files)
+        self.walk_roots(roots, cancel, |file_info| {
+            if indexed_paths.get(&file_info.path.to_lowercase()).is_some_and(|path| *path != file_info.path) {
+                remove_case_variants(conn, &file_info.path)?;
+            }
+            // 插入或更新文件信息
+            upsert_file_info(conn, &file_info)?;
+            update_file_content(conn, &file_info, &content_roots)?;
+            if hash_contents {
+                update_file_hash(conn, &file_info)?;
+            }
+            
+            scanned += 1;
+            if interval > 0 && scanned % interval == 0 {
+                on_progress(&ScanProgress { scanned, current_path: file_info.path });
+            }
+            Ok(())
+        })?;
+        
+        Ok(scanned)
+    }
+
+    /// 增量扫描指定目录：跳过修改时间、大小和权限都未变化的条目，并删除磁盘上已不存在的条目
+    pub fn scan_directory_incremental<F>(
+        &self,
+        dir_path: &str,
+        interval: u64,
+        on_progress: F,
+    ) -> Result<ScanSummary, LocalPilotError>
+    where
+        F: FnMut(&ScanProgress),
+    {
+        let roots = [PathBuf::from(dir_path)];
+        self.scan_directories_incremental(&roots, interval, &CancellationToken::new(), on_progress)
     }
 
-    /// 搜索文件名
-    pub fn search_by_filename(&self, filename_pattern: &str) -> Result<Vec<FileInfo>, Box<dyn std::error::Error>> {
-        let conn = self.db_connection.lock().unwrap();
+    /// 同时增量扫描多个目录，进度和统计是所有目录的合计
+    /// 被取消时返回 `Cancelled`，不会删除任何条目（没遍历到的条目不能当作已从磁盘删除）
+    pub fn scan_directories_incremental<F>(
+        &self,
+        roots: &[PathBuf],
+        interval: u64,
+        cancel: &CancellationToken,
+        mut on_progress: F,
+    ) -> Result<ScanSummary, LocalPilotError>
+    where
+        F: FnMut(&ScanProgress),
+    {
+        let conn = self.db_connection.lock_or_recover();
+        let content_roots = load_content_roots(&conn)?;
+        let hash_contents = self.content_hashing();
+        let mut summary = ScanSummary::default();
         
-        let mut stmt = conn.prepare(
-            "SELECT path, name, extension, size, modified, created, is_directory 
-             FROM files 
-             WHERE name LIKE ?1"
-        )?;
+        // 读取这些目录下已索引的条目：修改时间、大小、是否已有哈希以及权限和所有者
+        let mut stored: HashMap<String, (String, u64, bool, Permissions)> = HashMap::new();
+        // 已索引内容的文件；刚开启内容索引的目录中未变化的文件也需要补充内容
+        let mut indexed_contents: HashSet<String> = HashSet::new();
+        for root in roots {
+            let dir_path = root.to_string_lossy();
+            let mut stmt = conn.prepare(
+                "SELECT path, modified, size, hash IS NOT NULL, mode, uid, gid FROM files WHERE path = ?1 OR path LIKE ?2 ESCAPE '\\'"
+            )?;
+            let rows = stmt.query_map(params![dir_path, subtree_pattern(&dir_path)], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    (
+                        row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                        row.get::<_, Option<u64>>(2)?.unwrap_or_default(),
+                        row.get::<_, bool>(3)?,
+                        (row.get(4)?, row.get(5)?, row.get(6)?),
+                    ),
+                ))
+            })?;
+            for row in rows {
+                let (path, state) = row?;
+                stored.insert(path, state);
+            }
+            
+            let mut stmt = conn.prepare(
+                "SELECT path FROM file_contents WHERE path LIKE ?1 ESCAPE '\\'"
+            )?;
+            let rows = stmt.query_map([subtree_pattern(&dir_path)], |row| row.get::<_, String>(0))?;
+            for row in rows {
+                indexed_contents.insert(row?);
+            }
+        }
         
-        let file_iter = stmt.query_map([format!("%{}%", filename_pattern)], |row| {
-            Ok(FileInfo {
-                path: row.get(0)?,
-                name: row.get(1)?,
-                extension: row.get(2)?,
-                size: row.get(3)?,
-                modified: row.get(4)?,
-                created: row.get(5)?,
-                is_directory: row.get(6)?,
-            })
+        let mut scanned = 0u64;
+        self.walk_roots(roots, cancel, |file_info| {
+            match stored.remove(&file_info.path) {
+                // 只改了权限或所有者（chmod、chown）的文件修改时间不变，也算作更新
+                Some((modified, size, hashed, permissions))
+                    if modified == file_info.modified
+                        && size == file_info.size
+                        && permissions == (file_info.mode, file_info.uid, file_info.gid) =>
+                {
+                    if !indexed_contents.contains(&file_info.path) {
+                        update_file_content(&conn, &file_info, &content_roots)?;
+                    }
+                    if hash_contents && !hashed {
+                        update_file_hash(&conn, &file_info)?;
+                    }
+                    summary.unchanged += 1;
+                }
+                Some(_) => {
+                    upsert_file_info(&conn, &file_info)?;
+                    update_file_content(&conn, &file_info, &content_roots)?;
+                    if hash_contents {
+                        update_file_hash(&conn, &file_info)?;
+                    }
+                    summary.updated += 1;
+                }
+                None => {
+                    upsert_file_info(&conn, &file_info)?;
+                    update_file_content(&conn, &file_info, &content_roots)?;
+                    if hash_contents {
+                        update_file_hash(&conn, &file_info)?;
+                    }
+                    summary.added += 1;
+                }
+            }
+            
+            scanned += 1;
+            if interval > 0 && scanned % interval == 0 {
+                on_progress(&ScanProgress { scanned, current_path: file_info.path });
+            }
+            Ok(())
         })?;
         
-        let mut files = Vec::new();
-        for file_result in file_iter {
-            files.push(file_result?);
+        // 剩下的条目在磁盘上已经不存在
+        for path in stored.keys() {
+            conn.execute("DELETE FROM files WHERE path = ?1", [path])?;
+            conn.execute("DELETE FROM file_contents WHERE path = ?1", [path])?;
+            summary.removed += 1;
+        }
+        
+        Ok(summary)
+    }
+
+    /// 遍历各根目录并读取文件信息，交给 `on_entry` 处理
+    /// 最多 `scan_parallelism` 个线程同时遍历不同的根目录，`on_entry` 只在调用线程中执行，
+    /// 数据库写入因此仍是串行的；不同根目录的条目交错到达，顺序不固定
+    /// 每个条目之前检查 `cancel`，被取消时返回 `Cancelled`
+    fn walk_roots<F>(&self, roots: &[PathBuf], cancel: &CancellationToken, mut on_entry: F) -> Result<(), LocalPilotError>
+    where
+        F: FnMut(FileInfo) -> Result<(), LocalPilotError>,
+    {
+        let matcher = self.ignore_matcher.read_or_recover();
+        let matcher = &*matcher;
+        let filter = self.index_filter();
+        let filter = &filter;
+        let follow_symlinks = self.follow_symlinks();
+        let next_root = AtomicUsize::new(0);
+        let (sender, receiver) = mpsc::sync_channel(SCAN_CHANNEL_CAPACITY);
+        
+        std::thread::scope(|scope| {
+            for _ in 0..self.scan_parallelism().min(roots.len()) {
+                let sender = sender.clone();
+                let next_root = &next_root;
+                scope.spawn(move || {
+                    while let Some(root) = roots.get(next_root.fetch_add(1, Ordering::SeqCst)) {
+                        for entry in walk_entries(&root.to_string_lossy(), matcher, filter.skip_hidden, follow_symlinks) {
+                            if cancel.is_cancelled() {
+                                return;
+                            }
+                            if !(entry.file_type().is_file() || entry.file_type().is_dir()) {
+                                continue;
+                            }
+                            if let Some(file_info) = self.get_file_info(entry.path()).transpose() {
+                                if file_info.as_ref().is_ok_and(|file_info| !filter.accepts(file_info)) {
+                                    continue;
+                                }
+                                // 调用线程处理出错后不再接收，停止遍历
+                                if sender.send(file_info).is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+            drop(sender);
+            
+            // 出错返回时接收端被丢弃，遍历线程随之结束
+            for file_info in receiver {
+                if cancel.is_cancelled() {
+                    return Err(LocalPilotError::Cancelled);
+                }
+                on_entry(file_info?)?;
+            }
+            // 遍历线程被取消时可能在发送任何条目之前就已退出
+            if cancel.is_cancelled() {
+                return Err(LocalPilotError::Cancelled);
+            }
+            Ok(())
+        })
+    }
+
+    /// 重新索引单个路径：存在则插入或更新，不存在则删除该路径及其子路径的条目；
+    /// 被过滤条件排除的路径同样删除。返回路径是否仍然存在
+    pub fn index_path(&self, path: &Path) -> Result<bool, LocalPilotError> {
+        // 不区分大小写时，大小写不对的路径（如改名前的旧路径）仍能访问，需要换成磁盘上实际的写法
+        let path = if self.case_insensitive_paths { on_disk_case(path) } else { path.to_path_buf() };
+        let path = path.as_path();
+        let filter = self.index_filter();
+        match self.get_file_info(path)? {
+            Some(file_info) if !filter.accepts(&file_info) || (filter.skip_hidden && self.has_hidden_component(path)?) => {
+                self.remove_directory(&file_info.path)?;
+                Ok(true)
+            }
+            Some(file_info) => {
+                let conn = self.db_connection.lock_or_recover();
+                if self.case_insensitive_paths {
+                    remove_case_variants(&conn, &file_info.path)?;
+                }
+                upsert_file_info(&conn, &file_info)?;
+                update_file_content(&conn, &file_info, &load_content_roots(&conn)?)?;
+                if self.content_hashing() {
+                    update_file_hash(&conn, &file_info)?;
+                }
+                Ok(true)
+            }
+            None => {
+                self.remove_directory(&path.to_string_lossy())?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// 路径在索引根目录之下的部分是否含有隐藏的文件或目录，与扫描时跳过整个隐藏子树一致；
+    /// 不在任何根目录下时只检查路径本身的名称
+    fn has_hidden_component(&self, path: &Path) -> Result<bool, LocalPilotError> {
+        let roots = self.list_index_roots()?;
+        let relative = roots
+            .iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .min_by_key(|relative| relative.components().count());
+        Ok(match relative {
+            Some(relative) => relative.components().any(|component| is_hidden_name(&component.as_os_str().to_string_lossy())),
+            None => path.file_name().is_some_and(|name| is_hidden_name(&name.to_string_lossy())),
+        })
+    }
+
+    /// 删除指定目录（含目录本身）下的所有索引条目，返回删除的条数
+    pub fn remove_directory(&self, dir_path: &str) -> Result<usize, LocalPilotError> {
+        let conn = self.db_connection.lock_or_recover();
+        Ok(remove_subtree(&conn, dir_path)?)
+    }
+
+    /// 删除磁盘上已不存在的文件的索引条目（包括内容），返回删除的条数
+    /// 按路径分批读取并检查，检查文件系统时不持有写连接；无法确定是否存在的路径保留
+    pub fn prune_missing(&self) -> Result<usize, LocalPilotError> {
+        let mut removed = 0;
+        let mut last_path = String::new();
+        loop {
+            let batch: Vec<String> = {
+                let conn = self.read_pool.get();
+                let mut stmt = conn.prepare(
+                    "SELECT path FROM files WHERE path > ?1 ORDER BY path LIMIT ?2"
+                )?;
+                let rows = stmt.query_map(params![last_path, PRUNE_BATCH_SIZE], |row| row.get(0))?;
+                rows.collect::<Result<_, _>>()?
+            };
+            let Some(last) = batch.last() else { break };
+            last_path = last.clone();
+            
+            let missing: Vec<&String> = batch
+                .iter()
+                .filter(|path| matches!(Path::new(path).try_exists(), Ok(false)))
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+            
+            let conn = self.db_connection.lock_or_recover();
+            for path in missing {
+                removed += conn.execute("DELETE FROM files WHERE path = ?1", params![path])?;
+                conn.execute("DELETE FROM file_contents WHERE path = ?1", params![path])?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// 添加索引根目录，返回规范化后的路径
+    pub fn add_index_root(&self, path: &Path) -> Result<String, LocalPilotError> {
+        if !path.exists() {
+            return Err(LocalPilotError::InvalidInput(format!("Path does not exist: {}", path.display())));
+        }
+        if !path.is_dir() {
+            return Err(LocalPilotError::InvalidInput(format!("Path is not a directory: {}", path.display())));
+        }
+        
+        let root = fs::canonicalize(path)?.to_string_lossy().to_string();
+        let conn = self.db_connection.lock_or_recover();
+        conn.execute("INSERT OR REPLACE INTO index_roots (path) VALUES (?1)", [&root])?;
+        Ok(root)
+    }
+
+    /// 移除索引根目录，返回该目录此前是否已配置
+    pub fn remove_index_root(&self, path: &str) -> Result<bool, LocalPilotError> {
+        let conn = self.db_connection.lock_or_recover();
+        let removed = conn.execute("DELETE FROM index_roots WHERE path = ?1", [path])?;
+        Ok(removed > 0)
+    }
+
+    /// 列出用户配置的索引根目录
+    pub fn list_index_roots(&self) -> Result<Vec<String>, LocalPilotError> {
+        let conn = self.db_connection.lock_or_recover();
+        let mut stmt = conn.prepare("SELECT path FROM index_roots ORDER BY path")?;
+        let roots = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(roots)
+    }
+
+    /// 实际要扫描的根目录：优先使用用户配置，未配置时使用默认目录
+    pub fn effective_index_roots(&self) -> Result<Vec<PathBuf>, LocalPilotError> {
+        let configured = self.list_index_roots()?;
+        if configured.is_empty() {
+            Ok(default_index_roots())
+        } else {
+            Ok(configured.into_iter().map(PathBuf::from).collect())
+        }
+    }
+
+    /// 开启或关闭目录的内容索引，下次扫描时生效；关闭时会清除该目录下已索引的内容
+    pub fn set_content_indexing(&self, path: &Path, enabled: bool) -> Result<String, LocalPilotError> {
+        let root = if path.exists() {
+            fs::canonicalize(path)?.to_string_lossy().to_string()
+        } else {
+            path.to_string_lossy().to_string()
+        };
+        
+        let conn = self.db_connection.lock_or_recover();
+        if enabled {
+            if !path.is_dir() {
+                return Err(LocalPilotError::InvalidInput(format!("Path is not a directory: {}", path.display())));
+            }
+            conn.execute("INSERT OR REPLACE INTO content_roots (path) VALUES (?1)", [&root])?;
+        } else {
+            conn.execute("DELETE FROM content_roots WHERE path = ?1", [&root])?;
+            conn.execute(
+                "DELETE FROM file_contents WHERE path LIKE ?1 ESCAPE '\\'",
+                [subtree_pattern(&root)],
+            )?;
+        }
+        Ok(root)
+    }
+
+    /// 列出开启了内容索引的目录
+    pub fn list_content_roots(&self) -> Result<Vec<String>, LocalPilotError> {
+        let conn = self.db_connection.lock_or_recover();
+        let roots = load_content_roots(&conn)?;
+        Ok(roots.iter().map(|root| root.to_string_lossy().to_string()).collect())
+    }
+
+    /// 添加忽略规则，下次扫描时生效
+    pub fn add_ignore_pattern(&self, pattern: &str) -> Result<(), LocalPilotError> {
+        // 先校验规则能否编译
+        Glob::new(pattern.trim().trim_end_matches('/'))?;
+        
+        let conn = self.db_connection.lock_or_recover();
+        conn.execute("INSERT OR REPLACE INTO ignore_patterns (pattern) VALUES (?1)", [pattern.trim()])?;
+        *self.ignore_matcher.write_or_recover() = IgnoreMatcher::new(&load_ignore_patterns(&conn)?)?;
+        Ok(())
+    }
+
+    /// 移除用户添加的忽略规则，默认规则无法移除
+    pub fn remove_ignore_pattern(&self, pattern: &str) -> Result<bool, LocalPilotError> {
+        let conn = self.db_connection.lock_or_recover();
+        let removed = conn.execute("DELETE FROM ignore_patterns WHERE pattern = ?1", [pattern])?;
+        *self.ignore_matcher.write_or_recover() = IgnoreMatcher::new(&load_ignore_patterns(&conn)?)?;
+        Ok(removed > 0)
+    }
+
+    /// 列出当前生效的忽略规则（默认规则在前）
+    pub fn list_ignore_patterns(&self) -> Result<Vec<String>, LocalPilotError> {
+        let conn = self.db_connection.lock_or_recover();
+        load_ignore_patterns(&conn)
+    }
+
+    /// 设置并保存扫描时的过滤条件，下次扫描时生效；增量扫描会删除不再符合条件的条目
+    pub fn set_index_filter(&self, filter: IndexFilter) -> Result<(), LocalPilotError> {
+        let filter = filter.normalized();
+        let conn = self.db_connection.lock_or_recover();
+        conn.execute(
+            "INSERT OR REPLACE INTO index_settings (key, value) VALUES (?1, ?2)",
+            params![INDEX_FILTER_SETTING, serde_json::to_string(&filter)?],
+        )?;
+        *self.index_filter.write_or_recover() = filter;
+        Ok(())
+    }
+
+    /// 当前的扫描过滤条件
+    pub fn index_filter(&self) -> IndexFilter {
+        self.index_filter.read_or_recover().clone()
+    }
+
+    /// 判断路径是否匹配忽略规则
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        self.ignore_matcher.read_or_recover().is_ignored(path)
+    }
+
+    /// 标记索引构建开始；如果已有构建正在进行则返回 `None`
+    pub fn begin_indexing(&self) -> Option<IndexingGuard<'_>> {
+        self.indexing
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()?;
+        let cancel = CancellationToken::new();
+        *self.indexing_cancel.lock_or_recover() = cancel.clone();
+        Some(IndexingGuard { flag: &self.indexing, cancel })
+    }
+
+    /// 取消正在进行的索引构建，返回是否有构建正在进行
+    pub fn cancel_indexing(&self) -> bool {
+        if !self.is_indexing() {
+            return false;
+        }
+        self.indexing_cancel.lock_or_recover().cancel();
+        true
+    }
+
+    /// 是否有索引构建正在进行
+    pub fn is_indexing(&self) -> bool {
+        self.indexing.load(Ordering::SeqCst)
+    }
+
+    /// 从路径获取文件信息
+    fn get_file_info(&self, path: &Path) -> Result<Option<FileInfo>, LocalPilotError> {
+        match fs::metadata(path) {
+            Ok(metadata) => {
+                let file_type = metadata.file_type();
+                let is_directory = file_type.is_dir();
+                
+                // 获取文件名和扩展名
+                let name = path.file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string();
+                
+                let extension = path.extension()
+                    .map(|ext| ext.to_string_lossy().to_string());
+                
+                // 获取时间信息
+                let modified = metadata.modified()
+                    .ok()
+                    .map(format_timestamp)
+                    .unwrap_or_default();
+                
+                let created = metadata.created()
+                    .ok()
+                    .map(format_timestamp)
+                    .unwrap_or_default();
+                
+                let size = if is_directory {
+                    0 // 目录大小为0
+                } else {
+                    metadata.len()
+                };
+                
+                let is_symlink = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink());
+                
+                // 权限和所有者取自链接指向的目标，与其余字段一致
+                #[cfg(unix)]
+                let (mode, uid, gid) = {
+                    use std::os::unix::fs::MetadataExt;
+                    (Some(metadata.mode() & 0o7777), Some(metadata.uid()), Some(metadata.gid()))
+                };
+                #[cfg(not(unix))]
+                let (mode, uid, gid) = (None, None, None);
+                
+                Ok(Some(FileInfo {
+                    path: path.to_string_lossy().to_string(),
+                    name,
+                    extension,
+                    size,
+                    modified,
+                    created,
+                    is_directory,
+                    is_symlink,
+                    mode,
+                    uid,
+                    gid,
+                }))
+            }
+            Err(_) => Ok(None), // 无法访问的文件，跳过
+        }
+    }
+
+    /// 执行原始SQL查询，仅供模块内部使用
+    /// 查询结果的列必须与 `files` 表的列顺序一致；外部输入只能通过 `params` 传入
+    pub(crate) fn run_query(
+        &self,
+        sql: &str,
+        query_params: &[&dyn ToSql],
+    ) -> Result<Vec<FileInfo>, LocalPilotError> {
+        let conn = self.read_pool.get();
+        
+        let mut stmt = conn.prepare(sql)?;
+        let file_iter = stmt.query_map(query_params, row_to_file_info)?;
+        
+        let mut files = Vec::new();
+        for file_result in file_iter {
+            files.push(file_result?);
         }
         
         Ok(files)
     }
 
-    /// 搜索文件扩展名
-    pub fn search_by_extension(&self, extension: &str) -> Result<Vec<FileInfo>, Box<dyn std::error::Error>> {
-        let conn = self.db_connection.lock().unwrap();
+    /// 按条件搜索文件
+    pub fn search(&self, query: &FileQuery) -> Result<Vec<FileInfo>, LocalPilotError> {
+        let (sql, values) = query.to_sql();
+        let query_params: Vec<&dyn ToSql> = values.iter().map(|v| v.as_ref()).collect();
+        self.run_query(&sql, &query_params)
+    }
+
+    /// 索引中的文件数（不含目录）
+    pub fn count_indexed_files(&self) -> Result<u64, LocalPilotError> {
+        let conn = self.read_pool.get();
+        let count: u64 = conn.query_row("SELECT COUNT(*) FROM files WHERE NOT is_directory", [], |row| row.get(0))?;
+        Ok(count)
+    }
+
+    /// 将索引中的全部条目按路径顺序导出到 `path`，逐行写入而不是先读入整个索引；返回导出的条目数
+    pub fn export(&self, format: ExportFormat, path: &Path) -> Result<u64, LocalPilotError> {
+        let conn = self.read_pool.get();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM files ORDER BY path", FILE_COLUMNS))?;
+        let rows = stmt.query_map([], row_to_file_info)?;
+        
+        let mut writer = BufWriter::new(fs::File::create(path)?);
+        match format {
+            ExportFormat::Json => writer.write_all(b"[")?,
+            ExportFormat::Csv => writeln!(writer, "{}", CSV_HEADER)?,
+        }
+        let mut count = 0;
+        for row in rows {
+            let file = row?;
+            match format {
+                ExportFormat::Json => {
+                    writer.write_all(if count == 0 { b"\n" } else { b",\n" })?;
+                    serde_json::to_writer(&mut writer, &file)?;
+                }
+                ExportFormat::Csv => writeln!(
+                    writer,
+                    "{},{},{},{},{},{},{},{},{},{},{}",
+                    csv_field(&file.path),
+                    csv_field(&file.name),
+                    csv_field(file.extension.as_deref().unwrap_or("")),
+                    file.size,
+                    csv_field(&file.modified),
+                    csv_field(&file.created),
+                    file.is_directory,
+                    file.is_symlink,
+                    optional_field(file.mode),
+                    optional_field(file.uid),
+                    optional_field(file.gid),
+                )?,
+            }
+            count += 1;
+        }
+        if format == ExportFormat::Json {
+            writer.write_all(if count == 0 { b"]\n" } else { b"\n]\n" })?;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// 索引统计：文件数、目录数、总大小以及文件数最多的扩展名
+    pub fn stats(&self) -> Result<IndexStats, LocalPilotError> {
+        let conn = self.read_pool.get();
+        let (total_files, total_size): (u64, u64) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM files WHERE NOT is_directory",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let total_directories: u64 = conn.query_row("SELECT COUNT(*) FROM files WHERE is_directory", [], |row| row.get(0))?;
         
         let mut stmt = conn.prepare(
-            "SELECT path, name, extension, size, modified, created, is_directory 
+            "SELECT extension, COUNT(*) AS count FROM files
+             WHERE NOT is_directory
+             GROUP BY extension
+             ORDER BY count DESC, extension
+             LIMIT ?1",
+        )?;
+        let top_extensions = stmt
+            .query_map(params![TOP_EXTENSIONS_LIMIT], |row| {
+                Ok(ExtensionCount { extension: row.get(0)?, count: row.get(1)? })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        
+        Ok(IndexStats { total_files, total_directories, total_size, top_extensions })
+    }
+
+    /// 数据库的存储占用：磁盘上的大小、文件表的行数，以及数据库内已用和空闲的空间
+    pub fn storage_stats(&self) -> Result<StorageStats, LocalPilotError> {
+        let conn = self.db_connection.lock_or_recover();
+        storage_stats(&conn, &self.db_path)
+    }
+
+    /// 压缩数据库：把WAL写回数据库文件，释放已删除的行占用的块
+    /// 持有写连接期间执行，扫描和其他写入会等待压缩完成
+    pub fn vacuum(&self) -> Result<VacuumSummary, LocalPilotError> {
+        let conn = self.db_connection.lock_or_recover();
+        let before = storage_stats(&conn, &self.db_path)?;
+        conn.execute_batch("CHECKPOINT")?;
+        let storage = storage_stats(&conn, &self.db_path)?;
+        Ok(VacuumSummary {
+            reclaimed_bytes: before.file_size.saturating_sub(storage.file_size),
+            storage,
+        })
+    }
+
+    /// 搜索文件名（分页）
+    /// 完全匹配排在最前，其次是前缀匹配，再次是其他子串匹配；同一档内按修改时间倒序
+    pub fn search_by_filename(
+        &self,
+        filename_pattern: &str,
+        limit: u32,
+        offset: u32,
+    ) -> Result<SearchPage, LocalPilotError> {
+        let conn = self.read_pool.get();
+        let pattern = format!("%{}%", escape_like(filename_pattern));
+        let total: u64 =
+            conn.query_row("SELECT COUNT(*) FROM files WHERE name LIKE ?1 ESCAPE '\\'", [&pattern], |row| row.get(0))?;
+        
+        // 档次（不区分大小写）：0 文件名或主文件名（去掉最后一个扩展名）与搜索词相同，1 以搜索词开头，2 其他
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} 
              FROM files 
-             WHERE extension = ?1"
+             WHERE name LIKE ?1 ESCAPE '\\'
+             ORDER BY CASE
+                 WHEN lower(name) = ?2 THEN 0
+                 WHEN ?2 <> '' AND lower(name) LIKE ?3 ESCAPE '\\'
+                     AND instr(substr(lower(name), length(?2) + 2), '.') = 0 THEN 0
+                 WHEN lower(name) LIKE ?4 ESCAPE '\\' THEN 1
+                 ELSE 2
+             END, modified DESC, path
+             LIMIT ?5 OFFSET ?6",
+            FILE_COLUMNS
+        ))?;
+        let lowered = filename_pattern.to_lowercase();
+        let escaped = escape_like(&lowered);
+        let rows = stmt.query_map(
+            params![pattern, lowered, format!("{}.%", escaped), format!("{}%", escaped), limit, offset],
+            row_to_file_info,
         )?;
+        let files = rows.collect::<Result<Vec<_>, _>>()?;
+        Ok(SearchPage { files, total, limit, offset })
+    }
+
+    /// 流式搜索文件名：每读到一行就交给 `on_match`，不必等整个查询结束，适合结果很多的查询
+    /// 结果按修改时间倒序，不像 `search_by_filename` 那样按匹配程度排序（排序需要先读完所有结果）；返回匹配的条目数
+    /// `cancel` 被取消后不再读取和交出结果，立即释放连接并返回 `LocalPilotError::Cancelled`
+    pub fn search_by_filename_streaming<F>(
+        &self,
+        filename_pattern: &str,
+        limit: u32,
+        cancel: &CancellationToken,
+        mut on_match: F,
+    ) -> Result<u64, LocalPilotError>
+    where
+        F: FnMut(FileInfo),
+    {
+        let conn = self.read_pool.get();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} 
+             FROM files 
+             WHERE name LIKE ?1 ESCAPE '\\'
+             ORDER BY modified DESC, path
+             LIMIT ?2",
+            FILE_COLUMNS
+        ))?;
+        let pattern = format!("%{}%", escape_like(filename_pattern));
+        let rows = stmt.query_map(params![pattern, limit], row_to_file_info)?;
         
-        let file_iter = stmt.query_map([extension], |row| {
-            Ok(FileInfo {
-                path: row.get(0)?,
-                name: row.get(1)?,
-                extension: row.get(2)?,
-                size: row.get(3)?,
-                modified: row.get(4)?,
-                created: row.get(5)?,
-                is_directory: row.get(6)?,
-            })
+        let mut count = 0;
+        for row in rows {
+            if cancel.is_cancelled() {
+                return Err(LocalPilotError::Cancelled);
+            }
+            on_match(row?);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// 模糊搜索文件名，结果按匹配度从高到低排序，分数相同时最近修改的在前
+    /// 在数据库中为每个条目打分（0~1）：包含查询的文件名最高，其次是按顺序包含查询各字符的主文件名，
+    /// 其余按与主文件名的编辑距离计算相似度；拼写错误很少出现在首字母上，首字母不同的不按编辑距离匹配
+    pub fn search_fuzzy(&self, query: &str, limit: u32) -> Result<Vec<FileInfo>, LocalPilotError> {
+        let query = query.trim().to_lowercase();
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+        // 子序列匹配的LIKE模式，如 `rpt` 对应 `%r%p%t%`
+        let subsequence: String = query.chars().map(|c| format!("%{}", escape_like(&c.to_string()))).collect::<String>() + "%";
+        
+        let conn = self.read_pool.get();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM (
+                 SELECT *, CASE
+                     WHEN instr(lower(name), ?1) > 0 THEN 0.9 + 0.1 * length(?1) / length(name)
+                     WHEN stem LIKE ?2 ESCAPE '\\' THEN 0.6 + 0.3 * length(?1) / length(stem)
+                     WHEN substr(stem, 1, 1) = substr(?1, 1, 1) THEN 0.6 * (1.0 - 1.0 * levenshtein(stem, ?1)
+                         / CASE WHEN length(stem) > length(?1) THEN length(stem) ELSE length(?1) END)
+                 END AS score
+                 FROM (
+                     SELECT *, CASE
+                         WHEN extension IS NULL THEN lower(name)
+                         ELSE substr(lower(name), 1, length(name) - length(extension) - 1)
+                     END AS stem
+                     FROM files
+                 )
+             )
+             WHERE score >= ?3
+             ORDER BY score DESC, modified DESC, path
+             LIMIT ?4",
+            FILE_COLUMNS
+        ))?;
+        // 编辑距离的分数为 0.6 × 相似度，子串和子序列匹配的分数都在0.6以上
+        let min_score = 0.6 * FUZZY_MIN_SIMILARITY;
+        let rows = stmt.query_map(params![query, subsequence, min_score, limit], row_to_file_info)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// 按文件内容搜索（不区分大小写），返回匹配的文件及匹配处的文本片段
+    pub fn search_by_content(&self, query: &str, limit: u32) -> Result<Vec<ContentMatch>, LocalPilotError> {
+        if query.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+        
+        let conn = self.read_pool.get();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {}, content 
+             FROM files 
+             JOIN file_contents USING (path) 
+             WHERE lower(content) LIKE ?1 ESCAPE '\\'
+             ORDER BY modified DESC, path
+             LIMIT ?2",
+            FILE_COLUMNS
+        ))?;
+        
+        let pattern = format!("%{}%", escape_like(&query.to_lowercase()));
+        let match_iter = stmt.query_map(params![pattern, limit], |row| {
+            Ok((row_to_file_info(row)?, row.get::<_, String>(11)?))
         })?;
         
+        let mut matches = Vec::new();
+        for match_result in match_iter {
+            let (file, content) = match_result?;
+            matches.push(ContentMatch {
+                snippet: make_snippet(&content, query),
+                file,
+            });
+        }
+        
+        Ok(matches)
+    }
+
+    /// 搜索文件扩展名
+    pub fn search_by_extension(&self, extension: &str) -> Result<Vec<FileInfo>, LocalPilotError> {
+        let conn = self.read_pool.get();
+        
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} 
+             FROM files 
+             WHERE extension = ?1",
+            FILE_COLUMNS
+        ))?;
+        
+        let file_iter = stmt.query_map([extension], row_to_file_info)?;
+        
         let mut files = Vec::new();
         for file_result in file_iter {
             files.push(file_result?);
@@ -215,54 +1492,1642 @@ impl FileIndexer {
         Ok(files)
     }
 
+    /// 修改时间在 `start` 和 `end` 之间（含两端）的文件，不含目录，最近修改的在前
+    pub fn search_modified_between(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<FileInfo>, LocalPilotError> {
+        if start > end {
+            return Err(LocalPilotError::InvalidInput(format!(
+                "The start of the time range ({}) is after its end ({})",
+                start.to_rfc3339(),
+                end.to_rfc3339()
+            )));
+        }
+        // 存储的时间精确到秒，前后各放宽一秒后的开区间即包含两端的闭区间
+        let second = chrono::Duration::seconds(1);
+        let query = FileQuery::new()
+            .is_directory(false)
+            .modified_after(start - second)
+            .modified_before(end + second)
+            .limit(limit);
+        self.search(&query)
+    }
+
+    /// 最近 `days` 天内修改过的文件，最近修改的在前
+    pub fn search_recent(&self, days: u32, limit: u32) -> Result<Vec<FileInfo>, LocalPilotError> {
+        let end = Utc::now();
+        self.search_modified_between(end - chrono::Duration::days(i64::from(days)), end, limit)
+    }
+
+    /// 权限位中含有 `bits` 任意一位的文件，不含目录，最近修改的在前
+    /// 例如 `0o002` 查找所有人可写的文件，`0o4000` 查找setuid文件；没有记录权限的条目（非Unix平台）不会匹配
+    pub fn search_by_permission(&self, bits: u32, limit: u32) -> Result<Vec<FileInfo>, LocalPilotError> {
+        if bits == 0 || bits > 0o7777 {
+            return Err(LocalPilotError::InvalidInput(format!(
+                "Permission bits must be between 0o1 and 0o7777, got {:#o}",
+                bits
+            )));
+        }
+        let sql = format!(
+            "SELECT {} 
+             FROM files 
+             WHERE NOT is_directory AND mode IS NOT NULL AND (mode & ?1) <> 0
+             ORDER BY modified DESC, path
+             LIMIT ?2",
+            FILE_COLUMNS
+        );
+        self.run_query(&sql, params![bits, limit])
+    }
+
+    /// 查找内容相同的文件，只包括计算过哈希的非空文件（见 `set_content_hashing`）
+    /// 按重复占用的空间从大到小排序，组内按路径排序
+    pub fn find_duplicates(&self) -> Result<Vec<DuplicateGroup>, LocalPilotError> {
+        let conn = self.read_pool.get();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {}, hash 
+             FROM files 
+             WHERE hash IN (
+                 SELECT hash FROM files 
+                 WHERE hash IS NOT NULL AND NOT is_directory AND size > 0 
+                 GROUP BY hash HAVING COUNT(*) > 1
+             )
+             ORDER BY hash, path",
+            FILE_COLUMNS
+        ))?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(11)?, row_to_file_info(row)?)))?;
+        
+        let mut groups: Vec<DuplicateGroup> = Vec::new();
+        for row in rows {
+            let (hash, file) = row?;
+            match groups.last_mut() {
+                Some(group) if group.hash == hash => group.files.push(file),
+                _ => groups.push(DuplicateGroup { hash, size: file.size, files: vec![file] }),
+            }
+        }
+        groups.sort_by_key(|group| std::cmp::Reverse(group.size * (group.files.len() as u64 - 1)));
+        Ok(groups)
+    }
+
     /// 获取数据库连接
     pub fn get_connection(&self) -> Arc<Mutex<Connection>> {
         Arc::clone(&self.db_connection)
     }
 }
 
-/// 初始化文件索引器
-pub fn initialize_file_indexer(app_handle: &AppHandle) -> Result<FileIndexer, Box<dyn std::error::Error>> {
-    // 获取应用数据目录
-    let app_data_dir = app_handle.path().app_data_dir()?;
-    let db_path = app_data_dir.join("file_index.db");
-    
-    // 创建文件索引器
-    let indexer = FileIndexer::new(db_path.to_str().unwrap())?;
-    
-    // 获取用户目录
-    if let Some(user_dirs) = UserDirs::new() {
-        // 扫描常见目录
-        if let Some(download_dir) = user_dirs.download_dir() {
-            if download_dir.exists() {
-                indexer.scan_directory(download_dir.to_str().unwrap())?;
-            }
-        }
-        
-        if let Some(desktop_dir) = user_dirs.desktop_dir() {
-            if desktop_dir.exists() {
-                indexer.scan_directory(desktop_dir.to_str().unwrap())?;
+/// 遍历目录，被忽略的子树整体跳过而不是逐个遍历后丢弃
+/// 跟随符号链接时记录已访问的目标，同一目标（包括指回上级目录形成环的链接）只返回第一次遇到的路径；
+/// 因此指向同一文件的硬链接也只返回一个
+/// `skip_hidden` 为true时隐藏的文件和目录同样整体跳过
+fn walk_entries<'a>(
+    dir_path: &str,
+    matcher: &'a IgnoreMatcher,
+    skip_hidden: bool,
+    follow_symlinks: bool,
+) -> impl Iterator<Item = DirEntry> + 'a {
+    let mut visited = HashSet::new();
+    WalkDir::new(dir_path)
+        .follow_links(follow_symlinks)
+        .into_iter()
+        .filter_entry(move |e| {
+            if e.depth() > 0 && matcher.is_ignored(e.path()) {
+                return false;
             }
-        }
-        
-        if let Some(documents_dir) = user_dirs.document_dir() {
-            if documents_dir.exists() {
-                indexer.scan_directory(documents_dir.to_str().unwrap())?;
+            if e.depth() > 0 && skip_hidden && is_hidden_name(&e.file_name().to_string_lossy()) {
+                return false;
             }
-        }
+            // 无法读取元数据的条目交给调用方按无法访问处理
+            !follow_symlinks || file_identity(e.path()).is_none_or(|identity| visited.insert(identity))
+        })
+        .filter_map(|e| e.ok())
+}
+
+/// 文件系统中目标文件的唯一标识（设备号和inode），用于识别经由不同路径到达的同一目标
+#[cfg(unix)]
+fn file_identity(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// 文件系统中目标文件的唯一标识，没有inode的平台使用解析链接后的规范路径
+#[cfg(not(unix))]
+fn file_identity(path: &Path) -> Option<PathBuf> {
+    fs::canonicalize(path).ok()
+}
+
+/// 读取保存的扫描过滤条件，未设置时不过滤
+fn load_index_filter(conn: &Connection) -> Result<IndexFilter, LocalPilotError> {
+    let mut stmt = conn.prepare("SELECT value FROM index_settings WHERE key = ?1")?;
+    let mut rows = stmt.query_map([INDEX_FILTER_SETTING], |row| row.get::<_, String>(0))?;
+    match rows.next() {
+        Some(value) => Ok(serde_json::from_str(&value?)?),
+        None => Ok(IndexFilter::default()),
+    }
+}
+
+/// 读取默认规则和用户添加的忽略规则
+fn load_ignore_patterns(conn: &Connection) -> Result<Vec<String>, LocalPilotError> {
+    let mut patterns: Vec<String> = DEFAULT_IGNORE_PATTERNS.iter().map(|p| p.to_string()).collect();
+    let mut stmt = conn.prepare("SELECT pattern FROM ignore_patterns ORDER BY pattern")?;
+    for pattern in stmt.query_map([], |row| row.get::<_, String>(0))? {
+        patterns.push(pattern?);
+    }
+    Ok(patterns)
+}
+
+/// 读取开启了内容索引的目录
+fn load_content_roots(conn: &Connection) -> Result<Vec<PathBuf>, LocalPilotError> {
+    let mut stmt = conn.prepare("SELECT path FROM content_roots ORDER BY path")?;
+    let roots = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(roots.into_iter().map(PathBuf::from).collect())
+}
+
+/// 更新文件的内容索引：只处理内容索引目录下的小文本文件，
+/// 文件变大、变为非文本或无法按UTF-8读取时清除旧内容
+fn update_file_content(
+    conn: &Connection,
+    file_info: &FileInfo,
+    content_roots: &[PathBuf],
+) -> Result<(), LocalPilotError> {
+    let path = Path::new(&file_info.path);
+    if file_info.is_directory || !content_roots.iter().any(|root| path.starts_with(root)) {
+        return Ok(());
     }
     
-    Ok(indexer)
+    let is_text = file_info
+        .extension
+        .as_deref()
+        .map_or(false, |ext| TEXT_EXTENSIONS.contains(&ext.to_lowercase().as_str()));
+    let content = if is_text && file_info.size <= MAX_CONTENT_SIZE {
+        fs::read_to_string(path).ok()
+    } else {
+        None
+    };
+    
+    match content {
+        Some(content) => conn.execute(
+            "INSERT OR REPLACE INTO file_contents (path, content) VALUES (?1, ?2)",
+            params![file_info.path, content],
+        )?,
+        None => conn.execute("DELETE FROM file_contents WHERE path = ?1", [&file_info.path])?,
+    };
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// 计算文件内容的哈希：目录、超过 `MAX_HASH_SIZE` 或无法读取的文件清除旧哈希
+fn update_file_hash(conn: &Connection, file_info: &FileInfo) -> Result<(), LocalPilotError> {
+    let hash = if file_info.is_directory || file_info.size > MAX_HASH_SIZE {
+        None
+    } else {
+        hash_file(Path::new(&file_info.path)).ok()
+    };
+    conn.execute("UPDATE files SET hash = ?1 WHERE path = ?2", params![hash, file_info.path])?;
+    Ok(())
+}
+
+/// 文件内容的BLAKE3哈希（十六进制），分块读取不会把整个文件载入内存
+fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// 截取匹配位置前后的文本片段，换行替换为空格
+fn make_snippet(content: &str, query: &str) -> String {
+    let chars: Vec<char> = content.chars().collect();
+    let needle: Vec<char> = query.to_lowercase().chars().collect();
+    let lowered: Vec<char> = chars
+        .iter()
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect();
     
-    #[test]
-    fn test_file_indexer_creation() {
-        let temp_dir = std::env::temp_dir().join("test_file_indexer.db");
-        let indexer = FileIndexer::new(temp_dir.to_str().unwrap());
-        assert!(indexer.is_ok());
+    let position = lowered
+        .windows(needle.len().max(1))
+        .position(|window| window == needle.as_slice())
+        .unwrap_or(0);
+    let start = position.saturating_sub(SNIPPET_CONTEXT);
+    let end = (position + needle.len() + SNIPPET_CONTEXT).min(chars.len());
+    
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.extend(chars[start..end].iter().map(|c| if c.is_whitespace() { ' ' } else { *c }));
+    if end < chars.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// 匹配目录下所有子路径的LIKE模式
+fn subtree_pattern(dir_path: &str) -> String {
+    let dir = dir_path.trim_end_matches(MAIN_SEPARATOR);
+    format!("{}{}%", escape_like(dir), escape_like(&MAIN_SEPARATOR.to_string()))
+}
+
+/// 将路径的最后一段换成目录中实际的大小写，找不到时原样返回
+fn on_disk_case(path: &Path) -> PathBuf {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return path.to_path_buf();
+    };
+    let name = name.to_string_lossy().to_lowercase();
+    fs::read_dir(parent)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .find(|entry| entry.file_name().to_string_lossy().to_lowercase() == name)
+        .map(|entry| parent.join(entry.file_name()))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// 读取数据库的存储占用；磁盘大小包含尚未写回数据库文件的WAL
+fn storage_stats(conn: &Connection, db_path: &Path) -> Result<StorageStats, LocalPilotError> {
+    let mut wal_path = db_path.as_os_str().to_owned();
+    wal_path.push(".wal");
+    let file_size = [db_path, Path::new(&wal_path)]
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let row_count: u64 = conn.query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))?;
+    let (block_size, used_blocks, free_blocks): (u64, u64, u64) = conn.query_row(
+        "SELECT block_size, used_blocks, free_blocks FROM pragma_database_size() WHERE database_name = current_database()",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    Ok(StorageStats {
+        file_size,
+        row_count,
+        used_size: block_size * used_blocks,
+        free_size: block_size * free_blocks,
+    })
+}
+
+/// 删除与 `path` 只有大小写不同的条目（如改名只改了大小写），不区分大小写时每个文件只保留一行
+/// 需要比较整张表，只在发现大小写改名或重新索引单个路径时调用
+fn remove_case_variants(conn: &Connection, path: &str) -> Result<(), duckdb::Error> {
+    for table in ["files", "file_contents"] {
+        conn.execute(
+            &format!("DELETE FROM {} WHERE lower(path) = lower(?1) AND path <> ?1", table),
+            [path],
+        )?;
+    }
+    Ok(())
+}
+
+/// 删除指定目录（含目录本身）下的所有条目及其内容，返回删除的条数
+fn remove_subtree(conn: &Connection, dir_path: &str) -> Result<usize, duckdb::Error> {
+    let removed = conn.execute(
+        "DELETE FROM files WHERE path = ?1 OR path LIKE ?2 ESCAPE '\\'",
+        params![dir_path, subtree_pattern(dir_path)],
+    )?;
+    conn.execute(
+        "DELETE FROM file_contents WHERE path = ?1 OR path LIKE ?2 ESCAPE '\\'",
+        params![dir_path, subtree_pattern(dir_path)],
+    )?;
+    Ok(removed)
+}
+
+/// 插入或更新一条文件信息
+fn upsert_file_info(conn: &Connection, file_info: &FileInfo) -> Result<(), duckdb::Error> {
+    conn.execute(
+        // 修改时间或大小变化后旧的哈希不再对应文件内容，清空以便重新计算
+        "INSERT INTO files (path, name, extension, size, modified, created, is_directory, is_symlink, mode, uid, gid)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+         ON CONFLICT (path) DO UPDATE SET
+             name = excluded.name, extension = excluded.extension, size = excluded.size,
+             modified = excluded.modified, created = excluded.created, is_directory = excluded.is_directory,
+             is_symlink = excluded.is_symlink, mode = excluded.mode, uid = excluded.uid, gid = excluded.gid,
+             hash = CASE
+                 WHEN files.modified IS NOT DISTINCT FROM excluded.modified AND files.size IS NOT DISTINCT FROM excluded.size
+                 THEN files.hash
+             END",
+        params![
+            file_info.path,
+            file_info.name,
+            file_info.extension,
+            file_info.size as i64,
+            file_info.modified,
+            file_info.created,
+            file_info.is_directory,
+            file_info.is_symlink,
+            file_info.mode,
+            file_info.uid,
+            file_info.gid
+        ],
+    )?;
+    Ok(())
+}
+
+/// 将查询结果行转换为文件信息，查询以 `FILE_COLUMNS` 开头
+fn row_to_file_info(row: &duckdb::Row) -> duckdb::Result<FileInfo> {
+    Ok(FileInfo {
+        path: row.get(0)?,
+        name: row.get(1)?,
+        extension: row.get(2)?,
+        size: row.get(3)?,
+        modified: row.get(4)?,
+        created: row.get(5)?,
+        is_directory: row.get(6)?,
+        is_symlink: row.get::<_, Option<bool>>(7)?.unwrap_or(false),
+        mode: row.get(8)?,
+        uid: row.get(9)?,
+        gid: row.get(10)?,
+    })
+}
+
+/// CSV字段，含逗号、引号或换行时加引号并将引号加倍
+fn csv_field(value: &str) -> std::borrow::Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\"")).into()
+    } else {
+        value.into()
     }
-}
\ No newline at end of file
+}
+
+/// 可选的数值字段，没有值时输出空字段
+fn optional_field(value: Option<u32>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// 将时间转换为RFC 3339格式的UTC字符串，如 `2023-01-01T00:00:00Z`
+fn format_timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// 解析旧版本使用 `format!("{:?}", SystemTime)` 存储的时间字符串
+/// Unix: `SystemTime { tv_sec: 1672531200, tv_nsec: 0 }`
+/// Windows: `SystemTime { intervals: 133170048000000000 }`（自1601年起的100纳秒间隔数）
+fn parse_legacy_timestamp(legacy: &str) -> Option<String> {
+    let field_value = |field: &str| -> Option<i64> {
+        let start = legacy.find(field)? + field.len();
+        let digits: String = legacy[start..]
+            .chars()
+            .skip_while(|c| c.is_whitespace())
+            .take_while(|c| c.is_ascii_digit() || *c == '-')
+            .collect();
+        digits.parse().ok()
+    };
+    
+    let secs = if let Some(secs) = field_value("tv_sec:") {
+        secs
+    } else {
+        const WINDOWS_TO_UNIX_EPOCH_SECS: i64 = 11_644_473_600;
+        field_value("intervals:")? / 10_000_000 - WINDOWS_TO_UNIX_EPOCH_SECS
+    };
+    
+    DateTime::<Utc>::from_timestamp(secs, 0)
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+/// 为旧版本创建的 `files` 表添加 `is_symlink` 列，已有的条目视为不是符号链接
+fn add_symlink_column(conn: &Connection) -> Result<(), LocalPilotError> {
+    if conn.prepare("SELECT is_symlink FROM files LIMIT 0").is_err() {
+        conn.execute("ALTER TABLE files ADD COLUMN is_symlink BOOLEAN DEFAULT false", [])?;
+    }
+    Ok(())
+}
+
+/// 为旧版本创建的 `files` 表添加 `hash` 列，已有的条目视为未计算哈希
+fn add_hash_column(conn: &Connection) -> Result<(), LocalPilotError> {
+    if conn.prepare("SELECT hash FROM files LIMIT 0").is_err() {
+        conn.execute("ALTER TABLE files ADD COLUMN hash TEXT", [])?;
+    }
+    Ok(())
+}
+
+/// 为旧版本创建的 `files` 表添加权限和所有者列；已有的条目在下次扫描时补充（见 `scan_directories_incremental`）
+fn add_permission_columns(conn: &Connection) -> Result<(), LocalPilotError> {
+    if conn.prepare("SELECT mode FROM files LIMIT 0").is_err() {
+        conn.execute_batch(
+            "ALTER TABLE files ADD COLUMN mode INTEGER;
+             ALTER TABLE files ADD COLUMN uid INTEGER;
+             ALTER TABLE files ADD COLUMN gid INTEGER;"
+        )?;
+    }
+    Ok(())
+}
+
+/// 将旧格式的时间列重写为ISO 8601格式，无法解析的值置为空字符串
+fn migrate_legacy_timestamps(conn: &Connection) -> Result<usize, LocalPilotError> {
+    let mut stmt = conn.prepare(
+        "SELECT path, modified, created FROM files
+         WHERE modified LIKE 'SystemTime%' OR created LIKE 'SystemTime%'"
+    )?;
+    let legacy_rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    
+    let convert = |value: Option<String>| -> String {
+        match value {
+            Some(v) if v.starts_with("SystemTime") => parse_legacy_timestamp(&v).unwrap_or_default(),
+            Some(v) => v,
+            None => String::new(),
+        }
+    };
+    
+    for (path, modified, created) in &legacy_rows {
+        conn.execute(
+            "UPDATE files SET modified = ?1, created = ?2 WHERE path = ?3",
+            params![convert(modified.clone()), convert(created.clone()), path],
+        )?;
+    }
+    
+    Ok(legacy_rows.len())
+}
+
+/// 默认索引的用户目录：下载、桌面、文档（仅包含实际存在的目录）
+pub fn default_index_roots() -> Vec<PathBuf> {
+    let mut roots = Vec::new();
+    
+    if let Some(user_dirs) = UserDirs::new() {
+        let candidates = [
+            user_dirs.download_dir(),
+            user_dirs.desktop_dir(),
+            user_dirs.document_dir(),
+        ];
+        for dir in candidates.into_iter().flatten() {
+            if dir.exists() {
+                roots.push(dir.to_path_buf());
+            }
+        }
+    }
+    
+    roots
+}
+
+/// 索引数据库在数据目录中的文件名
+pub const INDEX_DB_FILE: &str = "file_index.db";
+
+/// 在 `data_dir` 中打开索引数据库，目录不存在时先创建
+pub fn open_file_index(data_dir: &Path) -> Result<FileIndexer, LocalPilotError> {
+    fs::create_dir_all(data_dir).map_err(|e| {
+        LocalPilotError::Io(std::io::Error::new(
+            e.kind(),
+            format!("Cannot create the data directory {} for the file index: {}", data_dir.display(), e),
+        ))
+    })?;
+    FileIndexer::new(data_dir.join(INDEX_DB_FILE))
+}
+
+/// 初始化文件索引器
+pub fn initialize_file_indexer(app_handle: &AppHandle) -> Result<FileIndexer, LocalPilotError> {
+    // 获取应用数据目录
+    let app_data_dir = app_handle.path().app_data_dir().map_err(|e| {
+        LocalPilotError::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("The app data directory is unavailable: {}", e),
+        ))
+    })?;
+    
+    // 创建文件索引器，目录扫描由调用方在后台进行
+    open_file_index(&app_data_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// 生成唯一的临时路径，避免测试之间互相干扰
+    fn unique_temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}_{}", name, uuid::Uuid::new_v4()))
+    }
+    
+    #[test]
+    fn test_file_indexer_creation() {
+        let temp_dir = std::env::temp_dir().join("test_file_indexer.db");
+        let indexer = FileIndexer::new(temp_dir.to_str().unwrap());
+        assert!(indexer.is_ok());
+    }
+    
+    #[test]
+    fn test_index_is_opened_in_a_missing_data_directory() {
+        let data_dir = unique_temp_path("test_missing_data_dir").join("nested");
+        let indexer = open_file_index(&data_dir).unwrap();
+        assert!(data_dir.join(INDEX_DB_FILE).exists());
+        assert_eq!(indexer.count_indexed_files().unwrap(), 0);
+        
+        // 数据目录的位置被文件占用时，错误中指明是哪个目录
+        let blocker = unique_temp_path("test_data_dir_blocker");
+        fs::write(&blocker, "not a directory").unwrap();
+        let error = open_file_index(&blocker.join("data")).err().unwrap();
+        assert!(matches!(error, LocalPilotError::Io(_)));
+        assert!(error.user_message().contains(&blocker.display().to_string()));
+        
+        fs::remove_dir_all(data_dir.parent().unwrap()).ok();
+        fs::remove_file(&blocker).ok();
+    }
+    
+    #[test]
+    fn test_stored_timestamps_are_rfc3339() {
+        let scan_dir = unique_temp_path("test_timestamps_dir");
+        fs::create_dir_all(&scan_dir).unwrap();
+        fs::write(scan_dir.join("timestamp_probe.txt"), "hello").unwrap();
+        
+        let db_path = unique_temp_path("test_timestamps.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        
+        let files = indexer.search_by_filename("timestamp_probe", DEFAULT_SEARCH_LIMIT, 0).unwrap().files;
+        assert_eq!(files.len(), 1);
+        let parsed = DateTime::parse_from_rfc3339(&files[0].modified).unwrap();
+        assert_eq!(parsed.to_rfc3339_opts(SecondsFormat::Secs, true), files[0].modified);
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_legacy_timestamps_are_migrated() {
+        let db_path = unique_temp_path("test_migration.db");
+        {
+            let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+            let conn = indexer.get_connection();
+            conn.lock().unwrap().execute(
+                "INSERT INTO files (path, name, extension, size, modified, created, is_directory)
+                 VALUES ('/tmp/legacy.txt', 'legacy.txt', 'txt', 1, ?1, ?2, false)",
+                params![
+                    "SystemTime { tv_sec: 1672531200, tv_nsec: 500 }",
+                    "SystemTime { intervals: 133170048000000000 }"
+                ],
+            ).unwrap();
+        }
+        
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        let files = indexer.search_by_filename("legacy", DEFAULT_SEARCH_LIMIT, 0).unwrap().files;
+        assert_eq!(files[0].modified, "2023-01-01T00:00:00Z");
+        assert_eq!(files[0].created, "2023-01-01T00:00:00Z");
+    }
+    
+    #[test]
+    fn test_stats_counts_files_directories_and_extensions() {
+        let scan_dir = unique_temp_path("test_stats_dir");
+        fs::create_dir_all(scan_dir.join("nested")).unwrap();
+        fs::write(scan_dir.join("a.txt"), "12345").unwrap();
+        fs::write(scan_dir.join("b.txt"), "123").unwrap();
+        fs::write(scan_dir.join("nested").join("c.txt"), "1").unwrap();
+        fs::write(scan_dir.join("image.png"), "1234567890").unwrap();
+        fs::write(scan_dir.join("README"), "12").unwrap();
+        
+        let db_path = unique_temp_path("test_stats.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        
+        let stats = indexer.stats().unwrap();
+        assert_eq!(stats.total_files, 5);
+        assert_eq!(stats.total_directories, 2);  // 扫描根目录本身和nested
+        assert_eq!(stats.total_size, 21);
+        assert_eq!(stats.top_extensions[0], ExtensionCount { extension: Some("txt".to_string()), count: 3 });
+        assert_eq!(stats.top_extensions.len(), 3);
+        assert!(stats.top_extensions.contains(&ExtensionCount { extension: None, count: 1 }));
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    /// 解析一行CSV，只处理导出时会产生的引号规则
+    fn parse_csv_line(line: &str) -> Vec<String> {
+        let mut fields = vec![String::new()];
+        let mut quoted = false;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    fields.last_mut().unwrap().push('"');
+                    chars.next();
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => fields.push(String::new()),
+                c => fields.last_mut().unwrap().push(c),
+            }
+        }
+        fields
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_cycles_are_followed_once() {
+        use std::os::unix::fs::symlink;
+        
+        let scan_dir = unique_temp_path("test_symlink_dir");
+        let outside = unique_temp_path("test_symlink_outside");
+        fs::create_dir_all(scan_dir.join("docs")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(scan_dir.join("docs").join("report.txt"), "12345").unwrap();
+        fs::write(outside.join("external.txt"), "1").unwrap();
+        // docs/up指回根目录形成环；shortcut.txt和report.txt是同一个文件
+        symlink(&scan_dir, scan_dir.join("docs").join("up")).unwrap();
+        symlink(scan_dir.join("docs").join("report.txt"), scan_dir.join("shortcut.txt")).unwrap();
+        symlink(&outside, scan_dir.join("linked")).unwrap();
+        
+        let db_path = unique_temp_path("test_symlink.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        let all = || indexer.search(&FileQuery::new()).unwrap();
+        
+        // 默认不跟随，符号链接被跳过
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        let mut names: Vec<String> = all().into_iter().map(|f| f.name).filter(|name| name.as_str() != scan_dir.file_name().unwrap()).collect();
+        names.sort();
+        assert_eq!(names, ["docs", "report.txt"]);
+        
+        // 新的索引，避免上一次扫描留下的条目
+        let indexer = FileIndexer::new(unique_temp_path("test_symlink_follow.db").to_str().unwrap()).unwrap();
+        indexer.set_follow_symlinks(true);
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        let files = indexer.search(&FileQuery::new()).unwrap();
+        // 环被截断，同一目标只索引一次
+        assert_eq!(files.iter().filter(|f| f.name == "report.txt" || f.name == "shortcut.txt").count(), 1);
+        assert!(!files.iter().any(|f| f.path.contains("/up/")));
+        let linked = files.iter().find(|f| f.name == "linked").unwrap();
+        assert!(linked.is_symlink);
+        assert!(linked.is_directory);
+        let external = files.iter().find(|f| f.name == "external.txt").unwrap();
+        assert!(!external.is_symlink);
+        
+        // 单独重新索引符号链接时同样记录
+        indexer.index_path(&scan_dir.join("shortcut.txt")).unwrap();
+        let shortcut = indexer.search(&FileQuery::new().name_contains("shortcut")).unwrap();
+        assert!(shortcut[0].is_symlink);
+        assert_eq!(shortcut[0].size, 5);
+        
+        fs::remove_dir_all(&scan_dir).ok();
+        fs::remove_dir_all(&outside).ok();
+    }
+    
+    #[test]
+    fn test_export_index_as_json_and_csv() {
+        let scan_dir = unique_temp_path("test_export_dir");
+        fs::create_dir_all(scan_dir.join("nested")).unwrap();
+        fs::write(scan_dir.join("report, final.txt"), "12345").unwrap();
+        fs::write(scan_dir.join("nested").join("README"), "12").unwrap();
+        
+        let db_path = unique_temp_path("test_export.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        let mut expected = indexer.search(&FileQuery::new()).unwrap();
+        expected.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(expected.len(), 4);
+        
+        let json_path = unique_temp_path("test_export.json");
+        assert_eq!(indexer.export(ExportFormat::Json, &json_path).unwrap(), 4);
+        let exported: Vec<FileInfo> = serde_json::from_str(&fs::read_to_string(&json_path).unwrap()).unwrap();
+        assert_eq!(exported.len(), expected.len());
+        for (exported, expected) in exported.iter().zip(&expected) {
+            assert_eq!(exported.path, expected.path);
+            assert_eq!(exported.extension, expected.extension);
+            assert_eq!(exported.size, expected.size);
+            assert_eq!(exported.modified, expected.modified);
+            assert_eq!(exported.is_directory, expected.is_directory);
+        }
+        
+        let csv_path = unique_temp_path("test_export.csv");
+        assert_eq!(indexer.export(ExportFormat::Csv, &csv_path).unwrap(), 4);
+        let csv = fs::read_to_string(&csv_path).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some(CSV_HEADER));
+        let rows: Vec<Vec<String>> = lines.map(parse_csv_line).collect();
+        assert_eq!(rows.len(), expected.len());
+        for (row, expected) in rows.iter().zip(&expected) {
+            assert_eq!(row[0], expected.path);
+            assert_eq!(row[1], expected.name);
+            assert_eq!(row[2], expected.extension.clone().unwrap_or_default());
+            assert_eq!(row[3], expected.size.to_string());
+            assert_eq!(row[6], expected.is_directory.to_string());
+        }
+        assert!(rows.iter().any(|row| row[1] == "report, final.txt"));
+        
+        // 空索引导出为空数组
+        let empty = FileIndexer::new(unique_temp_path("test_export_empty.db").to_str().unwrap()).unwrap();
+        empty.export(ExportFormat::Json, &json_path).unwrap();
+        assert!(serde_json::from_str::<Vec<FileInfo>>(&fs::read_to_string(&json_path).unwrap()).unwrap().is_empty());
+        
+        fs::remove_file(&json_path).ok();
+        fs::remove_file(&csv_path).ok();
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_index_filter_skips_files_and_persists() {
+        let scan_dir = unique_temp_path("test_filter_dir");
+        fs::create_dir_all(scan_dir.join(".git_like")).unwrap();
+        fs::write(scan_dir.join(".DS_Store"), "ds").unwrap();
+        fs::write(scan_dir.join("Cargo.lock"), "").unwrap();
+        fs::write(scan_dir.join("tiny.txt"), "abc").unwrap();
+        fs::write(scan_dir.join("notes.txt"), "a".repeat(100)).unwrap();
+        fs::write(scan_dir.join("photo.PNG"), "p".repeat(100)).unwrap();
+        fs::write(scan_dir.join(".git_like").join("config.txt"), "c".repeat(100)).unwrap();
+        
+        let db_path = unique_temp_path("test_filter.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        let names = |indexer: &FileIndexer| {
+            let mut names: Vec<String> = indexer
+                .search(&FileQuery::new().is_directory(false))
+                .unwrap()
+                .into_iter()
+                .map(|f| f.name)
+                .collect();
+            names.sort();
+            names
+        };
+        let rescan = |indexer: &FileIndexer| {
+            indexer.scan_directory_incremental(scan_dir.to_str().unwrap(), 0, |_| {}).unwrap();
+        };
+        
+        rescan(&indexer);
+        assert_eq!(names(&indexer).len(), 6);
+        
+        // 空文件和隐藏文件可以分别跳过；不再符合条件的条目在增量扫描时删除
+        indexer.set_index_filter(IndexFilter { skip_empty: true, ..Default::default() }).unwrap();
+        rescan(&indexer);
+        assert_eq!(names(&indexer), [".DS_Store", "config.txt", "notes.txt", "photo.PNG", "tiny.txt"]);
+        
+        indexer.set_index_filter(IndexFilter { skip_hidden: true, ..Default::default() }).unwrap();
+        rescan(&indexer);
+        assert_eq!(names(&indexer), ["Cargo.lock", "notes.txt", "photo.PNG", "tiny.txt"]);
+        assert!(indexer.search(&FileQuery::new().name_contains("git_like")).unwrap().is_empty());
+        
+        // 单个路径重新索引时，索引根目录下隐藏目录中的文件同样跳过
+        let root = PathBuf::from(indexer.add_index_root(&scan_dir).unwrap());
+        indexer.index_path(&root.join(".git_like").join("config.txt")).unwrap();
+        assert!(indexer.search(&FileQuery::new().name_contains("config")).unwrap().is_empty());
+        
+        // 最小大小和扩展名名单，扩展名不区分大小写
+        indexer.set_index_filter(IndexFilter {
+            min_size: 10,
+            exclude_extensions: vec![".png".to_string()],
+            ..Default::default()
+        }).unwrap();
+        rescan(&indexer);
+        assert_eq!(names(&indexer), ["config.txt", "notes.txt"]);
+        
+        let filter = IndexFilter { include_extensions: vec!["PNG".to_string()], ..Default::default() };
+        indexer.set_index_filter(filter).unwrap();
+        rescan(&indexer);
+        assert_eq!(names(&indexer), ["photo.PNG"]);
+        
+        // 单个路径重新索引时同样过滤
+        indexer.index_path(&scan_dir.join("notes.txt")).unwrap();
+        assert_eq!(names(&indexer), ["photo.PNG"]);
+        
+        // 过滤条件保存在数据库中
+        drop(indexer);
+        let reopened = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.index_filter().include_extensions, ["png"]);
+        
+        fs::remove_dir_all(&scan_dir).ok();
+        fs::remove_file(&db_path).ok();
+    }
+    
+    #[test]
+    fn test_vacuum_reclaims_space_of_deleted_rows() {
+        let db_path = unique_temp_path("test_vacuum.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        let empty = indexer.storage_stats().unwrap();
+        assert_eq!(empty.row_count, 0);
+        
+        {
+            let conn = indexer.get_connection();
+            let conn = conn.lock().unwrap();
+            conn.execute_batch("BEGIN TRANSACTION").unwrap();
+            for i in 0..20_000 {
+                conn.execute(
+                    "INSERT INTO files (path, name, extension, size, modified, created, is_directory)
+                     VALUES (?1, ?2, 'txt', ?3, '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z', false)",
+                    params![format!("/bloat/some/deeply/nested/directory/file_{:06}.txt", i), format!("file_{:06}.txt", i), i],
+                ).unwrap();
+            }
+            conn.execute_batch("COMMIT").unwrap();
+        }
+        indexer.vacuum().unwrap();
+        let filled = indexer.storage_stats().unwrap();
+        assert_eq!(filled.row_count, 20_000);
+        assert!(filled.used_size > empty.used_size);
+        
+        assert_eq!(indexer.remove_directory("/bloat").unwrap(), 20_000);
+        let deleted = indexer.storage_stats().unwrap();
+        assert_eq!(deleted.row_count, 0);
+        
+        let summary = indexer.vacuum().unwrap();
+        assert!(summary.reclaimed_bytes > 0);
+        assert!(summary.storage.file_size < deleted.file_size);
+        assert!(summary.storage.used_size < filled.used_size);
+        assert_eq!(summary.storage, indexer.storage_stats().unwrap());
+        
+        fs::remove_file(&db_path).ok();
+    }
+    
+    #[test]
+    fn test_search_by_filename_pagination_boundaries() {
+        let scan_dir = unique_temp_path("test_pagination_dir");
+        fs::create_dir_all(&scan_dir).unwrap();
+        for i in 0..5 {
+            fs::write(scan_dir.join(format!("page_probe_{}.txt", i)), "x").unwrap();
+        }
+        // 搜索词中的 `_` 按字面匹配，不会匹配到这个文件
+        fs::write(scan_dir.join("pageXprobe_9.txt"), "x").unwrap();
+        
+        let db_path = unique_temp_path("test_pagination.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        
+        let first = indexer.search_by_filename("page_probe", 2, 0).unwrap();
+        assert_eq!(first.total, 5);
+        assert_eq!(first.files.len(), 2);
+        
+        let last = indexer.search_by_filename("page_probe", 2, 4).unwrap();
+        assert_eq!(last.files.len(), 1);
+        
+        let at_end = indexer.search_by_filename("page_probe", 2, 5).unwrap();
+        assert!(at_end.files.is_empty());
+        assert_eq!(at_end.total, 5);
+        
+        let past_end = indexer.search_by_filename("page_probe", 2, 100).unwrap();
+        assert!(past_end.files.is_empty());
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_search_by_filename_ranks_exact_then_prefix_then_substring() {
+        let scan_dir = unique_temp_path("test_rank_dir");
+        fs::create_dir_all(&scan_dir).unwrap();
+        let names = ["annual_report_draft.txt", "report_2024.txt", "report.txt", "old_report.txt"];
+        for (i, name) in names.iter().enumerate() {
+            let path = scan_dir.join(name);
+            fs::write(&path, "x").unwrap();
+            // 完全匹配的文件修改时间最早，确保排在前面靠的是匹配档次
+            let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000 + i as u64 * 60);
+            fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        }
+        
+        let db_path = unique_temp_path("test_rank.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        
+        let page = indexer.search_by_filename("report", 10, 0).unwrap();
+        let ranked: Vec<&str> = page.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(ranked, ["report.txt", "report_2024.txt", "old_report.txt", "annual_report_draft.txt"]);
+        assert_eq!(page.total, 4);
+        
+        let second = indexer.search_by_filename("report", 2, 2).unwrap();
+        let ranked: Vec<&str> = second.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(ranked, ["old_report.txt", "annual_report_draft.txt"]);
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_case_only_renames_keep_one_row_per_file() {
+        let scan_dir = unique_temp_path("test_case_dir");
+        fs::create_dir_all(&scan_dir).unwrap();
+        fs::write(scan_dir.join("Case_Probe.txt"), "hello").unwrap();
+        
+        // 在区分大小写的文件系统上模拟不区分大小写的行为
+        let db_path = unique_temp_path("test_case.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap())
+            .unwrap()
+            .with_case_insensitive_paths(true);
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        
+        fs::rename(scan_dir.join("Case_Probe.txt"), scan_dir.join("case_probe.txt")).unwrap();
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        let files = indexer.search(&FileQuery::new().name_contains("probe")).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "case_probe.txt");
+        
+        // 用大小写不同的路径重新索引时使用磁盘上的写法
+        indexer.index_path(&scan_dir.join("CASE_PROBE.TXT")).unwrap();
+        let files = indexer.search(&FileQuery::new().name_contains("probe")).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, scan_dir.join("case_probe.txt").to_string_lossy());
+        
+        // 增量扫描把旧写法当作已删除的条目
+        fs::rename(scan_dir.join("case_probe.txt"), scan_dir.join("CASE_probe.txt")).unwrap();
+        indexer.scan_directory_incremental(scan_dir.to_str().unwrap(), 0, |_| {}).unwrap();
+        let files = indexer.search(&FileQuery::new().name_contains("probe")).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "CASE_probe.txt");
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_file_query_filters_and_rejects_injection() {
+        let scan_dir = unique_temp_path("test_file_query_dir");
+        fs::create_dir_all(scan_dir.join("query_probe_dir")).unwrap();
+        fs::write(scan_dir.join("query_probe_small.txt"), "x").unwrap();
+        fs::write(scan_dir.join("query_probe_large.txt"), "x".repeat(4096)).unwrap();
+        fs::write(scan_dir.join("query_probe_100%.md"), "x").unwrap();
+        
+        let db_path = unique_temp_path("test_file_query.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        
+        let large_txt = indexer
+            .search(&FileQuery::new().name_contains("query_probe").extension("txt").min_size(1024))
+            .unwrap();
+        assert_eq!(large_txt.len(), 1);
+        assert_eq!(large_txt[0].name, "query_probe_large.txt");
+        
+        let dirs = indexer.search(&FileQuery::new().name_contains("query_probe").is_directory(true)).unwrap();
+        assert_eq!(dirs.len(), 1);
+        
+        // 通配符按字面匹配
+        let literal = indexer.search(&FileQuery::new().name_contains("100%")).unwrap();
+        assert_eq!(literal.len(), 1);
+        
+        let injected = indexer
+            .search(&FileQuery::new().name_contains("'; DROP TABLE files; --"))
+            .unwrap();
+        assert!(injected.is_empty());
+        assert_eq!(indexer.search(&FileQuery::new().name_contains("query_probe")).unwrap().len(), 4);
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_file_query_applies_each_filter() {
+        let db_path = unique_temp_path("test_file_query_each.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        {
+            let conn = indexer.get_connection();
+            let conn = conn.lock().unwrap();
+            for (name, extension, size, modified, is_directory) in [
+                ("notes.txt", Some("txt"), 10, "2024-01-01T00:00:00Z", false),
+                ("report.pdf", Some("pdf"), 5000, "2024-06-01T00:00:00Z", false),
+                ("old_report.pdf", Some("pdf"), 200, "2023-01-01T00:00:00Z", false),
+                ("reports", None, 0, "2024-06-01T00:00:00Z", true),
+            ] {
+                conn.execute(
+                    "INSERT INTO files (path, name, extension, size, modified, created, is_directory)
+                     VALUES (?1, ?2, ?3, ?4, ?5, '', ?6)",
+                    params![format!("/data/{}", name), name, extension, size, modified, is_directory],
+                ).unwrap();
+            }
+        }
+        let names = |query: FileQuery| {
+            let mut names: Vec<String> = indexer.search(&query).unwrap().into_iter().map(|f| f.name).collect();
+            names.sort();
+            names
+        };
+        let after = |text: &str| DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&Utc);
+        
+        assert_eq!(names(FileQuery::new().name_contains("report")), ["old_report.pdf", "report.pdf", "reports"]);
+        assert_eq!(names(FileQuery::new().extension("pdf")), ["old_report.pdf", "report.pdf"]);
+        assert_eq!(names(FileQuery::new().min_size(200)), ["old_report.pdf", "report.pdf"]);
+        assert_eq!(names(FileQuery::new().max_size(10)), ["notes.txt", "reports"]);
+        assert_eq!(names(FileQuery::new().is_directory(true)), ["reports"]);
+        assert_eq!(names(FileQuery::new().modified_after(after("2024-01-01T00:00:00Z"))), ["report.pdf", "reports"]);
+        assert_eq!(names(FileQuery::new().modified_before(after("2024-01-01T00:00:00Z"))), ["old_report.pdf"]);
+        
+        // 组合条件同时生效
+        assert_eq!(
+            names(FileQuery::new().name_contains("report").is_directory(false).min_size(100).max_size(1000)),
+            ["old_report.pdf"]
+        );
+        assert_eq!(
+            names(FileQuery::new().extension("pdf").modified_after(after("2023-06-01T12:00:00+08:00"))),
+            ["report.pdf"]
+        );
+        assert!(names(FileQuery::new().extension("txt").min_size(100)).is_empty());
+    }
+    
+    #[test]
+    fn test_identical_files_are_grouped_as_duplicates() {
+        let db_path = unique_temp_path("test_duplicates.db");
+        let scan_dir = unique_temp_path("test_duplicates_dir");
+        fs::create_dir_all(scan_dir.join("backup")).unwrap();
+        fs::write(scan_dir.join("photo.jpg"), b"same bytes").unwrap();
+        fs::write(scan_dir.join("backup").join("photo copy.jpg"), b"same bytes").unwrap();
+        fs::write(scan_dir.join("other.jpg"), b"different bytes").unwrap();
+        fs::write(scan_dir.join("empty1.txt"), b"").unwrap();
+        fs::write(scan_dir.join("empty2.txt"), b"").unwrap();
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        
+        // 默认不计算哈希
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        assert!(indexer.find_duplicates().unwrap().is_empty());
+        
+        // 开启后增量扫描为未变化的文件补算哈希；空文件不算重复
+        indexer.set_content_hashing(true);
+        indexer.scan_directory_incremental(scan_dir.to_str().unwrap(), 0, |_| {}).unwrap();
+        let groups = indexer.find_duplicates().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].size, 10);
+        assert_eq!(groups[0].hash, blake3::hash(b"same bytes").to_hex().to_string());
+        let paths: Vec<&str> = groups[0].files.iter().map(|f| f.path.as_str()).collect();
+        let expected = [scan_dir.join("backup").join("photo copy.jpg"), scan_dir.join("photo.jpg")];
+        assert_eq!(paths, expected.iter().map(|p| p.to_str().unwrap()).collect::<Vec<_>>());
+        
+        // 修改后的文件重新计算哈希，不再重复
+        fs::write(scan_dir.join("photo.jpg"), b"edited bytes!").unwrap();
+        indexer.index_path(&scan_dir.join("photo.jpg")).unwrap();
+        assert!(indexer.find_duplicates().unwrap().is_empty());
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_modified_file_loses_stale_hash() {
+        let db_path = unique_temp_path("test_stale_hash.db");
+        let scan_dir = unique_temp_path("test_stale_hash_dir");
+        fs::create_dir_all(&scan_dir).unwrap();
+        fs::write(scan_dir.join("a.txt"), b"same bytes").unwrap();
+        fs::write(scan_dir.join("b.txt"), b"same bytes").unwrap();
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        indexer.set_content_hashing(true);
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        assert_eq!(indexer.find_duplicates().unwrap().len(), 1);
+        
+        // 关闭哈希后修改文件，重新扫描时旧哈希被清空，而不是继续报告为重复
+        indexer.set_content_hashing(false);
+        fs::write(scan_dir.join("b.txt"), b"different bytes").unwrap();
+        let summary = indexer.scan_directory_incremental(scan_dir.to_str().unwrap(), 0, |_| {}).unwrap();
+        assert_eq!(summary.updated, 1);
+        assert!(indexer.find_duplicates().unwrap().is_empty());
+        let path = scan_dir.join("b.txt").to_string_lossy().to_string();
+        let hash: Option<String> = indexer
+            .db_connection
+            .lock_or_recover()
+            .query_row("SELECT hash FROM files WHERE path = ?1", [&path], |row| row.get(0))
+            .unwrap();
+        assert_eq!(hash, None);
+        
+        // 未变化的文件保留哈希
+        let path = scan_dir.join("a.txt").to_string_lossy().to_string();
+        indexer.index_path(&scan_dir.join("a.txt")).unwrap();
+        let hash: Option<String> = indexer
+            .db_connection
+            .lock_or_recover()
+            .query_row("SELECT hash FROM files WHERE path = ?1", [&path], |row| row.get(0))
+            .unwrap();
+        assert_eq!(hash, Some(blake3::hash(b"same bytes").to_hex().to_string()));
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_search_by_modification_time_range() {
+        let db_path = unique_temp_path("test_modified_range.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        let recent = (Utc::now() - chrono::Duration::hours(30)).to_rfc3339_opts(SecondsFormat::Secs, true);
+        {
+            let conn = indexer.get_connection();
+            let conn = conn.lock().unwrap();
+            for (name, modified, is_directory) in [
+                ("old.txt", "2023-12-31T23:59:59Z", false),
+                ("start.txt", "2024-01-01T00:00:00Z", false),
+                ("middle.txt", "2024-03-15T08:30:00Z", false),
+                ("folder", "2024-03-15T08:30:00Z", true),
+                ("end.txt", "2024-06-30T00:00:00Z", false),
+                ("later.txt", "2024-07-01T00:00:00Z", false),
+                ("yesterday.txt", recent.as_str(), false),
+            ] {
+                conn.execute(
+                    "INSERT INTO files (path, name, extension, size, modified, created, is_directory)
+                     VALUES (?1, ?2, NULL, 0, ?3, '', ?4)",
+                    params![format!("/data/{}", name), name, modified, is_directory],
+                ).unwrap();
+            }
+        }
+        let time = |text: &str| DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&Utc);
+        let names = |files: Vec<FileInfo>| files.into_iter().map(|f| f.name).collect::<Vec<_>>();
+        
+        // 两端都包含在内，目录不返回，最近修改的在前
+        let files = indexer
+            .search_modified_between(time("2024-01-01T00:00:00Z"), time("2024-06-30T00:00:00Z"), DEFAULT_SEARCH_LIMIT)
+            .unwrap();
+        assert_eq!(names(files), ["end.txt", "middle.txt", "start.txt"]);
+        
+        // 其他时区的时间按UTC比较
+        let files = indexer
+            .search_modified_between(time("2024-03-15T16:00:00+08:00"), time("2024-07-01T08:00:00+08:00"), DEFAULT_SEARCH_LIMIT)
+            .unwrap();
+        assert_eq!(names(files), ["later.txt", "end.txt", "middle.txt"]);
+        
+        let files = indexer
+            .search_modified_between(time("2024-01-01T00:00:00Z"), time("2024-12-31T00:00:00Z"), 2)
+            .unwrap();
+        assert_eq!(names(files), ["later.txt", "end.txt"]);
+        
+        assert_eq!(names(indexer.search_recent(2, DEFAULT_SEARCH_LIMIT).unwrap()), ["yesterday.txt"]);
+        assert!(indexer.search_recent(1, DEFAULT_SEARCH_LIMIT).unwrap().is_empty());
+        
+        let error = indexer
+            .search_modified_between(time("2024-06-30T00:00:00Z"), time("2024-01-01T00:00:00Z"), DEFAULT_SEARCH_LIMIT)
+            .unwrap_err();
+        assert!(matches!(error, LocalPilotError::InvalidInput(_)));
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn test_permissions_are_indexed_and_searchable() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        
+        let scan_dir = unique_temp_path("test_permissions_dir");
+        fs::create_dir_all(&scan_dir).unwrap();
+        let shared = scan_dir.join("shared.sh");
+        let private = scan_dir.join("private.txt");
+        fs::write(&shared, "#!/bin/sh").unwrap();
+        fs::write(&private, "secret").unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o757)).unwrap();
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o600)).unwrap();
+        
+        let db_path = unique_temp_path("test_permissions.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        
+        let owner = fs::metadata(&private).unwrap();
+        let files = indexer.search_by_filename("private", DEFAULT_SEARCH_LIMIT, 0).unwrap().files;
+        assert_eq!(files[0].mode, Some(0o600));
+        assert_eq!(files[0].uid, Some(owner.uid()));
+        assert_eq!(files[0].gid, Some(owner.gid()));
+        
+        let names = |files: Vec<FileInfo>| files.into_iter().map(|file| file.name).collect::<Vec<_>>();
+        assert_eq!(names(indexer.search_by_permission(0o002, DEFAULT_SEARCH_LIMIT).unwrap()), ["shared.sh"]);
+        assert!(indexer.search_by_permission(0o4000, DEFAULT_SEARCH_LIMIT).unwrap().is_empty());
+        
+        // 只改权限时修改时间不变，增量扫描也要更新
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o666)).unwrap();
+        let summary = indexer.scan_directory_incremental(scan_dir.to_str().unwrap(), 0, |_| {}).unwrap();
+        assert_eq!(summary.updated, 1);
+        assert_eq!(
+            names(indexer.search_by_permission(0o002, DEFAULT_SEARCH_LIMIT).unwrap()).len(),
+            2
+        );
+        
+        let error = indexer.search_by_permission(0o10000, DEFAULT_SEARCH_LIMIT).unwrap_err();
+        assert!(matches!(error, LocalPilotError::InvalidInput(_)));
+    }
+    
+    #[test]
+    fn test_scan_progress_callback_interval() {
+        // 根目录 + 2个子目录 + 7个文件 = 10个条目
+        let scan_dir = unique_temp_path("test_progress_dir");
+        fs::create_dir_all(scan_dir.join("a")).unwrap();
+        fs::create_dir_all(scan_dir.join("b")).unwrap();
+        for i in 0..3 {
+            fs::write(scan_dir.join("a").join(format!("{}.txt", i)), "x").unwrap();
+        }
+        for i in 0..4 {
+            fs::write(scan_dir.join("b").join(format!("{}.txt", i)), "x").unwrap();
+        }
+        
+        let db_path = unique_temp_path("test_progress.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        let mut reported = Vec::new();
+        let scanned = indexer
+            .scan_directory_with_progress(scan_dir.to_str().unwrap(), 3, |p| reported.push(p.scanned))
+            .unwrap();
+        
+        assert_eq!(scanned, 10);
+        assert_eq!(reported, vec![3, 6, 9]);
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_parallel_scan_indexes_every_root() {
+        let roots: Vec<PathBuf> = (0..3).map(|i| unique_temp_path(&format!("test_parallel_root{}", i))).collect();
+        for (i, root) in roots.iter().enumerate() {
+            fs::create_dir_all(root.join("sub")).unwrap();
+            for j in 0..20 {
+                fs::write(root.join("sub").join(format!("root{}_file{}.txt", i, j)), "x").unwrap();
+            }
+        }
+        let indexed_paths = |indexer: &FileIndexer| -> Vec<String> {
+            let mut paths: Vec<String> = indexer
+                .search(&FileQuery::new().name_contains("_file").limit(1000))
+                .unwrap()
+                .into_iter()
+                .map(|file| file.path)
+                .collect();
+            paths.sort();
+            paths
+        };
+        
+        let sequential = FileIndexer::new(unique_temp_path("test_parallel_seq.db").to_str().unwrap()).unwrap();
+        sequential.set_scan_parallelism(1);
+        let scanned = sequential.scan_directories_with_progress(&roots, 0, &CancellationToken::new(), |_| {});
+        assert_eq!(scanned.unwrap(), 66);
+        
+        let parallel = FileIndexer::new(unique_temp_path("test_parallel.db").to_str().unwrap()).unwrap();
+        parallel.set_scan_parallelism(3);
+        let mut reversed = roots.clone();
+        reversed.reverse();
+        let mut reported = Vec::new();
+        let summary = parallel
+            .scan_directories_incremental(&reversed, 10, &CancellationToken::new(), |p| reported.push(p.scanned))
+            .unwrap();
+        assert_eq!(summary.added, 66);
+        assert_eq!(reported, [10, 20, 30, 40, 50, 60]);
+        assert_eq!(indexed_paths(&parallel).len(), 60);
+        assert_eq!(indexed_paths(&parallel), indexed_paths(&sequential));
+        
+        // 再次增量扫描时各根目录的已有条目都能对上
+        fs::remove_file(roots[1].join("sub").join("root1_file0.txt")).unwrap();
+        let summary = parallel.scan_directories_incremental(&roots, 0, &CancellationToken::new(), |_| {}).unwrap();
+        assert_eq!((summary.added, summary.removed), (0, 1));
+        assert_eq!(summary.unchanged + summary.updated, 65);
+        
+        for root in &roots {
+            fs::remove_dir_all(root).ok();
+        }
+    }
+    
+    #[test]
+    fn test_searches_do_not_wait_for_each_other_or_writes() {
+        let scan_dir = unique_temp_path("test_read_pool_dir");
+        fs::create_dir_all(&scan_dir).unwrap();
+        fs::write(scan_dir.join("pooled.txt"), "x").unwrap();
+        let db_path = unique_temp_path("test_read_pool.db");
+        let indexer = Arc::new(FileIndexer::new(db_path.to_str().unwrap()).unwrap());
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        
+        // 模拟一次长时间的写入和占满其余连接的长时间搜索，只留一个空闲的只读连接
+        let writer = indexer.get_connection();
+        let _writing = writer.lock().unwrap();
+        let _searching: Vec<_> = (1..READ_POOL_SIZE).map(|_| indexer.read_pool.get()).collect();
+        
+        let (sender, receiver) = mpsc::channel();
+        let searcher = Arc::clone(&indexer);
+        std::thread::spawn(move || {
+            let found = searcher.search(&FileQuery::new().name_contains("pooled")).unwrap();
+            sender.send(found.len()).unwrap();
+        });
+        let found = receiver.recv_timeout(std::time::Duration::from_secs(5));
+        assert_eq!(found, Ok(1), "search was blocked by other connections");
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_indexing_guard_is_exclusive() {
+        let db_path = unique_temp_path("test_indexing_guard.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        
+        let guard = indexer.begin_indexing();
+        assert!(guard.is_some());
+        assert!(indexer.is_indexing());
+        assert!(indexer.begin_indexing().is_none());
+        
+        drop(guard);
+        assert!(!indexer.is_indexing());
+    }
+    
+    #[test]
+    fn test_cancelled_scan_stops_promptly_and_keeps_rows() {
+        let scan_dir = unique_temp_path("test_cancel_dir");
+        fs::create_dir_all(&scan_dir).unwrap();
+        for i in 0..500 {
+            fs::write(scan_dir.join(format!("{}.txt", i)), "x").unwrap();
+        }
+        let roots = [scan_dir.clone()];
+        let db_path = unique_temp_path("test_cancel.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        let indexed = || indexer.search(&FileQuery::new().extension("txt").limit(1000)).unwrap().len();
+        
+        let guard = indexer.begin_indexing().unwrap();
+        let result = indexer.scan_directories_with_progress(&roots, 10, guard.cancellation(), |_| {
+            indexer.cancel_indexing();
+        });
+        assert!(matches!(result, Err(LocalPilotError::Cancelled)));
+        // 第10个条目之后就停止了（根目录本身也是一个条目）
+        assert_eq!(indexed(), 9);
+        drop(guard);
+        assert!(!indexer.cancel_indexing());
+        
+        // 新的构建不受上次取消的影响
+        let guard = indexer.begin_indexing().unwrap();
+        indexer.scan_directories_incremental(&roots, 0, guard.cancellation(), |_| {}).unwrap();
+        assert_eq!(indexed(), 500);
+        
+        // 被取消的增量扫描不会把没遍历到的条目当作已删除
+        for i in 0..100 {
+            fs::remove_file(scan_dir.join(format!("{}.txt", i))).unwrap();
+        }
+        let cancel = guard.cancellation().clone();
+        cancel.cancel();
+        let result = indexer.scan_directories_incremental(&roots, 0, &cancel, |_| {});
+        assert!(matches!(result, Err(LocalPilotError::Cancelled)));
+        assert_eq!(indexed(), 500);
+        drop(guard);
+        
+        // 被取消的完整重建回滚，已清空的条目恢复
+        let guard = indexer.begin_indexing().unwrap();
+        let result = indexer.rebuild_directories_with_progress(&roots, 10, guard.cancellation(), |_| {
+            indexer.cancel_indexing();
+        });
+        assert!(matches!(result, Err(LocalPilotError::Cancelled)));
+        assert_eq!(indexed(), 500);
+        drop(guard);
+        
+        let guard = indexer.begin_indexing().unwrap();
+        let summary = indexer.rebuild_directories_with_progress(&roots, 0, guard.cancellation(), |_| {}).unwrap();
+        assert_eq!(summary.removed, 501);
+        assert_eq!(indexed(), 400);
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_incremental_scan_add_modify_delete() {
+        let scan_dir = unique_temp_path("test_incremental_dir");
+        fs::create_dir_all(&scan_dir).unwrap();
+        fs::write(scan_dir.join("keep.txt"), "keep").unwrap();
+        fs::write(scan_dir.join("change.txt"), "v1").unwrap();
+        fs::write(scan_dir.join("delete.txt"), "gone soon").unwrap();
+        let dir = scan_dir.to_str().unwrap();
+        
+        let db_path = unique_temp_path("test_incremental.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        
+        let first = indexer.scan_directory_incremental(dir, 0, |_| {}).unwrap();
+        assert_eq!(first.added, 4); // 根目录 + 3个文件
+        
+        fs::write(scan_dir.join("change.txt"), "version 2").unwrap();
+        fs::write(scan_dir.join("new.txt"), "new").unwrap();
+        fs::remove_file(scan_dir.join("delete.txt")).unwrap();
+        
+        let second = indexer.scan_directory_incremental(dir, 0, |_| {}).unwrap();
+        assert_eq!(second.added, 1);
+        assert_eq!(second.removed, 1);
+        assert!(second.updated >= 1);
+        
+        let changed = indexer.search(&FileQuery::new().name_contains("change.txt")).unwrap();
+        assert_eq!(changed[0].size, "version 2".len() as u64);
+        assert!(indexer.search(&FileQuery::new().name_contains("delete.txt")).unwrap().is_empty());
+        
+        let third = indexer.scan_directory_incremental(dir, 0, |_| {}).unwrap();
+        assert_eq!(third.added + third.updated + third.removed, 0);
+        assert_eq!(third.unchanged, 4);
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_prune_missing_removes_deleted_files() {
+        let scan_dir = unique_temp_path("test_prune_dir");
+        fs::create_dir_all(scan_dir.join("sub")).unwrap();
+        fs::write(scan_dir.join("keep.txt"), "keep").unwrap();
+        fs::write(scan_dir.join("gone.txt"), "gone").unwrap();
+        fs::write(scan_dir.join("sub").join("nested.txt"), "nested").unwrap();
+        let dir = scan_dir.to_str().unwrap();
+        
+        let db_path = unique_temp_path("test_prune.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        indexer.scan_directory(dir).unwrap();
+        assert_eq!(indexer.prune_missing().unwrap(), 0);
+        
+        fs::remove_file(scan_dir.join("gone.txt")).unwrap();
+        fs::remove_dir_all(scan_dir.join("sub")).unwrap();
+        
+        assert_eq!(indexer.prune_missing().unwrap(), 3); // gone.txt、sub 和 sub/nested.txt
+        assert!(indexer.search(&FileQuery::new().name_contains("gone")).unwrap().is_empty());
+        assert!(indexer.search(&FileQuery::new().name_contains("nested")).unwrap().is_empty());
+        assert_eq!(indexer.search(&FileQuery::new().name_contains("keep")).unwrap().len(), 1);
+        assert_eq!(indexer.prune_missing().unwrap(), 0);
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_index_path_updates_and_deletes_single_file() {
+        let scan_dir = unique_temp_path("test_index_path_dir");
+        fs::create_dir_all(&scan_dir).unwrap();
+        let path = scan_dir.join("single_probe.txt");
+        fs::write(&path, "v1").unwrap();
+        
+        let db_path = unique_temp_path("test_index_path.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        
+        fs::write(&path, "version 2").unwrap();
+        assert!(indexer.index_path(&path).unwrap());
+        let files = indexer.search(&FileQuery::new().name_contains("single_probe")).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].size, "version 2".len() as u64);
+        
+        fs::remove_file(&path).unwrap();
+        assert!(!indexer.index_path(&path).unwrap());
+        assert!(indexer.search(&FileQuery::new().name_contains("single_probe")).unwrap().is_empty());
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_index_roots_override_defaults() {
+        let db_path = unique_temp_path("test_index_roots.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        assert_eq!(indexer.effective_index_roots().unwrap(), default_index_roots());
+        
+        let root_dir = unique_temp_path("test_index_root_dir");
+        fs::create_dir_all(&root_dir).unwrap();
+        let file_path = root_dir.join("not_a_dir.txt");
+        fs::write(&file_path, "x").unwrap();
+        
+        assert!(indexer.add_index_root(&root_dir.join("missing")).is_err());
+        assert!(indexer.add_index_root(&file_path).is_err());
+        
+        let root = indexer.add_index_root(&root_dir).unwrap();
+        assert_eq!(indexer.list_index_roots().unwrap(), vec![root.clone()]);
+        assert_eq!(indexer.effective_index_roots().unwrap(), vec![PathBuf::from(&root)]);
+        
+        assert!(indexer.remove_index_root(&root).unwrap());
+        assert!(indexer.list_index_roots().unwrap().is_empty());
+        
+        fs::remove_dir_all(&root_dir).ok();
+    }
+    
+    #[test]
+    fn test_ignored_directories_are_pruned() {
+        let scan_dir = unique_temp_path("test_ignore_dir");
+        fs::create_dir_all(scan_dir.join("node_modules").join("left-pad")).unwrap();
+        fs::create_dir_all(scan_dir.join("build_output")).unwrap();
+        fs::write(scan_dir.join("node_modules").join("left-pad").join("ignored_probe.js"), "x").unwrap();
+        fs::write(scan_dir.join("build_output").join("ignored_probe.o"), "x").unwrap();
+        fs::write(scan_dir.join("kept_probe.txt"), "x").unwrap();
+        
+        let db_path = unique_temp_path("test_ignore.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        indexer.add_ignore_pattern("build_*/").unwrap();
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        
+        assert!(indexer.search(&FileQuery::new().name_contains("ignored_probe")).unwrap().is_empty());
+        assert!(indexer.search(&FileQuery::new().name_contains("node_modules")).unwrap().is_empty());
+        assert_eq!(indexer.search(&FileQuery::new().name_contains("kept_probe")).unwrap().len(), 1);
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_streaming_filename_search_yields_before_completion() {
+        let db_path = unique_temp_path("test_streaming_search.db");
+        let indexer = Arc::new(FileIndexer::new(db_path.to_str().unwrap()).unwrap());
+        {
+            let conn = indexer.get_connection();
+            let conn = conn.lock().unwrap();
+            conn.execute_batch("BEGIN").unwrap();
+            let file_info = |name: String, modified: String| FileInfo {
+                path: format!("/data/{}", name),
+                name,
+                extension: Some("txt".to_string()),
+                size: 1,
+                modified,
+                created: String::new(),
+                is_directory: false,
+                is_symlink: false,
+                mode: None,
+                uid: None,
+                gid: None,
+            };
+            for i in 0..5000 {
+                let modified = format!("2024-01-01T00:{:02}:{:02}Z", i / 60 % 60, i % 60);
+                upsert_file_info(&conn, &file_info(format!("log_{:05}.txt", i), modified)).unwrap();
+            }
+            // 搜索词中的 `_` 按字面匹配，不会匹配到这个文件
+            upsert_file_info(&conn, &file_info("logX00000.txt".to_string(), "2024-01-02T00:00:00Z".to_string())).unwrap();
+            conn.execute_batch("COMMIT").unwrap();
+        }
+        
+        // 容量为1的通道：接收方取走前一条之前搜索无法继续，所以收到第一条时搜索一定还没结束
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let finished = Arc::new(AtomicBool::new(false));
+        let search = {
+            let indexer = Arc::clone(&indexer);
+            let finished = Arc::clone(&finished);
+            std::thread::spawn(move || {
+                let count = indexer
+                    .search_by_filename_streaming("log_", 10_000, &CancellationToken::new(), |file| sender.send(file).unwrap())
+                    .unwrap();
+                finished.store(true, Ordering::SeqCst);
+                count
+            })
+        };
+        
+        let first = receiver.recv().unwrap();
+        assert!(!finished.load(Ordering::SeqCst));
+        let streamed = 1 + receiver.iter().count();
+        assert_eq!(search.join().unwrap(), 5000);
+        assert_eq!(streamed, 5000);
+        assert!(finished.load(Ordering::SeqCst));
+        // 最近修改的在前
+        assert_eq!(first.modified, "2024-01-01T00:59:59Z");
+        
+        // 限制条数
+        let mut limited = Vec::new();
+        let search = |limit, cancel: &CancellationToken, on_match: &mut dyn FnMut(FileInfo)| {
+            indexer.search_by_filename_streaming("log_", limit, cancel, on_match)
+        };
+        assert_eq!(search(3, &CancellationToken::new(), &mut |file| limited.push(file)).unwrap(), 3);
+        assert_eq!(limited.len(), 3);
+        
+        // 取消后不再交出结果
+        let cancel = CancellationToken::new();
+        let mut received = 0;
+        let result = search(10_000, &cancel, &mut |_| {
+            received += 1;
+            if received == 10 {
+                cancel.cancel();
+            }
+        });
+        assert!(matches!(result, Err(LocalPilotError::Cancelled)));
+        assert_eq!(received, 10);
+    }
+    
+    #[test]
+    fn test_fuzzy_search_ranks_closer_matches_first() {
+        let db_path = unique_temp_path("test_fuzzy.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        let conn = indexer.get_connection();
+        {
+            let conn = conn.lock().unwrap();
+            for name in ["receipt.pdf", "report.pdf", "readme.md", "zebra.png"] {
+                let file_info = FileInfo {
+                    path: format!("/docs/{}", name),
+                    name: name.to_string(),
+                    extension: name.rsplit_once('.').map(|(_, ext)| ext.to_string()),
+                    size: 1,
+                    modified: "2024-01-01T00:00:00Z".to_string(),
+                    created: "2024-01-01T00:00:00Z".to_string(),
+                    is_directory: false,
+                    is_symlink: false,
+                    mode: None,
+                    uid: None,
+                    gid: None,
+                };
+                upsert_file_info(&conn, &file_info).unwrap();
+            }
+        }
+        
+        let names: Vec<String> = indexer
+            .search_fuzzy("reprt", 10)
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        let report = names.iter().position(|n| n == "report.pdf").unwrap();
+        let receipt = names.iter().position(|n| n == "receipt.pdf").unwrap();
+        assert!(report < receipt);
+        assert!(!names.contains(&"zebra.png".to_string()));
+        
+        // 分数在数据库中计算，限制条数后仍是分数最高的条目
+        let top: Vec<String> = indexer.search_fuzzy("REPRT", 1).unwrap().into_iter().map(|f| f.name).collect();
+        assert_eq!(top, ["report.pdf"]);
+        // 子串匹配不要求首字母相同
+        let names: Vec<String> = indexer.search_fuzzy("ebra", 10).unwrap().into_iter().map(|f| f.name).collect();
+        assert_eq!(names, ["zebra.png"]);
+        
+        assert!(indexer.search_fuzzy("  ", 10).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_content_search_is_opt_in_per_root() {
+        let scan_dir = unique_temp_path("test_content_dir");
+        let notes_dir = scan_dir.join("notes");
+        let other_dir = scan_dir.join("other");
+        fs::create_dir_all(&notes_dir).unwrap();
+        fs::create_dir_all(&other_dir).unwrap();
+        fs::write(notes_dir.join("meeting.md"), "Agenda\nDiscuss the Quarterly Budget review\nEnd").unwrap();
+        fs::write(notes_dir.join("image.png"), "quarterly budget").unwrap();
+        fs::write(notes_dir.join("huge.txt"), "quarterly budget ".repeat(70_000)).unwrap();
+        fs::write(other_dir.join("plan.txt"), "quarterly budget").unwrap();
+        let scan_dir = fs::canonicalize(&scan_dir).unwrap();
+        let notes_dir = scan_dir.join("notes");
+        
+        let db_path = unique_temp_path("test_content.db");
+        let indexer = FileIndexer::new(db_path.to_str().unwrap()).unwrap();
+        indexer.scan_directory(scan_dir.to_str().unwrap()).unwrap();
+        assert!(indexer.search_by_content("quarterly budget", 10).unwrap().is_empty());
+        
+        // 开启后增量扫描会补充未变化文件的内容
+        indexer.set_content_indexing(&notes_dir, true).unwrap();
+        indexer.scan_directory_incremental(scan_dir.to_str().unwrap(), 0, |_| {}).unwrap();
+        let matches = indexer.search_by_content("quarterly budget", 10).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].file.name, "meeting.md");
+        assert_eq!(matches[0].snippet, "Agenda Discuss the Quarterly Budget review End");
+        
+        indexer.set_content_indexing(&notes_dir, false).unwrap();
+        assert!(indexer.search_by_content("budget", 10).unwrap().is_empty());
+        
+        fs::remove_dir_all(&scan_dir).ok();
+    }
+    
+    #[test]
+    fn test_snippet_is_trimmed_around_match() {
+        let content = format!("{}needle{}", "a".repeat(100), "b".repeat(100));
+        let snippet = make_snippet(&content, "NEEDLE");
+        assert_eq!(snippet, format!("…{}needle{}…", "a".repeat(SNIPPET_CONTEXT), "b".repeat(SNIPPET_CONTEXT)));
+    }
+}
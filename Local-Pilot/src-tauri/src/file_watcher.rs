@@ -0,0 +1,185 @@
+//! 文件系统监听模块
+//! 监听索引目录的变化，并将创建/修改/删除事件实时应用到文件索引
+
+use crate::file_index::FileIndexer;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::event::{CreateKind, ModifyKind};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// 事件静默多久后写入索引
+pub const DEBOUNCE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// 持续有事件时，最长多久强制写入一次
+pub const MAX_BATCH_DELAY: Duration = Duration::from_secs(2);
+
+/// 发送给后台线程的消息
+enum WatchMessage {
+    Event(notify::Result<Event>),
+    Stop,
+}
+
+/// 文件监听器
+pub struct FileWatcher {
+    _watcher: RecommendedWatcher,
+    sender: mpsc::Sender<WatchMessage>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl FileWatcher {
+    /// 开始监听指定目录，变化会应用到给定的索引器
+    pub fn start(indexer: Arc<FileIndexer>, roots: &[PathBuf]) -> Result<Self, Box<dyn std::error::Error>> {
+        let (sender, receiver) = mpsc::channel();
+
+        let event_sender = sender.clone();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = event_sender.send(WatchMessage::Event(event));
+        })?;
+
+        for root in roots {
+            watcher.watch(root, RecursiveMode::Recursive)?;
+        }
+
+        let worker = std::thread::spawn(move || run_worker(indexer, receiver));
+
+        Ok(Self {
+            _watcher: watcher,
+            sender,
+            worker: Some(worker),
+        })
+    }
+
+    /// 停止监听，写入尚未处理的变化后返回
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        let _ = self.sender.send(WatchMessage::Stop);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for FileWatcher {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// 后台线程：合并同一路径的事件，在事件静默后批量写入索引
+fn run_worker(indexer: Arc<FileIndexer>, receiver: mpsc::Receiver<WatchMessage>) {
+    // 路径 -> 是否需要重新扫描整个子树（新建或移入的目录）
+    let mut pending: HashMap<PathBuf, bool> = HashMap::new();
+    let mut batch_started: Option<Instant> = None;
+
+    loop {
+        match receiver.recv_timeout(DEBOUNCE_INTERVAL) {
+            Ok(WatchMessage::Event(Ok(event))) => {
+                if matches!(event.kind, EventKind::Access(_)) {
+                    continue;
+                }
+                let rescan = matches!(
+                    event.kind,
+                    EventKind::Create(CreateKind::Folder) | EventKind::Modify(ModifyKind::Name(_))
+                );
+                for path in event.paths {
+                    let entry = pending.entry(path).or_insert(false);
+                    *entry |= rescan;
+                }
+                batch_started.get_or_insert_with(Instant::now);
+
+                // 持续的事件流（如大量复制）也要定期写入
+                if batch_started.map_or(false, |start| start.elapsed() >= MAX_BATCH_DELAY) {
+                    flush(&indexer, &mut pending);
+                    batch_started = None;
+                }
+            }
+            Ok(WatchMessage::Event(Err(e))) => tracing::warn!(error = %e, "File watcher error"),
+            Err(RecvTimeoutError::Timeout) => {
+                flush(&indexer, &mut pending);
+                batch_started = None;
+            }
+            Ok(WatchMessage::Stop) | Err(RecvTimeoutError::Disconnected) => {
+                flush(&indexer, &mut pending);
+                break;
+            }
+        }
+    }
+}
+
+/// 将合并后的变化写入索引
+fn flush(indexer: &FileIndexer, pending: &mut HashMap<PathBuf, bool>) {
+    for (path, rescan) in pending.drain() {
+        if path.ancestors().any(|p| indexer.is_ignored(p)) {
+            continue;
+        }
+        if let Err(e) = apply_change(indexer, &path, rescan) {
+            tracing::warn!(path = %path.display(), error = %e, "Failed to update index");
+        }
+    }
+}
+
+fn apply_change(indexer: &FileIndexer, path: &Path, rescan: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if rescan && path.is_dir() {
+        indexer.scan_directory_incremental(&path.to_string_lossy(), 0, |_| {})?;
+    } else {
+        indexer.index_path(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_index::{FileInfo, FileQuery};
+    use std::fs;
+
+    /// 等待监听器把变化写入索引，超时后返回最后一次的查询结果
+    fn wait_for_index<F>(indexer: &FileIndexer, done: F) -> Vec<FileInfo>
+    where
+        F: Fn(&[FileInfo]) -> bool,
+    {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let files = indexer.search(&FileQuery::new().name_contains("watched")).unwrap();
+            if done(&files) || Instant::now() >= deadline {
+                return files;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn test_watcher_applies_create_modify_and_delete() {
+        let root = std::env::temp_dir().join(format!("test_watcher_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        let root = fs::canonicalize(&root).unwrap();
+        let db_path = std::env::temp_dir().join(format!("test_watcher_{}.db", uuid::Uuid::new_v4()));
+        let indexer = Arc::new(FileIndexer::new(db_path.to_str().unwrap()).unwrap());
+        indexer.add_index_root(&root).unwrap();
+        let watcher = FileWatcher::start(Arc::clone(&indexer), std::slice::from_ref(&root)).unwrap();
+
+        let path = root.join("watched.txt");
+        fs::write(&path, "draft").unwrap();
+        let files = wait_for_index(&indexer, |files| !files.is_empty());
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, path.to_string_lossy());
+        assert_eq!(files[0].size, 5);
+
+        fs::write(&path, "final version").unwrap();
+        let files = wait_for_index(&indexer, |files| files.first().is_some_and(|file| file.size == 13));
+        assert_eq!(files[0].size, 13);
+
+        fs::remove_file(&path).unwrap();
+        assert!(wait_for_index(&indexer, |files| files.is_empty()).is_empty());
+
+        watcher.stop();
+        fs::remove_dir_all(&root).ok();
+    }
+}
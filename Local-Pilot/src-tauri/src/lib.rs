@@ -1,88 +1,522 @@
+mod audit;
+mod error;
+mod logging;
 mod mcp;
+mod mock_provider;
+mod model_routing;
 mod orchestrator;
+mod preview;
+mod retry;
+mod token_budget;
 mod file_index;
+mod file_watcher;
+mod lock;
 
-use std::sync::Mutex;
-use tauri::State;
+use error::LocalPilotError;
+use lock::LockExt;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Emitter, Manager, State};
 use tokio;
+use tokio_util::sync::CancellationToken;
 
-// 存储MCP客户端实例
+// 存储MCP客户端实例（按服务器名称区分，可同时连接多个服务器）
 struct McpClientState {
-    client: Option<std::sync::Arc<Mutex<mcp::McpClient>>>,
+    clients: HashMap<String, Arc<tokio::sync::Mutex<mcp::McpClient>>>,
+    /// 各服务器进程的结束句柄，退出时客户端正忙也能结束进程
+    killers: HashMap<String, mcp::client::ProcessKiller>,
+    /// 正在启动的服务器名称，启动完成前同名的连接请求会被拒绝
+    connecting: HashSet<String>,
+}
+
+impl McpClientState {
+    /// 占用服务器名称，名称已连接或正在连接时返回错误
+    fn reserve_name(&mut self, name: &str) -> Result<(), String> {
+        if self.clients.contains_key(name) || !self.connecting.insert(name.to_string()) {
+            return Err(format!("MCP server '{}' is already connected", name));
+        }
+        Ok(())
+    }
+
+    /// 复制一份当前的客户端表，避免在await期间持有锁
+    fn snapshot(&self) -> HashMap<String, Arc<tokio::sync::Mutex<mcp::McpClient>>> {
+        self.clients.clone()
+    }
 }
 
 // 存储编排器实例
+// 编排器在多条消息之间共享以保留对话历史；外层的std Mutex只在取出Arc时短暂持有
 struct OrchestratorState {
-    orchestrator: Option<std::sync::Arc<Mutex<orchestrator::Orchestrator>>>,
+    orchestrator: Option<Arc<orchestrator::Orchestrator>>,
+    /// 正在处理和排队等待的消息编号及其取消令牌
+    in_flight: Vec<(u64, CancellationToken)>,
+    next_message_id: u64,
+    /// 等待用户批准的工具调用，批准和拒绝都按id查找
+    pending_approvals: orchestrator::PendingApprovals,
+}
+
+impl OrchestratorState {
+    /// 取出当前的编排器，尚未创建时以空配置创建
+    fn current(&mut self) -> Arc<orchestrator::Orchestrator> {
+        Arc::clone(self.orchestrator.get_or_insert_with(|| {
+            Arc::new(orchestrator::Orchestrator::new(String::new(), String::new(), String::new()))
+        }))
+    }
+
+    /// 登记一条开始处理的消息，返回消息编号和取消令牌
+    fn begin_message(&mut self) -> (u64, CancellationToken) {
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+        let token = CancellationToken::new();
+        self.in_flight.push((id, token.clone()));
+        (id, token)
+    }
+
+    /// 消息处理结束，其他消息的令牌保持不变
+    fn finish_message(&mut self, id: u64) {
+        self.in_flight.retain(|(current, _)| *current != id);
+    }
+
+    /// 按id取出多个待批准的工具调用；任何一个id不存在时不取出任何调用
+    fn take_pending(&mut self, ids: &[String]) -> Result<Vec<orchestrator::PendingApproval>, String> {
+        if let Some(missing) = ids.iter().find(|id| !self.pending_approvals.contains(id)) {
+            return Err(format!("No pending tool call with id {}", missing));
+        }
+        Ok(ids.iter().filter_map(|id| self.pending_approvals.take(id)).collect())
+    }
+
+    /// 取消正在处理的消息以及排队等待的消息，没有正在处理的消息时返回false
+    fn cancel_message(&mut self) -> bool {
+        let cancelled = !self.in_flight.is_empty();
+        for (_, token) in self.in_flight.drain(..) {
+            token.cancel();
+        }
+        cancelled
+    }
+}
+
+/// 连接期间占用的服务器名称，离开作用域时释放，连接失败或命令被取消时也不会一直占用
+struct NameReservation<'a> {
+    state: &'a Mutex<McpClientState>,
+    name: String,
+}
+
+impl Drop for NameReservation<'_> {
+    fn drop(&mut self) {
+        self.state.lock_or_recover().connecting.remove(&self.name);
+    }
+}
+
+/// 将编排器状态作为 `orchestrator-status` 事件发送给前端，达到迭代上限时另外发送 `max-iterations-reached` 事件
+struct TauriStatusEmitter(AppHandle);
+
+impl orchestrator::StatusEmitter for TauriStatusEmitter {
+    fn emit(&self, status: &orchestrator::OrchestratorStatus) {
+        if let Err(e) = self.0.emit("orchestrator-status", status) {
+            tracing::warn!(error = %e, "Failed to emit orchestrator status");
+        }
+    }
+
+    fn max_iterations_reached(&self, result: &orchestrator::ConversationResult) {
+        if let Err(e) = self.0.emit("max-iterations-reached", result) {
+            tracing::warn!(error = %e, "Failed to emit max-iterations-reached");
+        }
+    }
 }
 
 // 存储文件索引器实例
+// FileIndexer内部自带连接锁，这里只需要共享所有权；外层的std Mutex只在取出Arc时短暂持有，
+// 不会跨越await点
 struct FileIndexerState {
-    indexer: Option<std::sync::Arc<Mutex<file_index::FileIndexer>>>,
+    indexer: Option<Arc<file_index::FileIndexer>>,
+    /// 索引器初始化失败的原因，失败后不会再就绪
+    init_error: Option<String>,
+    /// 正在进行的流式搜索的取消令牌，新的流式搜索开始时取消它
+    streaming_search: Option<CancellationToken>,
+}
+
+impl FileIndexerState {
+    /// 取出索引器的共享引用
+    fn indexer(&self) -> Result<Arc<file_index::FileIndexer>, String> {
+        if let Some(error) = &self.init_error {
+            return Err(error.clone());
+        }
+        self.indexer
+            .clone()
+            .ok_or_else(|| LocalPilotError::NotInitialized("File indexer".to_string()).user_message())
+    }
+
+    /// 登记一次新的流式搜索并取消上一次，被取代的搜索不再发出结果
+    fn begin_streaming_search(&mut self) -> CancellationToken {
+        let token = CancellationToken::new();
+        if let Some(previous) = self.streaming_search.replace(token.clone()) {
+            previous.cancel();
+        }
+        token
+    }
+}
+
+// 存储文件监听器实例
+struct FileWatcherState {
+    watcher: Option<file_watcher::FileWatcher>,
+}
+
+/// 各子系统的就绪状态，用于问题排查
+#[derive(Debug, Serialize)]
+struct SystemStatus {
+    mcp_connected: bool,
+    mcp_servers: Vec<McpServerStatus>,
+    orchestrator_configured: bool,
+    file_indexer_ready: bool,
+    indexed_file_count: u64,
+    /// 查询过程中遇到的问题；出错的子系统按未就绪报告
+    errors: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct McpServerStatus {
+    name: String,
+    /// 握手时协商的协议版本；未握手或服务器正忙时为 `None`
+    protocol_version: Option<String>,
+}
+
+/// 收集各子系统的状态；锁被毒化或查询失败时记录错误并继续，不会panic
+fn collect_system_status(
+    mcp_state: &Mutex<McpClientState>,
+    orch_state: &Mutex<OrchestratorState>,
+    indexer_state: &Mutex<FileIndexerState>,
+) -> SystemStatus {
+    let mut status = SystemStatus {
+        mcp_connected: false,
+        mcp_servers: Vec::new(),
+        orchestrator_configured: false,
+        file_indexer_ready: false,
+        indexed_file_count: 0,
+        errors: Vec::new(),
+    };
+    
+    match mcp_state.lock() {
+        Ok(mcp) => {
+            let mut servers: Vec<_> = mcp.clients.iter().collect();
+            servers.sort_by(|a, b| a.0.cmp(b.0));
+            status.mcp_servers = servers
+                .into_iter()
+                .map(|(name, client)| McpServerStatus {
+                    name: name.clone(),
+                    // 客户端正在执行请求时不等待
+                    protocol_version: client
+                        .try_lock()
+                        .ok()
+                        .and_then(|client| client.protocol_version()),
+                })
+                .collect();
+            status.mcp_connected = !status.mcp_servers.is_empty();
+        }
+        Err(_) => status.errors.push("MCP client state is unavailable (lock poisoned)".to_string()),
+    }
+    
+    match orch_state.lock() {
+        Ok(orch) => {
+            status.orchestrator_configured = orch
+                .orchestrator
+                .as_ref()
+                .is_some_and(|orchestrator| orchestrator.is_configured());
+        }
+        Err(_) => status.errors.push("Orchestrator state is unavailable (lock poisoned)".to_string()),
+    }
+    
+    let indexer = match indexer_state.lock() {
+        Ok(indexer) => {
+            status.errors.extend(indexer.init_error.clone());
+            indexer.indexer.clone()
+        }
+        Err(_) => {
+            status.errors.push("File indexer state is unavailable (lock poisoned)".to_string());
+            None
+        }
+    };
+    if let Some(indexer) = indexer {
+        status.file_indexer_ready = true;
+        match indexer.count_indexed_files() {
+            Ok(count) => status.indexed_file_count = count,
+            Err(e) => status.errors.push(format!("Failed to count indexed files: {}", e.user_message())),
+        }
+    }
+    
+    status
+}
+
+/// 在阻塞线程池中执行文件索引操作，避免DuckDB查询和磁盘扫描占用tokio工作线程
+async fn run_blocking<T, F>(task: F) -> Result<T, String>
+where
+    F: FnOnce() -> Result<T, String> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(task)
+        .await
+        .map_err(|e| format!("Index task failed: {}", e))?
+}
+
+/// 扫描所有索引根目录（多个根目录同时扫描），并通过 `index-progress` 事件报告进度
+/// 默认进行增量扫描；`full` 为true时先清空根目录下的条目再完整重建
+fn rebuild_file_index(
+    indexer: &file_index::FileIndexer,
+    app_handle: &AppHandle,
+    full: bool,
+) -> Result<file_index::ScanSummary, String> {
+    let guard = indexer
+        .begin_indexing()
+        .ok_or("File index build already in progress")?;
+    
+    let roots = indexer
+        .effective_index_roots()
+        .map_err(|e| format!("Error loading index roots: {}", e.user_message()))?;
+    
+    let report = |progress: &file_index::ScanProgress| {
+        let _ = app_handle.emit("index-progress", progress.clone());
+    };
+    let scan_error = |e: LocalPilotError| match e {
+        LocalPilotError::Cancelled => "File index scan cancelled".to_string(),
+        e => format!("Error scanning index roots: {}", e.user_message()),
+    };
+    
+    if full {
+        indexer
+            .rebuild_directories_with_progress(&roots, file_index::PROGRESS_INTERVAL, guard.cancellation(), report)
+            .map_err(scan_error)
+    } else {
+        indexer
+            .scan_directories_incremental(&roots, file_index::PROGRESS_INTERVAL, guard.cancellation(), report)
+            .map_err(scan_error)
+    }
+}
+
+/// 设置了 `LOCAL_PILOT_MCP_ADDR` 时通过TCP向编辑器等外部客户端提供本地工具，令牌写在应用数据目录下
+fn start_mcp_tcp_server(app_handle: &AppHandle, indexer: Arc<file_index::FileIndexer>) {
+    let data_dir = match app_handle.path().app_data_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            tracing::error!(error = %e, "Cannot serve MCP over TCP without the app data directory");
+            return;
+        }
+    };
+    tauri::async_runtime::spawn(async move {
+        let transport = match mcp::transport::bind_from_env(&data_dir).await {
+            Ok(Some(transport)) => transport,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to start the MCP TCP server");
+                return;
+            }
+        };
+        let token_file = data_dir.join(mcp::transport::TOKEN_FILE);
+        tracing::info!(addr = ?transport.local_addr().ok(), token_file = %token_file.display(), "Serving MCP over TCP");
+        if let Err(e) = transport.serve(Arc::new(mcp::server::McpServer::new(indexer))).await {
+            tracing::error!(error = %e, "MCP TCP server stopped");
+        }
+    });
+}
+
+#[tauri::command]
+async fn system_status(
+    mcp_state: State<'_, Arc<Mutex<McpClientState>>>,
+    orch_state: State<'_, Arc<Mutex<OrchestratorState>>>,
+    indexer_state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<SystemStatus, String> {
+    let mcp_state = Arc::clone(&mcp_state);
+    let orch_state = Arc::clone(&orch_state);
+    let indexer_state = Arc::clone(&indexer_state);
+    run_blocking(move || Ok(collect_system_status(&mcp_state, &orch_state, &indexer_state))).await
 }
 
-// Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
 fn greet(name: &str) -> String {
     format!("Hello, {}! You've been greeted from Rust!", name)
 }
 
 #[tauri::command]
-async fn init_mcp(state: State<'_, std::sync::Arc<Mutex<McpClientState>>>) -> Result<String, String> {
+async fn init_mcp(
+    root: Option<String>,
+    state: State<'_, Arc<Mutex<McpClientState>>>,
+    indexer_state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<String, String> {
     mcp::init_mcp();
     
-    // 尝试启动MCP客户端（这里使用模拟命令，实际部署时需要根据具体情况调整）
-    match tokio::spawn(async {
-        mcp::McpClient::new(vec!["npx", "@modelcontextprotocol/server-filesystem"]).await
-    }).await {
-        Ok(client_result) => {
-            match client_result {
-                Ok(client) => {
-                    // 注意：这里简化了实现，实际情况下需要正确存储Arc<Mutex<McpClient>>
-                    // 由于所有权问题，我们这里仅记录初始化成功
-                    Ok("MCP initialized successfully".to_string())
-                }
-                Err(e) => Err(format!("Failed to create MCP client: {}", e))
-            }
-        }
-        Err(e) => Err(format!("Failed to spawn MCP client task: {}", e))
+    // 索引器尚未就绪时只能退回到用户主目录
+    let index_roots = match indexer_state.lock_or_recover().indexer() {
+        Ok(indexer) => indexer.effective_index_roots().unwrap_or_default(),
+        Err(_) => Vec::new(),
+    };
+    let root = mcp::filesystem_server_root(root.as_deref().map(std::path::Path::new), &index_roots)
+        .map_err(|e| e.user_message())?;
+    
+    // 默认连接文件系统服务器
+    connect_mcp_server(
+        mcp::DEFAULT_SERVER_NAME.to_string(),
+        "npx".to_string(),
+        mcp::filesystem_server_args(&root),
+        None,
+        None,
+        None,
+        Some(true),
+        state,
+    ).await?;
+    
+    Ok("MCP initialized successfully".to_string())
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn connect_mcp_server(
+    name: String,
+    command: String,
+    args: Vec<String>,
+    framing: Option<mcp::framing::Framing>,
+    cwd: Option<String>,
+    env: Option<HashMap<String, String>>,
+    auto_restart: Option<bool>,
+    state: State<'_, Arc<Mutex<McpClientState>>>,
+) -> Result<String, String> {
+    if name.is_empty() || name.contains(mcp::TOOL_NAME_SEPARATOR) {
+        return Err(format!("Invalid MCP server name: {}", name));
+    }
+    // 在启动服务器之前占用名称，同时到达的同名请求不会各自启动一个服务器
+    state.lock_or_recover().reserve_name(&name)?;
+    let _reservation = NameReservation { state: &state, name: name.clone() };
+    
+    let mut server_cmd = vec![command.as_str()];
+    server_cmd.extend(args.iter().map(String::as_str));
+    let options = mcp::client::LaunchOptions {
+        framing: framing.unwrap_or_default(),
+        working_dir: cwd.map(std::path::PathBuf::from),
+        env: env.unwrap_or_default(),
+        restart: auto_restart.unwrap_or(false).then(mcp::client::RestartPolicy::default),
+    };
+    let mut client = mcp::McpClient::new_with_options(server_cmd, options)
+        .await
+        .map_err(|e| format!("Failed to create MCP client: {}", e.user_message()))?;
+    // 不支持握手的旧服务器仍可使用，只是无法得知协议版本
+    if let Err(e) = client.initialize().await {
+        tracing::warn!(server = %name, error = %e, "MCP server did not complete the initialize handshake");
     }
+    
+    let mut state = state.lock_or_recover();
+    state.killers.insert(name.clone(), client.process_killer());
+    state.clients.insert(name.clone(), Arc::new(tokio::sync::Mutex::new(client)));
+    
+    Ok(format!("MCP server '{}' connected", name))
 }
 
 #[tauri::command]
-async fn list_mcp_tools(state: State<'_, std::sync::Arc<Mutex<McpClientState>>>) -> Result<Vec<mcp::protocol::Tool>, String> {
-    // 这里应该获取存储的客户端实例并调用list_tools
-    // 由于所有权问题，简化为返回示例数据
-    Ok(vec![
-        mcp::protocol::Tool {
-            name: "file_reader".to_string(),
-            description: "读取本地文件内容".to_string(),
-            input_schema: serde_json::json!({
-                "type": "object",
-                "properties": {
-                    "path": {
-                        "type": "string",
-                        "description": "文件路径"
+async fn disconnect_mcp_server(
+    name: String,
+    state: State<'_, Arc<Mutex<McpClientState>>>,
+) -> Result<String, String> {
+    let client = {
+        let mut state = state.lock_or_recover();
+        state.killers.remove(&name);
+        state.clients.remove(&name)
+    };
+    match client {
+        Some(client) => {
+            client
+                .lock()
+                .await
+                .shutdown()
+                .await
+                .map_err(|e| format!("MCP server '{}' disconnected, but did not shut down cleanly: {}", name, e.user_message()))?;
+            Ok(format!("MCP server '{}' disconnected", name))
+        }
+        None => Err(format!("MCP server '{}' is not connected", name)),
+    }
+}
+
+/// 正常关闭一个MCP服务器的最长时间，超时后直接结束进程
+const MCP_SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// 关闭所有MCP服务器，退出应用时调用，避免留下孤儿进程
+/// 不等待正在执行请求的客户端，直接结束其服务器进程；正常关闭超时或失败时同样结束进程
+async fn shutdown_mcp_clients(state: &Mutex<McpClientState>) {
+    let (clients, mut killers) = {
+        let mut state = state.lock_or_recover();
+        let clients: Vec<_> = state.clients.drain().collect();
+        (clients, std::mem::take(&mut state.killers))
+    };
+    let mut shutdowns = tokio::task::JoinSet::new();
+    for (name, client) in clients {
+        let killer = killers.remove(&name);
+        shutdowns.spawn(async move {
+            let shut_down = match client.try_lock() {
+                Ok(mut client) => match tokio::time::timeout(MCP_SHUTDOWN_TIMEOUT, client.shutdown()).await {
+                    Ok(Ok(())) => true,
+                    Ok(Err(e)) => {
+                        tracing::warn!(server = %name, error = %e, "Failed to shut down MCP server");
+                        false
                     }
-                },
-                "required": ["path"]
-            }),
-        },
-        mcp::protocol::Tool {
-            name: "shell_executor".to_string(),
-            description: "在本地执行shell命令".to_string(),
-            input_schema: serde_json::json!({
-                "type": "object",
-                "properties": {
-                    "command": {
-                        "type": "string",
-                        "description": "要执行的命令"
+                    Err(_) => {
+                        tracing::warn!(server = %name, "MCP server did not shut down in time");
+                        false
                     }
                 },
-                "required": ["command"]
-            }),
-        }
-    ])
+                Err(_) => {
+                    tracing::warn!(server = %name, "MCP server is busy, killing it");
+                    false
+                }
+            };
+            if let (false, Some(killer)) = (shut_down, killer) {
+                if let Err(e) = killer.kill().await {
+                    tracing::warn!(server = %name, error = %e, "Failed to kill MCP server");
+                }
+            }
+        });
+    }
+    shutdowns.join_all().await;
+}
+
+#[tauri::command]
+async fn list_mcp_tools(state: State<'_, Arc<Mutex<McpClientState>>>) -> Result<Vec<mcp::protocol::Tool>, String> {
+    let clients = state.lock_or_recover().snapshot();
+    
+    // 汇总所有服务器的工具，工具名称以服务器名称作为前缀
+    // 填入实际是否需要批准，前端据此标出会先请求批准的工具
+    let mut tools = mcp::list_tools_across(&clients).await;
+    for tool in &mut tools {
+        let required = mcp::requires_approval(&tool.name, Some(&*tool));
+        tool.requires_approval = Some(required);
+    }
+    Ok(tools)
+}
+
+#[tauri::command]
+async fn list_mcp_resources(
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+    mcp_state: State<'_, Arc<Mutex<McpClientState>>>,
+) -> Result<Vec<mcp::protocol::Resource>, String> {
+    let orchestrator = state.lock_or_recover().current();
+    let clients = mcp_state.lock_or_recover().snapshot();
+    orchestrator.set_mcp_clients(clients).await;
+    
+    orchestrator
+        .list_available_resources()
+        .await
+        .map_err(|e| format!("Error listing resources: {}", e.user_message()))
+}
+
+#[tauri::command]
+async fn read_mcp_resource(
+    uri: String,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+    mcp_state: State<'_, Arc<Mutex<McpClientState>>>,
+) -> Result<serde_json::Value, String> {
+    let orchestrator = state.lock_or_recover().current();
+    let clients = mcp_state.lock_or_recover().snapshot();
+    orchestrator.set_mcp_clients(clients).await;
+    
+    orchestrator
+        .read_resource(&uri)
+        .await
+        .map_err(|e| format!("Error reading resource: {}", e.user_message()))
 }
 
 #[tauri::command]
@@ -91,55 +525,92 @@ async fn process_user_message(
     api_key: String,
     api_base: String,
     model_name: String,
-    state: State<'_, std::sync::Arc<Mutex<OrchestratorState>>>,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+    mcp_state: State<'_, Arc<Mutex<McpClientState>>>,
 ) -> Result<String, String> {
-    // 创建编排器实例
-    let orchestrator = orchestrator::Orchestrator::new(
-        api_key,
-        api_base,
-        model_name,
-    );
+    // 复用编排器以保留对话历史，使用本次请求的LLM配置
+    let (orchestrator, message_id, cancel) = {
+        let mut state = state.lock_or_recover();
+        let (message_id, cancel) = state.begin_message();
+        (state.current(), message_id, cancel)
+    };
+    // 未填写接口地址时按模型路由表选择
+    if api_base.is_empty() {
+        if let Err(e) = orchestrator.use_model(&model_name, &api_key) {
+            state.lock_or_recover().finish_message(message_id);
+            return Err(format!("Error processing message: {}", e.user_message()));
+        }
+    } else {
+        orchestrator.update_config(api_key, api_base, model_name);
+    }
+    let clients = mcp_state.lock_or_recover().snapshot();
+    orchestrator.set_mcp_clients(clients).await;
     
     // 处理用户消息
-    match orchestrator.process_user_message(&message).await {
-        Ok(results) => {
-            // 检查是否有需要审批的工具调用
-            let has_pending_approval = results.iter().any(|result| 
-                matches!(result.status, orchestrator::ToolCallStatus::PendingApproval)
-            );
+    let outcome = orchestrator.process_user_message(&message, &cancel).await;
+    let mut state = state.lock_or_recover();
+    state.finish_message(message_id);
+    match outcome {
+        Ok(result) => {
+            // 保存需要审批的工具调用，前端通过 list_pending_approvals 获取
+            let has_pending_approval = !state.pending_approvals.store(&result.tool_results).is_empty();
             
             if has_pending_approval {
                 Ok("PENDING_APPROVAL".to_string()) // 返回需要审批的信号
+            } else if result.max_iterations_reached {
+                // 详细结果通过 max-iterations-reached 事件发送
+                Ok(format!(
+                    "Stopped after {} steps with {} tool calls; the answer may be incomplete",
+                    result.iterations,
+                    result.tool_results.len()
+                ))
             } else {
-                Ok(format!("Processed with {} tool calls", results.len()))
+                Ok(format!("Processed with {} tool calls", result.tool_results.len()))
             }
         }
-        Err(e) => Err(format!("Error processing message: {}", e)),
+        Err(LocalPilotError::Cancelled) => Ok("CANCELLED".to_string()), // 用户取消了处理
+        Err(LocalPilotError::Busy) => Err("BUSY".to_string()), // 上一条消息仍在处理
+        Err(e) => Err(format!("Error processing message: {}", e.user_message())),
     }
 }
 
+#[tauri::command]
+fn cancel_current_message(state: State<'_, Arc<Mutex<OrchestratorState>>>) -> Result<String, String> {
+    if state.lock_or_recover().cancel_message() {
+        Ok("Cancellation requested".to_string())
+    } else {
+        Ok("No message in progress".to_string())
+    }
+}
+
+#[tauri::command]
+async fn list_pending_approvals(
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<Vec<orchestrator::PendingApproval>, String> {
+    Ok(state.lock_or_recover().pending_approvals.list())
+}
+
 #[tauri::command]
 async fn approve_tool_call(
-    tool_name: String,
-    arguments: String, // JSON字符串
-    state: State<'_, std::sync::Arc<Mutex<OrchestratorState>>>,
-) -> Result<String, String> {
-    // 创建编排器实例（在实际应用中，应从state获取已初始化的实例）
-    let api_key = "dummy"; // 在实际应用中，应从配置或状态中获取
-    let api_base = "dummy";
-    let model_name = "dummy";
-    let orchestrator = orchestrator::Orchestrator::new(
-        api_key.to_string(),
-        api_base.to_string(),
-        model_name.to_string(),
-    );
-    
-    // 解析参数
-    let args_value: serde_json::Value = serde_json::from_str(&arguments)
-        .map_err(|e| format!("Failed to parse arguments: {}", e))?;
+    id: String,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+    mcp_state: State<'_, Arc<Mutex<McpClientState>>>,
+) -> Result<String, String> {
+    // 使用当前对话的编排器，执行结果会记入对话历史
+    // 参数取自后端保存的待批准调用，而不是前端传入的值
+    let (orchestrator, pending) = {
+        let mut state = state.lock_or_recover();
+        let pending = state
+            .pending_approvals
+            .take(&id)
+            .ok_or_else(|| format!("No pending tool call with id {}", id))?;
+        (state.current(), pending)
+    };
+    let clients = mcp_state.lock_or_recover().snapshot();
+    orchestrator.set_mcp_clients(clients).await;
     
     // 批准工具调用
-    match orchestrator.approve_tool_call(tool_name, args_value).await {
+    match orchestrator.approve_tool_call(pending.tool_name, pending.arguments).await {
         Ok(result) => {
             match result.status {
                 orchestrator::ToolCallStatus::Approved | orchestrator::ToolCallStatus::Executed => {
@@ -148,71 +619,1015 @@ async fn approve_tool_call(
                 _ => Ok(format!("Tool call failed: {}", result.error.unwrap_or("Unknown error".to_string()))),
             }
         }
-        Err(e) => Err(format!("Error approving tool call: {}", e)),
+        Err(e) => Err(format!("Error approving tool call: {}", e.user_message())),
     }
 }
 
+#[tauri::command]
+async fn approve_tool_calls(
+    ids: Vec<String>,
+    stop_on_error: Option<bool>,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+    mcp_state: State<'_, Arc<Mutex<McpClientState>>>,
+) -> Result<Vec<orchestrator::ToolCallResult>, String> {
+    let (orchestrator, calls) = {
+        let mut state = state.lock_or_recover();
+        let calls = state.take_pending(&ids)?;
+        (state.current(), calls.into_iter().map(orchestrator::ToolCallRequest::from).collect())
+    };
+    let clients = mcp_state.lock_or_recover().snapshot();
+    orchestrator.set_mcp_clients(clients).await;
+    
+    orchestrator
+        .approve_tool_calls(calls, stop_on_error.unwrap_or(false))
+        .await
+        .map_err(|e| format!("Error approving tool calls: {}", e.user_message()))
+}
+
+#[tauri::command]
+async fn reject_tool_call(
+    id: String,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<String, String> {
+    let (orchestrator, pending) = {
+        let mut state = state.lock_or_recover();
+        let pending = state
+            .pending_approvals
+            .take(&id)
+            .ok_or_else(|| format!("No pending tool call with id {}", id))?;
+        (state.current(), pending)
+    };
+    let result = orchestrator.reject_tool_call(pending.tool_name, pending.arguments).await;
+    Ok(format!("Tool call rejected: {}", result.tool_name))
+}
+
+#[tauri::command]
+async fn new_conversation(state: State<'_, Arc<Mutex<OrchestratorState>>>) -> Result<String, String> {
+    let orchestrator = {
+        let mut state = state.lock_or_recover();
+        state.pending_approvals.clear();
+        state.current()
+    };
+    orchestrator.clear_history().await;
+    Ok("Started a new conversation".to_string())
+}
+
+#[tauri::command]
+async fn get_conversation_transcript(
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<Vec<orchestrator::Message>, String> {
+    let orchestrator = state.lock_or_recover().current();
+    Ok(orchestrator.display_transcript().await)
+}
+
+#[tauri::command]
+async fn set_output_verbosity(
+    verbosity: orchestrator::OutputVerbosity,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<String, String> {
+    let orchestrator = state.lock_or_recover().current();
+    orchestrator.set_output_verbosity(verbosity);
+    Ok(format!("Output verbosity set to {:?}", verbosity))
+}
+
+#[tauri::command]
+async fn get_audit_log(
+    limit: Option<u32>,
+    since: Option<String>,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<Vec<audit::AuditEntry>, String> {
+    let since = since
+        .map(|time| {
+            chrono::DateTime::parse_from_rfc3339(&time)
+                .map(|time| time.with_timezone(&chrono::Utc))
+                .map_err(|e| format!("Invalid since time '{}': {}", time, e))
+        })
+        .transpose()?;
+    let audit_log = state
+        .lock_or_recover()
+        .current()
+        .audit_log()
+        .ok_or_else(|| LocalPilotError::NotInitialized("Audit log".to_string()).user_message())?;
+    run_blocking(move || {
+        audit_log
+            .entries(limit.unwrap_or(audit::DEFAULT_AUDIT_LIMIT), since)
+            .map_err(|e| format!("Error reading audit log: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn get_tool_calls(
+    status_filter: Option<orchestrator::ToolCallStatus>,
+    limit: Option<u32>,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<Vec<audit::AuditEntry>, String> {
+    let audit_log = state
+        .lock_or_recover()
+        .current()
+        .audit_log()
+        .ok_or_else(|| LocalPilotError::NotInitialized("Audit log".to_string()).user_message())?;
+    run_blocking(move || {
+        audit_log
+            .tool_calls(status_filter.as_ref(), limit.unwrap_or(audit::DEFAULT_AUDIT_LIMIT))
+            .map_err(|e| format!("Error reading audit log: {}", e.user_message()))
+    }).await
+}
+
+/// 未指定行数时 `get_recent_logs` 返回的行数
+const DEFAULT_RECENT_LOG_LINES: usize = 200;
+
+#[tauri::command]
+async fn get_recent_logs(
+    lines: Option<usize>,
+    logger: State<'_, logging::Logger>,
+) -> Result<String, String> {
+    let logger = logger.inner().clone();
+    run_blocking(move || {
+        logger
+            .recent_logs(lines.unwrap_or(DEFAULT_RECENT_LOG_LINES))
+            .map_err(|e| format!("Error reading log file: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn set_log_level(
+    level: String,
+    logger: State<'_, logging::Logger>,
+) -> Result<String, String> {
+    logger.set_level(&level).map_err(|e| e.user_message())?;
+    Ok(format!("Log level set to {}", level))
+}
+
+#[tauri::command]
+async fn set_busy_policy(
+    policy: orchestrator::BusyPolicy,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<String, String> {
+    let orchestrator = state.lock_or_recover().current();
+    orchestrator.set_busy_policy(policy);
+    Ok(format!("Busy policy set to {:?}", policy))
+}
+
+#[tauri::command]
+async fn set_tool_result_framing(
+    enabled: bool,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<String, String> {
+    let orchestrator = state.lock_or_recover().current();
+    orchestrator.set_tool_result_framing(enabled);
+    Ok(format!("Tool result framing {}", if enabled { "enabled" } else { "disabled" }))
+}
+
+#[tauri::command]
+async fn set_tool_result_caching(
+    enabled: bool,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<String, String> {
+    let orchestrator = state.lock_or_recover().current();
+    orchestrator.set_tool_result_caching(enabled);
+    Ok(format!("Tool result caching {}", if enabled { "enabled" } else { "disabled" }))
+}
+
+#[tauri::command]
+async fn set_model_token_limit(
+    model_name: String,
+    limit: usize,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<String, String> {
+    let orchestrator = state.lock_or_recover().current();
+    orchestrator.set_token_limit(&model_name, limit);
+    Ok(format!("Token limit for {} set to {}", model_name, limit))
+}
+
+#[tauri::command]
+async fn set_model_vision_support(
+    model_name: String,
+    supported: bool,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<String, String> {
+    let orchestrator = state.lock_or_recover().current();
+    orchestrator.set_vision_support(&model_name, supported);
+    Ok(format!(
+        "Image input {} for {}",
+        if supported { "enabled" } else { "disabled" },
+        model_name
+    ))
+}
+
+#[tauri::command]
+async fn set_model_route(
+    pattern: String,
+    api_base: String,
+    api_key_env: Option<String>,
+    provider: orchestrator::Provider,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<String, String> {
+    let orchestrator = state.lock_or_recover().current();
+    orchestrator.set_model_route(model_routing::ModelRoute {
+        pattern: pattern.clone(),
+        api_base,
+        api_key_env,
+        provider,
+    });
+    Ok(format!("Models matching {} now use the {:?} provider", pattern, provider))
+}
+
+#[tauri::command]
+async fn set_tool_call_limit(
+    tool_name: String,
+    limit: usize,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<String, String> {
+    let orchestrator = state.lock_or_recover().current();
+    orchestrator.set_tool_call_limit(&tool_name, limit);
+    Ok(format!("Call limit for {} set to {}", tool_name, limit))
+}
+
+#[tauri::command]
+async fn set_system_prompt_template(
+    template: Option<String>,
+    state: State<'_, Arc<Mutex<OrchestratorState>>>,
+) -> Result<String, String> {
+    let orchestrator = state.lock_or_recover().current();
+    orchestrator
+        .set_system_prompt_template(template)
+        .map_err(|e| e.user_message())?;
+    Ok("System prompt template updated".to_string())
+}
+
+#[tauri::command]
+async fn preview_tool_call(
+    tool_name: String,
+    arguments: String, // JSON字符串
+) -> Result<String, String> {
+    // 预览不需要连接MCP服务器，也不需要调用LLM
+    let orchestrator = orchestrator::Orchestrator::new(String::new(), String::new(), String::new());
+    
+    let args_value: serde_json::Value = serde_json::from_str(&arguments)
+        .map_err(|e| format!("Failed to parse arguments: {}", e))?;
+    
+    // 展开通配符需要访问文件系统
+    run_blocking(move || {
+        orchestrator
+            .preview_tool_call(&tool_name, &args_value)
+            .map_err(|e| e.user_message())
+    }).await
+}
+
 #[tauri::command]
 async fn search_local_files(
     query: String,
-    state: State<'_, std::sync::Arc<Mutex<FileIndexerState>>>,
+    limit: Option<u32>,
+    offset: Option<u32>,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<file_index::SearchPage, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    let limit = limit.unwrap_or(file_index::DEFAULT_SEARCH_LIMIT);
+    
+    run_blocking(move || {
+        indexer
+            .search_by_filename(&query, limit, offset.unwrap_or(0))
+            .map_err(|e| format!("Error searching files: {}", e.user_message()))
+    }).await
+}
+
+/// 流式搜索每个 `search-results` 事件携带的最大条目数
+const STREAM_SEARCH_BATCH_SIZE: usize = 100;
+
+/// 流式搜索未指定条数时最多返回的条目数
+const STREAM_SEARCH_DEFAULT_LIMIT: u32 = 10_000;
+
+/// `search-results` 事件的内容；`search_id` 由前端指定，用于区分并发的搜索
+#[derive(Debug, Clone, Serialize)]
+struct SearchResultsBatch {
+    search_id: String,
+    files: Vec<file_index::FileInfo>,
+}
+
+#[tauri::command]
+async fn search_local_files_streaming(
+    query: String,
+    search_id: String,
+    limit: Option<u32>,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+    app_handle: AppHandle,
+) -> Result<u64, String> {
+    // 新的搜索取代之前的搜索，之前的搜索停止读取并不再发出事件
+    let (indexer, cancel) = {
+        let mut state = state.lock_or_recover();
+        (state.indexer()?, state.begin_streaming_search())
+    };
+    let limit = limit.unwrap_or(STREAM_SEARCH_DEFAULT_LIMIT);
+    
+    // 结果分批通过 `search-results` 事件发出，命令在全部发出后返回总条数
+    run_blocking(move || {
+        let mut batch = Vec::with_capacity(STREAM_SEARCH_BATCH_SIZE);
+        let emit = |files: Vec<file_index::FileInfo>| {
+            if !cancel.is_cancelled() {
+                let _ = app_handle.emit("search-results", SearchResultsBatch { search_id: search_id.clone(), files });
+            }
+        };
+        let count = indexer
+            .search_by_filename_streaming(&query, limit, &cancel, |file| {
+                batch.push(file);
+                if batch.len() == STREAM_SEARCH_BATCH_SIZE {
+                    emit(std::mem::replace(&mut batch, Vec::with_capacity(STREAM_SEARCH_BATCH_SIZE)));
+                }
+            })
+            .map_err(|e| match e {
+                LocalPilotError::Cancelled => "Search superseded by a newer search".to_string(),
+                e => format!("Error searching files: {}", e.user_message()),
+            })?;
+        if !batch.is_empty() {
+            emit(batch);
+        }
+        Ok(count)
+    }).await
+}
+
+#[tauri::command]
+async fn search_local_files_fuzzy(
+    query: String,
+    limit: Option<u32>,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
 ) -> Result<Vec<file_index::FileInfo>, String> {
-    let indexer_state = state.inner();
-    let indexer_guard = indexer_state.indexer.as_ref()
-        .ok_or("File indexer not initialized")?;
-    let indexer = indexer_guard.lock().unwrap();
+    let indexer = state.lock_or_recover().indexer()?;
+    let limit = limit.unwrap_or(file_index::DEFAULT_SEARCH_LIMIT);
     
-    match indexer.search_by_filename(&query) {
-        Ok(results) => Ok(results),
-        Err(e) => Err(format!("Error searching files: {}", e)),
-    }
+    run_blocking(move || {
+        indexer
+            .search_fuzzy(&query, limit)
+            .map_err(|e| format!("Error searching files: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn search_recent_files(
+    days: u32,
+    limit: Option<u32>,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<Vec<file_index::FileInfo>, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    let limit = limit.unwrap_or(file_index::DEFAULT_SEARCH_LIMIT);
+    
+    run_blocking(move || {
+        indexer
+            .search_recent(days, limit)
+            .map_err(|e| format!("Error searching files: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn search_files_modified_between(
+    start: String,
+    end: String,
+    limit: Option<u32>,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<Vec<file_index::FileInfo>, String> {
+    let parse = |name: &str, time: &str| {
+        chrono::DateTime::parse_from_rfc3339(time)
+            .map(|time| time.with_timezone(&chrono::Utc))
+            .map_err(|e| format!("Invalid {} time '{}': {}", name, time, e))
+    };
+    let start = parse("start", &start)?;
+    let end = parse("end", &end)?;
+    let limit = limit.unwrap_or(file_index::DEFAULT_SEARCH_LIMIT);
+    
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .search_modified_between(start, end, limit)
+            .map_err(|e| format!("Error searching files: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn search_files_by_permission(
+    bits: u32,
+    limit: Option<u32>,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<Vec<file_index::FileInfo>, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    let limit = limit.unwrap_or(file_index::DEFAULT_SEARCH_LIMIT);
+    
+    run_blocking(move || {
+        indexer
+            .search_by_permission(bits, limit)
+            .map_err(|e| format!("Error searching files: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn search_files_advanced(
+    name: Option<String>,
+    extension: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    is_directory: Option<bool>,
+    modified_after: Option<String>,
+    limit: Option<u32>,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<Vec<file_index::FileInfo>, String> {
+    let modified_after = modified_after
+        .map(|time| {
+            chrono::DateTime::parse_from_rfc3339(&time)
+                .map(|time| time.with_timezone(&chrono::Utc))
+                .map_err(|e| format!("Invalid modified_after time '{}': {}", time, e))
+        })
+        .transpose()?;
+    let query = file_index::FileQuery {
+        name_contains: name,
+        extension,
+        min_size,
+        max_size,
+        is_directory,
+        limit,
+        ..Default::default()
+    };
+    let query = match modified_after {
+        Some(time) => query.modified_after(time),
+        None => query,
+    };
+    
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .search(&query)
+            .map_err(|e| format!("Error searching files: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn search_file_contents(
+    query: String,
+    limit: Option<u32>,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<Vec<file_index::ContentMatch>, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    let limit = limit.unwrap_or(file_index::DEFAULT_SEARCH_LIMIT);
+    
+    run_blocking(move || {
+        indexer
+            .search_by_content(&query, limit)
+            .map_err(|e| format!("Error searching file contents: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn set_content_indexing(
+    path: String,
+    enabled: bool,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<String, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        let root = indexer
+            .set_content_indexing(std::path::Path::new(&path), enabled)
+            .map_err(|e| format!("Error updating content indexing: {}", e.user_message()))?;
+        Ok(format!(
+            "Content indexing {} for {}",
+            if enabled { "enabled" } else { "disabled" },
+            root
+        ))
+    }).await
+}
+
+#[tauri::command]
+async fn list_content_roots(
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<Vec<String>, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .list_content_roots()
+            .map_err(|e| format!("Error listing content roots: {}", e.user_message()))
+    }).await
 }
 
 #[tauri::command]
 async fn refresh_file_index(
-    state: State<'_, std::sync::Arc<Mutex<FileIndexerState>>>,
+    full: Option<bool>,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
     app_handle: AppHandle,
 ) -> Result<String, String> {
-    // 重新初始化文件索引器
-    match file_index::initialize_file_indexer(&app_handle) {
-        Ok(new_indexer) => {
-            let indexer_state = state.inner();
-            let mut indexer_guard = indexer_state.indexer.as_ref()
-                .ok_or("File indexer not initialized")?
-                .lock()
-                .unwrap();
-            
-            // 用新的索引器替换旧的
-            *indexer_guard = new_indexer;
-            
-            Ok("File index refreshed successfully".to_string())
+    let indexer = state.lock_or_recover().indexer()?;
+    
+    // 重新扫描默认目录，默认只处理有变化的文件
+    run_blocking(move || {
+        let summary = rebuild_file_index(&indexer, &app_handle, full.unwrap_or(false))?;
+        Ok(format!(
+            "File index refreshed successfully ({} added, {} updated, {} removed)",
+            summary.added, summary.updated, summary.removed
+        ))
+    }).await
+}
+
+#[tauri::command]
+async fn cancel_index_scan(state: State<'_, Arc<Mutex<FileIndexerState>>>) -> Result<String, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    if indexer.cancel_indexing() {
+        Ok("Cancellation requested".to_string())
+    } else {
+        Ok("No file index scan in progress".to_string())
+    }
+}
+
+#[tauri::command]
+async fn set_index_parallelism(
+    parallelism: usize,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<String, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    indexer.set_scan_parallelism(parallelism);
+    Ok(format!("Scanning up to {} index roots at a time", indexer.scan_parallelism()))
+}
+
+#[tauri::command]
+async fn set_index_follow_symlinks(
+    enabled: bool,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<String, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    indexer.set_follow_symlinks(enabled);
+    Ok(format!("Symbolic links are {} during scans", if enabled { "followed" } else { "skipped" }))
+}
+
+#[tauri::command]
+async fn set_content_hashing(
+    enabled: bool,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<String, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    indexer.set_content_hashing(enabled);
+    Ok(format!("File contents are {} during scans", if enabled { "hashed" } else { "not hashed" }))
+}
+
+#[tauri::command]
+async fn find_duplicate_files(
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<Vec<file_index::DuplicateGroup>, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .find_duplicates()
+            .map_err(|e| format!("Error finding duplicate files: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn reindex_file(
+    path: String,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<String, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    
+    run_blocking(move || {
+        match indexer.index_path(std::path::Path::new(&path)) {
+            Ok(true) => Ok(format!("Reindexed {}", path)),
+            Ok(false) => Ok(format!("Removed {} from the index", path)),
+            Err(e) => Err(format!("Error reindexing {}: {}", path, e.user_message())),
+        }
+    }).await
+}
+
+#[tauri::command]
+async fn is_index_building(
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<bool, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    Ok(indexer.is_indexing())
+}
+
+#[tauri::command]
+async fn file_index_stats(
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<file_index::IndexStats, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .stats()
+            .map_err(|e| format!("Error reading index stats: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn index_storage_stats(
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<file_index::StorageStats, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .storage_stats()
+            .map_err(|e| format!("Error reading index storage stats: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn vacuum_index(
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<file_index::VacuumSummary, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .vacuum()
+            .map_err(|e| format!("Error compacting file index: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn prune_index(
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<usize, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .prune_missing()
+            .map_err(|e| format!("Error pruning file index: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn export_index(
+    format: file_index::ExportFormat,
+    path: String,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<String, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .export(format, std::path::Path::new(&path))
+            .map(|count| format!("Exported {} entries to {}", count, path))
+            .map_err(|e| format!("Error exporting file index: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn add_index_root(
+    path: String,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<String, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .add_index_root(std::path::Path::new(&path))
+            .map_err(|e| format!("Error adding index root: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn remove_index_root(
+    path: String,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<String, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        let removed = indexer
+            .remove_index_root(&path)
+            .map_err(|e| format!("Error removing index root: {}", e.user_message()))?;
+        if !removed {
+            return Err(format!("Index root not configured: {}", path));
         }
-        Err(e) => Err(format!("Error refreshing file index: {}", e)),
+        
+        // 移除该目录下已索引的条目
+        indexer
+            .remove_directory(&path)
+            .map_err(|e| format!("Error removing indexed entries: {}", e.user_message()))?;
+        Ok(format!("Index root removed: {}", path))
+    }).await
+}
+
+#[tauri::command]
+async fn list_index_roots(
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<Vec<String>, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .list_index_roots()
+            .map_err(|e| format!("Error listing index roots: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn add_ignore_pattern(
+    pattern: String,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<String, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .add_ignore_pattern(&pattern)
+            .map_err(|e| format!("Error adding ignore pattern: {}", e.user_message()))?;
+        Ok(format!("Ignore pattern added: {}", pattern))
+    }).await
+}
+
+#[tauri::command]
+async fn remove_ignore_pattern(
+    pattern: String,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<bool, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .remove_ignore_pattern(&pattern)
+            .map_err(|e| format!("Error removing ignore pattern: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn list_ignore_patterns(
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<Vec<String>, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .list_ignore_patterns()
+            .map_err(|e| format!("Error listing ignore patterns: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn get_index_filter(
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<file_index::IndexFilter, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    Ok(indexer.index_filter())
+}
+
+#[tauri::command]
+async fn set_index_filter(
+    filter: file_index::IndexFilter,
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+) -> Result<(), String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    run_blocking(move || {
+        indexer
+            .set_index_filter(filter)
+            .map_err(|e| format!("Error saving index filter: {}", e.user_message()))
+    }).await
+}
+
+#[tauri::command]
+async fn start_file_watcher(
+    state: State<'_, Arc<Mutex<FileIndexerState>>>,
+    watcher_state: State<'_, Arc<Mutex<FileWatcherState>>>,
+) -> Result<String, String> {
+    let indexer = state.lock_or_recover().indexer()?;
+    let mut watcher_state = watcher_state.lock_or_recover();
+    if watcher_state.watcher.is_some() {
+        return Err("File watcher is already running".to_string());
     }
+    
+    let roots = indexer
+        .effective_index_roots()
+        .map_err(|e| format!("Error loading index roots: {}", e.user_message()))?;
+    let watcher = file_watcher::FileWatcher::start(indexer, &roots)
+        .map_err(|e| format!("Failed to start file watcher: {}", e))?;
+    watcher_state.watcher = Some(watcher);
+    
+    Ok(format!("Watching {} directories", roots.len()))
+}
+
+#[tauri::command]
+async fn stop_file_watcher(
+    watcher_state: State<'_, Arc<Mutex<FileWatcherState>>>,
+) -> Result<String, String> {
+    let watcher = watcher_state.lock_or_recover().watcher.take()
+        .ok_or("File watcher is not running")?;
+    
+    // 停止时会写入尚未处理的变化，放到阻塞线程池中执行
+    run_blocking(move || {
+        watcher.stop();
+        Ok("File watcher stopped".to_string())
+    }).await
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run(app_handle: tauri::AppHandle) {
-    let mcp_state = std::sync::Arc::new(Mutex::new(McpClientState { client: None }));
-    let orch_state = std::sync::Arc::new(Mutex::new(OrchestratorState { orchestrator: None }));
+pub fn run() {
+    let mcp_state = Arc::new(Mutex::new(McpClientState { clients: HashMap::new(), killers: HashMap::new(), connecting: HashSet::new() }));
+    let orch_state = Arc::new(Mutex::new(OrchestratorState {
+        orchestrator: None,
+        in_flight: Vec::new(),
+        next_message_id: 0,
+        pending_approvals: orchestrator::PendingApprovals::default(),
+    }));
+    let file_indexer_state = Arc::new(Mutex::new(FileIndexerState { indexer: None, init_error: None, streaming_search: None }));
+    let file_watcher_state = Arc::new(Mutex::new(FileWatcherState { watcher: None }));
     
-    // 初始化文件索引器
-    let file_indexer = match file_index::initialize_file_indexer(&app_handle) {
-        Ok(indexer) => Some(std::sync::Arc::new(Mutex::new(indexer))),
-        Err(e) => {
-            eprintln!("Failed to initialize file indexer: {}", e);
-            None
-        }
-    };
-    let file_indexer_state = std::sync::Arc::new(Mutex::new(FileIndexerState { indexer: file_indexer }));
+    let exit_mcp_state = Arc::clone(&mcp_state);
+    
+    // 最先初始化日志，之后的启动过程都能记录下来；日志目录与 `app_data_dir()` 相同，
+    // 数据目录不可用时只输出到标准输出
+    let context = tauri::generate_context!();
+    let log_dir = directories::BaseDirs::new()
+        .map(|dirs| dirs.data_dir().join(&context.config().identifier).join(logging::LOG_DIR));
+    let logger = logging::init(log_dir);
     
     tauri::Builder::default()
+        .manage(logger)
         .manage(mcp_state)
-        .manage(orch_state)
-        .manage(file_indexer_state)
+        .manage(Arc::clone(&orch_state))
+        .manage(Arc::clone(&file_indexer_state))
+        .manage(file_watcher_state)
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_shell::init())
-        .invoke_handler(tauri::generate_handler![greet, init_mcp, list_mcp_tools, process_user_message, approve_tool_call, search_local_files, refresh_file_index])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
-}
\ No newline at end of file
+        .setup(move |app| {
+            // 收到SIGTERM或SIGINT时走正常的退出流程，关闭MCP服务器
+            #[cfg(unix)]
+            {
+                let app_handle = app.handle().clone();
+                tauri::async_runtime::spawn(async move {
+                    use tokio::signal::unix::{signal, SignalKind};
+                    let (Ok(mut terminate), Ok(mut interrupt)) =
+                        (signal(SignalKind::terminate()), signal(SignalKind::interrupt()))
+                    else {
+                        return;
+                    };
+                    tokio::select! {
+                        _ = terminate.recv() => {}
+                        _ = interrupt.recv() => {}
+                    }
+                    app_handle.exit(0);
+                });
+            }
+            
+            // 编排器在启动时创建，以便向前端发送状态事件
+            let status_emitter = TauriStatusEmitter(app.handle().clone());
+            let orchestrator = orchestrator::Orchestrator::new(String::new(), String::new(), String::new())
+                .with_status_emitter(Box::new(status_emitter));
+            // 审计日志不可用时工具调用照常进行，只是不留记录
+            let orchestrator = match audit::initialize_audit_log(app.handle()) {
+                Ok(audit_log) => orchestrator.with_audit_log(Arc::new(audit_log)),
+                Err(e) => {
+                    tracing::warn!(error = %e, "Failed to open tool audit log");
+                    orchestrator
+                }
+            };
+            orch_state.lock_or_recover().orchestrator = Some(Arc::new(orchestrator));
+            
+            // 在阻塞线程池中初始化文件索引器，首次扫描不会阻塞应用启动
+            let app_handle = app.handle().clone();
+            tauri::async_runtime::spawn_blocking(move || {
+                match file_index::initialize_file_indexer(&app_handle) {
+                    Ok(indexer) => {
+                        let indexer = Arc::new(indexer);
+                        file_indexer_state.lock_or_recover().indexer = Some(Arc::clone(&indexer));
+                        orch_state.lock_or_recover().current().set_file_indexer(Arc::clone(&indexer));
+                        start_mcp_tcp_server(&app_handle, Arc::clone(&indexer));
+                        if let Err(e) = rebuild_file_index(&indexer, &app_handle, false) {
+                            tracing::error!(error = %e, "Failed to build file index");
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to initialize file indexer");
+                        // 之后的文件搜索等命令返回这个原因，而不是一直提示正在启动
+                        file_indexer_state.lock_or_recover().init_error =
+                            Some(format!("The file index could not be opened. {}", e.user_message()));
+                    }
+                }
+            });
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![greet, system_status, init_mcp, connect_mcp_server, disconnect_mcp_server, list_mcp_tools, list_mcp_resources, read_mcp_resource, process_user_message, cancel_current_message, set_busy_policy, list_pending_approvals, approve_tool_call, approve_tool_calls, reject_tool_call, preview_tool_call, new_conversation, get_conversation_transcript, set_output_verbosity, get_audit_log, get_tool_calls, get_recent_logs, set_log_level, set_model_token_limit, set_model_vision_support, set_model_route, set_tool_call_limit, set_tool_result_framing, set_tool_result_caching, set_system_prompt_template, search_local_files, search_local_files_streaming, search_local_files_fuzzy, search_recent_files, search_files_modified_between, search_files_by_permission, search_files_advanced, search_file_contents, set_content_indexing, list_content_roots, refresh_file_index, cancel_index_scan, set_index_parallelism, set_index_follow_symlinks, set_content_hashing, find_duplicate_files, reindex_file, is_index_building, file_index_stats, index_storage_stats, vacuum_index, prune_index, export_index, start_file_watcher, stop_file_watcher, add_index_root, remove_index_root, list_index_roots, add_ignore_pattern, remove_ignore_pattern, list_ignore_patterns, get_index_filter, set_index_filter])
+        .build(context)
+        .expect("error while running tauri application")
+        .run(move |_app_handle, event| {
+            if let tauri::RunEvent::Exit = event {
+                tauri::async_runtime::block_on(shutdown_mcp_clients(&exit_mcp_state));
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn orchestrator_state(orchestrator: Option<orchestrator::Orchestrator>) -> Mutex<OrchestratorState> {
+        Mutex::new(OrchestratorState {
+            orchestrator: orchestrator.map(Arc::new),
+            in_flight: Vec::new(),
+            next_message_id: 0,
+            pending_approvals: orchestrator::PendingApprovals::default(),
+        })
+    }
+
+    #[test]
+    fn test_system_status_with_partial_initialization() {
+        let mcp_state = Mutex::new(McpClientState { clients: HashMap::new(), killers: HashMap::new(), connecting: HashSet::new() });
+        let orch_state = orchestrator_state(Some(orchestrator::Orchestrator::new(
+            String::new(),
+            "mock://".to_string(),
+            "mock".to_string(),
+        )));
+        let indexer_state = Mutex::new(FileIndexerState { indexer: None, init_error: None, streaming_search: None });
+        
+        let status = collect_system_status(&mcp_state, &orch_state, &indexer_state);
+        assert!(!status.mcp_connected);
+        assert!(status.orchestrator_configured);
+        assert!(!status.file_indexer_ready);
+        assert_eq!(status.indexed_file_count, 0);
+        assert!(status.errors.is_empty());
+        
+        // 锁被毒化时返回其余子系统的信息
+        let _ = std::panic::catch_unwind(|| {
+            let _guard = indexer_state.lock().unwrap();
+            panic!("indexer thread panicked");
+        });
+        let status = collect_system_status(&mcp_state, &orchestrator_state(None), &indexer_state);
+        assert!(!status.orchestrator_configured);
+        assert_eq!(status.errors, ["File indexer state is unavailable (lock poisoned)"]);
+        
+        // 初始化失败的原因由命令和状态报告，而不是一直提示正在启动
+        let error = "The file index could not be opened. File system error: disk full".to_string();
+        let indexer_state = Mutex::new(FileIndexerState { indexer: None, init_error: Some(error.clone()), streaming_search: None });
+        assert_eq!(indexer_state.lock_or_recover().indexer().err().unwrap(), error);
+        let status = collect_system_status(&mcp_state, &orch_state, &indexer_state);
+        assert!(!status.file_indexer_ready);
+        assert_eq!(status.errors, [error]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shutdown_kills_busy_mcp_servers() {
+        let client = mcp::McpClient::new_with_options(vec!["sh", "-c", "cat > /dev/null"], Default::default())
+            .await
+            .unwrap();
+        let killer = client.process_killer();
+        let client = Arc::new(tokio::sync::Mutex::new(client));
+        let state = Mutex::new(McpClientState {
+            clients: HashMap::from([("busy".to_string(), Arc::clone(&client))]),
+            killers: HashMap::from([("busy".to_string(), killer)]),
+            connecting: HashSet::new(),
+        });
+        
+        // 模拟一个长时间执行的工具调用占用客户端
+        let busy = client.lock().await;
+        assert!(busy.pid().await.is_some());
+        tokio::time::timeout(std::time::Duration::from_secs(2), shutdown_mcp_clients(&state))
+            .await
+            .expect("shutdown waited for the busy client");
+        assert!(busy.pid().await.is_none());
+        assert!(state.lock_or_recover().clients.is_empty());
+    }
+
+    #[test]
+    fn test_new_streaming_search_supersedes_the_previous_one() {
+        let mut state = FileIndexerState { indexer: None, init_error: None, streaming_search: None };
+        let first = state.begin_streaming_search();
+        let second = state.begin_streaming_search();
+        assert!(first.is_cancelled());
+        assert!(!second.is_cancelled());
+    }
+
+    #[test]
+    fn test_cancel_reaches_every_overlapping_message() {
+        let state = orchestrator_state(None);
+        let mut state = state.lock_or_recover();
+        let (first, first_token) = state.begin_message();
+        let (_, second_token) = state.begin_message();
+        
+        // 后开始的消息不会替换先前消息的令牌
+        assert!(state.cancel_message());
+        assert!(first_token.is_cancelled());
+        assert!(second_token.is_cancelled());
+        
+        // 已取消的消息结束时不影响之后开始的消息
+        let (_, third_token) = state.begin_message();
+        state.finish_message(first);
+        assert!(state.cancel_message());
+        assert!(third_token.is_cancelled());
+        assert!(!state.cancel_message());
+    }
+
+    #[test]
+    fn test_mcp_server_name_is_reserved_while_connecting() {
+        let state = Mutex::new(McpClientState { clients: HashMap::new(), killers: HashMap::new(), connecting: HashSet::new() });
+        state.lock_or_recover().reserve_name("files").unwrap();
+        let reservation = NameReservation { state: &state, name: "files".to_string() };
+        
+        // 第一个请求还在启动服务器时，同名请求被拒绝，其他名称不受影响
+        assert!(state.lock_or_recover().reserve_name("files").is_err());
+        assert!(state.lock_or_recover().reserve_name("other").is_ok());
+        
+        // 启动失败后名称被释放
+        drop(reservation);
+        assert!(state.lock_or_recover().reserve_name("files").is_ok());
+    }
+
+    #[test]
+    fn test_commands_recover_from_poisoned_state() {
+        let indexer_state = Mutex::new(FileIndexerState { indexer: None, init_error: None, streaming_search: None });
+        let _ = std::panic::catch_unwind(|| {
+            let _guard = indexer_state.lock().unwrap();
+            panic!("index build panicked");
+        });
+        assert!(indexer_state.is_poisoned());
+        
+        // 与 search_local_files 等命令相同的取锁路径：返回错误信息而不是panic
+        let result = indexer_state.lock_or_recover().indexer();
+        assert_eq!(result.err().unwrap(), LocalPilotError::NotInitialized("File indexer".to_string()).user_message());
+        assert!(!indexer_state.is_poisoned());
+    }
+}
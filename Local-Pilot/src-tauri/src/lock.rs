@@ -0,0 +1,75 @@
+//! 锁毒化恢复
+//! 持有锁的线程panic后锁会被毒化；这里取回其中的数据继续使用，避免之后的每个命令都panic
+
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+fn recover<G>(poisoned: PoisonError<G>) -> G {
+    tracing::warn!("Recovering from a poisoned lock: a thread panicked while holding it");
+    poisoned.into_inner()
+}
+
+/// `Mutex` 的加锁扩展
+pub trait LockExt<T> {
+    /// 加锁；锁被毒化时清除毒化标记并返回其中的数据
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> LockExt<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|poisoned| {
+            self.clear_poison();
+            recover(poisoned)
+        })
+    }
+}
+
+/// `RwLock` 的加锁扩展
+pub trait RwLockExt<T> {
+    /// 获取读锁；锁被毒化时清除毒化标记并返回其中的数据
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T>;
+
+    /// 获取写锁；锁被毒化时清除毒化标记并返回其中的数据
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T> RwLockExt<T> for RwLock<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(|poisoned| {
+            self.clear_poison();
+            recover(poisoned)
+        })
+    }
+
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(|poisoned| {
+            self.clear_poison();
+            recover(poisoned)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poisoned_locks_are_recovered() {
+        let mutex = Mutex::new(1);
+        let rwlock = RwLock::new(1);
+        let _ = std::panic::catch_unwind(|| {
+            let _mutex_guard = mutex.lock().unwrap();
+            let _rwlock_guard = rwlock.write().unwrap();
+            panic!("worker panicked");
+        });
+        assert!(mutex.is_poisoned());
+        assert!(rwlock.is_poisoned());
+
+        *mutex.lock_or_recover() += 1;
+        assert_eq!(*mutex.lock_or_recover(), 2);
+        assert!(!mutex.is_poisoned());
+
+        *rwlock.write_or_recover() += 1;
+        assert_eq!(*rwlock.read_or_recover(), 2);
+        assert!(!rwlock.is_poisoned());
+    }
+}
@@ -0,0 +1,290 @@
+//! 日志初始化与敏感信息脱敏
+//! 日志同时输出到标准输出和应用数据目录下按天滚动的日志文件，级别由 `RUST_LOG` 指定，运行中可调整；
+//! 请求头和请求体中的密钥在写入日志前替换为占位符
+
+use crate::error::LocalPilotError;
+use reqwest::header::HeaderMap;
+use serde_json::Value;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+/// 脱敏后的占位符
+pub const REDACTED: &str = "[REDACTED]";
+
+/// 需要脱敏的字段名（不区分大小写）
+const SENSITIVE_KEYS: &[&str] = &["api_key", "api-key", "x-api-key", "authorization"];
+
+/// 未设置 `RUST_LOG` 时的日志级别
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// 应用数据目录下存放日志文件的子目录
+pub const LOG_DIR: &str = "logs";
+
+/// 日志文件名为 `local-pilot.<日期>.log`，每天一个文件
+const LOG_FILE_PREFIX: &str = "local-pilot";
+const LOG_FILE_SUFFIX: &str = "log";
+
+/// 保留的日志文件数，更早的文件在滚动时删除
+const MAX_LOG_FILES: usize = 7;
+
+/// 读取最近日志时最多从文件末尾读取的字节数
+const MAX_LOG_TAIL_BYTES: u64 = 1024 * 1024;
+
+/// 运行中的日志设置：调整级别，读取当前的日志文件
+#[derive(Clone)]
+pub struct Logger {
+    filter: reload::Handle<EnvFilter, Registry>,
+    /// 未能创建日志文件时为 `None`，此时只输出到标准输出
+    log_dir: Option<PathBuf>,
+    /// 日志文件由后台线程写入，guard被丢弃时写完缓冲的日志并结束该线程，因此需在应用运行期间保留
+    _guard: Option<Arc<WorkerGuard>>,
+}
+
+impl Logger {
+    /// 修改日志级别，如 `debug`，也可以是 `RUST_LOG` 格式的过滤规则，如 `local_pilot_lib=debug,info`
+    pub fn set_level(&self, level: &str) -> Result<(), LocalPilotError> {
+        // 单独的一个词会被当作模块名，其他日志全部被过滤掉，因此必须是级别名
+        if !level.contains(['=', ',']) && level.trim().parse::<tracing::level_filters::LevelFilter>().is_err() {
+            return Err(LocalPilotError::InvalidInput(format!("Invalid log level '{}'", level)));
+        }
+        let filter = EnvFilter::try_new(level)
+            .map_err(|e| LocalPilotError::InvalidInput(format!("Invalid log level '{}': {}", level, e)))?;
+        self.filter
+            .reload(filter)
+            .map_err(|_| LocalPilotError::NotInitialized("Logging".to_string()))
+    }
+
+    /// 当前日志文件的最后 `lines` 行
+    pub fn recent_logs(&self, lines: usize) -> Result<String, LocalPilotError> {
+        let log_dir = self
+            .log_dir
+            .as_ref()
+            .ok_or_else(|| LocalPilotError::NotInitialized("The log file".to_string()))?;
+        match current_log_file(log_dir)? {
+            Some(path) => read_last_lines(&path, lines),
+            None => Ok(String::new()),
+        }
+    }
+}
+
+/// 初始化全局日志输出，`log_dir` 不为 `None` 时同时写入该目录下的日志文件
+/// 返回的 `Logger` 需要一直保留，丢弃后不再写入日志文件
+pub fn init(log_dir: Option<PathBuf>) -> Logger {
+    let (subscriber, mut logger, guard) = subscriber(log_dir);
+    if let Err(e) = subscriber.try_init() {
+        eprintln!("Failed to initialize logging: {}", e);
+    }
+    logger._guard = guard.map(Arc::new);
+    logger
+}
+
+/// 构建日志订阅者；日志目录无法创建时只输出到标准输出
+/// 写入日志文件不会阻塞记录日志的线程，返回的guard被丢弃前写完缓冲的日志
+fn subscriber(log_dir: Option<PathBuf>) -> (impl tracing::Subscriber + Send + Sync, Logger, Option<WorkerGuard>) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_LEVEL));
+    let (filter, handle) = reload::Layer::new(filter);
+    let appender = log_dir.as_ref().and_then(|dir| match file_appender(dir) {
+        Ok(appender) => Some(appender),
+        Err(e) => {
+            eprintln!("Failed to create log file in {}: {}", dir.display(), e);
+            None
+        }
+    });
+    let log_dir = log_dir.filter(|_| appender.is_some());
+    let (writer, guard) = appender.map(tracing_appender::non_blocking).unzip();
+
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .with(writer.map(|writer| fmt::layer().with_ansi(false).with_writer(writer)));
+    (subscriber, Logger { filter: handle, log_dir, _guard: None }, guard)
+}
+
+fn file_appender(log_dir: &Path) -> Result<RollingFileAppender, LocalPilotError> {
+    fs::create_dir_all(log_dir)?;
+    RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix(LOG_FILE_SUFFIX)
+        .max_log_files(MAX_LOG_FILES)
+        .build(log_dir)
+        .map_err(|e| LocalPilotError::InvalidInput(e.to_string()))
+}
+
+/// 目录中最新的日志文件；文件名中的日期使日志文件按名称排序即按时间排序
+fn current_log_file(log_dir: &Path) -> Result<Option<PathBuf>, LocalPilotError> {
+    let mut newest: Option<PathBuf> = None;
+    for entry in fs::read_dir(log_dir)? {
+        let path = entry?.path();
+        let is_log = path.file_name().and_then(|name| name.to_str()).is_some_and(|name| {
+            name.starts_with(LOG_FILE_PREFIX) && name.ends_with(LOG_FILE_SUFFIX)
+        });
+        if is_log && newest.as_ref().is_none_or(|newest| path > *newest) {
+            newest = Some(path);
+        }
+    }
+    Ok(newest)
+}
+
+/// 读取文件的最后 `lines` 行，最多读取末尾的 `MAX_LOG_TAIL_BYTES` 字节
+fn read_last_lines(path: &Path, lines: usize) -> Result<String, LocalPilotError> {
+    let mut file = fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    let start = size.saturating_sub(MAX_LOG_TAIL_BYTES);
+    file.seek(SeekFrom::Start(start))?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    let text = String::from_utf8_lossy(&bytes);
+
+    // 从文件中间开始读取时，第一行可能不完整
+    let mut all_lines: Vec<&str> = text.lines().collect();
+    if start > 0 && !all_lines.is_empty() {
+        all_lines.remove(0);
+    }
+    let tail = &all_lines[all_lines.len().saturating_sub(lines)..];
+    Ok(tail.iter().map(|line| format!("{}\n", line)).collect())
+}
+
+/// 字段名是否包含密钥
+pub fn is_sensitive(name: &str) -> bool {
+    SENSITIVE_KEYS.iter().any(|key| key.eq_ignore_ascii_case(name))
+}
+
+/// 将请求头转换为可记录的JSON对象，敏感头的值被替换
+pub fn redact_headers(headers: &HeaderMap) -> Value {
+    let redacted = headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if is_sensitive(name.as_str()) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.as_str().to_string(), Value::String(value))
+        })
+        .collect();
+    Value::Object(redacted)
+}
+
+/// 递归替换JSON中敏感字段的值
+pub fn redact_json(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| {
+                    let value = if is_sensitive(key) {
+                        Value::String(REDACTED.to_string())
+                    } else {
+                        redact_json(value)
+                    };
+                    (key.clone(), value)
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.iter().map(redact_json).collect()),
+        other => other.clone(),
+    }
+}
+
+/// 测试用：把日志写入内存缓冲区
+#[cfg(test)]
+pub mod capture {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::fmt::MakeWriter;
+
+    #[derive(Clone, Default)]
+    pub struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl CapturedLogs {
+        pub fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    impl Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for CapturedLogs {
+        type Writer = CapturedLogs;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    /// 在当前线程上启用记录所有级别的订阅者，返回的guard被丢弃前有效
+    pub fn capture() -> (CapturedLogs, tracing::subscriber::DefaultGuard) {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(logs.clone())
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_log_file_is_written_and_level_can_change() {
+        let log_dir = std::env::temp_dir().join(format!("test_logs_{}", uuid::Uuid::new_v4()));
+        let (subscriber, logger, guard) = subscriber(Some(log_dir.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            logger.set_level("info").unwrap();
+            tracing::info!("first message");
+            tracing::debug!("hidden debug message");
+            logger.set_level("debug").unwrap();
+            tracing::debug!("visible debug message");
+            assert!(logger.set_level("loud").is_err());
+            assert!(logger.set_level("local_pilot_lib=loud").is_err());
+        });
+        // 等后台线程写完
+        drop(guard);
+
+        assert!(current_log_file(&log_dir).unwrap().is_some());
+        let logs = logger.recent_logs(10).unwrap();
+        assert!(logs.contains("first message"));
+        assert!(!logs.contains("hidden debug message"));
+        assert!(logs.contains("visible debug message"));
+
+        let last = logger.recent_logs(1).unwrap();
+        assert_eq!(last.lines().count(), 1);
+        assert!(last.contains("visible debug message"));
+
+        fs::remove_dir_all(&log_dir).ok();
+    }
+
+    #[test]
+    fn test_redacts_sensitive_fields() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", HeaderValue::from_static("Bearer sk-secret"));
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        let headers = redact_headers(&headers);
+        assert_eq!(headers["authorization"], REDACTED);
+        assert_eq!(headers["content-type"], "application/json");
+
+        let body = serde_json::json!({ "config": { "API_KEY": "sk-secret" }, "items": [{ "x-api-key": "sk-secret" }] });
+        let redacted = redact_json(&body).to_string();
+        assert!(!redacted.contains("sk-secret"));
+    }
+}
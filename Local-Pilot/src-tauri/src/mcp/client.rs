@@ -1,73 +1,321 @@
 //! MCP (Model Context Protocol) 客户端实现
 //! 用于通过stdio与MCP服务器进行通信
 
-use crate::mcp::protocol::{RequestMessage, ResponseMessage, Tool, Resource};
+use crate::error::LocalPilotError;
+use crate::lock::{LockExt, RwLockExt};
+use crate::logging;
+use crate::mcp::framing::{self, Framing};
+use crate::mcp::protocol::{NotificationMessage, RequestMessage, ResponseMessage, Tool, Resource, MCP_PROTOCOL_VERSION};
 use serde_json::Value;
-use std::collections::HashMap;
-use tokio::process::Command;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use tokio::process::{ChildStdin, Command};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
+/// 未在下表中列出的请求的默认超时时间
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `ping` 的默认超时时间
+pub const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `initialize` 握手的超时时间
+pub const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `tools/call` 的默认超时时间，工具可能执行较长的命令
+pub const TOOL_CALL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// 关闭时等待服务器自行退出的时间，超时后强制结束进程
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// 各类请求的默认超时时间
+pub fn default_timeout(request: &RequestMessage) -> Duration {
+    match request {
+        RequestMessage::Ping {} => PING_TIMEOUT,
+        RequestMessage::Initialize { .. } => INITIALIZE_TIMEOUT,
+        RequestMessage::ToolCall { .. } => TOOL_CALL_TIMEOUT,
+        _ => DEFAULT_REQUEST_TIMEOUT,
+    }
+}
+
+/// 服务器进程意外退出后自动重启的策略
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// 客户端生命周期内最多重启的次数
+    pub max_restarts: u32,
+    /// 第一次重启前的等待时间，之后每次翻倍
+    pub initial_backoff: Duration,
+    /// 单次等待的上限
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// 第 `restart` 次重启（从0开始）前的等待时间
+    pub fn backoff(&self, restart: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(restart))
+            .min(self.max_backoff)
+    }
+}
+
+/// 启动MCP服务器进程的选项
+#[derive(Debug, Clone, Default)]
+pub struct LaunchOptions {
+    /// 发送请求时使用的分帧方式
+    pub framing: Framing,
+    /// 服务器进程的工作目录，未指定时继承应用的工作目录
+    pub working_dir: Option<PathBuf>,
+    /// 追加（或覆盖）到继承的环境变量中的变量
+    pub env: HashMap<String, String>,
+    /// 服务器进程退出后，下一个请求发出前自动重启；`None` 时不重启
+    pub restart: Option<RestartPolicy>,
+}
+
+/// 等待响应的请求：请求id -> 响应通道
+type ResponseChannels = Arc<Mutex<HashMap<String, mpsc::Sender<ResponseMessage>>>>;
+
 /// MCP客户端结构体
+/// 克隆得到的句柄共享同一个服务器进程和状态，可以在不持有外层锁的情况下并发发送请求
+#[derive(Clone)]
 pub struct McpClient {
     child_process: Arc<Mutex<Option<tokio::process::Child>>>,
-    stdin_tx: Arc<Mutex<Option<tokio::io::StdinWriteHalf>>>,
-    response_channels: Arc<Mutex<HashMap<String, mpsc::Sender<ResponseMessage>>>>,
+    /// 子进程的stdin只用于写入，无需 `tokio::io::split`（它要求流同时可读）
+    stdin_tx: Arc<Mutex<Option<ChildStdin>>>,
+    response_channels: ResponseChannels,
+    /// 握手后服务器确认的协议版本
+    protocol_version: Arc<std::sync::RwLock<Option<String>>>,
+    /// 启动服务器的命令和选项，重启时沿用
+    command: Vec<String>,
+    options: LaunchOptions,
+    /// 收到但没有对应等待中请求的响应数
+    unmatched_responses: Arc<AtomicU64>,
+    /// 读取服务器stdout和stderr的任务
+    reader_tasks: Arc<std::sync::Mutex<Vec<JoinHandle<()>>>>,
+    /// 已订阅的资源URI，关闭时逐一取消订阅
+    subscriptions: Arc<Mutex<HashSet<String>>>,
+    /// 服务器的stdout已关闭，即进程已退出
+    exited: Arc<AtomicBool>,
+    /// 已调用 `shutdown` 或进程已被 `ProcessKiller` 结束，之后不再重启
+    closed: Arc<AtomicBool>,
+    /// 已自动重启的次数
+    restarts: Arc<AtomicU32>,
+    /// 同一时间只有一个请求执行重启
+    restart_lock: Arc<Mutex<()>>,
 }
 
-impl McpClient {
-    /// 创建新的MCP客户端并启动子进程
-    pub async fn new(mcp_server_cmd: Vec<&str>) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut cmd = Command::new(mcp_server_cmd[0]);
-        for arg in &mcp_server_cmd[1..] {
-            cmd.arg(arg);
+/// 不经过 `McpClient` 的锁结束服务器进程，用于客户端正被长时间的请求占用时退出应用
+#[derive(Clone)]
+pub struct ProcessKiller {
+    child_process: Arc<Mutex<Option<tokio::process::Child>>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl ProcessKiller {
+    /// 强制结束服务器进程，之后不再自动重启；等待中的请求会以 `ServerExited` 失败
+    pub async fn kill(&self) -> Result<(), LocalPilotError> {
+        self.closed.store(true, Ordering::SeqCst);
+        if let Some(mut child) = self.child_process.lock().await.take() {
+            child.kill().await?;
         }
-        
-        let mut child = cmd.stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn()?;
+        Ok(())
+    }
+}
 
-        let stdin = child.stdin.take().unwrap();
-        let stdout = child.stdout.take().unwrap();
-        
-        let (stdin_reader, stdin_writer) = tokio::io::split(stdin);
-        let _stdout_reader = stdout;
-        
-        let response_channels: Arc<Mutex<HashMap<String, mpsc::Sender<ResponseMessage>>>> = 
-            Arc::new(Mutex::new(HashMap::new()));
+/// 启动的服务器进程
+struct ServerProcess {
+    child: tokio::process::Child,
+    stdin: ChildStdin,
+    reader_tasks: Vec<JoinHandle<()>>,
+}
+
+/// 启动服务器进程和读取其输出的任务
+/// 响应按id交给 `response_channels` 中等待的请求；stdout关闭时等待中的请求以 `ServerExited` 失败，并设置 `exited`
+fn spawn_server(
+    command: &[String],
+    options: &LaunchOptions,
+    response_channels: &ResponseChannels,
+    unmatched_responses: &Arc<AtomicU64>,
+    exited: &Arc<AtomicBool>,
+) -> Result<ServerProcess, LocalPilotError> {
+    let mut cmd = Command::new(&command[0]);
+    cmd.args(&command[1..]);
+    if let Some(dir) = &options.working_dir {
+        // 目录不存在时spawn的报错不会指明是哪个路径
+        if !dir.is_dir() {
+            return Err(LocalPilotError::InvalidInput(format!(
+                "MCP server working directory does not exist: {}",
+                dir.display()
+            )));
+        }
+        cmd.current_dir(dir);
+    }
+    cmd.envs(&options.env);
+    
+    // 客户端未经 `shutdown` 直接被丢弃时也不会留下孤儿进程
+    let mut child = cmd.stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    
+    // 启动监听stdout的异步任务
+    let channels_clone = Arc::clone(response_channels);
+    let unmatched_clone = Arc::clone(unmatched_responses);
+    let exited_clone = Arc::clone(exited);
+    let stdout_task = tokio::spawn(async move {
+        let mut reader = BufReader::new(stdout);
         
-        // 启动监听stdout的异步任务
-        let channels_clone = Arc::clone(&response_channels);
-        tokio::spawn(async move {
-            let reader = BufReader::new(stdout);
-            let mut lines = reader.lines();
-            
-            while let Ok(Some(line)) = lines.next_line().await {
-                if let Ok(response) = serde_json::from_str::<ResponseMessage>(&line) {
-                    if let Some(id) = &response.id {
-                        let mut channels = channels_clone.lock().await;
-                        if let Some(sender) = channels.remove(id) {
-                            let _ = sender.send(response.clone());
+        loop {
+            let message = match framing::read_message(&mut reader).await {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Stopped reading from MCP server");
+                    break;
+                }
+            };
+            if let Ok(response) = serde_json::from_str::<ResponseMessage>(&message) {
+                if let Some(id) = response.id.as_ref().map(ToString::to_string) {
+                    // 取出通道后立即释放锁，发送响应时不持有锁
+                    let sender = channels_clone.lock().await.remove(&id);
+                    match sender {
+                        Some(sender) => {
+                            let _ = sender.send(response).await;
+                        }
+                        None => {
+                            // 通常是已超时的请求的迟到响应
+                            unmatched_clone.fetch_add(1, Ordering::Relaxed);
+                            tracing::debug!(%id, "Dropping MCP response with no waiting request");
                         }
                     }
                 }
             }
-        });
+        }
+        
+        // 服务器已退出，丢弃通道让等待中的请求立即失败，而不是等到超时
+        // 先标记退出，这样请求失败后紧接着的下一个请求就会触发重启
+        exited_clone.store(true, Ordering::SeqCst);
+        channels_clone.lock().await.clear();
+    });
+    
+    // 服务器的日志输出到stderr；必须持续读取，否则管道写满后服务器会阻塞
+    let stderr_task = tokio::spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            tracing::debug!(target: "mcp_server", "{}", line);
+        }
+    });
+    
+    Ok(ServerProcess { child, stdin, reader_tasks: vec![stdout_task, stderr_task] })
+}
+
+impl McpClient {
+    /// 创建新的MCP客户端并启动子进程，请求按行分帧发送
+    pub async fn new(mcp_server_cmd: Vec<&str>) -> Result<Self, LocalPilotError> {
+        Self::new_with_framing(mcp_server_cmd, Framing::default()).await
+    }
+
+    /// 创建新的MCP客户端并启动子进程，请求按 `framing` 分帧发送
+    pub async fn new_with_framing(mcp_server_cmd: Vec<&str>, framing: Framing) -> Result<Self, LocalPilotError> {
+        Self::new_with_options(mcp_server_cmd, LaunchOptions { framing, ..Default::default() }).await
+    }
+
+    /// 创建新的MCP客户端，按 `options` 指定的工作目录和环境变量启动子进程
+    pub async fn new_with_options(mcp_server_cmd: Vec<&str>, options: LaunchOptions) -> Result<Self, LocalPilotError> {
+        let command: Vec<String> = mcp_server_cmd.into_iter().map(str::to_string).collect();
+        let response_channels: ResponseChannels = Arc::new(Mutex::new(HashMap::new()));
+        let unmatched_responses = Arc::new(AtomicU64::new(0));
+        let exited = Arc::new(AtomicBool::new(false));
+        let process = spawn_server(&command, &options, &response_channels, &unmatched_responses, &exited)?;
         
         Ok(Self {
-            child_process: Arc::new(Mutex::new(Some(child))),
-            stdin_tx: Arc::new(Mutex::new(Some(stdin_writer))),
+            child_process: Arc::new(Mutex::new(Some(process.child))),
+            stdin_tx: Arc::new(Mutex::new(Some(process.stdin))),
             response_channels,
+            protocol_version: Arc::new(std::sync::RwLock::new(None)),
+            command,
+            options,
+            unmatched_responses,
+            reader_tasks: Arc::new(std::sync::Mutex::new(process.reader_tasks)),
+            subscriptions: Arc::new(Mutex::new(HashSet::new())),
+            exited,
+            closed: Arc::new(AtomicBool::new(false)),
+            restarts: Arc::new(AtomicU32::new(0)),
+            restart_lock: Arc::new(Mutex::new(())),
         })
     }
 
     /// 发送请求到MCP服务器并通过stdio接收响应
-    pub async fn send_request(&self, request: RequestMessage) -> Result<ResponseMessage, Box<dyn std::error::Error>> {
+    /// `timeout` 为 `None` 时使用该请求类型的默认超时时间；配置了自动重启时，服务器已退出则先重启
+    pub async fn send_request(
+        &self,
+        request: RequestMessage,
+        timeout: Option<Duration>,
+    ) -> Result<ResponseMessage, LocalPilotError> {
+        self.ensure_running().await?;
+        self.request(request, timeout).await
+    }
+
+    async fn request(
+        &self,
+        request: RequestMessage,
+        timeout: Option<Duration>,
+    ) -> Result<ResponseMessage, LocalPilotError> {
         let request_id = Uuid::new_v4().to_string();
+        let timeout = timeout.unwrap_or_else(|| default_timeout(&request));
+        let method = request.method();
+        let started = std::time::Instant::now();
+        
+        let result = self.send_and_wait(&request_id, request, timeout).await;
+        
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        match &result {
+            Ok(response) => {
+                tracing::debug!(method, id = %request_id, elapsed_ms, "MCP request completed");
+                tracing::trace!(
+                    id = %request_id,
+                    result = %logging::redact_json(response.result.as_ref().unwrap_or(&serde_json::Value::Null)),
+                    error = ?response.error,
+                    "MCP response"
+                );
+            }
+            Err(e) => tracing::debug!(method, id = %request_id, elapsed_ms, error = %e, "MCP request failed"),
+        }
+        
+        // 超时或出错时响应不会再被取走，需要移除等待中的通道
+        if result.is_err() {
+            self.response_channels.lock().await.remove(&request_id);
+        }
+        result
+    }
+
+    async fn send_and_wait(
+        &self,
+        request_id: &str,
+        request: RequestMessage,
+        timeout: Duration,
+    ) -> Result<ResponseMessage, LocalPilotError> {
+        let request_id = request_id.to_string();
         
         // 创建响应通道
         let (response_tx, mut response_rx) = mpsc::channel(1);
@@ -97,10 +345,16 @@ impl McpClient {
             RequestMessage::ResourcesList {} => {
                 request_map.insert("method".to_string(), Value::String("resources/list".to_string()));
             },
-            RequestMessage::ResourceRead { uri } => {
+            RequestMessage::ResourceRead { uri, offset, length } => {
                 request_map.insert("method".to_string(), Value::String("resources/read".to_string()));
                 let mut params = serde_json::Map::new();
                 params.insert("uri".to_string(), Value::String(uri));
+                if let Some(offset) = offset {
+                    params.insert("offset".to_string(), Value::from(offset));
+                }
+                if let Some(length) = length {
+                    params.insert("length".to_string(), Value::from(length));
+                }
                 request_map.insert("params".to_string(), Value::Object(params));
             },
             RequestMessage::ResourceSubscribe { uri } => {
@@ -118,80 +372,587 @@ impl McpClient {
             RequestMessage::Ping {} => {
                 request_map.insert("method".to_string(), Value::String("ping".to_string()));
             },
+            RequestMessage::Initialize { protocol_version, capabilities, client_info } => {
+                request_map.insert("method".to_string(), Value::String("initialize".to_string()));
+                let mut params = serde_json::Map::new();
+                params.insert("protocolVersion".to_string(), Value::String(protocol_version));
+                params.insert("capabilities".to_string(), capabilities);
+                params.insert("clientInfo".to_string(), client_info);
+                request_map.insert("params".to_string(), Value::Object(params));
+            },
         }
         
         let json_request = serde_json::Value::Object(request_map);
-        let request_str = serde_json::to_string(&json_request)?;
+        let request_str = json_request.to_string();
+        tracing::trace!(request = %logging::redact_json(&json_request), "MCP request");
+        
+        self.write_message(&request_str).await?;
+        
+        // 等待响应
+        match tokio::time::timeout(timeout, response_rx.recv()).await {
+            Ok(Some(response)) => Ok(response),
+            Ok(None) => Err(LocalPilotError::ServerExited),
+            Err(_) => Err(LocalPilotError::Timeout(timeout)),
+        }
+    }
+
+    /// 发送通知：不注册响应通道，也不等待回复
+    pub async fn send_notification(&self, notification: NotificationMessage) -> Result<(), LocalPilotError> {
+        let message = notification.to_json_rpc();
+        tracing::debug!(method = %notification.method, "MCP notification");
+        tracing::trace!(notification = %logging::redact_json(&message), "MCP notification");
+        self.write_message(&message.to_string()).await
+    }
+
+    /// 把一条消息按配置的分帧方式写入服务器的stdin
+    async fn write_message(&self, message: &str) -> Result<(), LocalPilotError> {
+        let mut stdin = self.stdin_tx.lock().await;
+        let writer = stdin.as_mut().ok_or(LocalPilotError::ServerExited)?;
+        writer.write_all(&framing::encode(self.options.framing, message)).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// 与服务器进行初始化握手，记录服务器确认的协议版本
+    pub async fn initialize(&mut self) -> Result<String, LocalPilotError> {
+        self.ensure_running().await?;
+        self.handshake().await
+    }
+
+    /// 发送 `initialize` 请求和 `initialized` 通知
+    async fn handshake(&self) -> Result<String, LocalPilotError> {
+        let request = RequestMessage::Initialize {
+            protocol_version: MCP_PROTOCOL_VERSION.to_string(),
+            capabilities: serde_json::json!({}),
+            client_info: serde_json::json!({
+                "name": "local-pilot",
+                "version": env!("CARGO_PKG_VERSION")
+            }),
+        };
+        let response = self.request(request, None).await?;
         
-        // 发送到stdin
+        let result = response.result.ok_or_else(|| missing_result(response.error))?;
+        let version = result
+            .get("protocolVersion")
+            .and_then(Value::as_str)
+            .ok_or_else(|| LocalPilotError::Protocol("initialize result has no protocolVersion".to_string()))?
+            .to_string();
+        *self.protocol_version.write_or_recover() = Some(version.clone());
+        // 握手完成后告知服务器可以开始正常通信
+        self.send_notification(NotificationMessage::initialized()).await?;
+        Ok(version)
+    }
+
+    /// 服务器进程已退出且配置了自动重启时，按退避时间等待后重新启动，并恢复握手和资源订阅
+    /// 重启次数达到上限后不再重启，请求以 `ServerExited` 失败
+    async fn ensure_running(&self) -> Result<(), LocalPilotError> {
+        let Some(policy) = &self.options.restart else {
+            return Ok(());
+        };
+        let needs_restart = || self.exited.load(Ordering::SeqCst) && !self.closed.load(Ordering::SeqCst);
+        if !needs_restart() {
+            return Ok(());
+        }
+        let _guard = self.restart_lock.lock().await;
+        // 等待锁的期间其他请求可能已经完成了重启
+        if !needs_restart() {
+            return Ok(());
+        }
+        let restarts = self.restarts.load(Ordering::SeqCst);
+        if restarts >= policy.max_restarts {
+            return Err(LocalPilotError::ServerExited);
+        }
+        self.restarts.store(restarts + 1, Ordering::SeqCst);
+        tokio::time::sleep(policy.backoff(restarts)).await;
+        tracing::warn!(attempt = restarts + 1, max_restarts = policy.max_restarts, "MCP server exited, restarting it");
+        
+        // stdout已关闭，但进程本身可能还没有结束
+        if let Some(mut child) = self.child_process.lock().await.take() {
+            let _ = child.kill().await;
+        }
+        // 旧进程的读取任务退出前会清空等待中的通道并标记退出，必须在新进程握手之前结束它，
+        // 否则会丢掉新请求的通道；这里代替它让旧进程上等待的请求失败
+        let old_tasks: Vec<JoinHandle<()>> = self.reader_tasks.lock_or_recover().drain(..).collect();
+        for task in old_tasks {
+            task.abort();
+            let _ = task.await;
+        }
+        self.response_channels.lock().await.clear();
+        // 新进程的读取任务可能在这之后立即发现它退出
+        self.exited.store(false, Ordering::SeqCst);
+        let process = match spawn_server(
+            &self.command,
+            &self.options,
+            &self.response_channels,
+            &self.unmatched_responses,
+            &self.exited,
+        ) {
+            Ok(process) => process,
+            Err(e) => {
+                self.exited.store(true, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
         {
-            let mut stdin = self.stdin_tx.lock().await;
-            if let Some(ref mut writer) = *stdin {
-                writer.write_all(request_str.as_bytes()).await?;
-                writer.write_all(b"\n").await?;
-                writer.flush().await?;
+            let mut child = self.child_process.lock().await;
+            // 重启期间进程可能已被 `ProcessKiller` 结束，不能留下新进程
+            if self.closed.load(Ordering::SeqCst) {
+                let mut process = process;
+                let _ = process.child.kill().await;
+                for task in process.reader_tasks {
+                    task.abort();
+                }
+                return Err(LocalPilotError::ServerExited);
             }
+            *child = Some(process.child);
         }
+        *self.stdin_tx.lock().await = Some(process.stdin);
+        self.reader_tasks.lock_or_recover().extend(process.reader_tasks);
         
-        // 等待响应
-        match tokio::time::timeout(tokio::time::Duration::from_secs(30), response_rx.recv()).await {
-            Ok(Some(response)) => Ok(response),
-            Ok(None) => Err("Channel closed unexpectedly".into()),
-            Err(_) => Err("Timeout waiting for response".into()),
+        // 只有原来握手过的服务器才需要重新握手
+        if self.protocol_version.read_or_recover().is_some() {
+            self.handshake().await?;
+        }
+        let subscriptions: Vec<String> = self.subscriptions.lock().await.iter().cloned().collect();
+        for uri in subscriptions {
+            if let Err(e) = self.request(RequestMessage::ResourceSubscribe { uri: uri.clone() }, None).await {
+                tracing::warn!(%uri, error = %e, "Failed to resubscribe after restarting the MCP server");
+            }
         }
+        Ok(())
+    }
+
+    /// 自动重启服务器的次数
+    pub fn restart_count(&self) -> u32 {
+        self.restarts.load(Ordering::SeqCst)
+    }
+
+    /// 关闭服务器：先关闭其stdin让它自行退出（MCP stdio传输的关闭方式），
+    /// 超过 `SHUTDOWN_GRACE_PERIOD` 仍未退出则强制结束进程，然后停止读取任务。
+    /// 等待中的请求会以 `ServerExited` 失败。重复调用是安全的
+    pub async fn shutdown(&mut self) -> Result<(), LocalPilotError> {
+        self.closed.store(true, Ordering::SeqCst);
+        
+        // 取消订阅，避免服务器端残留订阅状态；服务器无响应时不影响关闭
+        let subscriptions: Vec<String> = self.subscriptions.lock().await.drain().collect();
+        for uri in subscriptions {
+            let request = RequestMessage::ResourceUnsubscribe { uri: uri.clone() };
+            if let Err(e) = self.send_request(request, Some(SHUTDOWN_GRACE_PERIOD)).await {
+                tracing::warn!(%uri, error = %e, "Failed to unsubscribe while shutting down the MCP server");
+            }
+        }
+        
+        self.stdin_tx.lock().await.take();
+        
+        let child = self.child_process.lock().await.take();
+        if let Some(mut child) = child {
+            match tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, child.wait()).await {
+                Ok(status) => {
+                    status?;
+                }
+                Err(_) => {
+                    tracing::warn!(grace_period = ?SHUTDOWN_GRACE_PERIOD, "MCP server did not exit in time, killing it");
+                    child.kill().await?;
+                }
+            }
+        }
+        
+        for task in self.reader_tasks.lock_or_recover().drain(..) {
+            task.abort();
+        }
+        self.response_channels.lock().await.clear();
+        Ok(())
+    }
+
+    /// 可在不持有客户端的情况下结束服务器进程的句柄，自动重启后仍然有效
+    pub fn process_killer(&self) -> ProcessKiller {
+        ProcessKiller {
+            child_process: Arc::clone(&self.child_process),
+            closed: Arc::clone(&self.closed),
+        }
+    }
+
+    /// 服务器进程的pid，进程已关闭时为 `None`
+    pub async fn pid(&self) -> Option<u32> {
+        self.child_process.lock().await.as_ref().and_then(|child| child.id())
+    }
+
+    /// 收到但没有对应等待中请求（如已超时）而被丢弃的响应数
+    pub fn unmatched_response_count(&self) -> u64 {
+        self.unmatched_responses.load(Ordering::Relaxed)
+    }
+
+    /// 握手后服务器确认的协议版本，尚未握手时为 `None`
+    pub fn protocol_version(&self) -> Option<String> {
+        self.protocol_version.read_or_recover().clone()
     }
 
     /// 获取可用工具列表
-    pub async fn list_tools(&self) -> Result<Vec<Tool>, Box<dyn std::error::Error>> {
+    pub async fn list_tools(&self) -> Result<Vec<Tool>, LocalPilotError> {
         let request = RequestMessage::ToolsList {};
-        let response = self.send_request(request).await?;
+        let response = self.send_request(request, None).await?;
 
         if let Some(result) = response.result {
             let tools: Vec<Tool> = serde_json::from_value(result)?;
             Ok(tools)
         } else {
-            Err("No result in response".into())
+            Err(missing_result(response.error))
         }
     }
 
-    /// 调用指定工具
+    /// 调用指定工具，`timeout` 为 `None` 时使用 `TOOL_CALL_TIMEOUT`
     pub async fn call_tool(
         &self,
         name: String,
         arguments: Option<HashMap<String, Value>>,
-    ) -> Result<Value, Box<dyn std::error::Error>> {
+        timeout: Option<Duration>,
+    ) -> Result<Value, LocalPilotError> {
         let request = RequestMessage::ToolCall { name, arguments };
-        let response = self.send_request(request).await?;
+        let response = self.send_request(request, timeout).await?;
 
         if let Some(result) = response.result {
             Ok(result)
         } else {
-            Err("No result in response".into())
+            Err(missing_result(response.error))
         }
     }
 
     /// 获取可用资源列表
-    pub async fn list_resources(&self) -> Result<Vec<Resource>, Box<dyn std::error::Error>> {
+    pub async fn list_resources(&self) -> Result<Vec<Resource>, LocalPilotError> {
         let request = RequestMessage::ResourcesList {};
-        let response = self.send_request(request).await?;
+        let response = self.send_request(request, None).await?;
 
         if let Some(result) = response.result {
             let resources: Vec<Resource> = serde_json::from_value(result)?;
             Ok(resources)
         } else {
-            Err("No result in response".into())
+            Err(missing_result(response.error))
         }
     }
 
-    /// 读取指定资源内容
-    pub async fn read_resource(&self, uri: String) -> Result<Value, Box<dyn std::error::Error>> {
-        let request = RequestMessage::ResourceRead { uri };
-        let response = self.send_request(request).await?;
+    /// 读取指定资源内容，文件资源可用 `offset` 和 `length` 只读取其中一段
+    pub async fn read_resource(
+        &self,
+        uri: String,
+        offset: Option<u64>,
+        length: Option<u64>,
+    ) -> Result<Value, LocalPilotError> {
+        let request = RequestMessage::ResourceRead { uri, offset, length };
+        let response = self.send_request(request, None).await?;
 
         if let Some(result) = response.result {
             Ok(result)
         } else {
-            Err("No result in response".into())
+            Err(missing_result(response.error))
+        }
+    }
+
+    /// 订阅资源变化，服务器会在资源更新时发送 `notifications/resources/updated`
+    pub async fn subscribe_resource(&self, uri: String) -> Result<(), LocalPilotError> {
+        let request = RequestMessage::ResourceSubscribe { uri: uri.clone() };
+        let response = self.send_request(request, None).await?;
+
+        if response.result.is_some() {
+            self.subscriptions.lock().await.insert(uri);
+            Ok(())
+        } else {
+            Err(missing_result(response.error))
+        }
+    }
+
+    /// 取消订阅资源
+    pub async fn unsubscribe_resource(&self, uri: String) -> Result<(), LocalPilotError> {
+        self.subscriptions.lock().await.remove(&uri);
+        let request = RequestMessage::ResourceUnsubscribe { uri };
+        let response = self.send_request(request, None).await?;
+
+        if response.result.is_some() {
+            Ok(())
+        } else {
+            Err(missing_result(response.error))
         }
     }
-}
\ No newline at end of file
+
+    /// 当前已订阅的资源URI
+    pub async fn active_subscriptions(&self) -> Vec<String> {
+        let mut subscriptions: Vec<String> = self.subscriptions.lock().await.iter().cloned().collect();
+        subscriptions.sort();
+        subscriptions
+    }
+}
+
+impl Drop for McpClient {
+    fn drop(&mut self) {
+        // 还有其他克隆的句柄时读取任务继续运行
+        if Arc::strong_count(&self.reader_tasks) > 1 {
+            return;
+        }
+        // 子进程由 `kill_on_drop` 负责结束
+        for task in self.reader_tasks.lock_or_recover().iter() {
+            task.abort();
+        }
+    }
+}
+
+/// 响应中没有结果时，优先使用服务器返回的错误信息
+fn missing_result(error: Option<crate::mcp::protocol::ResponseError>) -> LocalPilotError {
+    match error {
+        Some(error) => LocalPilotError::Protocol(format!("{} (code {})", error.message, error.code)),
+        None => LocalPilotError::Protocol("No result in response".to_string()),
+    }
+}
+
+/// 测试用：以shell脚本实现的模拟MCP服务器
+#[cfg(all(test, unix))]
+pub mod mock_server {
+    /// 用 `sh -c` 运行的模拟服务器脚本，脚本之后的参数可以在其中用 `$1`、`$2` 引用
+    /// 每条请求行依次与 `responses` 中的 `case` 模式比较，执行第一个匹配项的命令；命令通常给 `result`
+    /// 赋值，也可以 `continue` 不回复或直接退出。`$line` 是请求本身，`$id` 是请求id，
+    /// 没有匹配项时把请求原样作为结果返回。设置了 `REQUEST_LOG` 环境变量时把收到的每一行追加到该文件
+    pub fn mock_server_script(responses: &[(&str, &str)]) -> String {
+        let arms: String = responses
+            .iter()
+            .map(|(pattern, command)| format!("        {}) {} ;;\n", pattern, command))
+            .collect();
+        format!(
+            r#"while IFS= read -r line; do
+    if [ -n "$REQUEST_LOG" ]; then printf '%s\n' "$line" >> "$REQUEST_LOG"; fi
+    id=$(printf '%s' "$line" | sed -E 's/.*"id":"([^"]*)".*/\1/')
+    case "$line" in
+{}        *) result=$line ;;
+    esac
+    printf '{{"jsonrpc":"2.0","id":"%s","result":%s}}\n' "$id" "$result"
+done"#,
+            arms
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use super::mock_server::mock_server_script;
+    use crate::mcp::protocol::RequestId;
+    use std::path::Path;
+
+    #[test]
+    fn test_default_timeouts_per_method() {
+        assert_eq!(default_timeout(&RequestMessage::Ping {}), PING_TIMEOUT);
+        assert_eq!(
+            default_timeout(&RequestMessage::ToolCall { name: "ls".to_string(), arguments: None }),
+            TOOL_CALL_TIMEOUT
+        );
+        assert_eq!(default_timeout(&RequestMessage::ToolsList {}), DEFAULT_REQUEST_TIMEOUT);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_request_reaches_server_and_response_comes_back() {
+        let script = mock_server_script(&[]);
+        let client = McpClient::new(vec!["sh", "-c", &script]).await.unwrap();
+        
+        for _ in 0..2 {
+            let result = client
+                .call_tool("echo".to_string(), Some(HashMap::from([("text".to_string(), Value::from("hi"))])), None)
+                .await
+                .unwrap();
+            assert_eq!(result["method"], "tools/call");
+            assert_eq!(result["params"]["name"], "echo");
+            assert_eq!(result["params"]["arguments"]["text"], "hi");
+        }
+        assert!(client.response_channels.lock().await.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_server_runs_in_configured_directory_with_env() {
+        let script = mock_server_script(&[("*", r#"result="{\"cwd\":\"$(pwd -P)\",\"root\":\"$ALLOWED_ROOT\"}""#)]);
+        let dir = std::fs::canonicalize(std::env::temp_dir()).unwrap();
+        let options = LaunchOptions {
+            working_dir: Some(dir.clone()),
+            env: HashMap::from([("ALLOWED_ROOT".to_string(), "/srv/notes".to_string())]),
+            ..Default::default()
+        };
+        let client = McpClient::new_with_options(vec!["sh", "-c", &script], options).await.unwrap();
+        
+        let result = client.send_request(RequestMessage::Ping {}, None).await.unwrap().result.unwrap();
+        assert_eq!(result["cwd"], dir.to_str().unwrap());
+        assert_eq!(result["root"], "/srv/notes");
+        
+        let options = LaunchOptions { working_dir: Some(dir.join("missing_dir")), ..Default::default() };
+        let error = McpClient::new_with_options(vec!["sh"], options).await.err().unwrap();
+        assert!(matches!(error, LocalPilotError::InvalidInput(_)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_requests_get_their_own_responses() {
+        let script = mock_server_script(&[]);
+        let client = Arc::new(McpClient::new(vec!["sh", "-c", &script]).await.unwrap());
+        
+        let tasks: Vec<_> = (0..50)
+            .map(|_| {
+                let client = Arc::clone(&client);
+                tokio::spawn(async move { client.send_request(RequestMessage::Ping {}, None).await })
+            })
+            .collect();
+        for task in tasks {
+            let response = task.await.unwrap().unwrap();
+            // 回显的请求id与响应id一致，说明响应交给了发出该请求的任务
+            assert_eq!(response.result.unwrap()["id"].as_str(), Some(response.id.unwrap().to_string().as_str()));
+        }
+        assert!(client.response_channels.lock().await.is_empty());
+        assert_eq!(client.unmatched_response_count(), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unmatched_responses_are_counted() {
+        let script = mock_server_script(&[("*", "id=unknown; result={}")]);
+        let client = McpClient::new(vec!["sh", "-c", &script]).await.unwrap();
+        
+        let error = client
+            .send_request(RequestMessage::Ping {}, Some(Duration::from_millis(500)))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, LocalPilotError::Timeout(_)));
+        assert_eq!(client.unmatched_response_count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_notification_is_written_without_a_pending_channel() {
+        // cat把收到的通知原样写回；通知没有id，不会被当作任何请求的响应
+        let client = McpClient::new(vec!["cat"]).await.unwrap();
+        
+        client
+            .send_notification(NotificationMessage::cancelled(RequestId::String("abc".to_string()), "user cancelled"))
+            .await
+            .unwrap();
+        assert!(client.response_channels.lock().await.is_empty());
+        
+        // 后续请求仍能正常匹配到自己的响应
+        let response = client.send_request(RequestMessage::Ping {}, None).await.unwrap();
+        assert!(response.id.is_some());
+        assert!(client.response_channels.lock().await.is_empty());
+        assert_eq!(client.unmatched_response_count(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_shutdown_stops_the_server_process() {
+        // cat在stdin关闭后自行退出；sleep不读取stdin，只能被强制结束
+        for (command, graceful) in [(vec!["cat"], true), (vec!["sleep", "30"], false)] {
+            let mut client = McpClient::new(command).await.unwrap();
+            let pid = client.pid().await.unwrap();
+            assert!(Path::new(&format!("/proc/{}", pid)).exists());
+            
+            let started = std::time::Instant::now();
+            client.shutdown().await.unwrap();
+            assert_eq!(started.elapsed() < SHUTDOWN_GRACE_PERIOD, graceful);
+            assert!(!Path::new(&format!("/proc/{}", pid)).exists());
+            assert!(client.pid().await.is_none());
+            assert!(client.reader_tasks.lock_or_recover().is_empty());
+            
+            // 关闭后的请求立即失败
+            let error = client.send_request(RequestMessage::Ping {}, None).await.unwrap_err();
+            assert!(matches!(error, LocalPilotError::ServerExited));
+            client.shutdown().await.unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shutdown_unsubscribes_active_subscriptions() {
+        // 记录收到的每条请求后回显
+        let script = mock_server_script(&[]);
+        let log = std::env::temp_dir().join(format!("test_unsubscribe_{}.log", Uuid::new_v4()));
+        let options = LaunchOptions {
+            env: HashMap::from([("REQUEST_LOG".to_string(), log.to_string_lossy().into_owned())]),
+            ..Default::default()
+        };
+        let mut client = McpClient::new_with_options(vec!["sh", "-c", &script], options).await.unwrap();
+
+        client.subscribe_resource("local://workspace/a.txt".to_string()).await.unwrap();
+        client.subscribe_resource("local://workspace/b.txt".to_string()).await.unwrap();
+        client.unsubscribe_resource("local://workspace/b.txt".to_string()).await.unwrap();
+        assert_eq!(client.active_subscriptions().await, vec!["local://workspace/a.txt"]);
+
+        client.shutdown().await.unwrap();
+        assert!(client.active_subscriptions().await.is_empty());
+
+        let requests: Vec<Value> = std::fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let last = requests.last().unwrap();
+        assert_eq!(last["method"], "resources/unsubscribe");
+        assert_eq!(last["params"]["uri"], "local://workspace/a.txt");
+        let unsubscribes = requests.iter().filter(|request| request["method"] == "resources/unsubscribe").count();
+        assert_eq!(unsubscribes, 2);
+        std::fs::remove_file(&log).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_crashed_server_is_restarted_before_next_request() {
+        // 收到 `crash` 工具调用时不回复直接退出；其余请求回复进程id和是否已收到 `initialized` 通知
+        let script = mock_server_script(&[
+            (r#"*'"method":"notifications/initialized"'*"#, "initialized=1; continue"),
+            (r#"*'"name":"crash"'*"#, "exit 1"),
+            (r#"*'"method":"initialize"'*"#, r#"result='{"protocolVersion":"2024-11-05"}'"#),
+            ("*", r#"result="{\"pid\":$$,\"initialized\":${initialized:-0}}""#),
+        ]);
+        let options = LaunchOptions {
+            restart: Some(RestartPolicy {
+                max_restarts: 1,
+                initial_backoff: Duration::from_millis(50),
+                max_backoff: Duration::from_millis(50),
+            }),
+            ..Default::default()
+        };
+        let mut client = McpClient::new_with_options(vec!["sh", "-c", &script], options).await.unwrap();
+        client.initialize().await.unwrap();
+        let crash = || RequestMessage::ToolCall { name: "crash".to_string(), arguments: None };
+
+        let first = client.send_request(RequestMessage::Ping {}, None).await.unwrap().result.unwrap();
+        assert_eq!(first["initialized"], 1);
+
+        // 崩溃时等待中的请求立即失败
+        let started = std::time::Instant::now();
+        let error = client.send_request(crash(), None).await.unwrap_err();
+        assert!(matches!(error, LocalPilotError::ServerExited));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // 下一个请求前服务器被重启并重新握手
+        let second = client.send_request(RequestMessage::Ping {}, None).await.unwrap().result.unwrap();
+        assert_ne!(second["pid"], first["pid"]);
+        assert_eq!(second["initialized"], 1);
+        assert_eq!(client.restart_count(), 1);
+        assert_eq!(client.protocol_version().as_deref(), Some("2024-11-05"));
+
+        // 达到重启上限后不再重启
+        client.send_request(crash(), None).await.unwrap_err();
+        let error = client.send_request(RequestMessage::Ping {}, None).await.unwrap_err();
+        assert!(matches!(error, LocalPilotError::ServerExited));
+        assert_eq!(client.restart_count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_short_timeout_expires_promptly() {
+        // 不读取输入也不输出任何内容的服务器
+        let client = McpClient::new(vec!["sleep", "30"]).await.unwrap();
+        
+        let started = std::time::Instant::now();
+        let error = client
+            .send_request(RequestMessage::Ping {}, Some(Duration::from_millis(100)))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, LocalPilotError::Timeout(_)));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(client.response_channels.lock().await.is_empty());
+    }
+}
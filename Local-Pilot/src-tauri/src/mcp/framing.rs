@@ -0,0 +1,225 @@
+//! MCP消息分帧
+//! 支持两种分帧方式：每行一条JSON消息，或LSP风格的 `Content-Length` 头加消息体。
+//! 读取时自动识别每条消息使用的方式，发送时使用配置的方式或与对方相同的方式
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// 单条消息的最大字节数，超过时视为协议错误
+pub const MAX_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+
+/// 发送消息时使用的分帧方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Framing {
+    /// 每条消息一行，以 `\n` 结尾
+    #[default]
+    Newline,
+    /// `Content-Length: N\r\n\r\n` 后跟N字节的消息体
+    ContentLength,
+}
+
+/// 按指定方式为一条JSON消息分帧
+pub fn encode(framing: Framing, json: &str) -> Vec<u8> {
+    match framing {
+        Framing::Newline => format!("{}\n", json).into_bytes(),
+        Framing::ContentLength => format!("Content-Length: {}\r\n\r\n{}", json.len(), json).into_bytes(),
+    }
+}
+
+/// 读取下一条消息，返回其JSON文本；流结束时返回 `None`
+/// 以 `Content-Length` 头开头的消息按长度读取消息体，其他消息逐行读取直到构成完整的JSON，
+/// 因此跨多行的JSON也能正确读取。无法解析为JSON的输出（如服务器打印的日志）和超长的行会被跳过
+pub async fn read_message<R>(reader: &mut R) -> io::Result<Option<String>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    Ok(read_framed_message(reader).await?.map(|(message, _)| message))
+}
+
+/// 与 `read_message` 相同，同时返回这条消息使用的分帧方式，以便按相同方式回复
+pub async fn read_framed_message<R>(reader: &mut R) -> io::Result<Option<(String, Framing)>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut pending = String::new();
+    let mut buffer = Vec::new();
+    loop {
+        buffer.clear();
+        let limit = (MAX_MESSAGE_BYTES - pending.len()) as u64;
+        if (&mut *reader).take(limit).read_until(b'\n', &mut buffer).await? == 0 {
+            return Ok(None);
+        }
+        if !buffer.ends_with(b"\n") && buffer.len() as u64 == limit {
+            tracing::warn!(bytes = pending.len() + buffer.len(), "Ignoring oversized MCP input");
+            pending.clear();
+            skip_line(reader).await?;
+            continue;
+        }
+        let line = String::from_utf8_lossy(&buffer);
+
+        if pending.is_empty() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+            if let Some(length) = content_length(trimmed) {
+                let body = read_framed_body(reader, length?).await?;
+                return Ok(Some((body, Framing::ContentLength)));
+            }
+        }
+
+        pending.push_str(&line);
+        let mut parsed = serde_json::from_str::<serde::de::IgnoredAny>(&pending);
+        if matches!(&parsed, Err(e) if !e.is_eof()) && pending.len() > line.len() {
+            // 之前残留的不完整输出（如单独一行 `{`）不能吞掉随后的消息，从当前行重新开始
+            let stale = pending.len() - line.len();
+            tracing::warn!(input = pending[..stale].trim(), "Ignoring incomplete MCP input");
+            pending.drain(..stale);
+            parsed = serde_json::from_str(&pending);
+        }
+        match parsed {
+            Ok(_) => return Ok(Some((pending.trim().to_string(), Framing::Newline))),
+            Err(e) if e.is_eof() && pending.len() < MAX_MESSAGE_BYTES => continue,
+            Err(_) => {
+                tracing::warn!(input = pending.trim(), "Ignoring non-JSON MCP input");
+                pending.clear();
+            }
+        }
+    }
+}
+
+/// 丢弃输入直到下一个换行符或流结束
+async fn skip_line<R>(reader: &mut R) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+{
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(());
+        }
+        match available.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let skipped = available.len();
+                reader.consume(skipped);
+            }
+        }
+    }
+}
+
+/// 解析 `Content-Length` 头，不是该头时返回 `None`
+fn content_length(header: &str) -> Option<io::Result<usize>> {
+    let (name, value) = header.split_once(':')?;
+    if !name.trim().eq_ignore_ascii_case("content-length") {
+        return None;
+    }
+    Some(value.trim().parse::<usize>().map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("Invalid Content-Length '{}': {}", value.trim(), e))
+    }))
+}
+
+/// 跳过其余的头部（如 `Content-Type`），然后读取恰好 `length` 字节的消息体
+async fn read_framed_body<R>(reader: &mut R, length: usize) -> io::Result<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    if length > MAX_MESSAGE_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("MCP message of {} bytes exceeds the {} byte limit", length, MAX_MESSAGE_BYTES),
+        ));
+    }
+
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    String::from_utf8(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use tokio::io::BufReader;
+
+    /// 使用很小的缓冲区读取，使消息跨越多次读取
+    async fn read_all(input: &[u8]) -> Vec<Value> {
+        let mut reader = BufReader::with_capacity(3, input);
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut reader).await.unwrap() {
+            messages.push(serde_json::from_str(&message).unwrap());
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_newline_framing_with_multi_line_json() {
+        let pretty = serde_json::to_string_pretty(&json!({"id": "2", "result": {"lines": ["a", "b"]}})).unwrap();
+        let input = format!("{{\"id\":\"1\",\"result\":{{}}}}\nserver log line\n\n{}\n", pretty);
+
+        let messages = read_all(input.as_bytes()).await;
+        assert_eq!(messages, [json!({"id": "1", "result": {}}), json!({"id": "2", "result": {"lines": ["a", "b"]}})]);
+    }
+
+    #[tokio::test]
+    async fn test_content_length_framing_with_multi_line_json() {
+        let first = serde_json::to_string_pretty(&json!({"id": "1", "result": {"text": "line\nbreak"}})).unwrap();
+        let second = r#"{"id":"2","result":"ü"}"#;
+        let mut input = encode(Framing::ContentLength, &first);
+        input.extend(format!("Content-Length: {}\r\nContent-Type: application/json\r\n\r\n{}", second.len(), second).bytes());
+        // 两种分帧方式可以混用
+        input.extend(encode(Framing::Newline, r#"{"id":"3","result":null}"#));
+
+        let messages = read_all(&input).await;
+        assert_eq!(
+            messages,
+            [
+                json!({"id": "1", "result": {"text": "line\nbreak"}}),
+                json!({"id": "2", "result": "ü"}),
+                json!({"id": "3", "result": null}),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stray_brace_does_not_swallow_following_messages() {
+        let input = "{\n{\"id\":\"1\",\"result\":{}}\nlog {\n{\n  \"id\": \"2\",\n  \"result\": null\n}\n";
+
+        let messages = read_all(input.as_bytes()).await;
+        assert_eq!(messages, [json!({"id": "1", "result": {}}), json!({"id": "2", "result": null})]);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_line_is_skipped() {
+        let mut input = vec![b'{'; MAX_MESSAGE_BYTES + 10];
+        input.extend(b"\n{\"id\":\"1\",\"result\":{}}\n");
+        let mut reader = BufReader::new(&input[..]);
+
+        let message = read_message(&mut reader).await.unwrap().unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&message).unwrap(), json!({"id": "1", "result": {}}));
+        assert!(read_message(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_truncated_content_length_body_is_an_error() {
+        let mut reader = BufReader::new(&b"Content-Length: 100\r\n\r\n{\"id\":"[..]);
+        let error = read_message(&mut reader).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
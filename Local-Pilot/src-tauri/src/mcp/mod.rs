@@ -3,17 +3,125 @@
 
 pub mod protocol;
 pub mod client;
+pub mod framing;
 pub mod server;
+pub mod subscriptions;
+pub mod transport;
+pub mod web_search;
 
 pub use client::McpClient;
 
+use crate::error::LocalPilotError;
+use protocol::{Resource, Tool};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// 默认MCP服务器名称
+pub const DEFAULT_SERVER_NAME: &str = "filesystem";
+
+/// 默认文件系统服务器的npm包
+pub const FILESYSTEM_SERVER_PACKAGE: &str = "@modelcontextprotocol/server-filesystem";
+
+/// 文件系统服务器的启动参数，允许访问的根目录作为最后一个参数
+pub fn filesystem_server_args(root: &Path) -> Vec<String> {
+    vec![FILESYSTEM_SERVER_PACKAGE.to_string(), root.to_string_lossy().to_string()]
+}
+
+/// 文件系统服务器的根目录：优先使用指定的目录，其次是第一个索引根目录，最后是用户主目录
+/// 目录必须存在，否则服务器启动后才会报错
+pub fn filesystem_server_root(requested: Option<&Path>, index_roots: &[PathBuf]) -> Result<PathBuf, LocalPilotError> {
+    let root = match requested {
+        Some(root) => root.to_path_buf(),
+        None => index_roots
+            .first()
+            .cloned()
+            .or_else(|| directories::UserDirs::new().map(|dirs| dirs.home_dir().to_path_buf()))
+            .ok_or_else(|| LocalPilotError::InvalidInput("No directory to serve with the filesystem server".to_string()))?,
+    };
+    if !root.is_dir() {
+        return Err(LocalPilotError::InvalidInput(format!(
+            "Filesystem server root is not a directory: {}",
+            root.display()
+        )));
+    }
+    Ok(root)
+}
+
+/// 服务器名称与工具名称之间的分隔符
+/// 使用`__`而不是`/`，因为LLM API要求工具名称只包含字母、数字、`_`和`-`
+pub const TOOL_NAME_SEPARATOR: &str = "__";
+
+/// 生成带服务器前缀的工具名称
+pub fn qualify_tool_name(server: &str, tool: &str) -> String {
+    format!("{}{}{}", server, TOOL_NAME_SEPARATOR, tool)
+}
+
+/// 拆分带服务器前缀的工具名称，返回 (服务器名称, 工具名称)
+pub fn split_tool_name(qualified: &str) -> Option<(&str, &str)> {
+    qualified
+        .split_once(TOOL_NAME_SEPARATOR)
+        .filter(|(server, tool)| !server.is_empty() && !tool.is_empty())
+}
+
+/// 汇总多个服务器的工具列表，每个工具名称都带上服务器前缀
+pub async fn list_tools_across(clients: &HashMap<String, Arc<Mutex<McpClient>>>) -> Vec<Tool> {
+    let mut tools = Vec::new();
+    
+    for (server, client) in clients {
+        let client = client.lock().await;
+        match client.list_tools().await {
+            Ok(server_tools) => {
+                for mut tool in server_tools {
+                    tool.name = qualify_tool_name(server, &tool.name);
+                    tools.push(tool);
+                }
+            }
+            Err(e) => tracing::warn!(%server, error = %e, "Failed to list tools from MCP server"),
+        }
+    }
+    
+    tools.sort_by(|a, b| a.name.cmp(&b.name));
+    tools
+}
+
+/// 汇总多个服务器的资源列表，按URI排序；资源URI本身不带服务器前缀
+pub async fn list_resources_across(clients: &HashMap<String, Arc<Mutex<McpClient>>>) -> Vec<Resource> {
+    let mut resources = Vec::new();
+    
+    for (server, client) in clients {
+        let client = client.lock().await;
+        match client.list_resources().await {
+            Ok(server_resources) => resources.extend(server_resources),
+            Err(e) => tracing::warn!(%server, error = %e, "Failed to list resources from MCP server"),
+        }
+    }
+    
+    resources.sort_by(|a, b| a.uri.cmp(&b.uri));
+    resources
+}
+
+/// `uri` 是否为该资源本身或其下的子资源，如 `local://workspace/notes.txt` 属于 `local://workspace`
+pub fn resource_contains(resource: &Resource, uri: &str) -> bool {
+    uri.strip_prefix(resource.uri.as_str())
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || resource.uri.ends_with('/'))
+}
+
 /// 初始化MCP功能
 pub fn init_mcp() {
     println!("Initializing MCP (Model Context Protocol)...");
 }
 
 /// 工具安全检查 - 判断是否需要审批
-pub fn requires_approval(tool_name: &str) -> bool {
+/// 优先使用工具定义中服务器声明的 `requires_approval`，未声明或找不到定义时按名称中的关键词判断
+pub fn requires_approval(tool_name: &str, tool: Option<&Tool>) -> bool {
+    if let Some(required) = tool.and_then(|tool| tool.requires_approval) {
+        return required;
+    }
+    
+    // 只根据工具本身的名称判断，忽略服务器前缀
+    let tool_name = split_tool_name(tool_name).map(|(_, tool)| tool).unwrap_or(tool_name);
     let unsafe_keywords = ["write", "delete", "move", "rm", "remove", "mv", "rename", "modify"];
     
     for keyword in &unsafe_keywords {
@@ -22,4 +130,79 @@ pub fn requires_approval(tool_name: &str) -> bool {
         }
     }
     false
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qualified_tool_name_round_trip() {
+        let qualified = qualify_tool_name("git", "git_status");
+        assert_eq!(qualified, "git__git_status");
+        assert_eq!(split_tool_name(&qualified), Some(("git", "git_status")));
+        assert_eq!(split_tool_name("file_reader"), None);
+    }
+
+    #[test]
+    fn test_resource_contains_sub_resources() {
+        let resource = Resource {
+            uri: "local://workspace".to_string(),
+            name: "Workspace".to_string(),
+            description: String::new(),
+        };
+        assert!(resource_contains(&resource, "local://workspace"));
+        assert!(resource_contains(&resource, "local://workspace/notes/todo.txt"));
+        assert!(!resource_contains(&resource, "local://workspace2/todo.txt"));
+        assert!(!resource_contains(&resource, "local://documents"));
+    }
+
+    #[test]
+    fn test_filesystem_server_command_ends_with_root() {
+        let dir = std::env::temp_dir();
+        let root = filesystem_server_root(Some(&dir), &[]).unwrap();
+        let args = filesystem_server_args(&root);
+        assert_eq!(args, [FILESYSTEM_SERVER_PACKAGE.to_string(), dir.to_string_lossy().to_string()]);
+        
+        // 未指定时使用第一个索引根目录
+        let index_roots = [dir.clone(), PathBuf::from("/somewhere/else")];
+        assert_eq!(filesystem_server_root(None, &index_roots).unwrap(), dir);
+        
+        let missing = dir.join(format!("missing_root_{}", uuid::Uuid::new_v4()));
+        assert!(filesystem_server_root(Some(&missing), &[]).is_err());
+    }
+
+    #[test]
+    fn test_requires_approval_ignores_server_prefix() {
+        assert!(requires_approval("filesystem__write_file", None));
+        assert!(!requires_approval("mover__read_file", None));
+    }
+
+    #[test]
+    fn test_declared_approval_hint_overrides_name_heuristic() {
+        let tool = |name: &str, requires_approval: Option<bool>| Tool {
+            name: name.to_string(),
+            description: String::new(),
+            input_schema: serde_json::json!({ "type": "object" }),
+            requires_approval,
+        };
+        
+        // 名称像写操作，但服务器声明为安全
+        assert!(!requires_approval("notes__write_draft", Some(&tool("notes__write_draft", Some(false)))));
+        // 名称看起来无害，但服务器声明需要批准
+        assert!(requires_approval("deploy__publish", Some(&tool("deploy__publish", Some(true)))));
+        // 未声明时按名称判断
+        assert!(requires_approval("fs__delete_file", Some(&tool("fs__delete_file", None))));
+        assert!(!requires_approval("fs__read_file", Some(&tool("fs__read_file", None))));
+        
+        // 服务器返回的工具定义中没有该字段时为None，序列化时也省略
+        let parsed: Tool = serde_json::from_value(serde_json::json!({
+            "name": "read_file",
+            "description": "Read a file",
+            "input_schema": { "type": "object" }
+        }))
+        .unwrap();
+        assert_eq!(parsed.requires_approval, None);
+        assert!(serde_json::to_value(&parsed).unwrap().get("requires_approval").is_none());
+    }
+}
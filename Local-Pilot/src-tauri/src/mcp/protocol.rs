@@ -4,10 +4,24 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+/// 客户端和服务器支持的MCP协议版本
+pub const MCP_PROTOCOL_VERSION: &str = "2024-11-05";
+
 /// MCP请求消息
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "method", content = "params")]
 pub enum RequestMessage {
+    /// 初始化握手，协商协议版本
+    #[serde(rename = "initialize")]
+    Initialize {
+        #[serde(rename = "protocolVersion")]
+        protocol_version: String,
+        #[serde(default)]
+        capabilities: serde_json::Value,
+        #[serde(rename = "clientInfo", default)]
+        client_info: serde_json::Value,
+    },
+    
     /// 请求可用的工具列表
     #[serde(rename = "tools/list")]
     ToolsList {},
@@ -23,10 +37,16 @@ pub enum RequestMessage {
     #[serde(rename = "resources/list")]
     ResourcesList {},
     
-    /// 获取指定资源内容
+    /// 获取指定资源内容，文件资源可以只读取其中一段
     #[serde(rename = "resources/read")]
     ResourceRead {
         uri: String,
+        /// 起始字节位置，默认为0
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        offset: Option<u64>,
+        /// 读取的字节数，默认为 `DEFAULT_READ_LENGTH`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        length: Option<u64>,
     },
     
     /// 订阅资源变更
@@ -46,14 +66,120 @@ pub enum RequestMessage {
     Ping {},
 }
 
+impl RequestMessage {
+    /// JSON-RPC方法名
+    pub fn method(&self) -> &'static str {
+        match self {
+            RequestMessage::Initialize { .. } => "initialize",
+            RequestMessage::ToolsList {} => "tools/list",
+            RequestMessage::ToolCall { .. } => "tools/call",
+            RequestMessage::ResourcesList {} => "resources/list",
+            RequestMessage::ResourceRead { .. } => "resources/read",
+            RequestMessage::ResourceSubscribe { .. } => "resources/subscribe",
+            RequestMessage::ResourceUnsubscribe { .. } => "resources/unsubscribe",
+            RequestMessage::Ping {} => "ping",
+        }
+    }
+}
+
+/// JSON-RPC请求id，可以是数字或字符串，响应中需原样返回
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(i64),
+    String(String),
+}
+
+impl From<String> for RequestId {
+    fn from(id: String) -> Self {
+        RequestId::String(id)
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestId::Number(id) => write!(f, "{}", id),
+            RequestId::String(id) => f.write_str(id),
+        }
+    }
+}
+
+/// 服务端收到的带id的请求
+/// `RequestMessage` 只描述method和params，id由外层携带，以便在响应中回填
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestEnvelope {
+    /// 没有id的消息是通知
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    #[serde(flatten)]
+    pub request: RequestMessage,
+}
+
+impl From<RequestMessage> for RequestEnvelope {
+    fn from(request: RequestMessage) -> Self {
+        Self { id: None, request }
+    }
+}
+
 /// MCP响应消息
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ResponseMessage {
-    pub id: Option<String>,
+    pub id: Option<RequestId>,
     pub result: Option<serde_json::Value>,
     pub error: Option<ResponseError>,
 }
 
+impl ResponseMessage {
+    /// 完整的JSON-RPC消息，`result` 和 `error` 只包含其一
+    pub fn to_json_rpc(&self) -> serde_json::Value {
+        let mut message = serde_json::json!({ "jsonrpc": "2.0", "id": self.id });
+        match &self.error {
+            Some(error) => message["error"] = serde_json::to_value(error).unwrap_or(serde_json::Value::Null),
+            None => message["result"] = self.result.clone().unwrap_or(serde_json::Value::Null),
+        }
+        message
+    }
+}
+
+/// 客户端完成初始化握手后发送的通知
+pub const INITIALIZED_NOTIFICATION: &str = "notifications/initialized";
+
+/// 取消之前发出的请求的通知
+pub const CANCELLED_NOTIFICATION: &str = "notifications/cancelled";
+
+/// MCP通知消息：没有id，接收方不回复。客户端和服务端都可以发送
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotificationMessage {
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<serde_json::Value>,
+}
+
+impl NotificationMessage {
+    /// `notifications/initialized`
+    pub fn initialized() -> Self {
+        Self { method: INITIALIZED_NOTIFICATION.to_string(), params: None }
+    }
+
+    /// `notifications/cancelled`，通知对方不再需要指定请求的结果
+    pub fn cancelled(request_id: RequestId, reason: impl Into<String>) -> Self {
+        Self {
+            method: CANCELLED_NOTIFICATION.to_string(),
+            params: Some(serde_json::json!({ "requestId": request_id, "reason": reason.into() })),
+        }
+    }
+
+    /// 完整的JSON-RPC消息
+    pub fn to_json_rpc(&self) -> serde_json::Value {
+        let mut message = serde_json::json!({ "jsonrpc": "2.0", "method": self.method });
+        if let Some(params) = &self.params {
+            message["params"] = params.clone();
+        }
+        message
+    }
+}
+
 /// MCP错误响应
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ResponseError {
@@ -62,12 +188,94 @@ pub struct ResponseError {
     pub data: Option<serde_json::Value>,
 }
 
+/// 收到的内容不是有效的JSON
+pub const PARSE_ERROR: i32 = -32700;
+
+/// 请求不是有效的JSON-RPC请求（如未通过认证）
+pub const INVALID_REQUEST: i32 = -32600;
+
+/// 方法不存在
+pub const METHOD_NOT_FOUND: i32 = -32601;
+
+/// 参数无效（如路径越界）
+pub const INVALID_PARAMS: i32 = -32602;
+
+/// 服务端内部错误
+pub const INTERNAL_ERROR: i32 = -32603;
+
+/// 请求的资源不存在
+pub const RESOURCE_NOT_FOUND: i32 = -32002;
+
+/// 请求的路径不在允许访问的目录内
+pub const PERMISSION_DENIED: i32 = -32003;
+
+impl ResponseError {
+    /// 创建不带附加数据的错误
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
 /// 工具定义
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Tool {
     pub name: String,
     pub description: String,
     pub input_schema: serde_json::Value,
+    /// 服务器声明的调用前是否需要用户批准；未声明时按工具名称判断，见 `mcp::requires_approval`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requires_approval: Option<bool>,
+}
+
+/// 工具结果中的一个内容块
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ContentBlock {
+    Text { text: String },
+    /// base64编码的图片
+    Image {
+        data: String,
+        #[serde(rename = "mimeType")]
+        mime_type: String,
+    },
+    /// 嵌入的资源，格式与 `resources/read` 返回的一项相同
+    Resource { resource: serde_json::Value },
+}
+
+/// `tools/call` 的结果
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolResult {
+    pub content: Vec<ContentBlock>,
+    /// 工具执行了但没有成功（如命令退出码非0）；请求本身无效时返回JSON-RPC错误而不是设置此标记
+    #[serde(rename = "isError", default)]
+    pub is_error: bool,
+}
+
+impl ToolResult {
+    /// 只含一个文本块的结果
+    pub fn text(text: impl Into<String>, is_error: bool) -> Self {
+        Self { content: vec![ContentBlock::Text { text: text.into() }], is_error }
+    }
+
+    /// 所有内容块的文本，以换行分隔；图片和没有文本的资源以占位说明代替
+    pub fn to_text(&self) -> String {
+        self.content
+            .iter()
+            .map(|block| match block {
+                ContentBlock::Text { text } => text.clone(),
+                ContentBlock::Image { mime_type, .. } => format!("[{} image]", mime_type),
+                ContentBlock::Resource { resource } => match resource["text"].as_str() {
+                    Some(text) => text.to_string(),
+                    None => format!("[resource {}]", resource["uri"].as_str().unwrap_or("without uri")),
+                },
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
 }
 
 /// 文件信息定义
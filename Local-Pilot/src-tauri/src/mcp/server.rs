@@ -1,22 +1,267 @@
 //! MCP (Model Context Protocol) 服务端实现
 //! 处理来自MCP客户端的请求
 
-use crate::mcp::protocol::{RequestMessage, ResponseMessage, ResponseError, Tool, Resource};
+use crate::error::LocalPilotError;
+use crate::file_index::{FileIndexer, DEFAULT_SEARCH_LIMIT};
+use crate::mcp::protocol::{NotificationMessage, RequestEnvelope, CANCELLED_NOTIFICATION, INITIALIZED_NOTIFICATION, RequestMessage, RequestId, ResponseMessage, ResponseError, Tool, ToolResult, ContentBlock, Resource, INTERNAL_ERROR, INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND, MCP_PROTOCOL_VERSION, PERMISSION_DENIED, RESOURCE_NOT_FOUND};
+use crate::mcp::subscriptions::{ConnectionId, SubscriptionRegistry};
+use crate::mcp::web_search::{self, SearchProvider, SEARXNG_URL_ENV, WEB_SEARCH_RESULT_LIMIT};
+use base64::Engine;
+use directories::UserDirs;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc;
+use walkdir::WalkDir;
+
+/// shell命令的默认超时时间
+pub const DEFAULT_SHELL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// shell命令标准输出和标准错误各自保留的默认字节数
+pub const DEFAULT_SHELL_OUTPUT_LIMIT: usize = 256 * 1024;
+
+/// 默认允许执行的命令（只读类命令）
+pub const DEFAULT_ALLOWED_COMMANDS: &[&str] = &[
+    "ls", "pwd", "echo", "cat", "head", "tail", "wc", "grep", "find", "date", "whoami", "uname", "which",
+];
+
+/// 允许的命令中会执行其他程序或写入、删除文件的参数，出现时整条命令被拒绝
+const DENIED_ARGUMENTS: &[(&str, &[&str])] = &[(
+    "find",
+    &["-exec", "-execdir", "-ok", "-okdir", "-delete", "-fprint", "-fprint0", "-fprintf", "-fls"],
+)];
+
+/// 默认不传给shell命令的环境变量，这些变量的值也会从命令输出中去掉
+pub const DEFAULT_ENV_DENYLIST: &[&str] = &[
+    "API_KEY", "OPENAI_API_KEY", "ANTHROPIC_API_KEY", "AWS_SECRET_ACCESS_KEY", "AWS_SESSION_TOKEN", "GITHUB_TOKEN", "GH_TOKEN",
+];
+
+/// 默认允许在shell命令中以 `$NAME` 引用的环境变量，其他变量一律不展开
+pub const DEFAULT_EXPANDABLE_ENV: &[&str] = &["HOME", "USER", "LOGNAME", "PWD", "TMPDIR", "LANG"];
+
+/// 命令输出中替换敏感值的文本
+const REDACTED: &str = "[REDACTED]";
+
+/// 本地资源URI的前缀
+pub const LOCAL_SCHEME: &str = "local://";
+
+/// 单次读取文件的最大字节数，更大的文件需分段读取
+pub const MAX_RESOURCE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// 未指定长度时读取文件的字节数
+pub const DEFAULT_READ_LENGTH: u64 = 64 * 1024;
+
+/// `list_directory` 单次返回的最大条目数，超出部分被截断
+pub const MAX_DIRECTORY_ENTRIES: usize = 1000;
+
+/// `file_tail` 未指定行数时返回的行数
+pub const DEFAULT_TAIL_LINES: u64 = 100;
+
+/// `file_tail` 从文件末尾向前读取时每次读取的字节数
+const TAIL_CHUNK_SIZE: u64 = 8 * 1024;
+
+/// 按文件名在索引中查找时最多检查的条目数
+const MAX_NAME_MATCHES: u32 = 200;
+
+/// `write_file` 的写入方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WriteMode {
+    /// 覆盖已存在的文件（默认）
+    Overwrite,
+    /// 追加到已存在的文件末尾
+    Append,
+    /// 只创建新文件，文件已存在时失败
+    CreateNew,
+}
+
+impl WriteMode {
+    fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "overwrite" => Some(Self::Overwrite),
+            "append" => Some(Self::Append),
+            "create_new" => Some(Self::CreateNew),
+            _ => None,
+        }
+    }
+}
+
+/// shell执行配置
+#[derive(Debug, Clone)]
+pub struct ShellConfig {
+    /// 允许执行的程序名，必须与命令的第一个词完全一致
+    pub allowed_commands: Vec<String>,
+    /// 超时后进程及其启动的子进程会被终止
+    pub timeout: Duration,
+    /// 标准输出和标准错误各自最多保留的字节数，超出部分丢弃
+    pub max_output_bytes: usize,
+    /// 这些环境变量不会传给命令，也不能在命令中引用，其值出现在输出中时会被替换为 `[REDACTED]`
+    pub env_denylist: Vec<String>,
+    /// 可以在命令中以 `$NAME` 或 `${NAME}` 引用的环境变量，引用其他变量时命令被拒绝
+    pub expandable_env: Vec<String>,
+}
+
+impl Default for ShellConfig {
+    fn default() -> Self {
+        Self {
+            allowed_commands: DEFAULT_ALLOWED_COMMANDS.iter().map(|c| c.to_string()).collect(),
+            timeout: DEFAULT_SHELL_TIMEOUT,
+            max_output_bytes: DEFAULT_SHELL_OUTPUT_LIMIT,
+            env_denylist: DEFAULT_ENV_DENYLIST.iter().map(|name| name.to_string()).collect(),
+            expandable_env: DEFAULT_EXPANDABLE_ENV.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+}
 
 /// MCP服务端结构体
-pub struct McpServer {}
+pub struct McpServer {
+    indexer: Arc<FileIndexer>,
+    shell_config: ShellConfig,
+    /// 资源名 -> 对应的本地目录，如 `workspace` 对应 `local://workspace`
+    resource_roots: HashMap<String, PathBuf>,
+    subscriptions: SubscriptionRegistry,
+    /// 未配置时 `web_search` 返回错误
+    search_provider: Option<Box<dyn SearchProvider>>,
+    /// `file_reader` 和资源只能访问这些目录内的文件，未配置时使用文件索引的根目录
+    allowed_roots: Option<Vec<PathBuf>>,
+}
 
 impl McpServer {
-    /// 创建新的MCP服务端
-    pub fn new() -> Self {
-        Self {}
+    /// 创建新的MCP服务端，文件搜索使用共享的文件索引器
+    pub fn new(indexer: Arc<FileIndexer>) -> Self {
+        let mut resource_roots = HashMap::new();
+        if let Ok(workspace) = std::env::current_dir() {
+            resource_roots.insert("workspace".to_string(), workspace);
+        }
+        if let Some(documents) = UserDirs::new().and_then(|dirs| dirs.document_dir().map(Path::to_path_buf)) {
+            resource_roots.insert("documents".to_string(), documents);
+        }
+        
+        Self {
+            indexer,
+            shell_config: ShellConfig::default(),
+            resource_roots,
+            subscriptions: SubscriptionRegistry::new(),
+            search_provider: web_search::provider_from_env(),
+            allowed_roots: None,
+        }
+    }
+
+    /// 将 `local://<name>` 映射到指定目录，已存在的映射会被替换
+    pub fn with_resource_root(mut self, name: &str, path: PathBuf) -> Self {
+        self.resource_roots.insert(name.to_string(), path);
+        self
+    }
+
+    /// 使用自定义的shell执行配置
+    pub fn with_shell_config(mut self, config: ShellConfig) -> Self {
+        self.shell_config = config;
+        self
+    }
+
+    /// 使用指定的网络搜索后端
+    pub fn with_search_provider(mut self, provider: impl SearchProvider + 'static) -> Self {
+        self.search_provider = Some(Box::new(provider));
+        self
+    }
+
+    /// 只允许访问指定目录内的文件，替代默认的文件索引根目录
+    pub fn with_allowed_roots(mut self, roots: Vec<PathBuf>) -> Self {
+        self.allowed_roots = Some(roots);
+        self
+    }
+
+    /// 注册新的客户端连接，返回连接标识和发往该连接的通知流
+    pub fn connect(&self) -> (ConnectionId, mpsc::UnboundedReceiver<NotificationMessage>) {
+        self.subscriptions.connect()
+    }
+
+    /// 客户端断开时调用，清除该连接的所有订阅
+    pub fn disconnect(&self, connection: ConnectionId) {
+        self.subscriptions.disconnect(connection);
     }
 
-    /// 处理MCP请求
-    pub async fn handle_request(&self, request: RequestMessage) -> ResponseMessage {
+    /// 资源当前的订阅者数量
+    pub fn subscriber_count(&self, uri: &str) -> usize {
+        self.subscriptions.subscriber_count(uri)
+    }
+
+    /// 处理来自指定连接的MCP请求，响应的id与请求的id相同
+    pub async fn handle_request(&self, connection: ConnectionId, request: impl Into<RequestEnvelope>) -> ResponseMessage {
+        let RequestEnvelope { id, request } = request.into();
+        let mut response = self.dispatch(connection, request).await;
+        response.id = id;
+        response
+    }
+
+    /// 处理来自指定连接的一条JSON-RPC消息，返回要发回的JSON-RPC消息
+    /// 消息可以是单个请求或通知，也可以是由它们组成的批量消息（数组）。批量消息中的各项按顺序处理，
+    /// 响应按相同的顺序组成数组返回；通知没有响应，只含通知的消息返回 `None`
+    pub async fn handle_message(&self, connection: ConnectionId, message: Value) -> Option<Value> {
+        match message {
+            Value::Array(messages) if messages.is_empty() => {
+                Some(error_response(ResponseError::new(INVALID_REQUEST, "Empty batch")))
+            }
+            Value::Array(messages) => {
+                let mut responses = Vec::new();
+                for message in messages {
+                    responses.extend(self.handle_single_message(connection, message).await);
+                }
+                (!responses.is_empty()).then_some(Value::Array(responses))
+            }
+            message => self.handle_single_message(connection, message).await,
+        }
+    }
+
+    async fn handle_single_message(&self, connection: ConnectionId, mut message: Value) -> Option<Value> {
+        if !message.is_object() {
+            return Some(error_response(ResponseError::new(INVALID_REQUEST, "Message must be a JSON object")));
+        }
+        
+        // 没有id的消息是通知，处理后不回复
+        if message.get("id").is_none() {
+            match serde_json::from_value::<NotificationMessage>(message) {
+                Ok(notification) => self.handle_notification(connection, notification).await,
+                Err(e) => tracing::warn!(connection, error = %e, "Ignoring malformed MCP notification"),
+            }
+            return None;
+        }
+        
+        // 没有参数的请求也需要空的params才能解析
+        let id: Option<RequestId> = message.get("id").cloned().and_then(|id| serde_json::from_value(id).ok());
+        if let Some(object) = message.as_object_mut() {
+            object.remove("jsonrpc");
+            object.entry("params").or_insert_with(|| serde_json::json!({}));
+        }
+        let response = match serde_json::from_value::<RequestEnvelope>(message) {
+            Ok(envelope) => self.handle_request(connection, envelope).await,
+            Err(e) => ResponseMessage {
+                id,
+                result: None,
+                error: Some(ResponseError::new(METHOD_NOT_FOUND, format!("Unsupported request: {}", e))),
+            },
+        };
+        Some(response.to_json_rpc())
+    }
+
+    /// 处理来自指定连接的通知，通知不需要也不会得到回复
+    pub async fn handle_notification(&self, connection: ConnectionId, notification: NotificationMessage) {
+        match notification.method.as_str() {
+            INITIALIZED_NOTIFICATION => {}
+            // 请求按顺序同步处理，收到取消通知时对应的请求已经完成，无需处理
+            CANCELLED_NOTIFICATION => {
+                tracing::debug!(connection, params = ?notification.params, "MCP request cancelled by client");
+            }
+            method => tracing::debug!(connection, method, "Ignoring unknown MCP notification"),
+        }
+    }
+
+    async fn dispatch(&self, connection: ConnectionId, request: RequestMessage) -> ResponseMessage {
         match request {
             RequestMessage::ToolsList {} => {
                 let tools = self.get_available_tools().await;
@@ -34,14 +279,10 @@ impl McpServer {
                         result: Some(result),
                         error: None,
                     },
-                    Err(e) => ResponseMessage {
+                    Err(error) => ResponseMessage {
                         id: None,
                         result: None,
-                        error: Some(ResponseError {
-                            code: -1,
-                            message: e.to_string(),
-                            data: None,
-                        }),
+                        error: Some(error),
                     },
                 }
             }
@@ -54,37 +295,46 @@ impl McpServer {
                     error: None,
                 }
             }
-            RequestMessage::ResourceRead { uri } => {
-                match self.read_resource(&uri).await {
+            RequestMessage::ResourceRead { uri, offset, length } => {
+                match self.read_resource_range(&uri, offset, length).await {
                     Ok(content) => ResponseMessage {
                         id: None,
                         result: Some(content),
                         error: None,
                     },
-                    Err(e) => ResponseMessage {
+                    Err(error) => ResponseMessage {
                         id: None,
                         result: None,
-                        error: Some(ResponseError {
-                            code: -1,
-                            message: e.to_string(),
-                            data: None,
-                        }),
+                        error: Some(error),
                     },
                 }
             }
             RequestMessage::ResourceSubscribe { uri } => {
-                // TODO: 实现资源订阅逻辑
-                ResponseMessage {
-                    id: None,
-                    result: Some(serde_json::json!({"subscribed": true, "uri": uri})),
-                    error: None,
+                let subscribed = match self.resolve_resource_path(&uri).await {
+                    Ok(path) => self
+                        .subscriptions
+                        .subscribe(connection, &uri, path)
+                        .map_err(|e| ResponseError::new(INTERNAL_ERROR, e.to_string())),
+                    Err(error) => Err(error),
+                };
+                match subscribed {
+                    Ok(()) => ResponseMessage {
+                        id: None,
+                        result: Some(serde_json::json!({"subscribed": true, "uri": uri})),
+                        error: None,
+                    },
+                    Err(error) => ResponseMessage {
+                        id: None,
+                        result: None,
+                        error: Some(error),
+                    },
                 }
             }
             RequestMessage::ResourceUnsubscribe { uri } => {
-                // TODO: 实现取消订阅逻辑
+                let unsubscribed = self.subscriptions.unsubscribe(connection, &uri);
                 ResponseMessage {
                     id: None,
-                    result: Some(serde_json::json!({"unsubscribed": true, "uri": uri})),
+                    result: Some(serde_json::json!({"unsubscribed": unsubscribed, "uri": uri})),
                     error: None,
                 }
             }
@@ -95,6 +345,24 @@ impl McpServer {
                     error: None,
                 }
             }
+            RequestMessage::Initialize { .. } => {
+                // 只支持一个协议版本，客户端请求其他版本时由客户端决定是否继续
+                ResponseMessage {
+                    id: None,
+                    result: Some(serde_json::json!({
+                        "protocolVersion": MCP_PROTOCOL_VERSION,
+                        "capabilities": {
+                            "tools": {},
+                            "resources": { "subscribe": true }
+                        },
+                        "serverInfo": {
+                            "name": "local-pilot",
+                            "version": env!("CARGO_PKG_VERSION")
+                        }
+                    })),
+                    error: None,
+                }
+            }
         }
     }
 
@@ -103,21 +371,101 @@ impl McpServer {
         vec![
             Tool {
                 name: "file_reader".to_string(),
-                description: "读取本地文件内容".to_string(),
+                description: "读取本地文件内容（仅限允许访问的目录），二进制文件以base64编码返回并附带MIME类型".to_string(),
+                requires_approval: Some(false),
+                input_schema: serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "文件的绝对路径；也可以只给出文件名或路径的末尾部分（如 report.pdf），在文件索引中查找，有多个匹配时返回候选路径"
+                        },
+                        "offset": {
+                            "type": "integer",
+                            "minimum": 0,
+                            "description": "起始字节位置，默认为0"
+                        },
+                        "length": {
+                            "type": "integer",
+                            "minimum": 0,
+                            "description": "读取的字节数，默认为65536；结果中的total_size为文件总大小，可据此分段读取"
+                        }
+                    },
+                    "required": ["path"]
+                }),
+            },
+            Tool {
+                name: "list_directory".to_string(),
+                description: "列出本地目录中的文件和子目录（仅限允许访问的目录，跳过忽略规则匹配的条目）".to_string(),
+                requires_approval: Some(false),
+                input_schema: serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "目录路径"
+                        },
+                        "recursive": {
+                            "type": "boolean",
+                            "description": "是否递归列出子目录的内容，默认为false"
+                        }
+                    },
+                    "required": ["path"]
+                }),
+            },
+            Tool {
+                name: "file_tail".to_string(),
+                description: "读取本地文件的最后若干行（仅限允许访问的目录），适合查看日志，不会读取整个文件".to_string(),
+                requires_approval: Some(false),
                 input_schema: serde_json::json!({
                     "type": "object",
                     "properties": {
                         "path": {
                             "type": "string",
                             "description": "文件路径"
+                        },
+                        "lines": {
+                            "type": "integer",
+                            "minimum": 0,
+                            "description": "返回的行数，默认为100"
                         }
                     },
                     "required": ["path"]
                 }),
             },
+            Tool {
+                name: "write_file".to_string(),
+                description: "写入本地文件（仅限允许访问的目录），不存在的上级目录会被创建；返回写入后的文件大小以及是否新建了文件".to_string(),
+                requires_approval: Some(true),
+                input_schema: serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "文件的绝对路径"
+                        },
+                        "content": {
+                            "type": "string",
+                            "description": "要写入的文本内容"
+                        },
+                        "mode": {
+                            "type": "string",
+                            "enum": ["overwrite", "append", "create_new"],
+                            "description": "overwrite覆盖已存在的文件（默认），append追加到文件末尾，create_new只创建新文件，文件已存在时失败"
+                        },
+                        "atomic": {
+                            "type": "boolean",
+                            "description": "先写入同目录下的临时文件再重命名，写入中途出错不会损坏原文件，默认为false"
+                        }
+                    },
+                    "required": ["path", "content"]
+                }),
+            },
             Tool {
                 name: "shell_executor".to_string(),
-                description: "在本地执行shell命令".to_string(),
+                description: "在本地执行允许列表中的命令，返回标准输出、标准错误和退出码".to_string(),
+                // 命令的路径参数不受允许目录的限制，每条命令都要经过用户批准
+                requires_approval: Some(true),
                 input_schema: serde_json::json!({
                     "type": "object",
                     "properties": {
@@ -132,6 +480,7 @@ impl McpServer {
             Tool {
                 name: "web_search".to_string(),
                 description: "执行网络搜索".to_string(),
+                requires_approval: Some(false),
                 input_schema: serde_json::json!({
                     "type": "object",
                     "properties": {
@@ -146,6 +495,7 @@ impl McpServer {
             Tool {
                 name: "search_local_files".to_string(),
                 description: "在本地文件索引中搜索文件".to_string(),
+                requires_approval: Some(false),
                 input_schema: serde_json::json!({
                     "type": "object",
                     "properties": {
@@ -160,27 +510,45 @@ impl McpServer {
         ]
     }
 
-    /// 执行指定工具
-    async fn execute_tool(&self, name: &str, arguments: HashMap<String, Value>) -> Result<Value, Box<dyn std::error::Error>> {
-        match name {
+    /// 执行指定工具，结果为MCP规范的内容块（见 `tool_result`）
+    /// 未知工具返回 `METHOD_NOT_FOUND`，参数缺失或无效返回 `INVALID_PARAMS`，执行失败返回 `INTERNAL_ERROR`
+    async fn execute_tool(&self, name: &str, arguments: HashMap<String, Value>) -> Result<Value, ResponseError> {
+        let result = match name {
             "file_reader" => {
-                let path = arguments.get("path").and_then(|v| v.as_str()).unwrap_or("");
-                self.read_file(path).await
+                let path = required_str(&arguments, "path")?;
+                let offset = optional_u64(&arguments, "offset")?;
+                let length = optional_u64(&arguments, "length")?;
+                self.read_file(path, offset, length).await
             }
-            "shell_executor" => {
-                let command = arguments.get("command").and_then(|v| v.as_str()).unwrap_or("");
-                self.execute_shell_command(command).await
+            "list_directory" => {
+                let path = required_str(&arguments, "path")?;
+                let recursive = optional_bool(&arguments, "recursive")?.unwrap_or(false);
+                self.list_directory(path, recursive).await
             }
-            "web_search" => {
-                let query = arguments.get("query").and_then(|v| v.as_str()).unwrap_or("");
-                self.perform_web_search(query).await
+            "file_tail" => {
+                let path = required_str(&arguments, "path")?;
+                let lines = optional_u64(&arguments, "lines")?.unwrap_or(DEFAULT_TAIL_LINES);
+                self.tail_file(path, lines).await
             }
-            "search_local_files" => {
-                let query = arguments.get("query").and_then(|v| v.as_str()).unwrap_or("");
-                self.search_local_files(query).await
+            "write_file" => {
+                let path = required_str(&arguments, "path")?;
+                let content = required_str(&arguments, "content")?;
+                let mode = match arguments.get("mode") {
+                    None | Some(Value::Null) => WriteMode::Overwrite,
+                    Some(mode) => mode.as_str().and_then(WriteMode::parse).ok_or_else(|| {
+                        ResponseError::new(INVALID_PARAMS, "Argument 'mode' must be one of overwrite, append, create_new")
+                    })?,
+                };
+                let atomic = optional_bool(&arguments, "atomic")?.unwrap_or(false);
+                self.write_file(path, content, mode, atomic).await
             }
-            _ => Err(format!("Unknown tool: {}", name).into()),
-        }
+            "shell_executor" => self.execute_shell_command(required_str(&arguments, "command")?).await,
+            "web_search" => self.perform_web_search(required_str(&arguments, "query")?).await,
+            "search_local_files" => self.search_local_files(required_str(&arguments, "query")?).await,
+            _ => return Err(ResponseError::new(METHOD_NOT_FOUND, format!("Unknown tool: {}", name))),
+        };
+        let result = tool_result(name, result.map_err(execution_error)?);
+        serde_json::to_value(result).map_err(|e| ResponseError::new(INTERNAL_ERROR, e.to_string()))
     }
 
     /// 获取可用资源列表
@@ -199,54 +567,1564 @@ impl McpServer {
         ]
     }
 
-    /// 读取指定资源
-    async fn read_resource(&self, uri: &str) -> Result<Value, Box<dyn std::error::Error>> {
-        if uri.starts_with("local://") {
-            // TODO: 实现本地资源读取
-            Ok(serde_json::json!({ "content": format!("Content of resource: {}", uri) }))
+    /// 读取指定资源：目录返回条目列表，文件返回开头的 `DEFAULT_READ_LENGTH` 字节
+    async fn read_resource(&self, uri: &str) -> Result<Value, ResponseError> {
+        self.read_resource_range(uri, None, None).await
+    }
+
+    /// 读取指定资源：目录返回条目列表，文件返回从 `offset` 开始的 `length` 字节
+    /// （文本直接返回，其他类型以base64编码），并附带文件总大小以便分段读取
+    async fn read_resource_range(
+        &self,
+        uri: &str,
+        offset: Option<u64>,
+        length: Option<u64>,
+    ) -> Result<Value, ResponseError> {
+        let path = self.resolve_resource_path(uri).await?;
+        let not_found = |e: std::io::Error| {
+            ResponseError::new(RESOURCE_NOT_FOUND, format!("Resource not found: {} ({})", uri, e))
+        };
+        
+        let metadata = tokio::fs::metadata(&path).await.map_err(not_found)?;
+        let content = if metadata.is_dir() {
+            let mut entries = tokio::fs::read_dir(&path).await.map_err(not_found)?;
+            let mut names = Vec::new();
+            while let Some(entry) = entries.next_entry().await.map_err(not_found)? {
+                let mut name = entry.file_name().to_string_lossy().to_string();
+                if entry.file_type().await.is_ok_and(|t| t.is_dir()) {
+                    name.push('/');
+                }
+                names.push(name);
+            }
+            names.sort();
+            serde_json::json!({ "uri": uri, "mimeType": "inode/directory", "text": names.join("\n") })
         } else {
-            Err("Unsupported URI scheme".into())
+            let (offset, length) = byte_range(metadata.len(), offset, length)
+                .map_err(|message| ResponseError::new(INVALID_PARAMS, format!("{}: {}", message, uri)))?;
+            let bytes = read_byte_range(&path, offset, length).await.map_err(not_found)?;
+            let mime = mime_guess::from_path(&path).first_or_octet_stream();
+            let is_text = mime.type_() == mime_guess::mime::TEXT
+                || mime.subtype() == mime_guess::mime::JSON
+                || mime.subtype() == mime_guess::mime::XML;
+            let cut_short = offset + length < metadata.len();
+            let mut content = match utf8_range(&bytes, offset, cut_short) {
+                Some((offset, text)) if is_text => serde_json::json!({
+                    "uri": uri,
+                    "mimeType": mime.essence_str(),
+                    "text": text,
+                    "length": text.len(),
+                    "offset": offset,
+                }),
+                _ => serde_json::json!({
+                    "uri": uri,
+                    "mimeType": mime.essence_str(),
+                    "blob": base64::engine::general_purpose::STANDARD.encode(&bytes),
+                    "length": bytes.len(),
+                    "offset": offset,
+                }),
+            };
+            content["totalSize"] = Value::from(metadata.len());
+            content
+        };
+        
+        Ok(serde_json::json!({ "contents": [content] }))
+    }
+
+    /// 将 `local://<root>/<relative>` 解析为本地路径，拒绝越出根目录的路径
+    async fn resolve_resource_path(&self, uri: &str) -> Result<PathBuf, ResponseError> {
+        let rest = uri
+            .strip_prefix(LOCAL_SCHEME)
+            .ok_or_else(|| ResponseError::new(INVALID_PARAMS, format!("Unsupported URI scheme: {}", uri)))?;
+        let (root_name, relative) = rest.split_once('/').unwrap_or((rest, ""));
+        let root = self
+            .resource_roots
+            .get(root_name)
+            .ok_or_else(|| ResponseError::new(RESOURCE_NOT_FOUND, format!("Unknown resource: {}", uri)))?;
+        
+        let relative = Path::new(relative);
+        if relative.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return Err(ResponseError::new(INVALID_PARAMS, format!("Path escapes resource root: {}", uri)));
+        }
+        
+        // 符号链接也可能指向根目录之外
+        let not_found = |_| ResponseError::new(RESOURCE_NOT_FOUND, format!("Resource not found: {}", uri));
+        let root = tokio::fs::canonicalize(root).await.map_err(not_found)?;
+        let path = tokio::fs::canonicalize(root.join(relative)).await.map_err(not_found)?;
+        if !path.starts_with(&root) {
+            return Err(ResponseError::new(INVALID_PARAMS, format!("Path escapes resource root: {}", uri)));
+        }
+        
+        self.jail_path(&path)
+            .await
+            .map_err(|e| match e {
+                LocalPilotError::PermissionDenied(_) => ResponseError::new(PERMISSION_DENIED, e.to_string()),
+                e => ResponseError::new(INTERNAL_ERROR, e.to_string()),
+            })
+    }
+
+    /// 规范化路径（解析 `..` 和符号链接），不在允许的目录内时返回 `PermissionDenied`
+    async fn jail_path(&self, path: &Path) -> Result<PathBuf, LocalPilotError> {
+        let roots = match &self.allowed_roots {
+            Some(roots) => roots.clone(),
+            None => self.indexer.effective_index_roots()?,
+        };
+        let path = tokio::fs::canonicalize(path).await?;
+        for root in roots {
+            // 不存在的根目录不可能包含任何文件
+            if let Ok(root) = tokio::fs::canonicalize(&root).await {
+                if path.starts_with(&root) {
+                    return Ok(path);
+                }
+            }
+        }
+        Err(LocalPilotError::PermissionDenied(path.display().to_string()))
+    }
+
+    /// 检查要创建或覆盖的路径：已存在时同 `jail_path`；不存在时检查最近的已存在的上级目录，
+    /// 其余部分不能含有 `..`。返回规范化后的路径
+    /// 悬空的符号链接也算已存在，规范化失败而被拒绝，不会写到链接指向的位置
+    async fn jail_new_path(&self, path: &Path) -> Result<PathBuf, LocalPilotError> {
+        if !path.is_absolute() {
+            return Err(LocalPilotError::InvalidInput(format!("Path must be absolute: {}", path.display())));
+        }
+        let mut existing = path;
+        while tokio::fs::symlink_metadata(existing).await.is_err() {
+            match existing.parent() {
+                Some(parent) => existing = parent,
+                None => break,
+            }
         }
+        if existing == path {
+            return self.jail_path(path).await;
+        }
+        
+        let rest = path.strip_prefix(existing).unwrap_or(path);
+        if rest.components().any(|component| !matches!(component, Component::Normal(_))) {
+            return Err(LocalPilotError::InvalidInput(format!("Path must not contain '..': {}", path.display())));
+        }
+        Ok(self.jail_path(existing).await?.join(rest))
     }
 
-    /// 读取文件
-    async fn read_file(&self, path: &str) -> Result<Value, Box<dyn std::error::Error>> {
-        use tokio::fs;
-        match fs::read_to_string(path).await {
-            Ok(content) => Ok(serde_json::json!({ "path": path, "content": content })),
-            Err(e) => Err(e.into()),
+    /// 按 `mode` 写入文件，不存在的上级目录会被创建；写入后重新索引该文件
+    /// `atomic` 为true时先写入同目录下的临时文件再重命名为目标文件，读者只会看到写入前或写入后的完整内容
+    async fn write_file(
+        &self,
+        path: &str,
+        content: &str,
+        mode: WriteMode,
+        atomic: bool,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let real_path = self.jail_new_path(Path::new(path)).await?;
+        let existing = tokio::fs::metadata(&real_path).await.ok();
+        match &existing {
+            Some(metadata) if metadata.is_dir() => {
+                return Err(LocalPilotError::InvalidInput(format!("Is a directory: {}", path)).into());
+            }
+            Some(_) if mode == WriteMode::CreateNew => {
+                return Err(LocalPilotError::InvalidInput(format!("File already exists: {}", path)).into());
+            }
+            _ => {}
+        }
+        if let Some(parent) = real_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
         }
+        
+        if atomic {
+            let mut data = match (mode, &existing) {
+                (WriteMode::Append, Some(_)) => tokio::fs::read(&real_path).await?,
+                _ => Vec::new(),
+            };
+            data.extend_from_slice(content.as_bytes());
+            write_atomically(&real_path, &data, existing.as_ref().map(|metadata| metadata.permissions())).await?;
+        } else {
+            let mut options = tokio::fs::OpenOptions::new();
+            match mode {
+                WriteMode::Overwrite => options.write(true).create(true).truncate(true),
+                WriteMode::Append => options.append(true).create(true),
+                WriteMode::CreateNew => options.write(true).create_new(true),
+            };
+            let mut file = options.open(&real_path).await.map_err(|e| match e.kind() {
+                std::io::ErrorKind::AlreadyExists => LocalPilotError::InvalidInput(format!("File already exists: {}", path)),
+                _ => e.into(),
+            })?;
+            file.write_all(content.as_bytes()).await?;
+            file.flush().await?;
+        }
+        let size = tokio::fs::metadata(&real_path).await?.len();
+        
+        // 只索引索引根目录下未被忽略的文件，其他路径本来就不在索引中
+        let indexer = Arc::clone(&self.indexer);
+        let indexed = real_path.clone();
+        tokio::task::spawn_blocking(move || -> Result<(), LocalPilotError> {
+            let roots = indexer.effective_index_roots()?;
+            let in_roots = roots.iter().filter_map(|root| std::fs::canonicalize(root).ok()).any(|root| indexed.starts_with(root));
+            if in_roots && !indexed.ancestors().any(|p| indexer.is_ignored(p)) {
+                indexer.index_path(&indexed)?;
+            }
+            Ok(())
+        })
+        .await??;
+        
+        Ok(serde_json::json!({
+            "path": real_path.to_string_lossy(),
+            "bytes_written": content.len(),
+            "size": size,
+            "created": existing.is_none(),
+        }))
+    }
+
+    /// 读取文件从 `offset` 开始的 `length` 字节，大文件可据返回的 `total_size` 分段读取
+    async fn read_file(
+        &self,
+        path: &str,
+        offset: Option<u64>,
+        length: Option<u64>,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        // 相对路径视为文件名或路径的末尾部分，在文件索引中查找
+        let indexed = Path::new(path).is_relative();
+        let real_path = if indexed {
+            self.resolve_indexed_path(path).await?
+        } else {
+            self.jail_path(Path::new(path)).await?
+        };
+        let total_size = tokio::fs::metadata(&real_path).await?.len();
+        let (offset, length) = byte_range(total_size, offset, length).map_err(LocalPilotError::InvalidInput)?;
+        let bytes = read_byte_range(&real_path, offset, length).await?;
+        
+        // 含有空字节或不是有效UTF-8的内容视为二进制，以base64编码返回
+        let text = if bytes.contains(&0) { None } else { utf8_range(&bytes, offset, offset + length < total_size) };
+        let mut result = match text {
+            Some((offset, text)) => serde_json::json!({
+                "content": text,
+                "content_type": mime_guess::from_path(path).first_or_text_plain().essence_str(),
+                "encoding": "utf-8",
+                "length": text.len(),
+                "offset": offset,
+            }),
+            None => serde_json::json!({
+                "content": base64::engine::general_purpose::STANDARD.encode(&bytes),
+                "content_type": sniff_mime_type(&bytes)
+                    .map(str::to_string)
+                    .unwrap_or_else(|| mime_guess::from_path(path).first_or_octet_stream().essence_str().to_string()),
+                "encoding": "base64",
+                "length": bytes.len(),
+                "offset": offset,
+            }),
+        };
+        result["path"] = if indexed { Value::from(real_path.to_string_lossy()) } else { Value::from(path) };
+        result["total_size"] = Value::from(total_size);
+        Ok(result)
+    }
+
+    /// 在文件索引中查找文件名相同、路径以 `partial` 结尾的文件，只考虑允许访问的目录内的文件
+    /// 没有匹配时返回 `NotFound`；有多个匹配时不作猜测，返回列出所有候选路径的 `InvalidInput`
+    async fn resolve_indexed_path(&self, partial: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let partial_path = Path::new(partial);
+        let name = partial_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| LocalPilotError::InvalidInput(format!("Not a file name: {}", partial)))?
+            .to_string();
+        
+        // 索引查询是阻塞操作，放到阻塞线程池执行
+        let indexer = Arc::clone(&self.indexer);
+        let pattern = name.clone();
+        let files = tokio::task::spawn_blocking(move || {
+            indexer
+                .search_by_filename(&pattern, MAX_NAME_MATCHES, 0)
+                .map(|page| page.files)
+                .map_err(|e| e.to_string())
+        })
+        .await??;
+        
+        let mut matches: Vec<PathBuf> = Vec::new();
+        for file in files {
+            // 按名称搜索是子串匹配，这里要求文件名完全相同
+            let path = PathBuf::from(&file.path);
+            if file.is_directory || file.name != name || !path.ends_with(partial_path) {
+                continue;
+            }
+            // 索引的根目录可能超出允许访问的目录
+            if let Ok(path) = self.jail_path(&path).await {
+                if !matches.contains(&path) {
+                    matches.push(path);
+                }
+            }
+        }
+        
+        match matches.len() {
+            0 => Err(LocalPilotError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No indexed file matches {}", partial),
+            ))
+            .into()),
+            1 => Ok(matches.remove(0)),
+            count => {
+                matches.sort();
+                let candidates: Vec<String> = matches.iter().map(|path| path.display().to_string()).collect();
+                Err(LocalPilotError::InvalidInput(format!(
+                    "{} indexed files match {}; call file_reader again with one of these paths: {}",
+                    count,
+                    partial,
+                    candidates.join(", ")
+                ))
+                .into())
+            }
+        }
+    }
+
+    /// 读取文件的最后 `lines` 行，从文件末尾向前分块读取；文件末尾的换行不算作新的一行
+    /// 结果超过 `MAX_RESOURCE_SIZE` 时只返回最后的完整行，并标记为截断
+    async fn tail_file(&self, path: &str, lines: u64) -> Result<Value, Box<dyn std::error::Error>> {
+        let real_path = self.jail_path(Path::new(path)).await?;
+        let metadata = tokio::fs::metadata(&real_path).await?;
+        if metadata.is_dir() {
+            return Err(LocalPilotError::InvalidInput(format!("Not a file: {}", path)).into());
+        }
+        let (start, bytes, truncated) = read_tail(&real_path, metadata.len(), lines).await?;
+        let content = String::from_utf8_lossy(&bytes);
+        Ok(serde_json::json!({
+            "path": path,
+            "content": content,
+            "lines": content.lines().count(),
+            "offset": start,
+            "total_size": metadata.len(),
+            "truncated": truncated,
+        }))
+    }
+
+    /// 列出目录内容，条目名为相对于该目录的路径，按名称排序
+    /// 递归时不跟随符号链接，被忽略的目录整体跳过
+    async fn list_directory(&self, path: &str, recursive: bool) -> Result<Value, Box<dyn std::error::Error>> {
+        let real_path = self.jail_path(Path::new(path)).await?;
+        if !tokio::fs::metadata(&real_path).await?.is_dir() {
+            return Err(LocalPilotError::InvalidInput(format!("Not a directory: {}", path)).into());
+        }
+        
+        // 目录遍历是阻塞操作，放到阻塞线程池执行
+        let indexer = Arc::clone(&self.indexer);
+        let root = real_path.clone();
+        let (entries, truncated) = tokio::task::spawn_blocking(move || {
+            let walker = WalkDir::new(&root)
+                .min_depth(1)
+                .max_depth(if recursive { usize::MAX } else { 1 })
+                .sort_by_file_name()
+                .into_iter()
+                .filter_entry(|entry| !indexer.is_ignored(entry.path()));
+            let mut entries = Vec::new();
+            for entry in walker.filter_map(Result::ok) {
+                if entries.len() == MAX_DIRECTORY_ENTRIES {
+                    return (entries, true);
+                }
+                let name = entry.path().strip_prefix(&root).unwrap_or(entry.path());
+                let is_directory = entry.file_type().is_dir();
+                let size = if is_directory { 0 } else { entry.metadata().map(|m| m.len()).unwrap_or(0) };
+                entries.push(serde_json::json!({
+                    "name": name.to_string_lossy(),
+                    "is_directory": is_directory,
+                    "size": size,
+                }));
+            }
+            (entries, false)
+        })
+        .await?;
+        
+        Ok(serde_json::json!({
+            "path": path,
+            "entries": entries,
+            "truncated": truncated,
+        }))
     }
 
     /// 执行shell命令
+    /// 命令按shell语法拆分后直接启动程序，不经过shell解释，因此管道、重定向等不会生效
+    /// 超时的命令会被终止并在结果中标记 `timed_out`，过长的输出被截断并加上 `[truncated N bytes]` 标记
+    /// 单引号之外的 `$NAME` 和 `${NAME}` 会替换为环境变量的值，只能引用 `expandable_env` 中的变量
     async fn execute_shell_command(&self, command: &str) -> Result<Value, Box<dyn std::error::Error>> {
-        use tauri_plugin_shell::ShellExt;
-        // 注意：实际实现中需要通过Tauri命令来执行shell
-        // 这里只是一个示例，实际实现会更复杂
-        Ok(serde_json::json!({ "command": command, "output": "Command executed", "success": true }))
+        self.run_shell_command(command, &std::env::vars().collect()).await
     }
 
-    /// 执行网络搜索
+    /// 以 `env` 作为环境执行shell命令，变量替换和传给命令的环境变量都取自 `env`
+    async fn run_shell_command(
+        &self,
+        command: &str,
+        env: &HashMap<String, String>,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let expanded = interpolate_env(command, env, &self.shell_config.expandable_env)?;
+        let words = shell_words::split(&expanded)
+            .map_err(|e| LocalPilotError::InvalidInput(format!("Cannot parse command: {}", e)))?;
+        let (program, args) = words
+            .split_first()
+            .ok_or_else(|| LocalPilotError::InvalidInput("Empty command".to_string()))?;
+        
+        if !self.shell_config.allowed_commands.iter().any(|allowed| allowed == program) {
+            return Err(format!("Command not allowed: {}", program).into());
+        }
+        let denylist = &self.shell_config.env_denylist;
+        // 在替换环境变量之后检查，变量的值也不能带入这些参数
+        let denied = DENIED_ARGUMENTS
+            .iter()
+            .filter(|(denied_program, _)| denied_program == program)
+            .flat_map(|(_, denied)| denied.iter());
+        for denied in denied {
+            if args.iter().any(|arg| arg == denied) {
+                return Err(format!("Argument {} is not allowed for {}", denied, program).into());
+            }
+        }
+        
+        let mut command_builder = Command::new(program);
+        command_builder
+            .env_clear()
+            .envs(env.iter().filter(|(name, _)| !denylist.contains(name)))
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        // 单独的进程组，超时时可以连同命令启动的子进程一起终止
+        #[cfg(unix)]
+        command_builder.process_group(0);
+        let mut child = command_builder.spawn()?;
+        let pid = child.id();
+        
+        // 一边运行一边读取输出，避免管道写满后命令阻塞
+        // 脱离进程组的子进程可能一直占用管道，读取输出同样受超时限制
+        let limit = self.shell_config.max_output_bytes;
+        let deadline = tokio::time::Instant::now() + self.shell_config.timeout;
+        let stdout = tokio::spawn(read_capped(child.stdout.take(), limit, deadline));
+        let stderr = tokio::spawn(read_capped(child.stderr.take(), limit, deadline));
+        let status = tokio::time::timeout_at(deadline, child.wait()).await;
+        // 命令结束后留在后台的子进程也会占用管道，一并终止
+        kill_process_group(&mut child, pid).await;
+        let status = match status {
+            Ok(status) => Some(status?),
+            Err(_) => None,
+        };
+        let stdout = stdout.await??;
+        let stderr = stderr.await??;
+        
+        // 命令可能通过其他途径（如配置文件）读到敏感值，输出中出现的一律替换
+        let secrets: Vec<String> = denylist
+            .iter()
+            .filter_map(|name| env.get(name))
+            .filter(|value| !value.is_empty())
+            .cloned()
+            .collect();
+        Ok(serde_json::json!({
+            "command": command,
+            "stdout": format_captured_output(&stdout, &secrets),
+            "stderr": format_captured_output(&stderr, &secrets),
+            "exit_code": status.and_then(|status| status.code()),
+            "success": status.is_some_and(|status| status.success()),
+            "timed_out": status.is_none(),
+        }))
+    }
+
+    /// 执行网络搜索，返回标题、链接和摘要
     async fn perform_web_search(&self, query: &str) -> Result<Value, Box<dyn std::error::Error>> {
-        // 这里可以调用搜索引擎API
-        Ok(serde_json::json!({ "query": query, "results": [] }))
+        let provider = self.search_provider.as_ref().ok_or_else(|| {
+            format!("Web search is not configured: set {} to the URL of a SearXNG instance", SEARXNG_URL_ENV)
+        })?;
+        let results = provider.search(query, WEB_SEARCH_RESULT_LIMIT).await?;
+        Ok(serde_json::json!({ "query": query, "results": results }))
     }
 
     /// 搜索本地文件
     async fn search_local_files(&self, query: &str) -> Result<Value, Box<dyn std::error::Error>> {
-        // 这里应该调用Tauri命令来搜索本地文件
-        // 为了演示，我们返回一个模拟结果
-        // 在实际实现中，这应该调用Tauri的search_local_files命令
-        Ok(serde_json::json!([
-            {
-                "path": "/Users/example/Downloads/example.pdf",
-                "name": "example.pdf",
-                "extension": "pdf",
-                "size": 1024000,
-                "modified": "2023-01-01T00:00:00Z",
-                "created": "2023-01-01T00:00:00Z",
-                "is_directory": false
-            }
-        ]))
-    }
-}
\ No newline at end of file
+        // 索引查询是阻塞操作，放到阻塞线程池执行
+        let indexer = Arc::clone(&self.indexer);
+        let query = query.to_string();
+        let files = tokio::task::spawn_blocking(move || {
+            indexer
+                .search_by_filename(&query, DEFAULT_SEARCH_LIMIT, 0)
+                .map(|page| page.files)
+                .map_err(|e| e.to_string())
+        })
+        .await??;
+        
+        Ok(serde_json::to_value(files)?)
+    }
+}
+
+/// 把 `data` 写入 `path` 所在目录下的临时文件并落盘，再重命名为 `path`，替换时保留原文件的权限
+/// 出错时删除临时文件，`path` 保持原样
+async fn write_atomically(path: &Path, data: &[u8], permissions: Option<std::fs::Permissions>) -> std::io::Result<()> {
+    let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let temp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, uuid::Uuid::new_v4()));
+    let result = async {
+        let mut file = tokio::fs::OpenOptions::new().write(true).create_new(true).open(&temp_path).await?;
+        file.write_all(data).await?;
+        file.sync_all().await?;
+        if let Some(permissions) = permissions {
+            tokio::fs::set_permissions(&temp_path, permissions).await?;
+        }
+        tokio::fs::rename(&temp_path, path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    result
+}
+
+/// 命令输出中保留的部分和总字节数
+struct CapturedOutput {
+    bytes: Vec<u8>,
+    total: usize,
+}
+
+/// 读取到流结束，只保留前 `limit` 字节；到 `deadline` 时已无数据可读则停止，返回已读到的部分
+async fn read_capped(
+    stream: Option<impl AsyncRead + Unpin>,
+    limit: usize,
+    deadline: tokio::time::Instant,
+) -> std::io::Result<CapturedOutput> {
+    let mut captured = CapturedOutput { bytes: Vec::new(), total: 0 };
+    let Some(mut stream) = stream else {
+        return Ok(captured);
+    };
+    let mut buffer = [0u8; 8192];
+    loop {
+        let Ok(read) = tokio::time::timeout_at(deadline, stream.read(&mut buffer)).await else {
+            return Ok(captured);
+        };
+        let read = read?;
+        if read == 0 {
+            return Ok(captured);
+        }
+        let keep = read.min(limit.saturating_sub(captured.bytes.len()));
+        captured.bytes.extend_from_slice(&buffer[..keep]);
+        captured.total += read;
+    }
+}
+
+/// 输出转为文本并去掉敏感值，被截断时在末尾加上丢弃的字节数
+fn format_captured_output(output: &CapturedOutput, secrets: &[String]) -> String {
+    let mut text = String::from_utf8_lossy(&output.bytes).into_owned();
+    for secret in secrets {
+        text = text.replace(secret.as_str(), REDACTED);
+    }
+    if output.total > output.bytes.len() {
+        text.push_str(&format!("\n[truncated {} bytes]", output.total - output.bytes.len()));
+    }
+    text
+}
+
+/// 将命令中的 `$NAME` 和 `${NAME}` 替换为 `env` 中的值，在 `shell_words::split` 之前执行
+/// 单引号内和反斜杠转义的 `$` 保持原样；替换后的值会加上引号或转义，不会被拆成多个参数
+/// 只能引用 `expandable` 中的变量，未设置的变量替换为空字符串；`$` 后面不是变量名时保持原样
+fn interpolate_env(
+    command: &str,
+    env: &HashMap<String, String>,
+    expandable: &[String],
+) -> Result<String, LocalPilotError> {
+    let mut result = String::with_capacity(command.len());
+    let mut in_single = false;
+    let mut in_double = false;
+    let mut rest = command;
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+        match c {
+            '\\' if !in_single => {
+                result.push(c);
+                if let Some(escaped) = rest.chars().next() {
+                    result.push(escaped);
+                    rest = &rest[escaped.len_utf8()..];
+                }
+            }
+            '\'' if !in_double => {
+                in_single = !in_single;
+                result.push(c);
+            }
+            '"' if !in_single => {
+                in_double = !in_double;
+                result.push(c);
+            }
+            '$' if !in_single => {
+                let (name, consumed) = if let Some(braced) = rest.strip_prefix('{') {
+                    let end = braced.find('}').ok_or_else(|| {
+                        LocalPilotError::InvalidInput(format!("Unclosed '${{' in command: {}", command))
+                    })?;
+                    (&braced[..end], end + 2)
+                } else {
+                    let end = rest
+                        .char_indices()
+                        .find(|&(i, c)| !(c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit())))
+                        .map_or(rest.len(), |(i, _)| i);
+                    (&rest[..end], end)
+                };
+                if name.is_empty() {
+                    result.push('$');
+                    continue;
+                }
+                if !expandable.iter().any(|allowed| allowed == name) {
+                    return Err(LocalPilotError::InvalidInput(format!(
+                        "Environment variable {} is not available to shell commands",
+                        name
+                    )));
+                }
+                let value = env.get(name).map(String::as_str).unwrap_or_default();
+                if in_double {
+                    for c in value.chars() {
+                        if matches!(c, '\\' | '"' | '$' | '`') {
+                            result.push('\\');
+                        }
+                        result.push(c);
+                    }
+                } else {
+                    result.push_str(&shell_words::quote(value));
+                }
+                rest = &rest[consumed..];
+            }
+            _ => result.push(c),
+        }
+    }
+    Ok(result)
+}
+
+/// 终止命令所在的整个进程组；其他平台只能终止命令本身
+/// `pid` 需在启动时取得，命令退出后 `Child::id` 返回 `None`，而进程组可能仍有成员
+async fn kill_process_group(child: &mut Child, pid: Option<u32>) {
+    #[cfg(unix)]
+    if let Some(pid) = pid {
+        // 进程组id与命令的pid相同，负数表示整个进程组；进程组仍存在时该id不会被新进程复用
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = pid;
+    child.kill().await.ok();
+}
+
+/// 将工具的输出转换为 `tools/call` 的结果：`file_reader` 读到的图片以 `image` 内容块返回，
+/// 其余输出序列化为JSON文本；shell命令退出码非0或超时时标记 `isError`
+fn tool_result(tool_name: &str, mut output: Value) -> ToolResult {
+    let is_error = tool_name == "shell_executor" && output["success"] != Value::Bool(true);
+    
+    let image_type = output["content_type"].as_str().filter(|mime| mime.starts_with("image/")).map(str::to_string);
+    if let (Some(mime_type), "base64") = (image_type, output["encoding"].as_str().unwrap_or_default()) {
+        if let Some(Value::String(data)) = output.as_object_mut().and_then(|fields| fields.remove("content")) {
+            return ToolResult {
+                content: vec![ContentBlock::Image { data, mime_type }, ContentBlock::Text { text: output.to_string() }],
+                is_error,
+            };
+        }
+    }
+    
+    let text = serde_json::to_string_pretty(&output).unwrap_or_else(|_| output.to_string());
+    ToolResult::text(text, is_error)
+}
+
+/// 无法对应到某个请求的错误响应，id为null
+fn error_response(error: ResponseError) -> Value {
+    ResponseMessage { id: None, result: None, error: Some(error) }.to_json_rpc()
+}
+
+/// 取出必需的字符串参数
+fn required_str<'a>(arguments: &'a HashMap<String, Value>, key: &str) -> Result<&'a str, ResponseError> {
+    match arguments.get(key) {
+        Some(Value::String(value)) => Ok(value),
+        Some(_) => Err(ResponseError::new(INVALID_PARAMS, format!("Argument '{}' must be a string", key))),
+        None => Err(ResponseError::new(INVALID_PARAMS, format!("Missing required argument '{}'", key))),
+    }
+}
+
+/// 取出可选的非负整数参数
+fn optional_u64(arguments: &HashMap<String, Value>, key: &str) -> Result<Option<u64>, ResponseError> {
+    match arguments.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value.as_u64().map(Some).ok_or_else(|| {
+            ResponseError::new(INVALID_PARAMS, format!("Argument '{}' must be a non-negative integer", key))
+        }),
+    }
+}
+
+/// 取出可选的布尔参数
+fn optional_bool(arguments: &HashMap<String, Value>, key: &str) -> Result<Option<bool>, ResponseError> {
+    match arguments.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value
+            .as_bool()
+            .map(Some)
+            .ok_or_else(|| ResponseError::new(INVALID_PARAMS, format!("Argument '{}' must be a boolean", key))),
+    }
+}
+
+/// 工具执行出错时的JSON-RPC错误：参数不合理返回 `INVALID_PARAMS`，越权访问返回 `PERMISSION_DENIED`，
+/// 其他错误返回 `INTERNAL_ERROR`
+fn execution_error(error: Box<dyn std::error::Error>) -> ResponseError {
+    let code = match error.downcast_ref::<LocalPilotError>() {
+        Some(LocalPilotError::InvalidInput(_)) => INVALID_PARAMS,
+        Some(LocalPilotError::PermissionDenied(_)) => PERMISSION_DENIED,
+        _ => INTERNAL_ERROR,
+    };
+    ResponseError::new(code, error.to_string())
+}
+
+/// 常见二进制格式的文件头
+const MAGIC_NUMBERS: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+];
+
+/// 根据文件头识别二进制内容的MIME类型，比扩展名更可靠
+fn sniff_mime_type(bytes: &[u8]) -> Option<&'static str> {
+    MAGIC_NUMBERS
+        .iter()
+        .find(|(magic, _)| bytes.starts_with(magic))
+        .map(|(_, mime)| *mime)
+}
+
+/// 计算实际读取的范围：未指定时从头读取 `DEFAULT_READ_LENGTH` 字节，超出文件末尾的部分被截掉
+/// 起始位置超出文件末尾或长度超过 `MAX_RESOURCE_SIZE` 时返回错误
+fn byte_range(total_size: u64, offset: Option<u64>, length: Option<u64>) -> Result<(u64, u64), String> {
+    let offset = offset.unwrap_or(0);
+    let length = length.unwrap_or(DEFAULT_READ_LENGTH);
+    if offset > total_size {
+        return Err(format!("Offset {} is beyond the end of the file ({} bytes)", offset, total_size));
+    }
+    if length > MAX_RESOURCE_SIZE {
+        return Err(format!("Length {} exceeds the maximum of {} bytes per read", length, MAX_RESOURCE_SIZE));
+    }
+    Ok((offset, length.min(total_size - offset)))
+}
+
+/// 只读取文件中指定的一段，不会把整个文件载入内存
+async fn read_byte_range(path: &Path, offset: u64, length: u64) -> std::io::Result<Vec<u8>> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut bytes = Vec::with_capacity(length as usize);
+    file.take(length).read_to_end(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/// 读取文件最后 `lines` 行，返回起始位置、内容以及是否因超过 `MAX_RESOURCE_SIZE` 而截断
+async fn read_tail(path: &Path, size: u64, lines: u64) -> std::io::Result<(u64, Vec<u8>, bool)> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    
+    if lines == 0 {
+        return Ok((size, Vec::new(), false));
+    }
+    let mut file = tokio::fs::File::open(path).await?;
+    // 从后向前读到的块，最后拼接时反转
+    let mut chunks = Vec::new();
+    let mut position = size;
+    let mut newlines = 0;
+    while position > 0 {
+        let chunk_len = TAIL_CHUNK_SIZE.min(position);
+        position -= chunk_len;
+        file.seek(std::io::SeekFrom::Start(position)).await?;
+        let mut chunk = vec![0; chunk_len as usize];
+        file.read_exact(&mut chunk).await?;
+        
+        for index in (0..chunk.len()).rev() {
+            // 文件末尾的换行只是结束最后一行
+            if chunk[index] != b'\n' || position + index as u64 == size - 1 {
+                continue;
+            }
+            newlines += 1;
+            if newlines == lines {
+                let start = position + index as u64 + 1;
+                chunks.push(chunk.split_off(index + 1));
+                chunks.reverse();
+                return Ok((start, chunks.concat(), false));
+            }
+        }
+        chunks.push(chunk);
+        
+        if size - position > MAX_RESOURCE_SIZE {
+            // 丢弃开头不完整的一行
+            chunks.reverse();
+            let bytes = chunks.concat();
+            let cut = bytes.iter().position(|&byte| byte == b'\n').map_or(bytes.len(), |index| index + 1);
+            return Ok((position + cut as u64, bytes[cut..].to_vec(), true));
+        }
+    }
+    chunks.reverse();
+    Ok((0, chunks.concat(), false))
+}
+
+/// 把一段字节解码为UTF-8文本，其中有无效字节时返回 `None`
+/// `cut_short` 表示这一段在文件末尾之前结束，此时末尾被截断的多字节字符会被去掉
+fn utf8_prefix(bytes: &[u8], cut_short: bool) -> Option<&str> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Some(text),
+        Err(e) if cut_short && e.error_len().is_none() => std::str::from_utf8(&bytes[..e.valid_up_to()]).ok(),
+        Err(_) => None,
+    }
+}
+
+/// 与 `utf8_prefix` 相同，但 `offset` 落在多字节字符中间时跳过开头残缺的字节，
+/// 返回对齐到下一个字符边界的起始位置和文本
+fn utf8_range(bytes: &[u8], offset: u64, cut_short: bool) -> Option<(u64, &str)> {
+    let skipped = if offset == 0 { 0 } else { bytes.iter().take(3).take_while(|&&b| b & 0xC0 == 0x80).count() };
+    utf8_prefix(&bytes[skipped..], cut_short).map(|text| (offset + skipped as u64, text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    fn test_indexer() -> Arc<FileIndexer> {
+        let db_path = std::env::temp_dir().join(format!("test_server_{}.db", uuid::Uuid::new_v4()));
+        Arc::new(FileIndexer::new(db_path.to_str().unwrap()).unwrap())
+    }
+
+    fn shell_server(allowed: &[&str], timeout: Duration) -> McpServer {
+        McpServer::new(test_indexer()).with_shell_config(ShellConfig {
+            allowed_commands: allowed.iter().map(|c| c.to_string()).collect(),
+            timeout,
+            ..ShellConfig::default()
+        })
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shell_command_captures_output() {
+        let server = McpServer::new(test_indexer());
+        let result = server.execute_shell_command("echo 'hello world'").await.unwrap();
+        assert_eq!(result["stdout"], "hello world\n");
+        assert_eq!(result["exit_code"], 0);
+        assert_eq!(result["success"], true);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shell_command_reports_failure() {
+        let server = McpServer::new(test_indexer());
+        let result = server.execute_shell_command("ls /definitely/not/here").await.unwrap();
+        assert_eq!(result["success"], false);
+        assert_ne!(result["exit_code"], 0);
+        assert!(!result["stderr"].as_str().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shell_command_enforces_allowlist_and_timeout() {
+        let server = McpServer::new(test_indexer());
+        assert!(server.execute_shell_command("rm -rf /tmp/anything").await.is_err());
+        assert!(server.execute_shell_command("/bin/echo hi").await.is_err());
+        
+        // find可以借助这些参数执行任意命令或删除、写入文件
+        for command in ["find . -delete", "find /tmp -name x -exec rm {} +", "find . -ok rm {} ;", "find . -fprint out.txt"] {
+            let error = server.execute_shell_command(command).await.unwrap_err();
+            assert!(error.to_string().contains("is not allowed for find"), "{}", command);
+        }
+        assert_eq!(server.execute_shell_command("find /definitely/not/here -name x").await.unwrap()["success"], false);
+        
+        let server = shell_server(&["sleep"], Duration::from_millis(100));
+        let started = std::time::Instant::now();
+        let result = server.execute_shell_command("sleep 5").await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(result["timed_out"], true);
+        assert_eq!(result["success"], false);
+        assert!(result["exit_code"].is_null());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_shell_output_read_times_out_when_a_detached_process_holds_it_open() {
+        // setsid启动的进程不在命令的进程组中，终止进程组后仍占用着stdout
+        let server = shell_server(&["sh"], Duration::from_millis(300));
+        let started = std::time::Instant::now();
+        let result = server.execute_shell_command("sh -c 'setsid sleep 5 & echo started'").await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(result["stdout"], "started\n");
+        assert_eq!(result["success"], true);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_denylisted_env_vars_are_hidden_from_shell_commands() {
+        let mut env: HashMap<String, String> = std::env::vars().collect();
+        env.insert("LOCAL_PILOT_TEST_SECRET".to_string(), "hunter2-secret".to_string());
+        env.insert("LOCAL_PILOT_TEST_GREETING".to_string(), "hello".to_string());
+        let mut server = shell_server(&["printenv", "echo"], DEFAULT_SHELL_TIMEOUT);
+        server.shell_config.env_denylist.push("LOCAL_PILOT_TEST_SECRET".to_string());
+        server.shell_config.expandable_env.push("LOCAL_PILOT_TEST_GREETING".to_string());
+        
+        let result = server.run_shell_command("printenv LOCAL_PILOT_TEST_GREETING", &env).await.unwrap();
+        assert_eq!(result["stdout"], "hello\n");
+        let result = server.run_shell_command("printenv LOCAL_PILOT_TEST_SECRET", &env).await.unwrap();
+        assert_eq!(result["stdout"], "");
+        assert_eq!(result["success"], false);
+        
+        let result = server.run_shell_command("echo 'key: hunter2-secret'", &env).await.unwrap();
+        assert_eq!(result["stdout"], "key: [REDACTED]\n");
+        
+        let result = server
+            .run_shell_command("echo $LOCAL_PILOT_TEST_GREETING-${LOCAL_PILOT_TEST_GREETING}!$", &env)
+            .await
+            .unwrap();
+        assert_eq!(result["stdout"], "hello-hello!$\n");
+        // 不在允许列表中的变量都不能引用，不只是禁止列表中的
+        for name in ["LOCAL_PILOT_TEST_SECRET", "AWS_SECRET_ACCESS_KEY", "PATH"] {
+            let error = server.run_shell_command(&format!("echo ${}", name), &env).await.unwrap_err();
+            assert!(error.to_string().contains(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_interpolate_env_respects_quotes() {
+        let env = HashMap::from([("HOME".to_string(), "/home/a b".to_string())]);
+        let expandable = vec!["HOME".to_string()];
+        let split = |command: &str| shell_words::split(&interpolate_env(command, &env, &expandable).unwrap()).unwrap();
+        
+        assert_eq!(split("ls $HOME/docs"), ["ls", "/home/a b/docs"]);
+        assert_eq!(split("echo \"${HOME}\""), ["echo", "/home/a b"]);
+        assert_eq!(split("echo '$HOME' '$SECRET'"), ["echo", "$HOME", "$SECRET"]);
+        assert_eq!(split("echo \\$HOME \"\\$SECRET\""), ["echo", "$HOME", "$SECRET"]);
+        assert_eq!(split("echo \"it's $HOME\""), ["echo", "it's /home/a b"]);
+        
+        // 变量的值不会被当作引号或新参数解析
+        let env = HashMap::from([("HOME".to_string(), "x' \"$y".to_string())]);
+        let split = |command: &str| shell_words::split(&interpolate_env(command, &env, &expandable).unwrap()).unwrap();
+        assert_eq!(split("echo $HOME \"$HOME\""), ["echo", "x' \"$y", "x' \"$y"]);
+        
+        assert!(interpolate_env("echo $SECRET", &env, &expandable).is_err());
+        assert!(interpolate_env("echo \"$SECRET\"", &env, &expandable).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shell_command_output_is_capped() {
+        let server = McpServer::new(test_indexer());
+        let result = server.execute_shell_command("head -c 300000 /dev/zero").await.unwrap();
+        let stdout = result["stdout"].as_str().unwrap();
+        assert!(stdout.ends_with(&format!("\n[truncated {} bytes]", 300000 - DEFAULT_SHELL_OUTPUT_LIMIT)));
+        assert_eq!(stdout.len(), DEFAULT_SHELL_OUTPUT_LIMIT + "\n[truncated 37856 bytes]".len());
+        assert_eq!(result["success"], true);
+        assert_eq!(result["timed_out"], false);
+    }
+
+    fn resource_server() -> (McpServer, PathBuf) {
+        let root = std::env::temp_dir().join(format!("test_resources_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(root.join("notes")).unwrap();
+        fs::write(root.join("notes").join("todo.txt"), "buy milk").unwrap();
+        fs::write(root.join("image.png"), [0x89, b'P', b'N', b'G']).unwrap();
+        let server = McpServer::new(test_indexer())
+            .with_resource_root("workspace", root.clone())
+            .with_allowed_roots(vec![root.clone()]);
+        (server, root)
+    }
+
+    #[tokio::test]
+    async fn test_read_resource_file_and_directory() {
+        let (server, root) = resource_server();
+        
+        let result = server.read_resource("local://workspace/notes/todo.txt").await.unwrap();
+        assert_eq!(result["contents"][0]["mimeType"], "text/plain");
+        assert_eq!(result["contents"][0]["text"], "buy milk");
+        
+        let result = server.read_resource("local://workspace/image.png").await.unwrap();
+        assert_eq!(result["contents"][0]["mimeType"], "image/png");
+        assert_eq!(result["contents"][0]["blob"], "iVBORw==");
+        
+        let result = server.read_resource("local://workspace").await.unwrap();
+        assert_eq!(result["contents"][0]["text"], "image.png\nnotes/");
+        
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_read_ranges_of_large_files() {
+        let (server, root) = resource_server();
+        let text: String = (0..DEFAULT_READ_LENGTH + 100).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+        fs::write(root.join("notes/large.txt"), &text).unwrap();
+        let path = root.join("notes/large.txt");
+        let path = path.to_str().unwrap();
+        
+        // 默认只读取开头的64KB
+        let result = server.read_file(path, None, None).await.unwrap();
+        assert_eq!(result["content"].as_str().unwrap().len() as u64, DEFAULT_READ_LENGTH);
+        assert_eq!(result["total_size"], DEFAULT_READ_LENGTH + 100);
+        
+        let result = server.read_file(path, Some(27), Some(5)).await.unwrap();
+        assert_eq!(result["content"], "bcdef");
+        assert_eq!(result["offset"], 27);
+        assert_eq!(result["length"], 5);
+        
+        // 超出末尾的长度被截掉，起始位置超出末尾则报错
+        let result = server.read_file(path, Some(DEFAULT_READ_LENGTH + 90), Some(50)).await.unwrap();
+        assert_eq!(result["length"], 10);
+        let error = server.read_file(path, Some(DEFAULT_READ_LENGTH + 101), None).await.unwrap_err();
+        assert!(error.to_string().contains("beyond the end of the file"));
+        
+        let result = server.read_resource_range("local://workspace/notes/large.txt", Some(26), Some(3)).await.unwrap();
+        assert_eq!(result["contents"][0]["text"], "abc");
+        assert_eq!(result["contents"][0]["totalSize"], DEFAULT_READ_LENGTH + 100);
+        let error = server
+            .read_resource_range("local://workspace/notes/large.txt", Some(u64::MAX), None)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, INVALID_PARAMS);
+        
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_file_reader_detects_binary_content() {
+        let (server, root) = resource_server();
+        let read = |name: &str| {
+            let path = root.join("notes").join(name);
+            let server = &server;
+            async move { server.read_file(path.to_str().unwrap(), None, None).await.unwrap() }
+        };
+        
+        fs::write(root.join("notes/utf8.txt"), "héllo wörld").unwrap();
+        let result = read("utf8.txt").await;
+        assert_eq!(result["content"], "héllo wörld");
+        assert_eq!(result["content_type"], "text/plain");
+        assert_eq!(result["encoding"], "utf-8");
+        
+        // 起始位置落在"é"中间时对齐到下一个字符
+        let path = root.join("notes/utf8.txt");
+        let result = server.read_file(path.to_str().unwrap(), Some(2), Some(4)).await.unwrap();
+        assert_eq!(result["content"], "llo");
+        assert_eq!(result["offset"], 3);
+        assert_eq!(result["encoding"], "utf-8");
+        
+        // Latin-1编码的"café"不是有效的UTF-8，按原始字节返回
+        let latin1 = b"caf\xe9".to_vec();
+        fs::write(root.join("notes/latin1.txt"), &latin1).unwrap();
+        let result = read("latin1.txt").await;
+        assert_eq!(result["encoding"], "base64");
+        assert_eq!(result["content_type"], "text/plain");
+        let decoded = base64::engine::general_purpose::STANDARD.decode(result["content"].as_str().unwrap()).unwrap();
+        assert_eq!(decoded, latin1);
+        
+        // 扩展名不可靠时根据文件头识别
+        let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        fs::write(root.join("notes/image.bin"), &png).unwrap();
+        let result = read("image.bin").await;
+        assert_eq!(result["encoding"], "base64");
+        assert_eq!(result["content_type"], "image/png");
+        assert_eq!(result["length"], png.len());
+        
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn test_multi_byte_character_cut_at_range_end() {
+        let text = "日本語".as_bytes();
+        assert_eq!(utf8_prefix(&text[..4], true), Some("日"));
+        assert_eq!(utf8_prefix(&text[..4], false), None);
+        assert_eq!(utf8_prefix(&text[1..], true), None);
+        assert_eq!(utf8_range(&text[1..], 1, true), Some((3, "本語")));
+        assert_eq!(utf8_range(&text[1..], 0, true), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_resource_rejects_traversal() {
+        let (server, root) = resource_server();
+        
+        let error = server.read_resource("local://workspace/notes/../../etc/passwd").await.unwrap_err();
+        assert_eq!(error.code, INVALID_PARAMS);
+        let error = server.read_resource("local://workspace//etc/passwd").await.unwrap_err();
+        assert_eq!(error.code, INVALID_PARAMS);
+        
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_file_reader_is_jailed_to_allowed_roots() {
+        let (server, root) = resource_server();
+        let outside = std::env::temp_dir().join(format!("test_outside_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret.txt"), "top secret").unwrap();
+        
+        let allowed = root.join("notes/todo.txt");
+        let result = server.read_file(allowed.to_str().unwrap(), None, None).await.unwrap();
+        assert_eq!(result["content"], "buy milk");
+        
+        let escape = root.join("notes/../..").join(outside.file_name().unwrap()).join("secret.txt");
+        let error = server.read_file(escape.to_str().unwrap(), None, None).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<LocalPilotError>(), Some(LocalPilotError::PermissionDenied(_))));
+        
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(outside.join("secret.txt"), root.join("notes/link.txt")).unwrap();
+            let link = root.join("notes/link.txt");
+            let error = server.read_file(link.to_str().unwrap(), None, None).await.unwrap_err();
+            assert!(matches!(error.downcast_ref::<LocalPilotError>(), Some(LocalPilotError::PermissionDenied(_))));
+            
+            // 资源根目录本身不在允许的目录内时同样拒绝
+            let server = McpServer::new(test_indexer())
+                .with_resource_root("outside", outside.clone())
+                .with_allowed_roots(vec![root.clone()]);
+            let error = server.read_resource("local://outside/secret.txt").await.unwrap_err();
+            assert_eq!(error.code, PERMISSION_DENIED);
+        }
+        
+        fs::remove_dir_all(&root).ok();
+        fs::remove_dir_all(&outside).ok();
+    }
+
+    #[tokio::test]
+    async fn test_file_reader_resolves_unique_file_name_from_index() {
+        let (server, root) = resource_server();
+        let root = fs::canonicalize(&root).unwrap();
+        // 索引中也有允许访问的目录之外的同名文件
+        let outside = std::env::temp_dir().join(format!("test_outside_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("todo.txt"), "top secret").unwrap();
+        server.indexer.scan_directory(root.to_str().unwrap()).unwrap();
+        server.indexer.scan_directory(outside.to_str().unwrap()).unwrap();
+        
+        for partial in ["todo.txt", "notes/todo.txt"] {
+            let result = server.read_file(partial, None, None).await.unwrap();
+            assert_eq!(result["content"], "buy milk");
+            assert_eq!(result["path"], root.join("notes/todo.txt").to_str().unwrap());
+        }
+        
+        let error = server.read_file("missing.txt", None, None).await.unwrap_err();
+        let error = error.downcast_ref::<LocalPilotError>().unwrap();
+        assert!(matches!(error, LocalPilotError::Io(e) if e.kind() == std::io::ErrorKind::NotFound));
+        
+        fs::remove_dir_all(&root).ok();
+        fs::remove_dir_all(&outside).ok();
+    }
+
+    #[tokio::test]
+    async fn test_file_reader_lists_candidates_for_ambiguous_file_name() {
+        let (server, root) = resource_server();
+        let root = fs::canonicalize(&root).unwrap();
+        fs::create_dir_all(root.join("archive")).unwrap();
+        fs::write(root.join("archive/todo.txt"), "buy eggs").unwrap();
+        server.indexer.scan_directory(root.to_str().unwrap()).unwrap();
+        
+        let error = server.read_file("todo.txt", None, None).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<LocalPilotError>(), Some(LocalPilotError::InvalidInput(_))));
+        let message = error.to_string();
+        assert!(message.contains(root.join("archive/todo.txt").to_str().unwrap()));
+        assert!(message.contains(root.join("notes/todo.txt").to_str().unwrap()));
+        
+        // 给出更多的路径部分即可区分
+        let result = server.read_file("archive/todo.txt", None, None).await.unwrap();
+        assert_eq!(result["content"], "buy eggs");
+        
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_list_directory_flat_and_recursive() {
+        let (server, root) = resource_server();
+        fs::create_dir_all(root.join("node_modules/pkg")).unwrap();
+        fs::write(root.join("node_modules/pkg/index.js"), "").unwrap();
+        let entry_names = |result: &Value| -> Vec<String> {
+            result["entries"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["name"].as_str().unwrap().replace('\\', "/"))
+                .collect()
+        };
+        
+        let result = server.list_directory(root.to_str().unwrap(), false).await.unwrap();
+        assert_eq!(entry_names(&result), ["image.png", "notes"]);
+        assert_eq!(result["entries"][0]["size"], 4);
+        assert_eq!(result["entries"][1]["is_directory"], true);
+        assert_eq!(result["truncated"], false);
+        
+        let result = server.list_directory(root.to_str().unwrap(), true).await.unwrap();
+        assert_eq!(entry_names(&result), ["image.png", "notes", "notes/todo.txt"]);
+        assert_eq!(result["entries"][2]["size"], 8);
+        
+        let outside = std::env::temp_dir();
+        let error = server.list_directory(outside.to_str().unwrap(), false).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<LocalPilotError>(), Some(LocalPilotError::PermissionDenied(_))));
+        
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_file_tail_reads_last_lines_of_long_log() {
+        let (server, root) = resource_server();
+        let log = root.join("app.log");
+        // 远大于单次读取的块，确保需要向前读取多次
+        let content: String = (1..=5000).map(|i| format!("line {}\n", i)).collect();
+        fs::write(&log, &content).unwrap();
+        
+        let result = server.tail_file(log.to_str().unwrap(), 3).await.unwrap();
+        assert_eq!(result["content"], "line 4998\nline 4999\nline 5000\n");
+        assert_eq!(result["lines"], 3);
+        assert_eq!(result["offset"], content.len() - "line 4998\nline 4999\nline 5000\n".len());
+        assert_eq!(result["truncated"], false);
+        
+        let result = server.tail_file(log.to_str().unwrap(), DEFAULT_TAIL_LINES).await.unwrap();
+        assert_eq!(result["lines"], 100);
+        assert!(result["content"].as_str().unwrap().starts_with("line 4901\n"));
+        
+        let outside = std::env::temp_dir().join(format!("outside_{}.log", uuid::Uuid::new_v4()));
+        fs::write(&outside, &content).unwrap();
+        let error = server.tail_file(outside.to_str().unwrap(), 3).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<LocalPilotError>(), Some(LocalPilotError::PermissionDenied(_))));
+        
+        fs::remove_file(&outside).ok();
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_file_tail_short_file_without_trailing_newline() {
+        let (server, root) = resource_server();
+        let path = root.join("short.txt");
+        fs::write(&path, "first\nsecond\nthird").unwrap();
+        
+        let result = server.tail_file(path.to_str().unwrap(), 10).await.unwrap();
+        assert_eq!(result["content"], "first\nsecond\nthird");
+        assert_eq!(result["lines"], 3);
+        assert_eq!(result["offset"], 0);
+        
+        let result = server.tail_file(path.to_str().unwrap(), 2).await.unwrap();
+        assert_eq!(result["content"], "second\nthird");
+        
+        let result = server.tail_file(path.to_str().unwrap(), 0).await.unwrap();
+        assert_eq!(result["content"], "");
+        
+        fs::write(&path, "").unwrap();
+        let result = server.tail_file(path.to_str().unwrap(), 10).await.unwrap();
+        assert_eq!(result["content"], "");
+        assert_eq!(result["lines"], 0);
+        
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_read_resource_missing() {
+        let (server, root) = resource_server();
+        
+        let error = server.read_resource("local://workspace/missing.txt").await.unwrap_err();
+        assert_eq!(error.code, RESOURCE_NOT_FOUND);
+        let error = server.read_resource("local://nowhere/file.txt").await.unwrap_err();
+        assert_eq!(error.code, RESOURCE_NOT_FOUND);
+        assert!(server.read_resource("http://example.com").await.is_err());
+        
+        fs::remove_dir_all(&root).ok();
+    }
+
+    struct FixedSearchProvider;
+
+    #[async_trait::async_trait]
+    impl SearchProvider for FixedSearchProvider {
+        async fn search(&self, query: &str, limit: usize) -> Result<Vec<web_search::SearchResult>, crate::error::LocalPilotError> {
+            Ok((0..limit)
+                .map(|i| web_search::SearchResult {
+                    title: format!("{} {}", query, i),
+                    url: format!("https://example.com/{}", i),
+                    snippet: String::new(),
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_web_search_uses_provider() {
+        let server = McpServer::new(test_indexer()).with_search_provider(FixedSearchProvider);
+        let result = server.perform_web_search("tauri").await.unwrap();
+        assert_eq!(result["query"], "tauri");
+        assert_eq!(result["results"].as_array().unwrap().len(), WEB_SEARCH_RESULT_LIMIT);
+        assert_eq!(result["results"][0]["title"], "tauri 0");
+        assert_eq!(result["results"][0]["url"], "https://example.com/0");
+        
+        let mut unconfigured = McpServer::new(test_indexer());
+        unconfigured.search_provider = None;
+        let error = unconfigured.perform_web_search("tauri").await.unwrap_err();
+        assert!(error.to_string().contains(SEARXNG_URL_ENV));
+    }
+
+    #[tokio::test]
+    async fn test_write_file_creates_parents_reindexes_and_is_jailed() {
+        let root = std::env::temp_dir().join(format!("test_write_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        let indexer = test_indexer();
+        indexer.add_index_root(&root).unwrap();
+        let server = McpServer::new(Arc::clone(&indexer)).with_allowed_roots(vec![root.clone()]);
+        let write = |path: PathBuf, content: &str| {
+            let arguments = HashMap::from([
+                ("path".to_string(), json!(path)),
+                ("content".to_string(), json!(content)),
+            ]);
+            server.execute_tool("write_file", arguments)
+        };
+        
+        let path = root.join("drafts/2024/plan.md");
+        let result: ToolResult = serde_json::from_value(write(path.clone(), "# 计划").await.unwrap()).unwrap();
+        assert!(!result.is_error);
+        let output: Value = serde_json::from_str(&result.to_text()).unwrap();
+        assert_eq!(output["bytes_written"], "# 计划".len());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "# 计划");
+        let indexed = indexer.search(&crate::file_index::FileQuery::new().name_contains("plan.md")).unwrap();
+        assert_eq!(indexed[0].size, "# 计划".len() as u64);
+        
+        // 覆盖已存在的文件
+        write(path.clone(), "done").await.unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "done");
+        
+        let outside = std::env::temp_dir().join(format!("test_write_outside_{}", uuid::Uuid::new_v4()));
+        assert_eq!(write(outside.join("x.txt"), "x").await.unwrap_err().code, PERMISSION_DENIED);
+        assert!(!outside.exists());
+        let escape = root.join("new_dir/../..").join(outside.file_name().unwrap()).join("x.txt");
+        assert_eq!(write(escape, "x").await.unwrap_err().code, INVALID_PARAMS);
+        assert!(!outside.exists());
+        assert_eq!(write(PathBuf::from("relative.txt"), "x").await.unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(write(root.join("drafts"), "x").await.unwrap_err().code, INVALID_PARAMS);
+        
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_write_file_modes_and_atomic_replace() {
+        let root = std::env::temp_dir().join(format!("test_write_modes_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        let server = &McpServer::new(test_indexer()).with_allowed_roots(vec![root.clone()]);
+        let path = root.join("log.txt");
+        let write = |content: &str, mode: &str, atomic: bool| {
+            let arguments = HashMap::from([
+                ("path".to_string(), json!(path)),
+                ("content".to_string(), json!(content)),
+                ("mode".to_string(), json!(mode)),
+                ("atomic".to_string(), json!(atomic)),
+            ]);
+            async move {
+                let result: ToolResult = serde_json::from_value(server.execute_tool("write_file", arguments).await?).unwrap();
+                Ok::<Value, ResponseError>(serde_json::from_str(&result.to_text()).unwrap())
+            }
+        };
+        
+        let output = write("one\n", "create_new", false).await.unwrap();
+        assert_eq!((output["size"].as_u64(), output["created"].as_bool()), (Some(4), Some(true)));
+        assert_eq!(write("again\n", "create_new", false).await.unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(write("again\n", "create_new", true).await.unwrap_err().code, INVALID_PARAMS);
+        
+        let output = write("two\n", "append", false).await.unwrap();
+        assert_eq!((output["size"].as_u64(), output["created"].as_bool()), (Some(8), Some(false)));
+        let output = write("three\n", "append", true).await.unwrap();
+        assert_eq!(output["size"], 14);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\nthree\n");
+        
+        // 原子写入替换文件，保留原文件的权限，不留下临时文件
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        let output = write("fresh\n", "overwrite", true).await.unwrap();
+        assert_eq!((output["size"].as_u64(), output["created"].as_bool()), (Some(6), Some(false)));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fresh\n");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        let entries: Vec<_> = fs::read_dir(&root).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(entries, ["log.txt"]);
+        
+        let output = write("short", "overwrite", false).await.unwrap();
+        assert_eq!(output["size"], 5);
+        assert_eq!(write("x", "truncate", false).await.unwrap_err().code, INVALID_PARAMS);
+        
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_search_local_files_uses_index() {
+        let (_, root) = resource_server();
+        let indexer = test_indexer();
+        indexer.scan_directory(root.to_str().unwrap()).unwrap();
+        let server = McpServer::new(Arc::clone(&indexer));
+        
+        let mut arguments = HashMap::new();
+        arguments.insert("query".to_string(), serde_json::json!("todo"));
+        let result: ToolResult = serde_json::from_value(server.execute_tool("search_local_files", arguments).await.unwrap()).unwrap();
+        let files: Value = serde_json::from_str(&result.to_text()).unwrap();
+        let files = files.as_array().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0]["name"], "todo.txt");
+        assert_eq!(
+            files[0]["path"],
+            root.join("notes").join("todo.txt").to_string_lossy().as_ref()
+        );
+        
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_response_id_matches_request_id() {
+        let server = McpServer::new(test_indexer());
+        let (connection, _notifications) = server.connect();
+        
+        for id in [json!(7), json!("req-7")] {
+            let envelope: RequestEnvelope =
+                serde_json::from_value(json!({"jsonrpc": "2.0", "id": id, "method": "ping", "params": {}})).unwrap();
+            let response = server.handle_request(connection, envelope).await;
+            assert_eq!(serde_json::to_value(&response.id).unwrap(), id);
+        }
+        
+        // 出错的响应同样带有请求的id
+        let envelope = RequestEnvelope {
+            id: Some(RequestId::Number(8)),
+            request: RequestMessage::ToolCall { name: "no_such_tool".to_string(), arguments: None },
+        };
+        let response = server.handle_request(connection, envelope).await;
+        assert!(response.error.is_some());
+        assert_eq!(response.id, Some(RequestId::Number(8)));
+    }
+
+    #[tokio::test]
+    async fn test_batch_of_requests_and_notifications() {
+        let server = McpServer::new(test_indexer());
+        let (connection, _notifications) = server.connect();
+        
+        let batch = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "ping"},
+            {"jsonrpc": "2.0", "method": "notifications/initialized"},
+            {"jsonrpc": "2.0", "id": "tools", "method": "tools/list"},
+            {"jsonrpc": "2.0", "method": "notifications/cancelled", "params": {"requestId": 1}},
+            {"jsonrpc": "2.0", "id": 3, "method": "no/such"},
+            42
+        ]);
+        let responses = server.handle_message(connection, batch).await.unwrap();
+        let responses = responses.as_array().unwrap();
+        // 通知没有响应，其余的响应与请求顺序相同
+        assert_eq!(responses.len(), 4);
+        assert_eq!(responses[0], json!({"jsonrpc": "2.0", "id": 1, "result": "pong"}));
+        assert_eq!(responses[1]["id"], "tools");
+        assert!(responses[1]["result"].is_array());
+        assert_eq!(responses[2]["id"], 3);
+        assert_eq!(responses[2]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(responses[3]["id"], Value::Null);
+        assert_eq!(responses[3]["error"]["code"], INVALID_REQUEST);
+        
+        let notifications = json!([{"jsonrpc": "2.0", "method": "notifications/initialized"}]);
+        assert!(server.handle_message(connection, notifications).await.is_none());
+        let empty = server.handle_message(connection, json!([])).await.unwrap();
+        assert_eq!(empty["error"]["code"], INVALID_REQUEST);
+        
+        // 单个请求仍然得到单个响应
+        let single = server.handle_message(connection, json!({"jsonrpc": "2.0", "id": 9, "method": "ping"})).await;
+        assert_eq!(single, Some(json!({"jsonrpc": "2.0", "id": 9, "result": "pong"})));
+    }
+
+    #[tokio::test]
+    async fn test_tool_errors_use_json_rpc_codes() {
+        let (server, root) = resource_server();
+        let (connection, _notifications) = server.connect();
+        let call = |name: &str, arguments: Value| RequestMessage::ToolCall {
+            name: name.to_string(),
+            arguments: serde_json::from_value(arguments).unwrap(),
+        };
+        let error_code = |response: ResponseMessage| response.error.unwrap().code;
+        
+        let response = server.handle_request(connection, call("no_such_tool", json!({}))).await;
+        assert_eq!(error_code(response), METHOD_NOT_FOUND);
+        
+        let response = server.handle_request(connection, call("file_reader", json!({}))).await;
+        assert_eq!(error_code(response), INVALID_PARAMS);
+        let response = server.handle_request(connection, call("file_reader", json!({ "path": 42 }))).await;
+        assert_eq!(error_code(response), INVALID_PARAMS);
+        let todo = root.join("notes/todo.txt");
+        let response = server
+            .handle_request(connection, call("file_reader", json!({ "path": todo, "offset": -1 })))
+            .await;
+        assert_eq!(error_code(response), INVALID_PARAMS);
+        let response = server
+            .handle_request(connection, call("file_reader", json!({ "path": todo, "offset": 1000 })))
+            .await;
+        assert_eq!(error_code(response), INVALID_PARAMS);
+        
+        let missing = root.join("notes/missing.txt");
+        let response = server.handle_request(connection, call("file_reader", json!({ "path": missing }))).await;
+        assert_eq!(error_code(response), INTERNAL_ERROR);
+        
+        let response = server.handle_request(connection, call("file_reader", json!({ "path": todo }))).await;
+        assert_eq!(response.result.unwrap()["isError"], false);
+        
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_built_in_tools_return_content_blocks() {
+        let (server, root) = resource_server();
+        let server = server.with_search_provider(FixedSearchProvider);
+        server.indexer.scan_directory(root.to_str().unwrap()).unwrap();
+        let run = |name: &'static str, arguments: Value| {
+            let server = &server;
+            async move {
+                let arguments = serde_json::from_value(arguments).unwrap();
+                let result = server.execute_tool(name, arguments).await.unwrap();
+                serde_json::from_value::<ToolResult>(result).unwrap()
+            }
+        };
+        let text_json = |result: &ToolResult| -> Value {
+            assert_eq!(result.content.len(), 1);
+            serde_json::from_str(&result.to_text()).unwrap()
+        };
+        
+        let result = run("file_reader", json!({ "path": root.join("notes/todo.txt") })).await;
+        assert!(!result.is_error);
+        assert_eq!(text_json(&result)["content"], "buy milk");
+        
+        let result = run("file_reader", json!({ "path": root.join("image.png") })).await;
+        assert_eq!(
+            result.content[0],
+            ContentBlock::Image { data: "iVBORw==".to_string(), mime_type: "image/png".to_string() }
+        );
+        let ContentBlock::Text { text } = &result.content[1] else { panic!("expected metadata text block") };
+        assert!(text.contains("\"total_size\":4") && !text.contains("iVBORw=="));
+        
+        let result = run("list_directory", json!({ "path": root })).await;
+        assert_eq!(text_json(&result)["entries"][0]["name"], "image.png");
+        
+        let result = run("search_local_files", json!({ "query": "todo" })).await;
+        assert_eq!(text_json(&result)[0]["name"], "todo.txt");
+        
+        let result = run("web_search", json!({ "query": "tauri" })).await;
+        assert_eq!(text_json(&result)["results"][0]["title"], "tauri 0");
+        
+        let result = run("shell_executor", json!({ "command": "echo hi" })).await;
+        assert!(!result.is_error);
+        assert_eq!(text_json(&result)["stdout"], "hi\n");
+        let result = run("shell_executor", json!({ "command": "ls /definitely/not/here" })).await;
+        assert!(result.is_error);
+        
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_subscribed_resource_change_is_notified() {
+        let (server, root) = resource_server();
+        let (connection, mut notifications) = server.connect();
+        
+        let uri = "local://workspace/notes/todo.txt";
+        let response = server
+            .handle_request(connection, RequestMessage::ResourceSubscribe { uri: uri.to_string() })
+            .await;
+        assert!(response.error.is_none());
+        
+        fs::write(root.join("notes").join("todo.txt"), "buy bread").unwrap();
+        let notification = tokio::time::timeout(Duration::from_secs(5), notifications.recv())
+            .await
+            .expect("no notification received")
+            .unwrap();
+        assert_eq!(notification.params.unwrap()["uri"], uri);
+        
+        let response = server
+            .handle_request(connection, RequestMessage::ResourceSubscribe { uri: "local://workspace/missing".to_string() })
+            .await;
+        assert_eq!(response.error.unwrap().code, RESOURCE_NOT_FOUND);
+        
+        server.disconnect(connection);
+        assert_eq!(server.subscriber_count(uri), 0);
+        fs::remove_dir_all(&root).ok();
+    }
+}
@@ -0,0 +1,338 @@
+//! MCP资源订阅
+//! 记录每个连接订阅的资源，资源在磁盘上变化时向订阅者发送 `notifications/resources/updated`
+
+use crate::lock::LockExt;
+use crate::mcp::protocol::NotificationMessage;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// 资源更新通知的方法名
+pub const RESOURCE_UPDATED_METHOD: &str = "notifications/resources/updated";
+
+/// 连接标识
+pub type ConnectionId = u64;
+
+/// 单个资源的订阅信息
+struct Subscription {
+    path: PathBuf,
+    subscribers: HashSet<ConnectionId>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_connection: ConnectionId,
+    /// 连接 -> 该连接的通知输出
+    connections: HashMap<ConnectionId, mpsc::UnboundedSender<NotificationMessage>>,
+    /// URI -> 订阅信息
+    subscriptions: HashMap<String, Subscription>,
+    /// 监听回调中发现不再有订阅者的路径；回调里不能操作监听器，留到下次访问注册表时取消监听
+    pending_unwatch: Vec<PathBuf>,
+}
+
+impl Registry {
+    /// 通知订阅了该路径（或其所在目录）的连接；发送失败的连接视为已断开，返回因此不再有订阅者的路径
+    fn resource_changed(&mut self, changed: &Path) -> Vec<PathBuf> {
+        let mut closed = Vec::new();
+        for (uri, subscription) in &self.subscriptions {
+            // 目录订阅在其直接子项变化时也需要通知（目录列表变了）
+            if changed != subscription.path && changed.parent() != Some(subscription.path.as_path()) {
+                continue;
+            }
+            for connection in &subscription.subscribers {
+                let notification = NotificationMessage {
+                    method: RESOURCE_UPDATED_METHOD.to_string(),
+                    params: Some(serde_json::json!({ "uri": uri })),
+                };
+                let delivered = self
+                    .connections
+                    .get(connection)
+                    .is_some_and(|sender| sender.send(notification).is_ok());
+                if !delivered {
+                    closed.push(*connection);
+                }
+            }
+        }
+
+        let mut unwatched = Vec::new();
+        for connection in closed {
+            unwatched.extend(self.remove_connection(connection));
+        }
+        unwatched
+    }
+
+    /// 移除连接及其所有订阅，返回不再有订阅者的路径
+    fn remove_connection(&mut self, connection: ConnectionId) -> Vec<PathBuf> {
+        self.connections.remove(&connection);
+        let mut unwatched = Vec::new();
+        self.subscriptions.retain(|_, subscription| {
+            subscription.subscribers.remove(&connection);
+            if subscription.subscribers.is_empty() {
+                unwatched.push(subscription.path.clone());
+                false
+            } else {
+                true
+            }
+        });
+        // 其他URI可能映射到同一路径
+        unwatched.retain(|path| !self.is_watched(path));
+        unwatched
+    }
+
+    /// 移除通知接收端已被丢弃（连接已关闭）却未调用 `disconnect` 的连接，返回不再有订阅者的路径，
+    /// 包括监听回调中留下的路径
+    fn remove_closed_connections(&mut self) -> Vec<PathBuf> {
+        let closed: Vec<ConnectionId> = self
+            .connections
+            .iter()
+            .filter(|(_, sender)| sender.is_closed())
+            .map(|(connection, _)| *connection)
+            .collect();
+        let mut unwatched = std::mem::take(&mut self.pending_unwatch);
+        // 留下之后可能又有了新的订阅
+        unwatched.retain(|path| !self.is_watched(path));
+        for connection in closed {
+            unwatched.extend(self.remove_connection(connection));
+        }
+        unwatched
+    }
+
+    fn is_watched(&self, path: &Path) -> bool {
+        self.subscriptions.values().any(|subscription| subscription.path == path)
+    }
+}
+
+/// 资源订阅注册表
+pub struct SubscriptionRegistry {
+    registry: Arc<Mutex<Registry>>,
+    /// 首次订阅时才创建
+    watcher: Mutex<Option<RecommendedWatcher>>,
+}
+
+impl SubscriptionRegistry {
+    /// 创建空的注册表
+    pub fn new() -> Self {
+        Self {
+            registry: Arc::new(Mutex::new(Registry::default())),
+            watcher: Mutex::new(None),
+        }
+    }
+
+    /// 注册新连接，返回连接标识和该连接的通知流
+    pub fn connect(&self) -> (ConnectionId, mpsc::UnboundedReceiver<NotificationMessage>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut registry = self.registry.lock_or_recover();
+        let connection = registry.next_connection;
+        registry.next_connection += 1;
+        registry.connections.insert(connection, sender);
+        (connection, receiver)
+    }
+
+    /// 断开连接并清除其所有订阅
+    pub fn disconnect(&self, connection: ConnectionId) {
+        let unwatched = {
+            let mut registry = self.registry.lock_or_recover();
+            let mut unwatched = registry.remove_connection(connection);
+            unwatched.extend(registry.remove_closed_connections());
+            unwatched
+        };
+        self.unwatch_all(&unwatched);
+    }
+
+    /// 订阅资源，`path` 为资源对应的本地路径
+    pub fn subscribe(&self, connection: ConnectionId, uri: &str, path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+        let (needs_watch, unwatched) = {
+            let mut registry = self.registry.lock_or_recover();
+            let unwatched = registry.remove_closed_connections();
+            if !registry.connections.contains_key(&connection) {
+                drop(registry);
+                self.unwatch_all(&unwatched);
+                return Err(format!("Unknown connection: {}", connection).into());
+            }
+            let needs_watch = !registry.is_watched(&path);
+            registry
+                .subscriptions
+                .entry(uri.to_string())
+                .or_insert_with(|| Subscription {
+                    path: path.clone(),
+                    subscribers: HashSet::new(),
+                })
+                .subscribers
+                .insert(connection);
+            (needs_watch, unwatched)
+        };
+        self.unwatch_all(&unwatched);
+
+        if needs_watch {
+            let mut watcher = self.watcher.lock_or_recover();
+            if watcher.is_none() {
+                *watcher = Some(self.create_watcher()?);
+            }
+            if let Some(watcher) = watcher.as_mut() {
+                watcher.watch(&path, RecursiveMode::NonRecursive)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 取消订阅，返回该连接此前是否订阅了该资源
+    pub fn unsubscribe(&self, connection: ConnectionId, uri: &str) -> bool {
+        let (removed, unwatched) = {
+            let mut registry = self.registry.lock_or_recover();
+            let Some(subscription) = registry.subscriptions.get_mut(uri) else {
+                return false;
+            };
+            let removed = subscription.subscribers.remove(&connection);
+            let mut unwatched = Vec::new();
+            if subscription.subscribers.is_empty() {
+                let path = subscription.path.clone();
+                registry.subscriptions.remove(uri);
+                if !registry.is_watched(&path) {
+                    unwatched.push(path);
+                }
+            }
+            (removed, unwatched)
+        };
+
+        self.unwatch_all(&unwatched);
+        removed
+    }
+
+    /// 资源当前的订阅者数量，已关闭的连接不计在内
+    pub fn subscriber_count(&self, uri: &str) -> usize {
+        let (count, unwatched) = {
+            let mut registry = self.registry.lock_or_recover();
+            let unwatched = registry.remove_closed_connections();
+            let count = registry
+                .subscriptions
+                .get(uri)
+                .map_or(0, |subscription| subscription.subscribers.len());
+            (count, unwatched)
+        };
+        self.unwatch_all(&unwatched);
+        count
+    }
+
+    /// 某个路径发生了变化，通知相关订阅者
+    pub fn resource_changed(&self, path: &Path) {
+        let unwatched = self.registry.lock_or_recover().resource_changed(path);
+        self.unwatch_all(&unwatched);
+    }
+
+    fn create_watcher(&self) -> notify::Result<RecommendedWatcher> {
+        let registry = Arc::clone(&self.registry);
+        notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) => {
+                if matches!(event.kind, EventKind::Access(_)) {
+                    return;
+                }
+                let mut registry = registry.lock_or_recover();
+                for path in &event.paths {
+                    let unwatched = registry.resource_changed(path);
+                    registry.pending_unwatch.extend(unwatched);
+                }
+            }
+            Err(e) => tracing::warn!(error = %e, "Resource watcher error"),
+        })
+    }
+
+    fn unwatch_all(&self, paths: &[PathBuf]) {
+        if let Some(watcher) = self.watcher.lock_or_recover().as_mut() {
+            for path in paths {
+                let _ = watcher.unwatch(path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_modified_file_notifies_subscribers() {
+        let dir = std::env::temp_dir().join(format!("test_subscriptions_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = fs::canonicalize(&dir).unwrap().join("watched.txt");
+        fs::write(&file, "v1").unwrap();
+
+        let registry = SubscriptionRegistry::new();
+        let (subscriber, mut notifications) = registry.connect();
+        let (other, mut other_notifications) = registry.connect();
+        registry.subscribe(subscriber, "local://workspace/watched.txt", file.clone()).unwrap();
+
+        fs::write(&file, "v2").unwrap();
+        let notification = tokio::time::timeout(Duration::from_secs(5), notifications.recv())
+            .await
+            .expect("no notification received")
+            .unwrap();
+        assert_eq!(notification.method, RESOURCE_UPDATED_METHOD);
+        assert_eq!(notification.params.unwrap()["uri"], "local://workspace/watched.txt");
+        assert!(other_notifications.try_recv().is_err());
+
+        // 取消订阅后不再收到通知
+        assert!(registry.unsubscribe(subscriber, "local://workspace/watched.txt"));
+        while notifications.try_recv().is_ok() {}
+        registry.resource_changed(&file);
+        assert!(notifications.try_recv().is_err());
+
+        registry.disconnect(other);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_disconnect_drops_subscriptions() {
+        let registry = SubscriptionRegistry::new();
+        let (connection, _notifications) = registry.connect();
+        let path = std::env::temp_dir();
+        registry.subscribe(connection, "local://workspace", path.clone()).unwrap();
+
+        registry.disconnect(connection);
+        assert!(!registry.unsubscribe(connection, "local://workspace"));
+        assert!(registry.subscribe(connection, "local://workspace", path).is_err());
+    }
+
+    #[test]
+    fn test_closed_connection_leaves_no_subscribers() {
+        let registry = SubscriptionRegistry::new();
+        let (closed, notifications) = registry.connect();
+        let (open, _open_notifications) = registry.connect();
+        let path = std::env::temp_dir();
+        registry.subscribe(closed, "local://workspace", path.clone()).unwrap();
+        registry.subscribe(open, "local://workspace", path).unwrap();
+        assert_eq!(registry.subscriber_count("local://workspace"), 2);
+
+        // 连接关闭但没有调用 `disconnect`
+        drop(notifications);
+        assert_eq!(registry.subscriber_count("local://workspace"), 1);
+        assert!(!registry.unsubscribe(closed, "local://workspace"));
+
+        registry.disconnect(open);
+        assert_eq!(registry.subscriber_count("local://workspace"), 0);
+    }
+
+    #[test]
+    fn test_failed_notification_releases_watched_path() {
+        let registry = SubscriptionRegistry::new();
+        let (closed, notifications) = registry.connect();
+        let path = std::env::temp_dir();
+        registry.subscribe(closed, "local://workspace", path.clone()).unwrap();
+
+        // 通知发送失败时移除连接，返回不再有订阅者、需要取消监听的路径
+        drop(notifications);
+        let unwatched = registry.registry.lock_or_recover().resource_changed(&path);
+        assert_eq!(unwatched, [path.as_path()]);
+
+        // 监听回调留下的路径在下次访问时取消监听，期间重新订阅的路径除外
+        let (open, _open_notifications) = registry.connect();
+        let other = path.join("other");
+        registry.subscribe(open, "local://workspace", path.clone()).unwrap();
+        registry.registry.lock_or_recover().pending_unwatch.extend([path.clone(), other.clone()]);
+        assert_eq!(registry.registry.lock_or_recover().remove_closed_connections(), [other]);
+        assert!(registry.registry.lock_or_recover().pending_unwatch.is_empty());
+    }
+}
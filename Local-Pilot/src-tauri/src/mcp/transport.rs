@@ -0,0 +1,372 @@
+//! MCP服务端的TCP传输
+//! 让编辑器等外部MCP客户端通过本地端口使用Local-Pilot的工具。每个连接上的请求按
+//! 收到的分帧方式读取和回复，资源订阅的通知也发往该连接。应用只在设置了 `LOCAL_PILOT_MCP_ADDR`
+//! 时监听端口，并且总是要求令牌
+
+use crate::error::LocalPilotError;
+use crate::mcp::framing::{self, Framing};
+use crate::mcp::protocol::{RequestId, ResponseError, ResponseMessage, INVALID_REQUEST, PARSE_ERROR};
+use crate::mcp::server::McpServer;
+use crate::mcp::subscriptions::ConnectionId;
+use serde_json::Value;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// 默认监听地址，只接受本机连接
+pub const DEFAULT_TCP_ADDR: &str = "127.0.0.1:7823";
+
+/// 配置了令牌时，连接上的第一条请求必须是此方法，参数为 `{"token": "..."}`
+pub const AUTHENTICATE_METHOD: &str = "authenticate";
+
+/// 设置后应用启动时在该地址上提供MCP服务，如 `127.0.0.1:7823`；未设置时不监听端口
+pub const MCP_TCP_ADDR_ENV: &str = "LOCAL_PILOT_MCP_ADDR";
+
+/// 客户端认证使用的令牌；未设置时每次启动生成随机令牌
+pub const MCP_TCP_TOKEN_ENV: &str = "LOCAL_PILOT_MCP_TOKEN";
+
+/// 应用数据目录下保存当前令牌的文件，只有当前用户可读，外部客户端从这里读取令牌
+pub const TOKEN_FILE: &str = "mcp-token";
+
+/// 监听TCP端口的MCP传输
+pub struct TcpTransport {
+    listener: TcpListener,
+    token: Option<String>,
+}
+
+impl TcpTransport {
+    /// 绑定到指定地址；不带令牌时只允许绑定回环地址，避免把本地工具暴露给网络
+    pub async fn bind(addr: SocketAddr, token: Option<String>) -> Result<Self, LocalPilotError> {
+        if !addr.ip().is_loopback() && token.is_none() {
+            return Err(LocalPilotError::InvalidInput(format!(
+                "Refusing to serve MCP on non-loopback address {} without a token",
+                addr
+            )));
+        }
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self { listener, token })
+    }
+
+    /// 实际监听的地址（绑定端口0时可用来获取分配的端口）
+    pub fn local_addr(&self) -> Result<SocketAddr, LocalPilotError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// 持续接受连接，每个连接在单独的任务中处理
+    pub async fn serve(self, server: Arc<McpServer>) -> Result<(), LocalPilotError> {
+        let token: Option<Arc<str>> = self.token.map(Into::into);
+        loop {
+            let (stream, peer) = self.listener.accept().await?;
+            let server = Arc::clone(&server);
+            let token = token.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(server, stream, token).await {
+                    tracing::warn!(%peer, error = %e, "MCP connection failed");
+                }
+            });
+        }
+    }
+}
+
+/// 在 `addr` 上提供MCP服务，直到监听出错
+pub async fn serve_tcp(server: Arc<McpServer>, addr: SocketAddr, token: Option<String>) -> Result<(), LocalPilotError> {
+    TcpTransport::bind(addr, token).await?.serve(server).await
+}
+
+/// 按 `MCP_TCP_ADDR_ENV` 和 `MCP_TCP_TOKEN_ENV` 绑定应用的TCP服务，未设置地址时返回 `None`
+pub async fn bind_from_env(data_dir: &Path) -> Result<Option<TcpTransport>, LocalPilotError> {
+    let Some(addr) = std::env::var(MCP_TCP_ADDR_ENV).ok().filter(|addr| !addr.trim().is_empty()) else {
+        return Ok(None);
+    };
+    let addr = addr
+        .trim()
+        .parse()
+        .map_err(|e| LocalPilotError::InvalidInput(format!("Invalid {} '{}': {}", MCP_TCP_ADDR_ENV, addr, e)))?;
+    let token = std::env::var(MCP_TCP_TOKEN_ENV).ok().filter(|token| !token.is_empty());
+    bind_with_token(addr, token, data_dir).await.map(Some)
+}
+
+/// 绑定到 `addr` 并要求令牌；未提供令牌时生成随机令牌，两种情况下都把令牌写入 `data_dir` 下的 `TOKEN_FILE`
+async fn bind_with_token(addr: SocketAddr, token: Option<String>, data_dir: &Path) -> Result<TcpTransport, LocalPilotError> {
+    let token = token.unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+    std::fs::create_dir_all(data_dir)?;
+    write_private_file(&data_dir.join(TOKEN_FILE), token.as_bytes())?;
+    TcpTransport::bind(addr, Some(token)).await
+}
+
+/// 写入只有当前用户可以读写的文件
+fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // 文件已存在时创建权限不起作用
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents)
+}
+
+/// 发往连接的一条消息；`framing` 为 `None` 时使用该连接最近一次请求的分帧方式
+struct Outgoing {
+    message: Value,
+    framing: Option<Framing>,
+}
+
+async fn handle_connection(
+    server: Arc<McpServer>,
+    stream: TcpStream,
+    token: Option<Arc<str>>,
+) -> Result<(), LocalPilotError> {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+    let (connection, mut notifications) = server.connect();
+    let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<Outgoing>();
+
+    // 响应和通知都经由同一个写任务发出，避免交错写入
+    let writer = tokio::spawn(async move {
+        let mut framing = Framing::default();
+        while let Some(outgoing) = outgoing_rx.recv().await {
+            framing = outgoing.framing.unwrap_or(framing);
+            let bytes = framing::encode(framing, &outgoing.message.to_string());
+            if write_half.write_all(&bytes).await.is_err() {
+                break;
+            }
+        }
+    });
+    let notification_tx = outgoing_tx.clone();
+    let forwarder = tokio::spawn(async move {
+        while let Some(notification) = notifications.recv().await {
+            let outgoing = Outgoing { message: notification.to_json_rpc(), framing: None };
+            if notification_tx.send(outgoing).is_err() {
+                break;
+            }
+        }
+    });
+
+    let result = serve_requests(&server, connection, &mut reader, &outgoing_tx, token.as_deref()).await;
+
+    server.disconnect(connection);
+    forwarder.abort();
+    drop(outgoing_tx);
+    let _ = writer.await;
+    result
+}
+
+/// 逐条读取请求并回复，直到连接关闭或认证失败；不是有效JSON的消息回复解析错误后继续读取
+async fn serve_requests(
+    server: &McpServer,
+    connection: ConnectionId,
+    reader: &mut BufReader<OwnedReadHalf>,
+    outgoing: &mpsc::UnboundedSender<Outgoing>,
+    token: Option<&str>,
+) -> Result<(), LocalPilotError> {
+    let mut authenticated = token.is_none();
+    while let Some((message, framing)) = framing::read_framed_message(reader).await? {
+        let message: Value = match serde_json::from_str(&message) {
+            Ok(message) => message,
+            Err(e) => {
+                let error = ResponseError::new(PARSE_ERROR, format!("Parse error: {}", e));
+                let _ = outgoing.send(Outgoing { message: failure(error).to_json_rpc(), framing: Some(framing) });
+                continue;
+            }
+        };
+
+        if !authenticated {
+            let id: Option<RequestId> = message.get("id").cloned().and_then(|id| serde_json::from_value(id).ok());
+            let reply = |mut response: ResponseMessage| {
+                response.id = id.clone();
+                let _ = outgoing.send(Outgoing { message: response.to_json_rpc(), framing: Some(framing) });
+            };
+            let presented = message.pointer("/params/token").and_then(Value::as_str);
+            let is_authenticate = message.get("method").and_then(Value::as_str) == Some(AUTHENTICATE_METHOD);
+            if is_authenticate && presented.zip(token).is_some_and(|(presented, token)| tokens_match(presented, token)) {
+                authenticated = true;
+                reply(success(serde_json::json!({ "authenticated": true })));
+                continue;
+            }
+            reply(failure(ResponseError::new(INVALID_REQUEST, "Unauthorized: authenticate with a valid token first")));
+            return Ok(());
+        }
+
+        if let Some(reply) = server.handle_message(connection, message).await {
+            let _ = outgoing.send(Outgoing { message: reply, framing: Some(framing) });
+        }
+    }
+    Ok(())
+}
+
+/// 比较令牌，耗时与两者在哪个位置不同无关
+fn tokens_match(presented: &str, expected: &str) -> bool {
+    presented.len() == expected.len()
+        && presented
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+fn success(result: Value) -> ResponseMessage {
+    ResponseMessage { id: None, result: Some(result), error: None }
+}
+
+fn failure(error: ResponseError) -> ResponseMessage {
+    ResponseMessage { id: None, result: None, error: Some(error) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_index::FileIndexer;
+    use crate::mcp::protocol::{NotificationMessage, METHOD_NOT_FOUND};
+    use serde_json::json;
+    use tokio::net::tcp::OwnedWriteHalf;
+
+    async fn start_server(token: Option<&str>) -> SocketAddr {
+        let db_path = std::env::temp_dir().join(format!("test_transport_{}.db", uuid::Uuid::new_v4()));
+        let indexer = Arc::new(FileIndexer::new(db_path.to_str().unwrap()).unwrap());
+        let server = Arc::new(McpServer::new(indexer));
+        let transport = TcpTransport::bind("127.0.0.1:0".parse().unwrap(), token.map(str::to_string))
+            .await
+            .unwrap();
+        let addr = transport.local_addr().unwrap();
+        tokio::spawn(transport.serve(server));
+        addr
+    }
+
+    async fn connect(addr: SocketAddr) -> (BufReader<OwnedReadHalf>, OwnedWriteHalf) {
+        let (read_half, write_half) = TcpStream::connect(addr).await.unwrap().into_split();
+        (BufReader::new(read_half), write_half)
+    }
+
+    async fn call(
+        (reader, writer): &mut (BufReader<OwnedReadHalf>, OwnedWriteHalf),
+        framing: Framing,
+        request: Value,
+    ) -> Option<Value> {
+        writer.write_all(&framing::encode(framing, &request.to_string())).await.unwrap();
+        let message = framing::read_message(reader).await.unwrap()?;
+        Some(serde_json::from_str(&message).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_client_lists_tools_over_tcp() {
+        let addr = start_server(None).await;
+        let mut connection = connect(addr).await;
+
+        let response = call(&mut connection, Framing::Newline, json!({"jsonrpc": "2.0", "id": 1, "method": "tools/list"}))
+            .await
+            .unwrap();
+        assert_eq!(response["id"], 1);
+        let tools = response["result"].as_array().unwrap();
+        assert!(tools.iter().any(|tool| tool["name"] == "file_reader"));
+
+        // 按请求的分帧方式回复
+        let response = call(&mut connection, Framing::ContentLength, json!({"jsonrpc": "2.0", "id": "p", "method": "ping"}))
+            .await
+            .unwrap();
+        assert_eq!(response, json!({"jsonrpc": "2.0", "id": "p", "result": "pong"}));
+
+        let response = call(&mut connection, Framing::Newline, json!({"jsonrpc": "2.0", "id": 2, "method": "no/such"}))
+            .await
+            .unwrap();
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_notifications_get_no_response() {
+        let addr = start_server(None).await;
+        let mut connection = connect(addr).await;
+        
+        let cancelled = NotificationMessage::cancelled(RequestId::Number(1), "user cancelled").to_json_rpc();
+        connection.1.write_all(&framing::encode(Framing::Newline, &cancelled.to_string())).await.unwrap();
+        
+        // 收到的第一条回复属于之后的请求
+        let response = call(&mut connection, Framing::Newline, json!({"jsonrpc": "2.0", "id": 2, "method": "ping"}))
+            .await
+            .unwrap();
+        assert_eq!(response["id"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_token_is_required_before_other_requests() {
+        let addr = start_server(Some("s3cret")).await;
+
+        let mut unauthenticated = connect(addr).await;
+        let response = call(&mut unauthenticated, Framing::Newline, json!({"jsonrpc": "2.0", "id": 1, "method": "tools/list"}))
+            .await
+            .unwrap();
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+        // 认证失败后连接被关闭
+        assert!(call(&mut unauthenticated, Framing::Newline, json!({"id": 2, "method": "ping"})).await.is_none());
+
+        let mut wrong_token = connect(addr).await;
+        let authenticate = |token: &str| json!({"id": 1, "method": AUTHENTICATE_METHOD, "params": {"token": token}});
+        let response = call(&mut wrong_token, Framing::Newline, authenticate("guess")).await.unwrap();
+        assert!(response.get("error").is_some());
+
+        let mut authenticated = connect(addr).await;
+        let response = call(&mut authenticated, Framing::Newline, authenticate("s3cret")).await.unwrap();
+        assert_eq!(response["result"]["authenticated"], true);
+        let response = call(&mut authenticated, Framing::Newline, json!({"id": 2, "method": "tools/list"})).await.unwrap();
+        assert!(response["result"].is_array());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_json_gets_parse_error_and_connection_stays_open() {
+        let addr = start_server(None).await;
+        let mut connection = connect(addr).await;
+        
+        connection.1.write_all(&framing::encode(Framing::ContentLength, "{\"id\": 1,")).await.unwrap();
+        let message = framing::read_message(&mut connection.0).await.unwrap().unwrap();
+        let response: Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+        assert!(response["id"].is_null());
+        
+        let response = call(&mut connection, Framing::Newline, json!({"jsonrpc": "2.0", "id": 2, "method": "ping"}))
+            .await
+            .unwrap();
+        assert_eq!(response["id"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_generated_token_is_written_and_required() {
+        let db_path = std::env::temp_dir().join(format!("test_transport_{}.db", uuid::Uuid::new_v4()));
+        let server = Arc::new(McpServer::new(Arc::new(FileIndexer::new(db_path.to_str().unwrap()).unwrap())));
+        let data_dir = std::env::temp_dir().join(format!("test_transport_token_{}", uuid::Uuid::new_v4()));
+        let transport = bind_with_token("127.0.0.1:0".parse().unwrap(), None, &data_dir).await.unwrap();
+        let addr = transport.local_addr().unwrap();
+        tokio::spawn(transport.serve(server));
+        
+        let token = std::fs::read_to_string(data_dir.join(TOKEN_FILE)).unwrap();
+        assert!(!token.is_empty());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(data_dir.join(TOKEN_FILE)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        
+        let mut unauthenticated = connect(addr).await;
+        let response = call(&mut unauthenticated, Framing::Newline, json!({"id": 1, "method": "ping"})).await.unwrap();
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+        
+        let mut authenticated = connect(addr).await;
+        let authenticate = json!({"id": 1, "method": AUTHENTICATE_METHOD, "params": {"token": token}});
+        let response = call(&mut authenticated, Framing::Newline, authenticate).await.unwrap();
+        assert_eq!(response["result"]["authenticated"], true);
+        std::fs::remove_dir_all(&data_dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_non_loopback_address_requires_token() {
+        let error = TcpTransport::bind("0.0.0.0:0".parse().unwrap(), None).await.err().unwrap();
+        assert!(matches!(error, LocalPilotError::InvalidInput(_)));
+        assert!(TcpTransport::bind("0.0.0.0:0".parse().unwrap(), Some("s3cret".to_string())).await.is_ok());
+    }
+}
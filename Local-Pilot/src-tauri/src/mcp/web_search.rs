@@ -0,0 +1,126 @@
+//! 网络搜索
+//! `web_search` 工具通过可替换的 `SearchProvider` 执行搜索，默认使用 `SEARXNG_URL` 指定的SearXNG实例
+
+use crate::error::LocalPilotError;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+/// 指定SearXNG实例地址的环境变量，如 `http://localhost:8888`
+pub const SEARXNG_URL_ENV: &str = "SEARXNG_URL";
+
+/// 每次搜索最多返回的结果数
+pub const WEB_SEARCH_RESULT_LIMIT: usize = 10;
+
+/// 单条搜索结果
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub title: String,
+    pub url: String,
+    pub snippet: String,
+}
+
+/// 搜索后端，实现此trait即可替换搜索服务
+#[async_trait]
+pub trait SearchProvider: Send + Sync {
+    /// 搜索 `query`，最多返回 `limit` 条结果
+    async fn search(&self, query: &str, limit: usize) -> Result<Vec<SearchResult>, LocalPilotError>;
+}
+
+/// 根据环境变量创建搜索后端，未配置时返回 `None`
+pub fn provider_from_env() -> Option<Box<dyn SearchProvider>> {
+    let base_url = std::env::var(SEARXNG_URL_ENV).ok().filter(|url| !url.trim().is_empty())?;
+    Some(Box::new(SearxngProvider::new(base_url)))
+}
+
+/// 使用SearXNG的JSON接口搜索（实例需在 `search.formats` 中启用 `json`）
+pub struct SearxngProvider {
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl SearxngProvider {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SearxngResponse {
+    #[serde(default)]
+    results: Vec<SearxngResult>,
+}
+
+#[derive(Deserialize)]
+struct SearxngResult {
+    #[serde(default)]
+    title: String,
+    url: String,
+    #[serde(default)]
+    content: String,
+}
+
+#[async_trait]
+impl SearchProvider for SearxngProvider {
+    async fn search(&self, query: &str, limit: usize) -> Result<Vec<SearchResult>, LocalPilotError> {
+        let response = self
+            .client
+            .get(format!("{}/search", self.base_url))
+            .query(&[("q", query), ("format", "json")])
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(LocalPilotError::Api {
+                status: status.as_u16(),
+                message: format!("SearXNG search failed: {}", response.text().await.unwrap_or_default()),
+            });
+        }
+
+        let body: SearxngResponse = response.json().await?;
+        Ok(body
+            .results
+            .into_iter()
+            .take(limit)
+            .map(|result| SearchResult { title: result.title, url: result.url, snippet: result.content })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_searxng_results_are_mapped() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/search")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("q".into(), "rust tauri".into()),
+                mockito::Matcher::UrlEncoded("format".into(), "json".into()),
+            ]))
+            .with_body(
+                r#"{"results": [
+                    {"title": "Tauri", "url": "https://tauri.app", "content": "Build apps", "engine": "ddg"},
+                    {"title": "Rust", "url": "https://www.rust-lang.org", "content": "A language"}
+                ]}"#,
+            )
+            .create_async()
+            .await;
+
+        let provider = SearxngProvider::new(format!("{}/", server.url()));
+        let results = provider.search("rust tauri", 1).await.unwrap();
+        assert_eq!(
+            results,
+            [SearchResult {
+                title: "Tauri".to_string(),
+                url: "https://tauri.app".to_string(),
+                snippet: "Build apps".to_string(),
+            }]
+        );
+        mock.assert_async().await;
+    }
+}
@@ -0,0 +1,75 @@
+//! 离线的模拟LLM提供商
+//! `api_base` 以 `mock://` 开头时使用，按脚本依次返回回复，无需网络和API密钥，用于测试和演示
+
+use crate::lock::LockExt;
+use crate::orchestrator::{Message, Role};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// 使用模拟提供商的 `api_base` 前缀
+pub const MOCK_API_BASE_PREFIX: &str = "mock://";
+
+/// 按脚本返回回复的模拟提供商
+#[derive(Default)]
+pub struct MockProvider {
+    script: Mutex<VecDeque<String>>,
+    /// 每次请求收到的对话历史
+    requests: Mutex<Vec<Vec<Message>>>,
+    /// 每次请求时以请求序号（从0开始）调用
+    on_request: Option<Box<dyn Fn(usize) + Send + Sync>>,
+}
+
+impl MockProvider {
+    /// 依次返回 `responses`，脚本用完后回显最后一条用户消息
+    pub fn new<I, S>(responses: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            script: Mutex::new(responses.into_iter().map(Into::into).collect()),
+            requests: Mutex::new(Vec::new()),
+            on_request: None,
+        }
+    }
+
+    /// 每次请求时调用 `on_request`，测试借此在对话进行到某一步时触发事件
+    pub fn with_on_request(mut self, on_request: impl Fn(usize) + Send + Sync + 'static) -> Self {
+        self.on_request = Some(Box::new(on_request));
+        self
+    }
+
+    /// 脚本中的工具调用回复，使用文字中的 `[TOOL_USE: ...]` 格式，与不使用原生工具调用的模型的回复相同
+    pub fn tool_call(name: &str, arguments: Value) -> String {
+        format!("[TOOL_USE: {} with args: {}]", name, arguments)
+    }
+
+    /// 返回下一条回复
+    pub fn respond(&self, history: &[Message]) -> String {
+        let index = {
+            let mut requests = self.requests.lock_or_recover();
+            requests.push(history.to_vec());
+            requests.len() - 1
+        };
+        if let Some(on_request) = &self.on_request {
+            on_request(index);
+        }
+        if let Some(response) = self.script.lock_or_recover().pop_front() {
+            return response;
+        }
+
+        let last_user_message = history
+            .iter()
+            .rev()
+            .find(|message| message.role == Role::User)
+            .map(|message| message.content.text())
+            .unwrap_or_default();
+        format!("(offline demo) You said: {}", last_user_message)
+    }
+
+    /// 已收到的所有请求的对话历史
+    pub fn requests(&self) -> Vec<Vec<Message>> {
+        self.requests.lock_or_recover().clone()
+    }
+}
@@ -0,0 +1,155 @@
+//! 模型路由表
+//! 按模型名称前缀选择LLM接口地址、API密钥所在的环境变量和提供商，切换模型时无需重新填写 `api_base`
+
+use crate::error::LocalPilotError;
+use crate::orchestrator::{Provider, OLLAMA_API_BASE};
+use serde::{Deserialize, Serialize};
+
+/// 一条路由：模型名称匹配 `pattern` 时使用的接口
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelRoute {
+    /// 模型名称模式，以 `*` 结尾时按前缀匹配（如 `claude-*`），否则要求完全相同
+    pub pattern: String,
+    pub api_base: String,
+    /// 保存API密钥的环境变量，本地模型等无需密钥时为 `None`
+    pub api_key_env: Option<String>,
+    pub provider: Provider,
+}
+
+impl ModelRoute {
+    pub fn new(pattern: &str, api_base: &str, api_key_env: Option<&str>, provider: Provider) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            api_base: api_base.to_string(),
+            api_key_env: api_key_env.map(str::to_string),
+            provider,
+        }
+    }
+
+    /// 匹配的长度，不匹配时为 `None`；前缀越长越具体
+    fn match_len(&self, model_name: &str) -> Option<usize> {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => model_name.starts_with(prefix).then_some(prefix.len()),
+            None => (self.pattern == model_name).then_some(self.pattern.len()),
+        }
+    }
+
+    /// 从环境变量读取API密钥；路由需要密钥但变量未设置时返回错误
+    pub fn api_key(&self, model_name: &str) -> Result<String, LocalPilotError> {
+        let Some(variable) = &self.api_key_env else {
+            return Ok(String::new());
+        };
+        match std::env::var(variable) {
+            Ok(key) if !key.is_empty() => Ok(key),
+            _ => Err(LocalPilotError::InvalidInput(format!(
+                "Model {} needs an API key in the environment variable {}, which is not set",
+                model_name, variable
+            ))),
+        }
+    }
+}
+
+/// 模型路由表
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelRouter {
+    routes: Vec<ModelRoute>,
+}
+
+impl Default for ModelRouter {
+    /// 默认路由：Claude走Anthropic，GPT走OpenAI，Llama走本机Ollama
+    fn default() -> Self {
+        Self {
+            routes: vec![
+                ModelRoute::new("claude-*", "https://api.anthropic.com/v1", Some("ANTHROPIC_API_KEY"), Provider::Anthropic),
+                ModelRoute::new(
+                    "gpt-*",
+                    "https://api.openai.com/v1/chat/completions",
+                    Some("OPENAI_API_KEY"),
+                    Provider::OpenAi,
+                ),
+                ModelRoute::new("llama-*", OLLAMA_API_BASE, None, Provider::Ollama),
+            ],
+        }
+    }
+}
+
+impl ModelRouter {
+    /// 添加路由，替换模式相同的已有路由
+    pub fn set_route(&mut self, route: ModelRoute) {
+        match self.routes.iter_mut().find(|existing| existing.pattern == route.pattern) {
+            Some(existing) => *existing = route,
+            None => self.routes.push(route),
+        }
+    }
+
+    /// 模型对应的路由，多条匹配时取模式最具体的一条
+    pub fn route(&self, model_name: &str) -> Result<&ModelRoute, LocalPilotError> {
+        self.routes
+            .iter()
+            .filter_map(|route| route.match_len(model_name).map(|len| (len, route)))
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, route)| route)
+            .ok_or_else(|| {
+                let patterns: Vec<&str> = self.routes.iter().map(|route| route.pattern.as_str()).collect();
+                LocalPilotError::InvalidInput(format!(
+                    "No provider is configured for model {}. Known model patterns: {}",
+                    model_name,
+                    patterns.join(", ")
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_routes_map_model_prefixes() {
+        let router = ModelRouter::default();
+
+        let route = router.route("claude-3-5-sonnet-20241022").unwrap();
+        assert_eq!(route.provider, Provider::Anthropic);
+        assert_eq!(route.api_base, "https://api.anthropic.com/v1");
+        assert_eq!(route.api_key_env.as_deref(), Some("ANTHROPIC_API_KEY"));
+
+        let route = router.route("gpt-4o").unwrap();
+        assert_eq!(route.provider, Provider::OpenAi);
+        assert_eq!(route.api_base, "https://api.openai.com/v1/chat/completions");
+        assert_eq!(route.api_key_env.as_deref(), Some("OPENAI_API_KEY"));
+
+        let route = router.route("llama-3.1-8b").unwrap();
+        assert_eq!(route.provider, Provider::Ollama);
+        assert_eq!(route.api_base, "http://localhost:11434/api/chat");
+        assert_eq!(route.api_key_env, None);
+        assert_eq!(route.api_key("llama-3.1-8b").unwrap(), "");
+    }
+
+    #[test]
+    fn test_unmapped_model_is_a_clear_error() {
+        let router = ModelRouter::default();
+        let error = router.route("mistral-large").unwrap_err();
+        assert!(matches!(error, LocalPilotError::InvalidInput(_)));
+        let message = error.to_string();
+        assert!(message.contains("mistral-large"));
+        assert!(message.contains("claude-*"));
+    }
+
+    #[test]
+    fn test_most_specific_route_wins_and_routes_can_be_replaced() {
+        let mut router = ModelRouter::default();
+        router.set_route(ModelRoute::new("gpt-4o-mini", "http://localhost:8080/v1/chat/completions", None, Provider::OpenAi));
+        assert_eq!(router.route("gpt-4o-mini").unwrap().api_base, "http://localhost:8080/v1/chat/completions");
+        assert_eq!(router.route("gpt-4o").unwrap().api_base, "https://api.openai.com/v1/chat/completions");
+
+        router.set_route(ModelRoute::new("gpt-*", "https://proxy.example/v1/chat/completions", Some("PROXY_KEY"), Provider::OpenAi));
+        assert_eq!(router.route("gpt-4o").unwrap().api_base, "https://proxy.example/v1/chat/completions");
+    }
+
+    #[test]
+    fn test_missing_api_key_variable_is_reported() {
+        let route = ModelRoute::new("test-*", "https://example.invalid", Some("LOCAL_PILOT_TEST_UNSET_KEY"), Provider::OpenAi);
+        let error = route.api_key("test-model").unwrap_err();
+        assert!(error.to_string().contains("LOCAL_PILOT_TEST_UNSET_KEY"));
+    }
+}
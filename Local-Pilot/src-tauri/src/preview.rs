@@ -0,0 +1,169 @@
+//! 破坏性工具调用的预览
+//! 在用户批准之前描述工具调用将产生的效果，不执行任何修改
+
+use crate::error::LocalPilotError;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// 预览处理函数：根据参数生成效果描述
+type PreviewHandler = fn(&Value) -> Result<String, LocalPilotError>;
+
+/// 描述中最多列出的目标数量
+const MAX_LISTED_TARGETS: usize = 10;
+
+/// 查找工具对应的预览处理函数，`tool_name` 不带服务器前缀
+fn preview_handler(tool_name: &str) -> Option<PreviewHandler> {
+    match tool_name {
+        "delete_file" | "delete_files" | "remove_file" | "delete" => Some(preview_delete),
+        "write_file" => Some(preview_write),
+        "edit_file" => Some(preview_edit),
+        "move_file" | "rename_file" => Some(preview_move),
+        _ => None,
+    }
+}
+
+/// 生成工具调用的效果描述；没有注册预览处理函数的工具返回错误
+pub fn preview(tool_name: &str, arguments: &Value) -> Result<String, LocalPilotError> {
+    let handler = preview_handler(tool_name).ok_or_else(|| {
+        LocalPilotError::InvalidInput(format!("No preview available for tool: {}", tool_name))
+    })?;
+    handler(arguments)
+}
+
+fn string_arg<'a>(arguments: &'a Value, key: &str) -> Result<&'a str, LocalPilotError> {
+    arguments
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| LocalPilotError::InvalidInput(format!("Missing string argument: {}", key)))
+}
+
+/// 删除：展开通配符并列出将被删除的文件
+fn preview_delete(arguments: &Value) -> Result<String, LocalPilotError> {
+    let patterns: Vec<&str> = match (arguments.get("paths"), arguments.get("path")) {
+        (Some(Value::Array(paths)), _) => paths.iter().filter_map(Value::as_str).collect(),
+        (_, Some(Value::String(path))) => vec![path.as_str()],
+        _ => return Err(LocalPilotError::InvalidInput("Missing argument: path or paths".to_string())),
+    };
+
+    let mut targets: Vec<PathBuf> = Vec::new();
+    for pattern in patterns {
+        let matches = glob::glob(pattern)
+            .map_err(|e| LocalPilotError::InvalidInput(format!("Invalid pattern {}: {}", pattern, e)))?;
+        targets.extend(matches.filter_map(Result::ok));
+    }
+    targets.sort();
+    targets.dedup();
+
+    if targets.is_empty() {
+        return Ok("will delete nothing: no files match".to_string());
+    }
+    Ok(format!(
+        "will delete {} {}: {}",
+        targets.len(),
+        if targets.len() == 1 { "file" } else { "files" },
+        list_paths(&targets)
+    ))
+}
+
+/// 写入：区分新建和覆盖，并给出大小变化
+fn preview_write(arguments: &Value) -> Result<String, LocalPilotError> {
+    let path = string_arg(arguments, "path")?;
+    let content = string_arg(arguments, "content")?;
+
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => Err(LocalPilotError::InvalidInput(format!(
+            "Cannot write to a directory: {}",
+            path
+        ))),
+        Ok(metadata) => Ok(format!(
+            "will overwrite {} ({} bytes -> {} bytes)",
+            path,
+            metadata.len(),
+            content.len()
+        )),
+        Err(_) => Ok(format!("will create {} ({} bytes)", path, content.len())),
+    }
+}
+
+/// 编辑：列出修改的次数
+fn preview_edit(arguments: &Value) -> Result<String, LocalPilotError> {
+    let path = string_arg(arguments, "path")?;
+    let edits = arguments.get("edits").and_then(Value::as_array).map_or(0, Vec::len);
+    if !Path::new(path).is_file() {
+        return Err(LocalPilotError::InvalidInput(format!("File does not exist: {}", path)));
+    }
+    Ok(format!(
+        "will apply {} {} to {}",
+        edits,
+        if edits == 1 { "edit" } else { "edits" },
+        path
+    ))
+}
+
+/// 移动：目标已存在时提示会被覆盖
+fn preview_move(arguments: &Value) -> Result<String, LocalPilotError> {
+    let source = string_arg(arguments, "source")?;
+    let destination = string_arg(arguments, "destination")?;
+    if !Path::new(source).exists() {
+        return Err(LocalPilotError::InvalidInput(format!("Source does not exist: {}", source)));
+    }
+
+    let mut description = format!("will move {} to {}", source, destination);
+    if Path::new(destination).exists() {
+        description.push_str(&format!(" (replacing existing {})", destination));
+    }
+    Ok(description)
+}
+
+fn list_paths(paths: &[PathBuf]) -> String {
+    let mut listed: Vec<String> = paths
+        .iter()
+        .take(MAX_LISTED_TARGETS)
+        .map(|path| path.display().to_string())
+        .collect();
+    if paths.len() > MAX_LISTED_TARGETS {
+        listed.push(format!("and {} more", paths.len() - MAX_LISTED_TARGETS));
+    }
+    listed.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_delete_preview_resolves_globs() {
+        let dir = std::env::temp_dir().join(format!("test_preview_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt", "keep.md"] {
+            fs::write(dir.join(name), "x").unwrap();
+        }
+
+        let pattern = dir.join("*.txt").to_string_lossy().to_string();
+        let description = preview("delete_file", &serde_json::json!({ "path": pattern })).unwrap();
+        assert_eq!(
+            description,
+            format!(
+                "will delete 3 files: {}, {}, {}",
+                dir.join("a.txt").display(),
+                dir.join("b.txt").display(),
+                dir.join("c.txt").display()
+            )
+        );
+
+        let existing = dir.join("keep.md").to_string_lossy().to_string();
+        let description = preview("write_file", &serde_json::json!({ "path": existing, "content": "hello" })).unwrap();
+        assert_eq!(description, format!("will overwrite {} (1 bytes -> 5 bytes)", existing));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_unknown_tool_has_no_preview() {
+        assert!(matches!(
+            preview("read_file", &serde_json::json!({ "path": "/tmp" })),
+            Err(LocalPilotError::InvalidInput(_))
+        ));
+    }
+}
@@ -0,0 +1,190 @@
+//! LLM API请求重试
+//! 对限流、服务端临时错误和连接失败进行指数退避重试
+
+use crate::error::LocalPilotError;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::time::{Duration, Instant};
+
+/// 重试策略
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// 首次请求之外最多重试的次数
+    pub max_retries: u32,
+    /// 第一次重试前的等待时间，之后每次翻倍
+    pub initial_backoff: Duration,
+    /// 单次等待的上限
+    pub max_backoff: Duration,
+    /// 从首次请求开始计算的总时长上限，超过后不再重试
+    pub max_elapsed: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_elapsed: Duration::from_secs(120),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// 第 `attempt` 次重试（从0开始）前的等待时间：指数退避，加上最多一半的随机抖动
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let base = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        let jitter_ms = (base.as_millis() as u64) / 2;
+        let jitter = if jitter_ms > 0 {
+            Duration::from_millis(rand::random_range(0..=jitter_ms))
+        } else {
+            Duration::ZERO
+        };
+        (base + jitter).min(self.max_backoff)
+    }
+}
+
+/// 是否为可重试的状态码；400/401/403等客户端错误重试也不会成功
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 500 | 502 | 503 | 504)
+}
+
+/// 解析 `Retry-After` 头，支持秒数和HTTP日期两种格式
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = date.with_timezone(&chrono::Utc) - chrono::Utc::now();
+    Some(wait.to_std().unwrap_or(Duration::ZERO))
+}
+
+/// 发送请求，遇到可重试的错误时按策略重试
+/// `build` 每次调用都要构建一个新的请求；不可重试的非2xx响应原样返回，由调用方处理
+pub async fn send_with_retry<F>(policy: &RetryPolicy, build: F) -> Result<Response, LocalPilotError>
+where
+    F: Fn() -> RequestBuilder,
+{
+    let started = Instant::now();
+    let mut attempt = 0;
+
+    loop {
+        let (delay, outcome) = match build().send().await {
+            Ok(response) if is_retryable_status(response.status()) => {
+                let delay = retry_after(response.headers()).unwrap_or_else(|| policy.backoff(attempt));
+                (delay, Ok(response))
+            }
+            Ok(response) => return Ok(response),
+            Err(e) if e.is_connect() || e.is_timeout() => (policy.backoff(attempt), Err(e)),
+            Err(e) => return Err(e.into()),
+        };
+
+        if attempt >= policy.max_retries || started.elapsed() + delay > policy.max_elapsed {
+            return outcome.map_err(LocalPilotError::from);
+        }
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fast_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 4,
+            initial_backoff: Duration::from_millis(5),
+            max_backoff: Duration::from_millis(20),
+            max_elapsed: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        let policy = RetryPolicy::default();
+        assert!(policy.backoff(0) >= Duration::from_millis(500));
+        assert!(policy.backoff(0) <= Duration::from_millis(750));
+        assert!(policy.backoff(2) >= Duration::from_secs(2));
+        assert_eq!(policy.backoff(20), policy.max_backoff);
+    }
+
+    #[test]
+    fn test_retry_after_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, "7".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(7)));
+
+        headers.insert(RETRY_AFTER, "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+
+        headers.insert(RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_failures_until_success() {
+        let mut server = mockito::Server::new_async().await;
+        let failures = server
+            .mock("POST", "/v1/chat")
+            .with_status(503)
+            .expect(2)
+            .create_async()
+            .await;
+        let success = server
+            .mock("POST", "/v1/chat")
+            .with_status(200)
+            .with_body("ok")
+            .expect(1)
+            .create_async()
+            .await;
+
+        let client = reqwest::Client::new();
+        let url = format!("{}/v1/chat", server.url());
+        let response = send_with_retry(&fast_policy(), || client.post(&url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "ok");
+        failures.assert_async().await;
+        success.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_are_not_retried() {
+        let mut server = mockito::Server::new_async().await;
+        let unauthorized = server
+            .mock("POST", "/v1/chat")
+            .with_status(401)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let client = reqwest::Client::new();
+        let url = format!("{}/v1/chat", server.url());
+        let response = send_with_retry(&fast_policy(), || client.post(&url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        unauthorized.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_retries() {
+        let mut server = mockito::Server::new_async().await;
+        let unavailable = server
+            .mock("POST", "/v1/chat")
+            .with_status(503)
+            .expect(3)
+            .create_async()
+            .await;
+
+        let policy = RetryPolicy { max_retries: 2, ..fast_policy() };
+        let client = reqwest::Client::new();
+        let url = format!("{}/v1/chat", server.url());
+        let response = send_with_retry(&policy, || client.post(&url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        unavailable.assert_async().await;
+    }
+}
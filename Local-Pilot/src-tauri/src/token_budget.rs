@@ -0,0 +1,86 @@
+//! 上下文长度控制
+//! 估算系统提示和对话历史的token数，超过模型上限时丢弃最旧的对话轮次
+
+use crate::orchestrator::{ContentPart, Message, MessageContent, Role};
+
+/// 未单独配置的模型使用的上下文上限
+pub const DEFAULT_TOKEN_LIMIT: usize = 8192;
+
+/// 每条消息在内容之外的固定开销（角色标记等）
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// token计数器
+pub trait Tokenizer: Send + Sync {
+    /// 估算文本的token数
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+/// 按字符数估算token：英文约4个字符一个token，中日韩字符按一个token计
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EstimateTokenizer;
+
+impl Tokenizer for EstimateTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        let wide = text.chars().filter(|c| c.len_utf8() >= 3).count();
+        let narrow = text.chars().count() - wide;
+        wide + narrow.div_ceil(4)
+    }
+}
+
+/// 单条消息的token数
+pub fn message_tokens(tokenizer: &dyn Tokenizer, message: &Message) -> usize {
+    let content = match &message.content {
+        MessageContent::Text(text) => tokenizer.count_tokens(text),
+        // 图片不按文字计数
+        MessageContent::Parts(parts) => parts
+            .iter()
+            .map(|part| match part {
+                ContentPart::Text { text } => tokenizer.count_tokens(text),
+                ContentPart::ToolUse { name, input, .. } => {
+                    tokenizer.count_tokens(name) + tokenizer.count_tokens(&input.to_string())
+                }
+                ContentPart::ToolResult { content, .. } => tokenizer.count_tokens(content),
+                ContentPart::Image(_) => 0,
+            })
+            .sum(),
+    };
+    content + MESSAGE_OVERHEAD_TOKENS
+}
+
+/// 选出能放进 `budget` 的最近消息，返回保留部分的起始下标
+/// 保留部分总是从用户消息开始；即使最后一轮对话本身超出预算也会保留，交给API报错
+pub fn fit_history(tokenizer: &dyn Tokenizer, history: &[Message], budget: usize) -> usize {
+    let mut used = 0;
+    let mut start = history.len();
+    let mut kept_turn = history.len();
+
+    for (index, message) in history.iter().enumerate().rev() {
+        used += message_tokens(tokenizer, message);
+        if used > budget && kept_turn < history.len() {
+            break;
+        }
+        start = index;
+        if message.role == Role::User {
+            kept_turn = index;
+        }
+    }
+
+    // 从完整轮次的开头开始，避免以回复或工具结果开头
+    if kept_turn < history.len() {
+        kept_turn
+    } else {
+        start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_tokenizer() {
+        assert_eq!(EstimateTokenizer.count_tokens(""), 0);
+        assert_eq!(EstimateTokenizer.count_tokens("abcdefgh"), 2);
+        assert_eq!(EstimateTokenizer.count_tokens("本地文件"), 4);
+    }
+}
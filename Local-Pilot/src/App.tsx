@@ -59,11 +59,8 @@ function App() {
     if (!pendingApproval) return;
     
     try {
-      // 调用后端批准工具调用
-      const result = await invoke("approve_tool_call", {
-        toolName: pendingApproval.toolName,
-        arguments: JSON.stringify(pendingApproval.arguments)
-      });
+      // 调用后端批准工具调用，参数由后端按id取出
+      const result = await invoke("approve_tool_call", { id: pendingApproval.id });
       
       // 添加操作结果到消息列表
       const resultMessage: Message = {
@@ -100,11 +97,19 @@ function App() {
   };
 
   // 拒绝操作
-  const handleRejectOperation = () => {
+  const handleRejectOperation = async () => {
+    if (!pendingApproval) return;
+    
+    try {
+      await invoke("reject_tool_call", { id: pendingApproval.id });
+    } catch (error) {
+      console.error("Error rejecting operation:", error);
+    }
+    
     // 添加拒绝消息到消息列表
     const rejectMessage: Message = {
       id: Date.now().toString(),
-      content: `操作已拒绝: ${pendingApproval?.description || '未知操作'}`,
+      content: `操作已拒绝: ${pendingApproval.description}`,
       role: "assistant",
       timestamp: new Date(),
     };
@@ -164,12 +169,14 @@ function App() {
       });
       
       if (result === "PENDING_APPROVAL") {
-        // 显示确认卡片 - 在实际应用中，后端可能返回更多详细信息
+        // 显示确认卡片，批准或拒绝时按后端分配的id指定调用
+        const pending = await invoke("list_pending_approvals");
+        const call = pending[0];
         setPendingApproval({
-          id: Date.now().toString(),
-          toolName: "dangerous_operation", // 实际应用中应从后端获取
-          arguments: { path: "/example/path" }, // 实际应用中应从后端获取
-          description: "执行潜在危险操作: 删除 /example/path"
+          id: call.id,
+          toolName: call.tool_name,
+          arguments: call.arguments,
+          description: `执行需要批准的操作: ${call.tool_name}`
         });
       } else {
         // 显示AI响应
@@ -53,8 +53,16 @@ export const invoke = async (command: string, args?: Record<string, unknown>): P
         return `Processed message: "${args?.message}"`;
       }
     
+    case 'list_pending_approvals':
+      return [
+        { id: 'pending-1', tool_name: 'fs__delete_file', arguments: { path: '/example/path' } }
+      ];
+    
     case 'approve_tool_call':
-      return `Tool call ${args?.toolName} approved and executed`;
+      return `Tool call ${args?.id} approved and executed`;
+    
+    case 'reject_tool_call':
+      return `Tool call rejected: ${args?.id}`;
     
     case 'search_local_files':
       // Return mock search results